@@ -1,162 +1,584 @@
 use anyhow::{Context, Result};
-use clap::Parser;
-use ffmpeg::segment::extract_non_video_streams;
-use futures::stream::{FuturesUnordered, StreamExt};
-use std::path::PathBuf;
-use std::sync::Arc;
-use tokio::sync::{Mutex, Semaphore};
-use tracing::{debug, error, info, instrument, warn};
-use video_encoding_system::chunk::{convert_files_to_chunks, verify_ffmpeg};
-use video_encoding_system::ffmpeg;
-
-pub mod video_encoding {
-    tonic::include_proto!("video_encoding");
-}
-
-use video_encoding::video_encoding_service_client::VideoEncodingServiceClient;
-use video_encoding::EncodeChunkRequest;
-use video_encoding_system::chunk::{split_video, Chunk};
-use video_encoding_system::config::create_temp_config;
-use video_encoding_system::ffmpeg::concat::concatenate_videos_and_copy_streams;
-use video_encoding_system::logging::init_logging;
-use video_encoding_system::settings::Settings;
-
-const MAX_MESSAGE_SIZE: usize = 1024 * 1024 * 1024; // 1 GB
+use clap::{Args, CommandFactory, Parser, Subcommand};
+use indicatif::MultiProgress;
+use std::path::{Path, PathBuf};
+use tracing::{debug, info, instrument, warn};
+use video_encoding_system::chunk::verify_ffmpeg;
+use video_encoding_system::encode::{encode, EncodeOptions};
+use video_encoding_system::encoder::profile::Profile;
+use video_encoding_system::encoder::EncoderKind;
+use video_encoding_system::ffmpeg::compare::{pick_frames, write_comparison, ComparisonLayout};
+use video_encoding_system::ffmpeg::trim::Position;
+use video_encoding_system::generate::{generate, Generate};
+use video_encoding_system::job::JobSpec;
+use video_encoding_system::logging::{
+    init_bars_logging, init_file_logging, init_logging, init_stderr_logging, LogFormat, Verbosity,
+};
+use video_encoding_system::nodes::{benchmark_nodes, check_nodes, print_node_status};
+use video_encoding_system::settings::{
+    Chapters, ConcatMethod, ContentDetection, ContentType, CrfSearch, Deinterlace, ExistingTemp,
+    OpenGop, OverwritePolicy, PackageFormat, QualityFloor, QualityMetric, ReportFormat, Settings,
+    SplitMethod, StdoutFormat, SyncCheck, VersionPolicy, VmafModel, DEFAULT_CONFIG,
+};
+use video_encoding_system::telemetry::shutdown_tracing;
 
 /// CLI arguments for the video encoding client
 #[derive(Parser, Debug, Clone)]
-#[command(author, version, about, long_about = None)]
+#[command(name = "client", author, version, about, long_about = None)]
 struct Cli {
-    /// Input video file path
-    #[arg(short, long)]
-    input_file: PathBuf,
-
-    /// Output video file path
-    #[arg(short, long)]
-    output_file: String,
+    #[command(subcommand)]
+    command: Command,
 
     /// Path to the configuration file
-    #[arg(long)]
+    #[arg(long, global = true)]
     config_file: Option<PathBuf>,
 
     /// List of node addresses
-    #[arg(short, long)]
+    #[arg(short, long, global = true)]
     nodes: Vec<String>,
 
+    #[command(flatten)]
+    verbosity: Verbosity,
+
+    /// Format of log lines, on the console and in the log file
+    #[arg(long, value_enum, default_value_t, global = true)]
+    log_format: LogFormat,
+}
+
+/// Options of an encode
+#[derive(Args, Debug, Clone)]
+struct EncodeArgs {
+    /// Input video file path, VapourSynth script with `.vpy` extension,
+    /// image sequence pattern like `frames/%06d.png`, or HTTP(S) URL to download.
+    /// Multiple inputs or a directory of them are encoded into output directory
+    #[arg(short, long, required_unless_present = "job", num_args = 1..)]
+    input_file: Vec<PathBuf>,
+
+    /// Output video file path, directory when encoding multiple inputs, or `-` for stdout
+    #[arg(short, long, required_unless_present = "job")]
+    output_file: Option<String>,
+
+    /// Job file in YAML, TOML or JSON with input, output, preset, zones, tracks and
+    /// nodes of the encode. Options of the command line override it
+    #[arg(long)]
+    job: Option<PathBuf>,
+
+    /// Check ffmpeg, nodes and inputs, and print the plan of the encode without
+    /// splitting or encoding anything
+    #[arg(long)]
+    dry_run: bool,
+
+    /// Encode a few chunks spread over the inputs, print size and time of the whole
+    /// encode they extrapolate to, and exit
+    #[arg(long, conflicts_with = "dry_run")]
+    estimate: bool,
+
+    /// Show a dashboard of nodes and chunks in the terminal instead of log lines,
+    /// logs are only written to the log file
+    #[arg(long)]
+    tui: bool,
+
+    /// Show progress bars of the job and every node, with log lines above them
+    #[arg(long, conflicts_with = "tui")]
+    progress: bool,
+
+    /// Serve metrics of the encode at /metrics for Prometheus, like `0.0.0.0:9100`
+    #[arg(long)]
+    metrics_address: Option<String>,
+
+    /// Serve a web UI of the encode, like `0.0.0.0:8080`
+    #[arg(long)]
+    web_address: Option<String>,
+
     /// List of slot numbers corresponding to each node
     #[arg(long)]
     slots: Vec<usize>,
 
+    /// Named preset of the settings file, like `animation` for `[presets.animation]`.
+    /// Options below override its settings
+    #[arg(long)]
+    preset: Option<String>,
+
+    /// Encoder that nodes encode chunks with
+    #[arg(long, value_enum)]
+    encoder: Option<EncoderKind>,
+
+    /// What is done when nodes have different versions of the encoder
+    #[arg(long, value_enum)]
+    version_policy: Option<VersionPolicy>,
+
+    /// Version of the encoder that nodes have to report, like `x264 0.164.3108 31e19f9`
+    #[arg(long)]
+    encoder_version: Option<String>,
+
     /// Encoder parameters, that include encoder and parameters for it
     #[arg(long)]
     encoder_params: Option<Vec<String>>,
 
+    /// Built-in parameters of the encoder, which --encoder-params override
+    #[arg(long, value_enum)]
+    profile: Option<Profile>,
+
+    /// Number of passes every chunk is encoded in, 1 or 2
+    #[arg(long)]
+    passes: Option<u32>,
+
+    /// ffmpeg filter chain applied to every chunk before it's encoded,
+    /// like `crop=1920:800,scale=-2:720`
+    #[arg(long = "vf")]
+    video_filter: Option<String>,
+
+    /// Pixel format chunks are encoded in, like yuv420p10le
+    #[arg(long)]
+    pix_fmt: Option<String>,
+
+    /// Bit depth chunks are encoded in. 8-bit sources are encoded in 10 bits
+    /// by AV1 encoders unless it's set
+    #[arg(long)]
+    bit_depth: Option<u32>,
+
+    /// Encode losslessly in pixel format of the source, for archival intermediates
+    #[arg(long)]
+    lossless: bool,
+
+    /// Average bitrate of the output in kbit/s, distributed across chunks by complexity
+    #[arg(long)]
+    target_bitrate: Option<u64>,
+
+    /// Detect animation or live action for every input or every chunk, and apply
+    /// parameters tuned for it
+    #[arg(long, value_enum)]
+    detect_content: Option<ContentDetection>,
+
+    /// Apply parameters tuned for this type of content, instead of detecting it
+    #[arg(long, value_enum)]
+    content_type: Option<ContentType>,
+
+    /// Select CRF of every output, so sampled chunks reach this score of quality metric
+    #[arg(long)]
+    target_quality: Option<f64>,
+
+    /// Metric that target quality is measured with
+    #[arg(long, value_enum)]
+    quality_metric: Option<QualityMetric>,
+
+    /// Model built into libvmaf that VMAF is computed with
+    #[arg(long, value_enum)]
+    vmaf_model: Option<VmafModel>,
+
+    /// JSON model file of libvmaf that VMAF is computed with, sent to nodes
+    #[arg(long)]
+    vmaf_model_path: Option<PathBuf>,
+
+    /// Measure quality of every this many frames only, which is faster but less exact
+    #[arg(long)]
+    quality_subsample: Option<u32>,
+
+    /// Break quality of every output down by scene
+    #[arg(long)]
+    scene_report: bool,
+
+    /// Metrics nodes measure quality of every chunk with, reported for every output
+    #[arg(long, value_enum, value_delimiter = ',')]
+    quality_report: Option<Vec<QualityMetric>>,
+
+    /// Score every chunk has to reach, chunks below it are encoded again with lower CRF
+    #[arg(long)]
+    quality_floor: Option<f64>,
+
+    /// Decode every encoded chunk before it's accepted, and encode it again when
+    /// it doesn't decode cleanly
+    #[arg(long)]
+    verify_decode: bool,
+
+    /// Encode chunk again when it has other number of frames than its source
+    #[arg(long)]
+    verify_frames: bool,
+
+    /// Check sync of audio and video of every output against its source
+    #[arg(long, value_enum)]
+    sync_check: Option<SyncCheck>,
+
+    /// Pin deterministic encoder parameters, and record hashes of encoded chunks
+    /// next to every output
+    #[arg(long)]
+    reproducible: bool,
+
+    /// Encode reproducibly, and compare hashes of chunks with the ones recorded
+    /// by a previous encode of the output
+    #[arg(long)]
+    verify_reproduction: bool,
+
+    /// Write report with scores, bitrate, encode time and node of every chunk next
+    /// to every output
+    #[arg(long, value_enum)]
+    report: Option<ReportFormat>,
+
+    /// Select CRF for every output on this machine, or for every chunk on its node
+    #[arg(long, value_enum)]
+    crf_search: Option<CrfSearch>,
+
+    /// How encoded chunks are joined into the output
+    #[arg(long, value_enum)]
+    concat: Option<ConcatMethod>,
+
+    /// Write MP4 outputs fragmented and compatible with CMAF
+    #[arg(long)]
+    fragmented: bool,
+
+    /// Container of output written to stdout, when output file is `-`
+    #[arg(long, value_enum)]
+    stdout_format: Option<StdoutFormat>,
+
+    /// Replace the output file when it exists
+    #[arg(long, group = "overwrite_policy")]
+    overwrite: bool,
+
+    /// Fail before anything is encoded when the output file exists
+    #[arg(long, group = "overwrite_policy")]
+    no_overwrite: bool,
+
+    /// Write output under the first free name when it exists, like `movie_1.mkv`
+    #[arg(long, group = "overwrite_policy")]
+    unique_name: bool,
+
+    /// What is done with files left in the temporary directory by an earlier encode
+    #[arg(long, value_enum)]
+    existing_temp: Option<ExistingTemp>,
+
+    /// Streams of the input muxed after video, in this order, like `a:1,a:0,s`
+    #[arg(long, value_delimiter = ',')]
+    map: Vec<String>,
+
+    /// Join chunks encoded from the start of every output into its preview while
+    /// the rest is encoded
+    #[arg(long)]
+    preview: bool,
+
+    /// Package every output into segments with DASH manifest, HLS playlists or both
+    #[arg(long, value_enum)]
+    package: Option<PackageFormat>,
+
+    /// Maximum rate of VBV buffer in kbit/s
+    #[arg(long)]
+    maxrate: Option<u64>,
+
+    /// Size of VBV buffer in kbit
+    #[arg(long)]
+    bufsize: Option<u64>,
+
+    /// Synthesize film grain of this strength in ISO, with a photon noise table
+    #[arg(long)]
+    photon_noise: Option<u32>,
+
+    /// Synthesize film grain that matches grain measured in the source
+    #[arg(long)]
+    measure_grain: bool,
+
+    /// Denoise chunks, 1.0 when strength isn't given, and synthesize the removed grain
+    #[arg(long, num_args = 0..=1, default_missing_value = "1.0")]
+    denoise: Option<f64>,
+
     /// Temporary directory for processing
     #[arg(long)]
     temp_dir: Option<PathBuf>,
 
-    /// Duration of each video segment in seconds
+    /// Duration of each video segment in seconds, maximum duration when splitting by scenes
     #[arg(long)]
     segment_duration: Option<f64>,
-}
 
-/// Represents a node connection with its processing capacity
-#[derive(Clone)]
-struct NodeConnection {
-    client: VideoEncodingServiceClient<tonic::transport::Channel>,
-    address: String,
-    semaphore: Arc<Semaphore>,
-}
+    /// Frame rate of image sequence input, like `24` or `24000/1001`
+    #[arg(long)]
+    frame_rate: Option<String>,
 
-/// Represents the state of the encoding process
-struct EncodingState {
-    /// Chunks waiting to be encoded
-    pending_chunks: Vec<Chunk>,
-    /// Chunks that have been successfully encoded
-    completed_chunks: Vec<Chunk>,
-}
+    /// Number of frames in each video segment, used instead of segment duration
+    #[arg(long)]
+    segment_frames: Option<usize>,
 
-#[tokio::main]
-#[instrument]
-async fn main() -> Result<()> {
-    init_logging();
-    info!("Starting video encoding client");
+    /// How input video is split into chunks
+    #[arg(long, value_enum)]
+    split_method: Option<SplitMethod>,
 
-    let cli = Cli::parse();
-    debug!("CLI arguments: {:?}", cli);
+    /// Split frame-accurately by re-encoding chunks to lossless intermediate
+    #[arg(long)]
+    lossless_intermediate: bool,
 
-    let settings = load_settings(&cli)?;
-    verify_ffmpeg()?;
+    /// What to do when input has open GOPs, which break chunks split by stream copy
+    #[arg(long, value_enum)]
+    open_gop: Option<OpenGop>,
 
-    let config = create_temp_config(&settings, &cli.input_file, &cli.output_file);
+    /// Extract chunks from the input when they are dispatched, instead of splitting it upfront
+    #[arg(long)]
+    extract_on_demand: bool,
 
-    let nodes = initialize_nodes(&settings.client.node_addresses, &cli.slots).await?;
+    /// Read split points from JSON file written by --export-splits, instead of computing them
+    #[arg(long)]
+    import_splits: Option<PathBuf>,
 
-    let segments = split_video(
-        &cli.input_file,
-        settings.processing.segment_duration,
-        &config.segment_dir(),
-        &settings.client.encoder_params,
-        &config.encode_dir(),
-    )?;
+    /// Write computed split points to JSON file
+    #[arg(long)]
+    export_splits: Option<PathBuf>,
 
-    let non_video_streams = extract_non_video_streams(&cli.input_file, &config.temp_dir)?;
+    /// File of zones that override encoder parameters for frame ranges, one per line
+    /// like `1000 1500 -crf 40`
+    #[arg(long)]
+    zones: Option<PathBuf>,
 
-    let chunks = convert_files_to_chunks(segments, settings.client.encoder_params)?;
+    /// Encode only part of the input starting here, as time (`90`, `01:30`) or frame (`2160f`)
+    #[arg(long)]
+    start: Option<Position>,
 
-    info!("Created {} chunks from segments", chunks.len());
+    /// Encode only part of the input ending here, as time (`90`, `01:30`) or frame (`2160f`)
+    #[arg(long)]
+    end: Option<Position>,
 
-    // Initializing client state
-    let encoding_state = Arc::new(Mutex::new(EncodingState {
-        pending_chunks: chunks,
-        completed_chunks: Vec::new(),
-    }));
+    /// How interlaced input is processed before splitting
+    #[arg(long, value_enum)]
+    deinterlace: Option<Deinterlace>,
 
-    let mut futures = FuturesUnordered::new();
+    /// Keep timestamps of variable frame rate input in the output, requires mkvmerge
+    #[arg(long)]
+    preserve_timestamps: bool,
 
-    // Start encoding tasks for each node
-    for node in nodes {
-        let state_clone = Arc::clone(&encoding_state);
-        futures.push(tokio::spawn(encode_chunks_on_node(node, state_clone)));
-    }
+    /// Transcode audio tracks to this ffmpeg codec, like `libopus`, instead of copying them
+    #[arg(long)]
+    audio_codec: Option<String>,
 
-    // Wait for all encoding tasks to complete
-    while let Some(result) = futures.next().await {
-        if let Err(e) = result {
-            error!("node task failed: {}", e);
-        }
-    }
+    /// Bitrate of transcoded audio tracks in kbit/s
+    #[arg(long)]
+    audio_bitrate: Option<u32>,
 
-    let encoding_state = encoding_state.lock().await;
-    let mut encoded_chunks = encoding_state.completed_chunks.clone();
-    encoded_chunks.sort_by_key(|chunk| chunk.index);
+    /// Keep only audio tracks of these languages, like `jpn,eng`
+    #[arg(long, value_delimiter = ',')]
+    audio_lang: Vec<String>,
 
-    if encoded_chunks.len()
-        != encoding_state.pending_chunks.len() + encoding_state.completed_chunks.len()
-    {
-        warn!("Some chunks were not encoded successfully");
-    }
+    /// Keep only audio tracks with these indices among audio tracks, from 0
+    #[arg(long, value_delimiter = ',')]
+    audio_tracks: Vec<usize>,
+
+    /// Keep only subtitle tracks of these languages, `none` drops all of them
+    #[arg(long, value_delimiter = ',')]
+    keep_subs: Vec<String>,
+
+    /// Keep only subtitle tracks with these indices among subtitle tracks, from 0
+    #[arg(long, value_delimiter = ',')]
+    sub_tracks: Vec<usize>,
+
+    /// Whether chapters of the input are kept in the output
+    #[arg(long, value_enum)]
+    chapters: Option<Chapters>,
+
+    /// Global tag written into every output, like `comment=Encoded for archive`.
+    /// Can be repeated
+    #[arg(long = "tag")]
+    tags: Vec<String>,
+
+    /// Upload limit for all nodes combined, in bytes per second
+    #[arg(long)]
+    upload_limit: Option<u64>,
+
+    /// Download limit for all nodes combined, in bytes per second
+    #[arg(long)]
+    download_limit: Option<u64>,
+}
 
-    info!("Concatenating encoded chunks");
+#[derive(Subcommand, Debug, Clone)]
+enum Command {
+    /// Encode inputs into outputs on nodes
+    Encode(EncodeArgs),
+    /// Continue an interrupted encode, given the same options. Files of the encode are
+    /// kept in the temporary directory, and chunks encoded already aren't sent again
+    Resume(EncodeArgs),
+    /// Print status of all configured nodes
+    Status,
+    /// Manage nodes
+    Nodes {
+        #[command(subcommand)]
+        command: NodesCommand,
+    },
+    /// Encode the start of an input on every node, and compare their speed
+    Benchmark {
+        /// Video the sample is cut from
+        input: PathBuf,
+        /// Duration of the sample in seconds
+        #[arg(long, default_value_t = 10.0)]
+        duration: f64,
+    },
+    /// Write matching frames of a source and its encode as images, to compare them by eye.
+    /// Frames are picked in dark and moving parts of the source, where encodes fail first
+    Compare {
+        /// Source video
+        source: PathBuf,
+        /// Encoded video
+        output: PathBuf,
+        /// Number of compared frames, spread over the source
+        #[arg(long, default_value_t = 8)]
+        frames: usize,
+        #[arg(long, value_enum, default_value_t)]
+        layout: ComparisonLayout,
+        /// Directory images are written into
+        #[arg(long, default_value = "comparison")]
+        dir: PathBuf,
+    },
+    /// Write a commented config file with default settings of client, node and processing
+    InitConfig {
+        /// Path the config is written to
+        #[arg(default_value = "config.toml")]
+        path: PathBuf,
+        /// Replace the file when it exists
+        #[arg(long)]
+        force: bool,
+    },
+    /// Generate shell completions or man pages
+    #[command(hide = true)]
+    Generate {
+        #[command(subcommand)]
+        what: Generate,
+    },
+}
+
+#[derive(Subcommand, Debug, Clone)]
+enum NodesCommand {
+    /// Check that every node is reachable and has the encoder settings select
+    Check,
+}
+
+#[tokio::main]
+#[instrument]
+async fn main() -> Result<()> {
+    let mut cli = Cli::parse();
+    // Completions are printed to stdout, without logs
+    if let Command::Generate { what } = &cli.command {
+        return Ok(generate(Cli::command(), what)?);
+    }
+    if let Command::Encode(args) | Command::Resume(args) = &mut cli.command {
+        if let Some(path) = args.job.clone() {
+            let job = JobSpec::from_file(&path)?;
+            apply_job(&mut cli.config_file, &mut cli.nodes, args, job);
+        }
+    }
+    // Output written to stdout can't be mixed with logs, neither can the dashboard
+    let filter = cli.verbosity.filter(env!("CARGO_CRATE_NAME"));
+    let log = Settings::log_settings(cli.config_file.as_deref());
+    let mut bars = None;
+    match &cli.command {
+        Command::Encode(args) | Command::Resume(args) if args.tui => {
+            init_file_logging(&filter, cli.log_format, &log)
+        }
+        Command::Encode(args) | Command::Resume(args) if args.progress => {
+            let multi = MultiProgress::new();
+            init_bars_logging(multi.clone(), &filter, cli.log_format, &log);
+            bars = Some(multi);
+        }
+        Command::Encode(args) | Command::Resume(args)
+            if args.output_file.as_deref() == Some("-") =>
+        {
+            init_stderr_logging(&filter, cli.log_format, &log)
+        }
+        _ => init_logging(&filter, cli.log_format, &log),
+    }
+    info!("Starting video encoding client");
+    debug!("CLI arguments: {:?}", cli);
 
-    let encoded_paths: Vec<PathBuf> = encoded_chunks
-        .iter()
-        .map(|chunk| chunk.encoded_path.clone().unwrap())
-        .collect();
+    let result = match &cli.command {
+        // Config is written before any is loaded, there may be none yet
+        Command::InitConfig { path, force } => init_config(path, *force),
+        Command::Encode(args) => {
+            encode(&encode_options(args), load_settings(&cli)?, false, bars).await
+        }
+        Command::Resume(args) => {
+            encode(&encode_options(args), load_settings(&cli)?, true, bars).await
+        }
+        Command::Status => print_node_status(&load_settings(&cli)?).await,
+        Command::Nodes {
+            command: NodesCommand::Check,
+        } => check_nodes(&load_settings(&cli)?).await,
+        Command::Benchmark { input, duration } => {
+            benchmark_nodes(input, *duration, &load_settings(&cli)?).await
+        }
+        Command::Compare {
+            source,
+            output,
+            frames,
+            layout,
+            dir,
+        } => compare_encode(source, output, *frames, *layout, dir).await,
+        Command::Generate { .. } => Ok(()),
+    };
+    shutdown_tracing().await;
+    result
+}
 
-    concatenate_videos_and_copy_streams(
-        encoded_paths,
-        &non_video_streams,
-        &PathBuf::from(&cli.output_file),
-        &config.temp_dir,
-        encoded_chunks.len(),
-    )?;
+/// Options of the encode of `args` that aren't settings
+fn encode_options(args: &EncodeArgs) -> EncodeOptions {
+    EncodeOptions {
+        input_file: args.input_file.clone(),
+        output_file: args.output_file.clone(),
+        slots: args.slots.clone(),
+        start: args.start,
+        end: args.end,
+        dry_run: args.dry_run,
+        estimate: args.estimate,
+        tui: args.tui,
+    }
+}
 
-    info!("Video encoding completed successfully");
+/// Fills options of an encode that aren't given with ones of `job`
+fn apply_job(
+    config_file: &mut Option<PathBuf>,
+    nodes: &mut Vec<String>,
+    args: &mut EncodeArgs,
+    job: JobSpec,
+) {
+    debug!("Applying job {:?}", job);
+    if args.input_file.is_empty() {
+        args.input_file = job.input;
+    }
+    if args.output_file.is_none() {
+        args.output_file = job
+            .output
+            .map(|output| output.to_string_lossy().into_owned());
+    }
+    if config_file.is_none() {
+        *config_file = job.config;
+    }
+    args.preset = args.preset.take().or(job.preset);
+    args.zones = args.zones.take().or(job.zones);
+    if nodes.is_empty() {
+        *nodes = job.nodes;
+    }
+    if args.slots.is_empty() {
+        args.slots = job.slots;
+    }
 
-    // Remove temp config folder recursively
-    config.delete()?;
+    let tracks = job.tracks;
+    if args.audio_lang.is_empty() && args.audio_tracks.is_empty() {
+        args.audio_lang = tracks.audio_languages;
+        args.audio_tracks = tracks.audio_tracks;
+    }
+    if args.keep_subs.is_empty() && args.sub_tracks.is_empty() {
+        args.keep_subs = tracks.subtitle_languages;
+        args.sub_tracks = tracks.subtitle_tracks;
+    }
+}
 
+/// Writes commented default config to `path`, without replacing existing file unless `force`
+fn init_config(path: &Path, force: bool) -> Result<()> {
+    if path.exists() && !force {
+        anyhow::bail!(
+            "{:?} already exists, use --force to replace it or choose other path",
+            path
+        );
+    }
+    if let Some(parent) = path
+        .parent()
+        .filter(|parent| !parent.as_os_str().is_empty())
+    {
+        std::fs::create_dir_all(parent)?;
+    }
+    std::fs::write(path, DEFAULT_CONFIG)?;
+    info!("Wrote default config to {:?}", path);
+    println!("Wrote default config to {}", path.display());
     Ok(())
 }
 
@@ -167,187 +589,353 @@ fn load_settings(cli: &Cli) -> Result<Settings> {
         .config_file
         .as_ref()
         .map(|path| Settings::from_file(path))
-        .unwrap_or_else(|| Settings::new())?;
+        .unwrap_or_else(Settings::new)?;
 
     if !cli.nodes.is_empty() {
         settings.client.node_addresses = cli.nodes.clone();
     }
 
+    match &cli.command {
+        Command::Encode(args) | Command::Resume(args) => apply_encode_args(&mut settings, args)?,
+        _ => settings.apply_profile(None)?,
+    }
+
+    settings.validate_client()?;
+    Ok(settings)
+}
+
+/// Overrides settings with options of an encode
+fn apply_encode_args(settings: &mut Settings, args: &EncodeArgs) -> Result<()> {
+    if let Some(preset) = &args.preset {
+        settings.apply_preset(preset)?;
+    }
+
+    if let Some(encoder) = args.encoder {
+        settings.client.encoder = encoder;
+    }
+
+    if let Some(passes) = args.passes {
+        settings.client.passes = passes;
+    }
+
+    if let Some(filter) = &args.video_filter {
+        settings.client.video_filters = vec![filter.clone()];
+    }
+
+    if let Some(pix_fmt) = &args.pix_fmt {
+        settings.client.pix_fmt = Some(pix_fmt.clone());
+    }
+
+    if let Some(bit_depth) = args.bit_depth {
+        settings.client.bit_depth = Some(bit_depth);
+    }
+
+    if let Some(target) = args.target_bitrate {
+        settings.client.bitrate.target = Some(target);
+    }
+
+    if let Some(maxrate) = args.maxrate {
+        settings.client.bitrate.maxrate = Some(maxrate);
+    }
+
+    if let Some(bufsize) = args.bufsize {
+        settings.client.bitrate.bufsize = Some(bufsize);
+    }
+
+    if args.lossless {
+        settings.client.lossless = true;
+    }
+
+    if let Some(policy) = args.version_policy {
+        settings.client.version_policy = policy;
+    }
+
+    if let Some(version) = &args.encoder_version {
+        settings.client.encoder_version = Some(version.clone());
+    }
+
+    if let Some(detect) = args.detect_content {
+        settings.client.content.detect = detect;
+    }
+
+    if let Some(content_type) = args.content_type {
+        settings.client.content.content_type = Some(content_type);
+    }
+
+    if let Some(target) = args.target_quality {
+        settings.client.crf.target = Some(target);
+    }
+
+    if let Some(metric) = args.quality_metric {
+        settings.client.crf.metric = metric;
+    }
+
+    if let Some(model) = args.vmaf_model {
+        settings.client.vmaf.model = model;
+    }
+
+    if let Some(path) = &args.vmaf_model_path {
+        settings.client.vmaf.model_path = Some(path.clone());
+    }
+
+    if let Some(subsample) = args.quality_subsample {
+        settings.client.quality.subsample = subsample;
+    }
+
+    if args.scene_report {
+        settings.client.quality.scenes = true;
+    }
+
+    if let Some(concat) = args.concat {
+        settings.client.concat = concat;
+    }
+
+    if args.fragmented {
+        settings.client.output.fragmented = true;
+    }
+
+    if args.overwrite {
+        settings.client.output.overwrite = OverwritePolicy::Overwrite;
+    } else if args.no_overwrite {
+        settings.client.output.overwrite = OverwritePolicy::Fail;
+    } else if args.unique_name {
+        settings.client.output.overwrite = OverwritePolicy::UniqueName;
+    }
+
+    if let Some(existing_temp) = args.existing_temp {
+        settings.processing.existing_temp = existing_temp;
+    }
+
+    if let Some(format) = args.stdout_format {
+        settings.client.output.stdout_format = format;
+    }
+
+    if !args.map.is_empty() {
+        settings.client.output.map = args.map.clone();
+    }
+
+    if args.preview {
+        settings.client.output.preview = true;
+    }
+
+    if let Some(address) = &args.metrics_address {
+        settings.client.metrics_address = Some(address.clone());
+    }
+    if let Some(address) = &args.web_address {
+        settings.client.web_address = Some(address.clone());
+    }
+
+    if let Some(format) = args.package {
+        settings.client.package.format = Some(format);
+    }
+
+    if let Some(search) = args.crf_search {
+        settings.client.crf.search = search;
+    }
+
+    if let Some(metrics) = &args.quality_report {
+        settings.client.quality.metrics = metrics.clone();
+    }
+
+    if let Some(format) = args.report {
+        settings.client.quality.report = Some(format);
+    }
+
+    if let Some(score) = args.quality_floor {
+        match &mut settings.client.quality.floor {
+            Some(floor) => floor.score = score,
+            None => settings.client.quality.floor = Some(QualityFloor::new(score)),
+        }
+    }
+
+    // Chunks are measured with metric of the floor, so it's reported as well
+    let quality = &mut settings.client.quality;
+    if let Some(floor) = &quality.floor {
+        if !quality.metrics.contains(&floor.metric) {
+            quality.metrics.push(floor.metric);
+        }
+    }
+
+    if args.verify_decode {
+        settings.client.verify.decode = true;
+    }
+
+    if args.verify_frames {
+        settings.client.verify.frame_count = true;
+    }
+
+    if let Some(check) = args.sync_check {
+        settings.client.sync.check = check;
+    }
+
+    if args.reproducible || args.verify_reproduction {
+        settings.client.reproducible.enabled = true;
+    }
+
+    if args.verify_reproduction {
+        settings.client.reproducible.verify = true;
+    }
+
+    if let Some(iso) = args.photon_noise {
+        settings.client.grain.photon_noise = Some(iso);
+    }
+
+    if args.measure_grain {
+        settings.client.grain.measure = true;
+    }
+
+    if let Some(denoise) = args.denoise {
+        settings.client.grain.denoise = Some(denoise);
+    }
+
+    if let Some(profile) = args.profile {
+        settings.client.profile = Some(profile);
+    }
+
     // We get Vec of single string from cli, and process it into multiple arguments
     // that will be used later
-    if let Some(encoder_params) = &cli.encoder_params {
+    let params = args.encoder_params.as_ref().map(|encoder_params| {
         // This is ugly but we can pass a lot of encoders and settings this way
         let mut params: Vec<String> = vec![];
-        encoder_params.into_iter().for_each(|x| {
+        encoder_params.iter().for_each(|x| {
             params.extend(
                 x.split(' ')
                     .map(|f| f.trim().to_string())
-                    .filter(|c| c != "")
+                    .filter(|c| !c.is_empty())
                     .collect::<Vec<String>>(),
             )
         });
-        // this ensures we don't have issues with overwriting
-        params.push("-y".to_string());
-        settings.client.encoder_params = params;
-    }
+        // this ensures we don't have issues with overwriting, standalone encoders
+        // overwrite anyway and don't know this option
+        let encoder = settings.client.encoder;
+        if encoder == EncoderKind::Ffmpeg || encoder.hardware().is_some() {
+            params.push("-y".to_string());
+        }
+        params
+    });
+    // Options given on the command line override options of the profile
+    settings.apply_profile(params)?;
 
-    if let Some(temp_dir) = &cli.temp_dir {
+    if let Some(temp_dir) = &args.temp_dir {
         settings.processing.temp_dir = temp_dir.clone();
     }
 
-    if let Some(segment_duration) = cli.segment_duration {
+    if let Some(segment_duration) = args.segment_duration {
         settings.processing.segment_duration = segment_duration;
     }
 
-    Ok(settings)
-}
+    if let Some(segment_frames) = args.segment_frames {
+        settings.processing.segment_frames = Some(segment_frames);
+    }
 
-/// Initialize connections to all provided node addresses with their corresponding slots
-#[instrument(skip(addresses, slots))]
-async fn initialize_nodes(addresses: &[String], slots: &[usize]) -> Result<Vec<NodeConnection>> {
-    let mut nodes = Vec::new();
+    if let Some(frame_rate) = &args.frame_rate {
+        settings.processing.frame_rate = Some(frame_rate.clone());
+    }
 
-    if addresses.len() != slots.len() {
-        return Err(anyhow::anyhow!(
-            "Number of node addresses does not match the number of slot specifications"
-        ));
+    if let Some(split_method) = args.split_method {
+        settings.processing.split_method = split_method;
     }
 
-    for (address, &slot_count) in addresses.iter().zip(slots.iter()) {
-        let channel = tonic::transport::Channel::from_shared(address.clone())
-            .context("Invalid node address")?
-            .connect()
-            .await
-            .context("Failed to connect to node")?;
+    if let Some(open_gop) = args.open_gop {
+        settings.processing.open_gop = open_gop;
+    }
 
-        let client = VideoEncodingServiceClient::new(channel)
-            .max_decoding_message_size(MAX_MESSAGE_SIZE)
-            .max_encoding_message_size(MAX_MESSAGE_SIZE);
+    if let Some(deinterlace) = args.deinterlace {
+        settings.processing.deinterlace = deinterlace;
+    }
 
-        nodes.push(NodeConnection {
-            client,
-            address: address.clone(),
-            semaphore: Arc::new(Semaphore::new(slot_count)),
-        });
-        info!("Connected to node at {} with {} slots", address, slot_count);
+    if args.lossless_intermediate {
+        settings.processing.lossless_intermediate = true;
     }
 
-    if nodes.is_empty() {
-        return Err(anyhow::anyhow!("No nodes available"));
+    if args.extract_on_demand {
+        settings.processing.extract_on_demand = true;
     }
 
-    Ok(nodes)
-}
+    if let Some(import_splits) = &args.import_splits {
+        settings.processing.import_splits = Some(import_splits.clone());
+    }
 
-#[instrument(skip(node, encoding_state))]
-async fn encode_chunks_on_node(
-    node: NodeConnection,
-    encoding_state: Arc<Mutex<EncodingState>>,
-) -> Result<()> {
-    let mut chunk_futures = FuturesUnordered::new();
-
-    loop {
-        // Try to acquire a permit
-        if let Ok(permit) = node.semaphore.clone().acquire_owned().await {
-            let chunk = {
-                let mut state = encoding_state.lock().await;
-                state.pending_chunks.pop()
-            };
-
-            match chunk {
-                Some(chunk) => {
-                    let client_clone = node.client.clone();
-                    let address = node.address.clone();
-                    let state_clone = Arc::clone(&encoding_state);
-
-                    chunk_futures.push(tokio::spawn(async move {
-                        let result = send_chunk(chunk.clone(), client_clone).await;
-                        drop(permit); // Release the permit after processing
-
-                        match result {
-                            Ok(encoded_chunk) => {
-                                let mut state = state_clone.lock().await;
-                                state.completed_chunks.push(encoded_chunk);
-                                info!(
-                                    "Chunk {} encoded successfully on node {}",
-                                    chunk.index, address
-                                );
-                            }
-                            Err(e) => {
-                                error!(
-                                    "Failed to encode chunk {} on node {}: {}",
-                                    chunk.index, address, e
-                                );
-                                let mut state = state_clone.lock().await;
-                                state.pending_chunks.push(chunk);
-                            }
-                        }
-                    }));
-                }
-                None => {
-                    // No more chunks to process
-                    drop(permit);
-                    break;
-                }
-            }
-        } else {
-            // If we can't acquire a permit, wait for some ongoing tasks to complete
-            if !chunk_futures.is_empty() {
-                chunk_futures.next().await;
-            } else {
-                // If there are no chunk futures and we can't acquire permits, we're done
-                break;
-            }
-        }
+    if let Some(export_splits) = &args.export_splits {
+        settings.processing.export_splits = Some(export_splits.clone());
+    }
+
+    if let Some(zones) = &args.zones {
+        settings.processing.zones = Some(zones.clone());
+    }
+
+    if args.preserve_timestamps {
+        settings.processing.preserve_timestamps = true;
+    }
+
+    if let Some(audio_codec) = &args.audio_codec {
+        settings.client.audio.codec = Some(audio_codec.clone());
+    }
+
+    if let Some(audio_bitrate) = args.audio_bitrate {
+        settings.client.audio.bitrate = Some(audio_bitrate);
     }
 
-    // Wait for all remaining chunk futures to complete
-    while let Some(_) = chunk_futures.next().await {}
+    // Tracks of a type selected on the command line replace the configured selection
+    let tracks = &mut settings.client.tracks;
+    if !args.audio_lang.is_empty() || !args.audio_tracks.is_empty() {
+        tracks.audio_languages = args.audio_lang.clone();
+        tracks.audio_tracks = args.audio_tracks.clone();
+    }
+    if !args.keep_subs.is_empty() || !args.sub_tracks.is_empty() {
+        tracks.subtitle_languages = args.keep_subs.clone();
+        tracks.subtitle_tracks = args.sub_tracks.clone();
+    }
+    if let Some(chapters) = args.chapters {
+        tracks.chapters = chapters;
+    }
+
+    for tag in &args.tags {
+        let (key, value) = tag
+            .split_once('=')
+            .with_context(|| format!("Tag {:?} isn't in form KEY=VALUE", tag))?;
+        settings
+            .client
+            .metadata
+            .tags
+            .insert(key.to_string(), value.to_string());
+    }
+
+    if let Some(upload_limit) = args.upload_limit {
+        settings.client.bandwidth.upload_limit = Some(upload_limit);
+    }
+
+    if let Some(download_limit) = args.download_limit {
+        settings.client.bandwidth.download_limit = Some(download_limit);
+    }
 
     Ok(())
 }
 
-#[instrument(skip(client), fields(chunk_index = chunk.index))]
-async fn send_chunk(
-    chunk: Chunk,
-    mut client: VideoEncodingServiceClient<tonic::transport::Channel>,
-) -> Result<Chunk> {
-    let chunk_data = std::fs::read(&chunk.source_path).context("Failed to read chunk data")?;
-
-    let request = tonic::Request::new(EncodeChunkRequest {
-        chunk_data,
-        chunk_index: chunk.index as i32,
-        encoder_parameters: chunk.encoder_parameters.clone(),
-    });
-
-    debug!("Sending encode request for chunk {}", chunk.index);
-    let response = client
-        .encode_chunk(request)
-        .await
-        .context("Failed to send encode request")?
-        .into_inner();
-
-    if response.success {
-        debug!("Successfully encoded chunk {}", chunk.index);
-
-        let encoded_path =
-            std::path::PathBuf::from(format!("./temp/encoded/encoded_chunk_{}.mkv", chunk.index));
-        std::fs::write(&encoded_path, response.encoded_chunk_data)
-            .context("Failed to write encoded chunk data")?;
-
-        Ok(Chunk {
-            encoded_path: Some(encoded_path),
-            ..chunk
-        })
-    } else {
-        error!(
-            "Failed to encode chunk {}: {}",
-            chunk.index, response.error_message
-        );
-        Err(anyhow::anyhow!(
-            "Failed to encode chunk {}: {}",
-            chunk.index,
-            response.error_message
-        ))
+/// Writes comparisons of `frames` frames of `source` and `output` into `dir`
+#[instrument]
+async fn compare_encode(
+    source: &Path,
+    output: &Path,
+    frames: usize,
+    layout: ComparisonLayout,
+    dir: &Path,
+) -> Result<()> {
+    verify_ffmpeg()?;
+    if frames == 0 {
+        anyhow::bail!("At least one frame has to be compared");
+    }
+    std::fs::create_dir_all(dir).context("Failed to create directory of comparisons")?;
+
+    info!("Picking {} frames of {:?}", frames, source);
+    let times = pick_frames(source, frames).await?;
+    let mut images = 0;
+    for (number, time) in times.into_iter().enumerate() {
+        images += write_comparison(source, output, time, number, layout, dir)
+            .await?
+            .len();
     }
+
+    info!("Wrote {} images into {:?}", images, dir);
+    Ok(())
 }
@@ -1,29 +1,60 @@
 use anyhow::Result;
-use clap::Parser;
+use clap::{CommandFactory, Parser, Subcommand, ValueEnum};
+use std::collections::HashMap;
 use std::fs;
 use std::path::PathBuf;
-use tonic::{transport::Server, Request, Response, Status};
-use tracing::{debug, error, info, instrument};
+use std::pin::Pin;
+use std::time::{Duration, Instant, UNIX_EPOCH};
+use tokio::sync::broadcast;
+use tokio_stream::wrappers::{errors::BroadcastStreamRecvError, BroadcastStream};
+use tokio_stream::{Stream, StreamExt};
+use tonic::{Request, Response, Status};
+use tracing::{debug, error, info, instrument, warn};
 use video_encoding::video_encoding_service_server::{
     VideoEncodingService, VideoEncodingServiceServer,
 };
-use video_encoding::{EncodeChunkRequest, EncodeChunkResponse};
-use video_encoding_system::chunk::{verify_ffmpeg, Chunk};
+use video_encoding::{
+    ChunkProgress, EncodeCachedChunkRequest, EncodeChunkRequest, EncodeChunkResponse,
+    EncodeFailure, FrameScores, GetStatusRequest, GetStatusResponse, TargetQuality, Vmaf,
+    WatchProgressRequest,
+};
+use video_encoding_system::cache::{hash_chunk, ChunkCache};
+use video_encoding_system::cgroup::{self, CgroupManager};
+use video_encoding_system::chunk::Chunk;
+use video_encoding_system::crf::search_chunk_crf;
+use video_encoding_system::encoder::hardware::{detect_codecs, hardware_codec};
+use video_encoding_system::encoder::{check_passes, warm, with_crf, Encoder, EncoderKind};
+use video_encoding_system::error::VideoEncodeError;
+use video_encoding_system::ffmpeg::progress::Progress;
+use video_encoding_system::ffmpeg::quality::{measure_frames, Measurement};
 
-pub mod video_encoding {
-    tonic::include_proto!("video_encoding");
-}
+use video_encoding_system::proto as video_encoding;
 
 use video_encoding_system::config::TempConfig;
-use video_encoding_system::logging::init_logging;
-use video_encoding_system::settings::Settings;
+use video_encoding_system::crypto::{Direction, MasterKey};
+use video_encoding_system::generate::{generate, Generate};
+use video_encoding_system::logging::{init_logging, LogFormat, Verbosity};
+use video_encoding_system::priority::{set_priority, IoClass};
+use video_encoding_system::settings::{
+    CrfSearch, CrfSettings, QualityMetric, Settings, VmafModel, VmafSettings,
+};
+use video_encoding_system::status::NodeStatus;
+use video_encoding_system::telemetry::accept_context;
+use video_encoding_system::transport::{self, quic, ListenAddress};
+use video_encoding_system::zones::override_params;
 
 const MAX_MESSAGE_SIZE: usize = 1024 * 1024 * 1024; // 1 GB
+const PROGRESS_CHANNEL_CAPACITY: usize = 256;
+/// Period usage of CPU and memory is sampled at, for status reports
+const USAGE_SAMPLE_PERIOD: Duration = Duration::from_secs(2);
 
 /// CLI arguments for the video encoding node
 #[derive(Parser, Debug, Clone)]
-#[command(author, version, about, long_about = None)]
+#[command(name = "node", author, version, about, long_about = None)]
 struct Cli {
+    #[command(subcommand)]
+    command: Option<Command>,
+
     /// Path to the configuration file
     #[arg(short, long)]
     config_file: Option<PathBuf>,
@@ -35,52 +66,411 @@ struct Cli {
     /// Temporary directory for processing
     #[arg(short, long)]
     temp_dir: Option<PathBuf>,
+
+    /// Maximum number of concurrent encodes
+    #[arg(short, long)]
+    slots: Option<usize>,
+
+    /// Maximum number of threads of every encode, 0 disables the limit
+    #[arg(long)]
+    threads: Option<usize>,
+
+    /// Niceness of encodes from -20 to 19, higher is lower priority
+    #[arg(long, allow_hyphen_values = true)]
+    nice: Option<i32>,
+
+    /// I/O scheduling class of encodes, on Linux
+    #[arg(long, value_enum)]
+    io_class: Option<IoClass>,
+
+    #[command(flatten)]
+    verbosity: Verbosity,
+
+    /// Format of log lines, on the console and in the log file
+    #[arg(long, value_enum, default_value_t)]
+    log_format: LogFormat,
+}
+
+#[derive(Subcommand, Debug, Clone)]
+enum Command {
+    /// Generate shell completions or man pages
+    #[command(hide = true)]
+    Generate {
+        #[command(subcommand)]
+        what: Generate,
+    },
+}
+
+/// Removes chunk files and directories when dropped
+struct CleanupGuard(Vec<PathBuf>);
+
+impl Drop for CleanupGuard {
+    fn drop(&mut self) {
+        for path in self.0.iter().filter(|path| path.exists()) {
+            debug!("Removing {:?}", path);
+            let removed = if path.is_dir() {
+                fs::remove_dir_all(path)
+            } else {
+                fs::remove_file(path)
+            };
+            if let Err(e) = removed {
+                error!("Failed to remove {:?}: {}", path, e);
+            }
+        }
+    }
 }
 
 /// Represents the video encoding node
 #[derive(Debug)]
 pub struct VideoEncodingNode {
     config: TempConfig,
+    cache: Option<ChunkCache>,
+    /// Progress of all chunks being encoded, consumed by progress watchers
+    progress: broadcast::Sender<ChunkProgress>,
+    /// Key for chunk payloads, only encrypted chunks are accepted when it's set
+    key: Option<MasterKey>,
+    status: NodeStatus,
+    /// Encoders available on this node
+    encoders: Vec<Box<dyn Encoder>>,
+    /// Hardware codecs of ffmpeg that work on this node
+    hardware_codecs: Vec<String>,
+    /// Exact versions of available encoders by their names
+    encoder_versions: HashMap<String, String>,
+    /// Maximum number of threads of every encode
+    threads: Option<usize>,
+    /// Creates cgroups that limit every encode, when limits are set
+    cgroups: Option<CgroupManager>,
+    /// Bytes left free on the temp disk, 0 when it's not checked
+    min_free_space: u64,
 }
 
-#[tonic::async_trait]
-impl VideoEncodingService for VideoEncodingNode {
-    /// Encodes a chunk of video
-    ///
-    /// # Arguments
-    ///
-    /// * `request` - The EncodeChunkRequest containing chunk data and metadata
-    ///
-    /// # Returns
-    ///
-    /// A Result containing the EncodeChunkResponse or a Status error
-    #[instrument(skip(self, request))]
-    async fn encode_chunk(
+impl VideoEncodingNode {
+    /// Error chunk is rejected with when writing `needed` bytes would leave less than
+    /// the minimum free on the temp disk, RESOURCE_EXHAUSTED the client recognizes
+    fn free_space_error(&self, needed: u64) -> Option<Status> {
+        if self.min_free_space == 0 {
+            return None;
+        }
+        let free = match fs2::available_space(&self.config.temp_dir) {
+            Ok(free) => free,
+            Err(e) => {
+                error!("Failed to get free disk space: {}", e);
+                return Some(Status::internal("Failed to get free disk space"));
+            }
+        };
+        if free < needed + self.min_free_space {
+            warn!(
+                "Rejecting chunk, temp disk has {} MiB free, {} MiB are kept free",
+                free / (1024 * 1024),
+                self.min_free_space / (1024 * 1024)
+            );
+            return Some(Status::resource_exhausted(format!(
+                "Temp disk of the node has {} MiB free, {} MiB are kept free",
+                free / (1024 * 1024),
+                self.min_free_space / (1024 * 1024)
+            )));
+        }
+        None
+    }
+
+    /// Encodes source file and builds response with encoded data.
+    /// Source is removed afterwards, unless it's owned by the cache.
+    /// If request is cancelled, encoding is stopped and files are removed.
+    #[allow(clippy::too_many_arguments)]
+    #[instrument(skip(
+        self,
+        encoder_parameters,
+        film_grain_table,
+        target_quality,
+        quality_metrics,
+        vmaf
+    ))]
+    async fn encode_source(
         &self,
-        request: Request<EncodeChunkRequest>,
+        input_path: PathBuf,
+        job_id: String,
+        chunk_index: i32,
+        encoder_parameters: Vec<String>,
+        encoder: String,
+        passes: u32,
+        film_grain_table: String,
+        video_filter: String,
+        target_quality: Option<TargetQuality>,
+        quality_metrics: Vec<String>,
+        vmaf: Option<Vmaf>,
+        quality_subsample: u32,
+        remove_source: bool,
     ) -> Result<Response<EncodeChunkResponse>, Status> {
-        let req = request.into_inner();
-        info!("Received encode request for chunk {}", req.chunk_index);
-
-        let input_path = self
-            .config
-            .segment_dir()
-            .join(format!("chunk_{}.mkv", req.chunk_index));
         let output_path = self
             .config
             .encode_dir()
-            .join(format!("encoded_chunk_{}.mkv", req.chunk_index));
+            .join(format!("encoded_chunk_{}.mkv", chunk_index));
 
-        debug!("Writing chunk data to file: {:?}", input_path);
-        fs::write(&input_path, &req.chunk_data).map_err(|e| {
-            error!("Failed to write chunk data to file: {}", e);
-            Status::internal("Failed to write chunk data to file")
+        // Files are removed when guard is dropped, which also happens
+        // when client cancels the request while chunk is being encoded
+        let mut cleanup = CleanupGuard(vec![output_path.clone()]);
+        if remove_source {
+            cleanup.0.push(input_path.clone());
+        }
+
+        let mut chunk = Chunk::new(input_path, chunk_index as usize, encoder_parameters);
+
+        // Parameters come from the client, so they are checked before waiting for a slot
+        let encoder = EncoderKind::from_name(&encoder)
+            .and_then(|kind| {
+                self.encoders
+                    .iter()
+                    .find(|available| available.name() == kind.name())
+            })
+            .ok_or_else(|| {
+                warn!(
+                    "Rejecting chunk {}: encoder {} is not available",
+                    chunk_index, encoder
+                );
+                Status::failed_precondition(format!(
+                    "Encoder {} is not available on this node",
+                    encoder
+                ))
+            })?;
+        if let Err(e) = encoder
+            .validate(&chunk.encoder_parameters)
+            .and_then(|()| check_passes(encoder.as_ref(), passes))
+        {
+            warn!("Rejecting chunk {}: {}", chunk_index, e);
+            return Err(Status::invalid_argument(e.to_string()));
+        }
+        if let Some(codec) = hardware_codec(&chunk.encoder_parameters) {
+            if !self
+                .hardware_codecs
+                .iter()
+                .any(|available| available == codec)
+            {
+                warn!(
+                    "Rejecting chunk {}: codec {} doesn't work",
+                    chunk_index, codec
+                );
+                return Err(Status::failed_precondition(format!(
+                    "Codec {} doesn't work on this node",
+                    codec
+                )));
+            }
+        }
+
+        let crf_settings = target_quality.map(crf_settings).transpose().map_err(|e| {
+            warn!("Rejecting chunk {}: {}", chunk_index, e);
+            Status::invalid_argument(e)
         })?;
+        let metrics = quality_metrics
+            .iter()
+            .map(|name| parse_metric(name))
+            .collect::<Result<Vec<_>, _>>()
+            .map_err(|e| {
+                warn!("Rejecting chunk {}: {}", chunk_index, e);
+                Status::invalid_argument(e)
+            })?;
+        let vmaf_model = vmaf
+            .as_ref()
+            .map(|vmaf| parse_vmaf_model(&vmaf.model))
+            .transpose()
+            .map_err(|e| {
+                warn!("Rejecting chunk {}: {}", chunk_index, e);
+                Status::invalid_argument(e)
+            })?
+            .unwrap_or_default();
+        if let Some(settings) = &crf_settings {
+            if encoder.crf_option(&chunk.encoder_parameters).is_none() {
+                warn!(
+                    "Rejecting chunk {}: {} can't encode at constant rate factor",
+                    chunk_index,
+                    encoder.name()
+                );
+                return Err(Status::invalid_argument(format!(
+                    "Encoder {} can't encode at constant rate factor",
+                    encoder.name()
+                )));
+            }
+            let probe_params = override_params(&chunk.encoder_parameters, &settings.probe_params);
+            if let Err(e) = encoder.validate(&probe_params) {
+                warn!("Rejecting chunk {}: {}", chunk_index, e);
+                return Err(Status::invalid_argument(e.to_string()));
+            }
+        }
+
+        if let Some(threads) = self.threads {
+            chunk.encoder_parameters = encoder.with_threads(&chunk.encoder_parameters, threads);
+        }
+
+        if !video_filter.is_empty() {
+            debug!("Filtering chunk {} with {}", chunk_index, video_filter);
+            chunk.encoder_parameters = encoder
+                .with_filter(&chunk.encoder_parameters, &video_filter)
+                .ok_or_else(|| {
+                    warn!(
+                        "Rejecting chunk {}: {} can't filter frames",
+                        chunk_index,
+                        encoder.name()
+                    );
+                    Status::invalid_argument(format!(
+                        "Encoder {} can't filter frames",
+                        encoder.name()
+                    ))
+                })?;
+        }
+
+        // libvmaf reads custom models from files only
+        let model_path = match vmaf
+            .map(|vmaf| vmaf.custom_model)
+            .filter(|model| !model.is_empty())
+        {
+            Some(custom_model) => {
+                let path = self
+                    .config
+                    .encode_dir()
+                    .join(format!("vmaf_{}.json", chunk_index));
+                cleanup.0.push(path.clone());
+                fs::write(&path, custom_model).map_err(|e| {
+                    error!("Failed to write VMAF model: {}", e);
+                    Status::internal("Failed to write VMAF model")
+                })?;
+                Some(path)
+            }
+            None => None,
+        };
+        let measurement = Measurement {
+            vmaf: VmafSettings {
+                model: vmaf_model,
+                model_path,
+            },
+            subsample: quality_subsample,
+        };
+
+        // Probes are measured against the source, so they are encoded without grain
+        let search_params = chunk.encoder_parameters.clone();
+
+        if !film_grain_table.is_empty() {
+            let table_path = self
+                .config
+                .encode_dir()
+                .join(format!("grain_{}.tbl", chunk_index));
+            chunk.encoder_parameters = encoder
+                .with_grain_table(&chunk.encoder_parameters, &table_path)
+                .ok_or_else(|| {
+                    warn!(
+                        "Rejecting chunk {}: {} can't apply film grain table",
+                        chunk_index,
+                        encoder.name()
+                    );
+                    Status::invalid_argument(format!(
+                        "Encoder {} can't apply film grain table",
+                        encoder.name()
+                    ))
+                })?;
+
+            cleanup.0.push(table_path.clone());
+            fs::write(&table_path, &film_grain_table).map_err(|e| {
+                error!("Failed to write film grain table: {}", e);
+                Status::internal("Failed to write film grain table")
+            })?;
+        }
+
+        let _slot = self.status.acquire_slot().await;
+
+        // Cgroup is removed when encode ends or request is cancelled
+        let cgroup = match &self.cgroups {
+            Some(cgroups) => Some(
+                cgroups
+                    .create(&format!("{}_{}", job_id, chunk_index))
+                    .map_err(|e| {
+                        error!("Failed to create cgroup of chunk {}: {}", chunk_index, e);
+                        Status::internal("Failed to create cgroup of the encode")
+                    })?,
+            ),
+            None => None,
+        };
 
-        let chunk = Chunk::new(input_path, req.chunk_index as usize, req.encoder_parameters);
+        let report_progress = |progress: &Progress| {
+            // Sending only fails when nobody is watching
+            let _ = self.progress.send(ChunkProgress {
+                job_id: job_id.clone(),
+                chunk_index,
+                frame: progress.frame,
+                fps: progress.fps,
+                bitrate_kbps: progress.bitrate_kbps,
+                out_time: progress.out_time,
+                speed: progress.speed,
+                done: progress.done,
+            });
+        };
 
-        match chunk.encode(output_path.clone()) {
-            Ok(encoded_chunk) => {
+        let search_dir = self
+            .config
+            .encode_dir()
+            .join(format!("crf_{}", chunk_index));
+        cleanup.0.push(search_dir.clone());
+
+        // Probe encodes and quality measurement take the slot and the cgroup of the encode
+        let encoded_path = output_path.clone();
+        let encoded = cgroup::scope(cgroup, async move {
+            let crf = match &crf_settings {
+                Some(settings) => {
+                    tokio::fs::create_dir_all(&search_dir).await?;
+                    let crf = search_chunk_crf(
+                        &chunk.source_path,
+                        encoder.as_ref(),
+                        &search_params,
+                        settings,
+                        &measurement,
+                        &video_filter,
+                        &search_dir,
+                    )
+                    .await?;
+                    info!("Encoding chunk {} at CRF {}", chunk_index, crf);
+                    chunk.encoder_parameters =
+                        with_crf(encoder.as_ref(), &chunk.encoder_parameters, crf)
+                            .expect("encoder was checked to support CRF");
+                    Some(crf)
+                }
+                None => None,
+            };
+            let started = Instant::now();
+            let encoded = chunk
+                .encode_with_progress(encoder.as_ref(), passes, output_path, report_progress)
+                .await?;
+            let encode_time = started.elapsed().as_secs_f64();
+
+            // Chunk is encoded already, so it's returned even when it can't be measured
+            let mut scores = HashMap::new();
+            let mut frame_scores = HashMap::new();
+            for metric in metrics {
+                match measure_frames(
+                    &encoded_path,
+                    &chunk.source_path,
+                    &video_filter,
+                    metric,
+                    &measurement,
+                )
+                .await
+                {
+                    Ok(measured) => {
+                        scores.insert(metric.name().to_string(), measured.score);
+                        frame_scores.insert(
+                            metric.name().to_string(),
+                            FrameScores {
+                                scores: measured.frames,
+                            },
+                        );
+                    }
+                    Err(e) => warn!(
+                        "Failed to measure {:?} of chunk {}: {}",
+                        metric, chunk_index, e
+                    ),
+                }
+            }
+            Ok::<_, VideoEncodeError>((encoded, crf, scores, frame_scores, encode_time))
+        })
+        .await;
+        match encoded {
+            Ok((encoded_chunk, crf, quality_scores, frame_scores, encode_time)) => {
                 debug!(
                     "Reading encoded chunk data: {:?}",
                     encoded_chunk.encoded_path
@@ -91,63 +481,402 @@ impl VideoEncodingService for VideoEncodingNode {
                 })?;
 
                 info!(
+                    chunk_index,
+                    duration_ms = (encode_time * 1000.0) as u64,
+                    bytes = encoded_data.len(),
                     "Successfully encoded chunk {}, size {}B",
-                    req.chunk_index,
+                    chunk_index,
                     encoded_data.len()
                 );
 
-                debug!(
-                    "Removing source {:?} and encoded {:?}",
-                    chunk.source_path, output_path
-                );
-                if let Err(e) = fs::remove_file(chunk.source_path) {
-                    error!("Failed to remove source file: {}", e);
-                }
-                if let Err(e) = fs::remove_file(&output_path) {
-                    error!("Failed to remove encoded file: {}", e);
-                }
+                let (encoded_data, encrypted) = match &self.key {
+                    Some(key) => {
+                        let encrypted_data = key
+                            .job_cipher(&job_id)
+                            .encrypt(&encoded_data, chunk_index, Direction::Response)
+                            .map_err(|e| {
+                                error!("Failed to encrypt encoded chunk: {}", e);
+                                Status::internal("Failed to encrypt encoded chunk")
+                            })?;
+                        (encrypted_data, true)
+                    }
+                    None => (encoded_data, false),
+                };
 
                 Ok(Response::new(EncodeChunkResponse {
                     encoded_chunk_data: encoded_data,
-                    chunk_index: req.chunk_index,
+                    chunk_index,
                     success: true,
                     error_message: String::new(),
+                    encrypted,
+                    crf: crf.unwrap_or_default(),
+                    quality_scores,
+                    encode_time,
+                    frame_scores,
                 }))
             }
             Err(e) => {
-                error!("Failed to encode chunk {}: {}", req.chunk_index, e);
+                error!("Failed to encode chunk {}: {}", chunk_index, e);
+                self.status
+                    .record_failure(&job_id, chunk_index, &e.to_string());
                 Ok(Response::new(EncodeChunkResponse {
                     encoded_chunk_data: Vec::new(),
-                    chunk_index: req.chunk_index,
+                    chunk_index,
                     success: false,
                     error_message: e.to_string(),
+                    encrypted: false,
+                    crf: 0,
+                    quality_scores: HashMap::new(),
+                    encode_time: 0.0,
+                    frame_scores: HashMap::new(),
                 }))
             }
         }
     }
 }
 
+#[tonic::async_trait]
+impl VideoEncodingService for VideoEncodingNode {
+    /// Encodes a chunk of video
+    ///
+    /// # Arguments
+    ///
+    /// * `request` - The EncodeChunkRequest containing chunk data and metadata
+    ///
+    /// # Returns
+    ///
+    /// A Result containing the EncodeChunkResponse or a Status error
+    #[instrument(skip(self, request))]
+    async fn encode_chunk(
+        &self,
+        request: Request<EncodeChunkRequest>,
+    ) -> Result<Response<EncodeChunkResponse>, Status> {
+        accept_context(request.metadata());
+        let req = request.into_inner();
+        if !valid_job_id(&req.job_id) {
+            return Err(Status::invalid_argument("Invalid job id"));
+        }
+        // Chunk is written, and encoded chunk is about as large
+        if let Some(status) = self.free_space_error(2 * req.chunk_data.len() as u64) {
+            return Err(status);
+        }
+        info!(
+            chunk_index = req.chunk_index,
+            bytes = req.chunk_data.len(),
+            "Received encode request for chunk {}",
+            req.chunk_index
+        );
+
+        let chunk_data = match (&self.key, req.encrypted) {
+            (Some(key), true) => key
+                .job_cipher(&req.job_id)
+                .decrypt(&req.chunk_data, req.chunk_index, Direction::Request)
+                .map_err(|e| {
+                    error!("Failed to decrypt chunk {}: {}", req.chunk_index, e);
+                    Status::invalid_argument(e.to_string())
+                })?,
+            (Some(_), false) => {
+                return Err(Status::failed_precondition(
+                    "Node only accepts encrypted chunks",
+                ))
+            }
+            (None, true) => {
+                return Err(Status::failed_precondition(
+                    "Node has no encryption key configured",
+                ))
+            }
+            (None, false) => req.chunk_data,
+        };
+
+        // Cached chunks are kept around for retries, so they are not removed after encoding.
+        // Entry isn't evicted while the chunk is encoded.
+        let (input_path, cached) = match &self.cache {
+            Some(cache) => {
+                let hash = hash_chunk(&chunk_data);
+                debug!("Caching chunk {} as {}", req.chunk_index, hash);
+                let entry = cache.insert(&hash, &chunk_data).map_err(|e| {
+                    error!("Failed to cache chunk data: {}", e);
+                    Status::internal("Failed to write chunk data to file")
+                })?;
+                (entry.path().to_path_buf(), Some(entry))
+            }
+            None => {
+                let path = self
+                    .config
+                    .segment_dir()
+                    .join(format!("chunk_{}.mkv", req.chunk_index));
+                debug!("Writing chunk data to file: {:?}", path);
+                fs::write(&path, &chunk_data).map_err(|e| {
+                    error!("Failed to write chunk data to file: {}", e);
+                    Status::internal("Failed to write chunk data to file")
+                })?;
+                (path, None)
+            }
+        };
+        let remove_source = cached.is_none();
+
+        self.encode_source(
+            input_path,
+            req.job_id,
+            req.chunk_index,
+            req.encoder_parameters,
+            req.encoder,
+            req.passes.max(1),
+            req.film_grain_table,
+            req.video_filter,
+            req.target_quality,
+            req.quality_metrics,
+            req.vmaf,
+            req.quality_subsample,
+            remove_source,
+        )
+        .await
+    }
+
+    /// Encodes a chunk that is already present in the node cache
+    #[instrument(skip(self, request))]
+    async fn encode_cached_chunk(
+        &self,
+        request: Request<EncodeCachedChunkRequest>,
+    ) -> Result<Response<EncodeChunkResponse>, Status> {
+        accept_context(request.metadata());
+        let req = request.into_inner();
+        if !valid_job_id(&req.job_id) {
+            return Err(Status::invalid_argument("Invalid job id"));
+        }
+        if let Some(status) = self.free_space_error(0) {
+            return Err(status);
+        }
+        info!(
+            "Received cached encode request for chunk {} ({})",
+            req.chunk_index, req.chunk_hash
+        );
+
+        // Entry isn't evicted while the chunk is encoded
+        let entry = self
+            .cache
+            .as_ref()
+            .and_then(|cache| cache.get(&req.chunk_hash))
+            .ok_or_else(|| {
+                debug!("Chunk {} is not in the cache", req.chunk_hash);
+                Status::not_found("Chunk is not in the cache")
+            })?;
+        let input_path = entry.path().to_path_buf();
+
+        self.encode_source(
+            input_path,
+            req.job_id,
+            req.chunk_index,
+            req.encoder_parameters,
+            req.encoder,
+            req.passes.max(1),
+            req.film_grain_table,
+            req.video_filter,
+            req.target_quality,
+            req.quality_metrics,
+            req.vmaf,
+            req.quality_subsample,
+            false,
+        )
+        .await
+    }
+
+    type WatchProgressStream =
+        Pin<Box<dyn Stream<Item = Result<ChunkProgress, Status>> + Send + 'static>>;
+
+    /// Streams progress of chunks that belong to requested job
+    #[instrument(skip(self, request))]
+    async fn watch_progress(
+        &self,
+        request: Request<WatchProgressRequest>,
+    ) -> Result<Response<Self::WatchProgressStream>, Status> {
+        let job_id = request.into_inner().job_id;
+        debug!("Client started watching progress of job {}", job_id);
+
+        let stream = BroadcastStream::new(self.progress.subscribe()).filter_map(move |update| {
+            match update {
+                Ok(progress) if progress.job_id == job_id => Some(Ok(progress)),
+                Ok(_) => None,
+                // Watcher is too slow, skipped updates are superseded by newer ones anyway
+                Err(BroadcastStreamRecvError::Lagged(_)) => None,
+            }
+        });
+
+        Ok(Response::new(Box::pin(stream)))
+    }
+
+    /// Reports load of the node and its recent failures
+    #[instrument(skip(self, _request))]
+    async fn get_status(
+        &self,
+        _request: Request<GetStatusRequest>,
+    ) -> Result<Response<GetStatusResponse>, Status> {
+        let disk_free_bytes = fs2::available_space(&self.config.temp_dir).map_err(|e| {
+            error!("Failed to get free disk space: {}", e);
+            Status::internal("Failed to get free disk space")
+        })?;
+        let disk_total_bytes = fs2::total_space(&self.config.temp_dir).map_err(|e| {
+            error!("Failed to get disk size: {}", e);
+            Status::internal("Failed to get disk size")
+        })?;
+        let usage = self.status.usage();
+
+        let recent_failures = self
+            .status
+            .recent_failures()
+            .into_iter()
+            .map(|failure| EncodeFailure {
+                job_id: failure.job_id,
+                chunk_index: failure.chunk_index,
+                error_message: failure.error_message,
+                timestamp: failure
+                    .time
+                    .duration_since(UNIX_EPOCH)
+                    .map(|time| time.as_secs())
+                    .unwrap_or_default(),
+            })
+            .collect();
+
+        Ok(Response::new(GetStatusResponse {
+            active_encodes: self.status.active() as u32,
+            queued_encodes: self.status.queued() as u32,
+            total_slots: self.status.slots().unwrap_or(0) as u32,
+            disk_free_bytes,
+            recent_failures,
+            encoders: self
+                .encoders
+                .iter()
+                .map(|encoder| encoder.name().to_string())
+                .collect(),
+            hardware_codecs: self.hardware_codecs.clone(),
+            encoder_versions: self.encoder_versions.clone(),
+            cpu_usage: usage.cpu,
+            memory_used_bytes: usage.memory_used,
+            memory_total_bytes: usage.memory_total,
+            disk_total_bytes,
+        }))
+    }
+}
+
 /// Initializes and runs the video encoding node
 #[tokio::main]
 #[instrument]
 async fn main() -> Result<()> {
-    init_logging();
-
     let cli = Cli::parse();
+    // Completions are printed to stdout, without logs
+    if let Some(Command::Generate { what }) = &cli.command {
+        return Ok(generate(Cli::command(), what)?);
+    }
+
+    init_logging(
+        &cli.verbosity.filter(env!("CARGO_CRATE_NAME")),
+        cli.log_format,
+        &Settings::log_settings(cli.config_file.as_deref()),
+    );
 
     info!("Starting video encoding node");
     debug!("CLI arguments: {:?}", cli);
 
     let settings = load_settings(&cli)?;
+    set_priority(settings.node.nice, settings.node.io_class)?;
 
-    verify_ffmpeg()?;
+    // Other encoders are optional, but they decode chunks with ffmpeg too.
+    // Hardware encoders are available when any of their codecs works.
+    let mut encoders = Vec::new();
+    let mut hardware_codecs = Vec::new();
+    for &kind in EncoderKind::ALL {
+        let encoder = kind.encoder();
+        if let Some(accel) = kind.hardware() {
+            let codecs = detect_codecs(accel);
+            if codecs.is_empty() {
+                info!(
+                    "Encoder {} is not available: no codec works",
+                    encoder.name()
+                );
+                continue;
+            }
+            info!(
+                "Encoder {} is available with {}",
+                encoder.name(),
+                codecs.join(", ")
+            );
+            hardware_codecs.extend(codecs);
+            encoders.push(encoder);
+            continue;
+        }
+        match encoder.probe() {
+            Ok(()) => encoders.push(encoder),
+            Err(e) if kind == EncoderKind::Ffmpeg => return Err(e.into()),
+            Err(e) => info!("Encoder {} is not available: {}", encoder.name(), e),
+        }
+    }
+    // Clients can require the same versions on all nodes, so output doesn't vary between chunks
+    let encoder_versions: HashMap<String, String> = encoders
+        .iter()
+        .filter_map(|encoder| Some((encoder.name().to_string(), encoder.version()?)))
+        .collect();
+    for (name, version) in &encoder_versions {
+        info!("Encoder {} has version {}", name, version);
+    }
 
     let config = TempConfig::new(
         Some(settings.processing.temp_dir),
         &PathBuf::from("dummy"),
         "dummy",
     );
-    let server = VideoEncodingNode { config };
+    let cache = if settings.node.chunk_cache_ttl > 0 {
+        let ttl = Duration::from_secs(settings.node.chunk_cache_ttl);
+        let cache = ChunkCache::new(config.cache_dir(), ttl)?;
+        tokio::spawn(evict_cache_periodically(cache.clone(), ttl));
+        Some(cache)
+    } else {
+        None
+    };
+
+    let quic_cert_dir = config.temp_dir.join("quic");
+    let (progress, _) = broadcast::channel(PROGRESS_CHANNEL_CAPACITY);
+    let key = settings
+        .encryption
+        .key_file
+        .as_deref()
+        .map(MasterKey::from_file)
+        .transpose()?;
+    if key.is_some() {
+        info!("Chunk encryption is enabled, unencrypted chunks will be rejected");
+    }
+
+    let threads = settings.node.encode_threads();
+    if let Some(threads) = threads {
+        info!("Every encode is limited to {} threads", threads);
+    }
+
+    let cgroups = CgroupManager::new(&settings.node.cgroup)?;
+    if settings.node.warm_processes > 0 {
+        // Warm processes are started before their encode, so its cgroup can't hold them
+        if cgroups.is_some() {
+            warn!("Warm encoder processes are disabled, because encodes are limited by cgroups");
+        } else {
+            warm::enable(config.temp_dir.join("warm"), settings.node.warm_processes)?;
+            tokio::spawn(evict_warm_periodically());
+        }
+    }
+
+    let status = NodeStatus::new(settings.node.slots);
+    status.sample_usage(USAGE_SAMPLE_PERIOD);
+
+    let server = VideoEncodingNode {
+        config,
+        cache,
+        progress,
+        key,
+        status,
+        encoders,
+        hardware_codecs,
+        encoder_versions,
+        threads,
+        cgroups,
+        min_free_space: settings.node.min_free_space * 1024 * 1024,
+    };
 
     let service = VideoEncodingServiceServer::new(server)
         .max_encoding_message_size(MAX_MESSAGE_SIZE)
@@ -157,14 +886,88 @@ async fn main() -> Result<()> {
         "Server configured, starting to serve on {}",
         settings.node.address
     );
-    Server::builder()
-        .add_service(service)
-        .serve(settings.node.address.parse()?)
-        .await?;
+    let router = transport::server(&settings.grpc).add_service(service);
+
+    match settings.node.address.parse()? {
+        ListenAddress::Tcp(addr) => router.serve(addr).await?,
+        ListenAddress::Quic(addr) => {
+            let incoming = quic::incoming(addr, &settings.quic, &quic_cert_dir)?;
+            router.serve_with_incoming(incoming).await?
+        }
+        #[cfg(unix)]
+        ListenAddress::Unix(path) => {
+            let incoming = transport::bind_unix(&path, settings.node.socket_permissions)?;
+            router.serve_with_incoming(incoming).await?
+        }
+        #[cfg(not(unix))]
+        ListenAddress::Unix(path) => {
+            return Err(anyhow::anyhow!(
+                "Unix sockets are not supported on this platform: {:?}",
+                path
+            ))
+        }
+    }
 
     Ok(())
 }
 
+/// Settings of CRF search of a chunk, from target quality requested by the client
+fn crf_settings(target: TargetQuality) -> Result<CrfSettings, String> {
+    let metric = parse_metric(&target.metric)?;
+    Ok(CrfSettings {
+        target: Some(target.score),
+        metric,
+        search: CrfSearch::Chunk,
+        probes: target.probes as usize,
+        min: (target.min_crf > 0).then_some(target.min_crf),
+        max: (target.max_crf > 0).then_some(target.max_crf),
+        probe_params: target.probe_params,
+        ..CrfSettings::default()
+    })
+}
+
+/// Whether job id sent by the client is safe to name files and cgroups after, like the
+/// UUIDs clients generate
+fn valid_job_id(job_id: &str) -> bool {
+    !job_id.is_empty()
+        && job_id
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_')
+}
+
+/// Quality metric of its name, requested by the client
+fn parse_metric(name: &str) -> Result<QualityMetric, String> {
+    QualityMetric::from_str(name, true).map_err(|_| format!("Unknown quality metric {}", name))
+}
+
+/// VMAF model of its name, requested by the client. Default model when empty.
+fn parse_vmaf_model(name: &str) -> Result<VmafModel, String> {
+    if name.is_empty() {
+        return Ok(VmafModel::default());
+    }
+    VmafModel::from_str(name, true).map_err(|_| format!("Unknown VMAF model {}", name))
+}
+
+/// Removes expired chunks from the cache for the lifetime of the node
+async fn evict_cache_periodically(cache: ChunkCache, ttl: Duration) {
+    let mut interval = tokio::time::interval(ttl / 2);
+    loop {
+        interval.tick().await;
+        if let Err(e) = cache.evict_expired() {
+            warn!("Failed to evict expired chunks from cache: {}", e);
+        }
+    }
+}
+
+/// Stops warm encoder processes that weren't used for the lifetime of the node
+async fn evict_warm_periodically() {
+    let mut interval = tokio::time::interval(Duration::from_secs(10));
+    loop {
+        interval.tick().await;
+        warm::evict_idle();
+    }
+}
+
 /// Loads settings from the configuration file or creates default settings
 #[instrument(skip(cli))]
 fn load_settings(cli: &Cli) -> Result<Settings> {
@@ -184,6 +987,23 @@ fn load_settings(cli: &Cli) -> Result<Settings> {
         debug!("Overriding temp directory with CLI option: {:?}", temp_dir);
         settings.processing.temp_dir = temp_dir.clone();
     }
+    if let Some(slots) = cli.slots {
+        debug!("Overriding slots with CLI option: {}", slots);
+        settings.node.slots = Some(slots);
+    }
+    if let Some(threads) = cli.threads {
+        debug!("Overriding threads with CLI option: {}", threads);
+        settings.node.threads = Some(threads);
+    }
+    if let Some(nice) = cli.nice {
+        debug!("Overriding niceness with CLI option: {}", nice);
+        settings.node.nice = Some(nice);
+    }
+    if let Some(io_class) = cli.io_class {
+        debug!("Overriding I/O class with CLI option: {:?}", io_class);
+        settings.node.io_class = Some(io_class);
+    }
 
+    settings.validate_node()?;
     Ok(settings)
 }
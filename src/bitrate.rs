@@ -0,0 +1,247 @@
+/// This module distributes the bit budget of an output across its chunks by their
+/// complexity, so complex chunks get more bits than simple ones at the same average
+/// bitrate, and checks that concatenated output stays within VBV constraints
+use std::{collections::HashMap, path::Path, process::Stdio};
+
+use tokio::process::Command;
+use tracing::{debug, error, instrument};
+
+use crate::chunk::{Chunk, SourceRange};
+use crate::encoder::{Vbv, VBV_INIT};
+use crate::error::VideoEncodeError;
+use crate::ffmpeg::probe::probe_packet_sizes;
+use crate::settings::BitrateSettings;
+
+/// Constant quantizer of the analysis encode
+const ANALYSIS_QUANTIZER: &str = "5";
+/// Height analysis encode is scaled to, relative complexity barely depends on resolution
+const ANALYSIS_HEIGHT: u32 = 360;
+/// Iterations of the search for bitrate scale, enough for kbit/s precision
+const SEARCH_ITERATIONS: usize = 64;
+
+/// Measures complexity of chunk as bitrate in kbit/s of a fast constant quality encode.
+/// Returns `None` for chunks of scripts and image sequences, which aren't analyzed.
+#[instrument(skip(chunk), fields(chunk_index = chunk.index))]
+pub async fn measure_complexity(chunk: &Chunk) -> Result<Option<f64>, VideoEncodeError> {
+    let Some(duration) = chunk.duration().filter(|&duration| duration > 0.0) else {
+        return Ok(None);
+    };
+
+    let mut command = Command::new("ffmpeg");
+    command.args(["-hide_banner", "-loglevel", "error"]);
+    match &chunk.range {
+        None => command.arg("-i").arg(&chunk.source_path),
+        Some(SourceRange::Video {
+            input_path,
+            start,
+            end,
+            ..
+        }) => command
+            .args(["-ss", &start.to_string(), "-to", &end.to_string(), "-i"])
+            .arg(input_path),
+        Some(_) => return Ok(None),
+    };
+
+    // Native mpeg4 encoder is in every ffmpeg build, and it's very fast
+    let output = command
+        .args([
+            "-map",
+            "0:v:0",
+            "-vf",
+            &format!("scale=-2:{}", ANALYSIS_HEIGHT),
+        ])
+        .args([
+            "-c:v",
+            "mpeg4",
+            "-q:v",
+            ANALYSIS_QUANTIZER,
+            "-f",
+            "m4v",
+            "-",
+        ])
+        .stdin(Stdio::null())
+        .kill_on_drop(true)
+        .output()
+        .await?;
+
+    if !output.status.success() {
+        error!(
+            "Failed to analyze chunk {}: {}",
+            chunk.index,
+            String::from_utf8_lossy(&output.stderr)
+        );
+        return Err(VideoEncodeError::Encoding(format!(
+            "Failed to analyze chunk {}",
+            chunk.index
+        )));
+    }
+
+    let complexity = output.stdout.len() as f64 * 8.0 / 1000.0 / duration;
+    debug!("Chunk {} has complexity {:.1}", chunk.index, complexity);
+    Ok(Some(complexity))
+}
+
+/// Distributes `target` bitrate in kbit/s across chunks by their complexity.
+/// Chunks are `(index, duration, complexity)`, chunks of unknown complexity get
+/// the average one. Returns bitrate of every chunk in kbit/s, by chunk index.
+pub fn allocate(
+    chunks: &[(usize, f64, Option<f64>)],
+    target: u64,
+    vbv: Option<Vbv>,
+    settings: &BitrateSettings,
+) -> HashMap<usize, u64> {
+    let known: Vec<f64> = chunks
+        .iter()
+        .filter_map(|(_, _, complexity)| *complexity)
+        .filter(|complexity| *complexity > 0.0)
+        .collect();
+    let average = if known.is_empty() {
+        1.0
+    } else {
+        known.iter().sum::<f64>() / known.len() as f64
+    };
+
+    let weights: Vec<f64> = chunks
+        .iter()
+        .map(|(_, _, complexity)| {
+            complexity
+                .filter(|complexity| *complexity > 0.0)
+                .unwrap_or(average)
+                .powf(settings.complexity_weight)
+        })
+        .collect();
+
+    // Every chunk stays close to the target, and within maximum rate of VBV
+    let target = target as f64;
+    let lowest = target / settings.max_deviation;
+    let highest = vbv
+        .map_or(f64::INFINITY, |vbv| vbv.maxrate as f64)
+        .min(target * settings.max_deviation)
+        .max(lowest);
+    let bitrate = |scale: f64, weight: f64| (scale * weight).clamp(lowest, highest);
+
+    // Total bits grow with the scale, so it's found by bisection
+    let duration: f64 = chunks.iter().map(|(_, duration, _)| duration).sum();
+    let budget = target * duration;
+    let bits = |scale: f64| {
+        chunks
+            .iter()
+            .zip(&weights)
+            .map(|((_, duration, _), &weight)| bitrate(scale, weight) * duration)
+            .sum::<f64>()
+    };
+    let (mut low, mut high) = (
+        0.0,
+        highest / weights.iter().copied().fold(f64::MAX, f64::min),
+    );
+    for _ in 0..SEARCH_ITERATIONS {
+        let scale = (low + high) / 2.0;
+        if bits(scale) < budget {
+            low = scale;
+        } else {
+            high = scale;
+        }
+    }
+
+    chunks
+        .iter()
+        .zip(&weights)
+        .map(|((index, _, _), &weight)| (*index, bitrate(low, weight).round() as u64))
+        .collect()
+}
+
+/// Simulates decoder buffer over packets of the file, and returns timestamps
+/// of packets that underflow it, which can't be played within VBV constraints
+#[instrument]
+pub fn check_vbv(path: &Path, vbv: Vbv) -> Result<Vec<f64>, VideoEncodeError> {
+    let underflows = vbv_underflows(&probe_packet_sizes(path)?, vbv);
+
+    debug!(
+        "{:?} underflows VBV buffer {} times",
+        path,
+        underflows.len()
+    );
+    Ok(underflows)
+}
+
+/// Timestamps of `packets`, given as timestamp and size in bytes, that underflow
+/// decoder buffer of `vbv`
+fn vbv_underflows(packets: &[(f64, u64)], vbv: Vbv) -> Vec<f64> {
+    let (rate, size) = (vbv.maxrate as f64 * 1000.0, vbv.bufsize as f64 * 1000.0);
+
+    let mut underflows = Vec::new();
+    let mut fullness = size * VBV_INIT;
+    let mut previous = packets.first().map_or(0.0, |(time, _)| *time);
+    for &(time, bytes) in packets {
+        fullness = (fullness + (time - previous).max(0.0) * rate).min(size);
+        previous = time;
+
+        let bits = bytes as f64 * 8.0;
+        if bits > fullness {
+            underflows.push(time);
+            fullness = 0.0;
+        } else {
+            fullness -= bits;
+        }
+    }
+    underflows
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn settings(complexity_weight: f64, max_deviation: f64) -> BitrateSettings {
+        BitrateSettings {
+            target: Some(1000),
+            maxrate: None,
+            bufsize: None,
+            complexity_weight,
+            max_deviation,
+        }
+    }
+
+    #[test]
+    fn bits_follow_complexity() {
+        let chunks = [(0, 10.0, Some(100.0)), (1, 10.0, Some(300.0))];
+
+        let flat = allocate(&chunks, 1000, None, &settings(0.0, 10.0));
+        assert_eq!(flat, HashMap::from([(0, 1000), (1, 1000)]));
+
+        let proportional = allocate(&chunks, 1000, None, &settings(1.0, 10.0));
+        assert_eq!(proportional, HashMap::from([(0, 500), (1, 1500)]));
+    }
+
+    #[test]
+    fn chunks_of_unknown_complexity_get_the_average() {
+        let chunks = [
+            (0, 10.0, Some(100.0)),
+            (1, 10.0, None),
+            (2, 10.0, Some(300.0)),
+        ];
+        let bitrates = allocate(&chunks, 1000, None, &settings(1.0, 10.0));
+        assert_eq!(bitrates, HashMap::from([(0, 500), (1, 1000), (2, 1500)]));
+    }
+
+    #[test]
+    fn bits_over_vbv_maxrate_go_to_other_chunks() {
+        let chunks = [(0, 10.0, Some(100.0)), (1, 10.0, Some(300.0))];
+        let vbv = Vbv {
+            maxrate: 1200,
+            bufsize: 2400,
+        };
+        let bitrates = allocate(&chunks, 1000, Some(vbv), &settings(1.0, 10.0));
+        assert_eq!(bitrates, HashMap::from([(0, 800), (1, 1200)]));
+    }
+
+    #[test]
+    fn packets_larger_than_buffer_fullness_underflow() {
+        let vbv = Vbv {
+            maxrate: 1000,
+            bufsize: 1000,
+        };
+        // Buffer starts half full with 500 kbit and fills with 100 kbit every 0.1 s
+        let packets = [(0.0, 50_000), (0.1, 50_000), (1.0, 10_000)];
+        assert_eq!(vbv_underflows(&packets, vbv), vec![0.1]);
+    }
+}
@@ -0,0 +1,217 @@
+/// This module implements node-side cache of received source chunks,
+/// so retried chunks don't have to be uploaded again
+use sha2::{Digest, Sha256};
+use std::{
+    collections::HashMap,
+    fs::{self, File},
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex},
+    time::{Duration, SystemTime},
+};
+use tracing::{debug, instrument, warn};
+
+use crate::error::VideoEncodeError;
+
+/// Computes hash that identifies chunk in the cache
+pub fn hash_chunk(data: &[u8]) -> String {
+    hex::encode(Sha256::digest(data))
+}
+
+/// Cache of source chunks stored on disk, keyed by hash of their content
+#[derive(Debug, Clone)]
+pub struct ChunkCache {
+    dir: PathBuf,
+    ttl: Duration,
+    /// Number of running encodes of every entry, entries in use aren't evicted
+    in_use: Arc<Mutex<HashMap<String, usize>>>,
+}
+
+/// Entry of the cache that an encode reads, kept from eviction until it's dropped
+#[derive(Debug)]
+pub struct CachedChunk {
+    path: PathBuf,
+    hash: String,
+    in_use: Arc<Mutex<HashMap<String, usize>>>,
+}
+
+impl CachedChunk {
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+}
+
+impl Drop for CachedChunk {
+    fn drop(&mut self) {
+        let mut in_use = self.in_use.lock().unwrap();
+        if let Some(count) = in_use.get_mut(&self.hash) {
+            *count -= 1;
+            if *count == 0 {
+                in_use.remove(&self.hash);
+            }
+        }
+        // Lifetime of the entry starts when its last encode is done
+        refresh(&self.path);
+    }
+}
+
+impl ChunkCache {
+    #[instrument]
+    pub fn new(dir: PathBuf, ttl: Duration) -> Result<Self, VideoEncodeError> {
+        fs::create_dir_all(&dir)?;
+        debug!("Created chunk cache at {:?} with ttl {:?}", dir, ttl);
+
+        Ok(ChunkCache {
+            dir,
+            ttl,
+            in_use: Arc::default(),
+        })
+    }
+
+    /// Stores chunk data in the cache and returns the cached entry
+    #[instrument(skip(self, data))]
+    pub fn insert(&self, hash: &str, data: &[u8]) -> Result<CachedChunk, VideoEncodeError> {
+        let path = self.path(hash)?;
+        fs::write(&path, data)?;
+
+        let mut in_use = self.in_use.lock().unwrap();
+        Ok(self.use_entry(&mut in_use, hash, path))
+    }
+
+    /// Returns the cached chunk, if it is present and not expired.
+    /// Successful lookup extends lifetime of the entry.
+    #[instrument(skip(self))]
+    pub fn get(&self, hash: &str) -> Option<CachedChunk> {
+        let path = self.path(hash).ok()?;
+        let mut in_use = self.in_use.lock().unwrap();
+
+        if !in_use.contains_key(hash) && self.is_expired(&path) {
+            return None;
+        }
+
+        refresh(&path);
+        Some(self.use_entry(&mut in_use, hash, path))
+    }
+
+    /// Removes expired entries that no encode reads, returns number of removed files
+    #[instrument(skip(self))]
+    pub fn evict_expired(&self) -> Result<usize, VideoEncodeError> {
+        let mut removed = 0;
+        let in_use = self.in_use.lock().unwrap();
+
+        for entry in fs::read_dir(&self.dir)?.filter_map(Result::ok) {
+            let path = entry.path();
+            let used = path
+                .file_stem()
+                .and_then(|stem| stem.to_str())
+                .is_some_and(|hash| in_use.contains_key(hash));
+            if !used && self.is_expired(&path) {
+                fs::remove_file(&path)?;
+                removed += 1;
+            }
+        }
+
+        debug!("Evicted {} chunks from cache", removed);
+        Ok(removed)
+    }
+
+    fn use_entry(
+        &self,
+        in_use: &mut HashMap<String, usize>,
+        hash: &str,
+        path: PathBuf,
+    ) -> CachedChunk {
+        *in_use.entry(hash.to_string()).or_default() += 1;
+
+        CachedChunk {
+            path,
+            hash: hash.to_string(),
+            in_use: self.in_use.clone(),
+        }
+    }
+
+    fn is_expired(&self, path: &Path) -> bool {
+        fs::metadata(path)
+            .and_then(|m| m.modified())
+            .map(|modified| modified.elapsed().unwrap_or_default() > self.ttl)
+            .unwrap_or(true)
+    }
+
+    /// Hash comes from the client, so it's validated before being used as file name
+    fn path(&self, hash: &str) -> Result<PathBuf, VideoEncodeError> {
+        if hash.is_empty() || !hash.chars().all(|c| c.is_ascii_hexdigit()) {
+            return Err(VideoEncodeError::ChunkProcessing(format!(
+                "Invalid chunk hash: {:?}",
+                hash
+            )));
+        }
+
+        Ok(self.dir.join(format!("{}.mkv", hash)))
+    }
+}
+
+/// Sets modification time of the cached file to now, which starts its lifetime again
+fn refresh(path: &Path) {
+    if let Err(e) = File::options()
+        .write(true)
+        .open(path)
+        .and_then(|f| f.set_modified(SystemTime::now()))
+    {
+        warn!("Failed to refresh cached chunk {:?}: {}", path, e);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Makes the cached file look like it was last used `age` ago
+    fn age(path: &Path, age: Duration) {
+        File::options()
+            .write(true)
+            .open(path)
+            .and_then(|f| f.set_modified(SystemTime::now() - age))
+            .unwrap();
+    }
+
+    #[test]
+    fn expired_entries_are_evicted() {
+        let dir = tempfile::tempdir().unwrap();
+        let cache = ChunkCache::new(dir.path().to_path_buf(), Duration::from_secs(60)).unwrap();
+        let path = cache.insert("ab12", b"chunk").unwrap().path().to_path_buf();
+
+        assert_eq!(cache.evict_expired().unwrap(), 0);
+        assert!(cache.get("ab12").is_some());
+
+        age(&path, Duration::from_secs(120));
+        assert!(cache.get("ab12").is_none());
+        assert_eq!(cache.evict_expired().unwrap(), 1);
+        assert!(!path.exists());
+    }
+
+    #[test]
+    fn entries_in_use_are_not_evicted() {
+        let dir = tempfile::tempdir().unwrap();
+        let cache = ChunkCache::new(dir.path().to_path_buf(), Duration::from_secs(60)).unwrap();
+        let entry = cache.insert("cd34", b"chunk").unwrap();
+
+        age(entry.path(), Duration::from_secs(120));
+        assert_eq!(cache.evict_expired().unwrap(), 0);
+        assert!(cache.get("cd34").is_some());
+
+        // Lifetime starts again when the encode is done
+        drop(entry);
+        assert_eq!(cache.evict_expired().unwrap(), 0);
+        assert!(cache.get("cd34").is_some());
+    }
+
+    #[test]
+    fn hashes_that_are_not_hex_are_rejected() {
+        let dir = tempfile::tempdir().unwrap();
+        let cache = ChunkCache::new(dir.path().to_path_buf(), Duration::from_secs(60)).unwrap();
+
+        assert!(cache.insert("../chunk", b"chunk").is_err());
+        assert!(cache.insert("", b"chunk").is_err());
+        assert!(cache.get("../../etc/passwd").is_none());
+        assert!(cache.insert("0123456789abcdefABCDEF", b"chunk").is_ok());
+    }
+}
@@ -0,0 +1,241 @@
+/// This module limits memory and CPU of encodes with cgroups v2 on Linux. Every encode
+/// runs in its own `rav1an-encode-*` cgroup under the cgroup of the node, so an encoder
+/// that runs out of memory is killed alone, instead of the node or other encodes. Node
+/// needs write access to its cgroup, like in a systemd service with `Delegate=yes`.
+use std::{future::Future, path::PathBuf, sync::Arc};
+
+use tracing::{debug, info, warn};
+
+use crate::error::VideoEncodeError;
+use crate::settings::CgroupSettings;
+
+/// Root of cgroup v2 hierarchy
+const CGROUP_ROOT: &str = "/sys/fs/cgroup";
+/// Cgroup the node itself is moved to, processes can only be in leaf cgroups
+const NODE_CGROUP: &str = "node";
+/// Prefix of cgroups of encodes, other cgroups next to the node are left alone
+const ENCODE_PREFIX: &str = "rav1an-encode-";
+/// Period of CPU quota in microseconds
+const CPU_PERIOD: u64 = 100_000;
+
+tokio::task_local! {
+    /// Cgroup of the encode that runs in current task
+    static CURRENT: Arc<Cgroup>;
+}
+
+/// Creates cgroups of encodes under the cgroup of the node
+#[derive(Debug)]
+pub struct CgroupManager {
+    /// Cgroup of the node, that cgroups of encodes are created in
+    root: PathBuf,
+    settings: CgroupSettings,
+}
+
+impl CgroupManager {
+    /// Moves the node into a leaf cgroup and enables controllers for cgroups of encodes.
+    /// Returns `None` when no limits are set.
+    pub fn new(settings: &CgroupSettings) -> Result<Option<CgroupManager>, VideoEncodeError> {
+        if settings.memory_max.is_none() && settings.cpu_max.is_none() {
+            return Ok(None);
+        }
+        if !cfg!(target_os = "linux") {
+            return Err(VideoEncodeError::Encoding(
+                "Limits of encodes are only supported on Linux".to_string(),
+            ));
+        }
+
+        let v2 = PathBuf::from(CGROUP_ROOT).join("cgroup.controllers");
+        if !v2.exists() {
+            return Err(VideoEncodeError::Encoding(format!(
+                "Limits of encodes require cgroup v2 mounted at {}",
+                CGROUP_ROOT
+            )));
+        }
+
+        // Line of cgroup v2 looks like `0::/system.slice/rav1an-node.service`
+        let own = std::fs::read_to_string("/proc/self/cgroup")?;
+        let own = own
+            .lines()
+            .find_map(|line| line.strip_prefix("0::"))
+            .ok_or_else(|| {
+                VideoEncodeError::Encoding("Limits of encodes require cgroup v2".to_string())
+            })?;
+        let mut root = PathBuf::from(CGROUP_ROOT).join(own.trim().trim_start_matches('/'));
+        // Node that was restarted in the same cgroup is already in its leaf
+        if root.ends_with(NODE_CGROUP) {
+            root.pop();
+        }
+
+        let access = |e: std::io::Error| {
+            VideoEncodeError::Encoding(format!(
+                "Can't manage cgroup {:?}, it has to be delegated to the node: {}",
+                root, e
+            ))
+        };
+        let node = root.join(NODE_CGROUP);
+        std::fs::create_dir_all(&node).map_err(access)?;
+        std::fs::write(node.join("cgroup.procs"), std::process::id().to_string())
+            .map_err(access)?;
+
+        let mut controllers = Vec::new();
+        if settings.memory_max.is_some() {
+            controllers.push("+memory");
+        }
+        if settings.cpu_max.is_some() {
+            controllers.push("+cpu");
+        }
+        std::fs::write(root.join("cgroup.subtree_control"), controllers.join(" "))
+            .map_err(access)?;
+
+        let manager = CgroupManager {
+            root,
+            settings: settings.clone(),
+        };
+        manager.remove_stale();
+        info!(
+            "Encodes are limited by cgroups in {:?}: {:?}",
+            manager.root, manager.settings
+        );
+        Ok(Some(manager))
+    }
+
+    /// Removes cgroups of encodes left by previous run of the node
+    fn remove_stale(&self) {
+        let Ok(entries) = std::fs::read_dir(&self.root) else {
+            return;
+        };
+        for entry in entries.flatten() {
+            let path = entry.path();
+            let encode = entry
+                .file_name()
+                .to_str()
+                .is_some_and(|name| name.starts_with(ENCODE_PREFIX));
+            if encode && path.is_dir() {
+                debug!("Removing stale cgroup {:?}", path);
+                remove(&path);
+            }
+        }
+    }
+
+    /// Creates cgroup with configured limits, which is removed when it's dropped
+    pub fn create(&self, name: &str) -> Result<Cgroup, VideoEncodeError> {
+        if name.contains('/') || name.contains("..") {
+            return Err(VideoEncodeError::Encoding(format!(
+                "Invalid name of cgroup {:?}",
+                name
+            )));
+        }
+        let path = self.root.join(format!("{}{}", ENCODE_PREFIX, name));
+        std::fs::create_dir(&path)?;
+        let cgroup = Cgroup { path };
+
+        if let Some(memory_max) = self.settings.memory_max {
+            let bytes = memory_max * 1024 * 1024;
+            std::fs::write(cgroup.path.join("memory.max"), bytes.to_string())?;
+            // Decoder and encoder are useless without each other, so both are killed
+            std::fs::write(cgroup.path.join("memory.oom.group"), "1")?;
+        }
+        if let Some(cpu_max) = self.settings.cpu_max {
+            let quota = (cpu_max * CPU_PERIOD as f64).round().max(1000.0) as u64;
+            std::fs::write(
+                cgroup.path.join("cpu.max"),
+                format!("{} {}", quota, CPU_PERIOD),
+            )?;
+        }
+
+        debug!("Created cgroup {:?}", cgroup.path);
+        Ok(cgroup)
+    }
+}
+
+/// Cgroup of a single encode
+#[derive(Debug)]
+pub struct Cgroup {
+    path: PathBuf,
+}
+
+impl Drop for Cgroup {
+    fn drop(&mut self) {
+        remove(&self.path);
+    }
+}
+
+/// Kills processes that are left in cgroup, and removes it
+fn remove(path: &std::path::Path) {
+    let _ = std::fs::write(path.join("cgroup.kill"), "1");
+    // Killed processes leave the cgroup shortly after
+    for _ in 0..10 {
+        match std::fs::remove_dir(path) {
+            Ok(()) => return,
+            Err(_) => std::thread::sleep(std::time::Duration::from_millis(10)),
+        }
+    }
+    warn!("Failed to remove cgroup {:?}", path);
+}
+
+/// Runs `future` with processes it starts placed in `cgroup`
+pub async fn scope<F: Future>(cgroup: Option<Cgroup>, future: F) -> F::Output {
+    match cgroup {
+        Some(cgroup) => CURRENT.scope(Arc::new(cgroup), future).await,
+        None => future.await,
+    }
+}
+
+/// Places process of `command` in cgroup of the current encode, if it has one
+pub(crate) fn attach(command: &mut tokio::process::Command) {
+    #[cfg(target_os = "linux")]
+    if let Some(procs) = current_procs() {
+        // SAFETY: closure only makes async-signal-safe calls
+        unsafe {
+            command.pre_exec(move || join(&procs));
+        }
+    }
+    #[cfg(not(target_os = "linux"))]
+    let _ = command;
+}
+
+/// Places process of `command` in cgroup of the current encode, if it has one
+#[cfg(feature = "rav1e")]
+pub(crate) fn attach_std(command: &mut std::process::Command) {
+    #[cfg(target_os = "linux")]
+    if let Some(procs) = current_procs() {
+        use std::os::unix::process::CommandExt;
+        // SAFETY: closure only makes async-signal-safe calls
+        unsafe {
+            command.pre_exec(move || join(&procs));
+        }
+    }
+    #[cfg(not(target_os = "linux"))]
+    let _ = command;
+}
+
+/// Path of `cgroup.procs` of the current encode, prepared before the fork
+#[cfg(target_os = "linux")]
+fn current_procs() -> Option<std::ffi::CString> {
+    use std::os::unix::ffi::OsStrExt;
+
+    CURRENT
+        .try_with(|cgroup| {
+            std::ffi::CString::new(cgroup.path.join("cgroup.procs").as_os_str().as_bytes()).ok()
+        })
+        .ok()
+        .flatten()
+}
+
+/// Moves the calling process into cgroup, runs in the child between fork and exec
+#[cfg(target_os = "linux")]
+fn join(procs: &std::ffi::CStr) -> std::io::Result<()> {
+    // Writing 0 moves the process that writes it
+    unsafe {
+        let fd = libc::open(procs.as_ptr(), libc::O_WRONLY | libc::O_CLOEXEC);
+        if fd < 0 {
+            return Err(std::io::Error::last_os_error());
+        }
+        let written = libc::write(fd, b"0".as_ptr().cast(), 1);
+        libc::close(fd);
+        if written != 1 {
+            return Err(std::io::Error::last_os_error());
+        }
+    }
+    Ok(())
+}
@@ -1,9 +1,25 @@
+use crate::encoder::{encode_passes, Encoder};
 use crate::error::VideoEncodeError;
-use crate::ffmpeg::segment::segment_video;
+use crate::ffmpeg::probe::{
+    probe_duration, probe_frame_times, probe_keyframe_times, probe_media, probe_resolution,
+    MediaInfo,
+};
+use crate::ffmpeg::progress::Progress;
+use crate::ffmpeg::scene::detect_scenes;
+use crate::ffmpeg::segment::{
+    extract_segment, frame_splits, keyframe_splits, scene_splits, segment_video,
+    segment_video_parallel,
+};
+use crate::ffmpeg::sequence::{extract_images, probe_sequence};
+use crate::settings::{ProcessingSettings, SplitMethod};
+use crate::vapoursynth::{extract_frames, probe_script};
+use crate::zones::{read_zones, zone_boundaries, zone_splits};
 use serde::{Deserialize, Serialize};
 use std::path::{Path, PathBuf};
-use std::process::Command;
-use tracing::{debug, error, info, instrument};
+use tracing::{debug, error, info, instrument, warn};
+
+/// Default limit of segmenting processes, segmenting is mostly bound by disk
+const MAX_SEGMENT_JOBS: usize = 4;
 
 /// Represents a video chunk for processing
 #[derive(Debug, Serialize, Deserialize, Clone)]
@@ -11,7 +27,74 @@ pub struct Chunk {
     pub source_path: PathBuf,
     pub encoded_path: Option<PathBuf>,
     pub index: usize,
+    /// Position of the chunk in the output. Chunks split from another one get its
+    /// position followed by their own, so they are concatenated where it was.
+    pub position: Vec<usize>,
     pub encoder_parameters: Vec<String>,
+    /// Part of the input this chunk is extracted from into `source_path` on demand.
+    /// Not set when chunk was already written to `source_path`.
+    pub range: Option<SourceRange>,
+    /// Properties of the source, gathered when input is split
+    pub metadata: Option<MediaInfo>,
+}
+
+/// Split points stored in a file, so they can be reused or tuned by hand
+#[derive(Debug, Serialize, Deserialize)]
+struct SplitFile {
+    /// Input the split points were computed for
+    input: PathBuf,
+    /// Timestamps in seconds at which chunks start, except the first one
+    splits: Vec<f64>,
+}
+
+/// Part of the input that makes up a chunk
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub enum SourceRange {
+    /// Part of video file between two timestamps in seconds
+    Video {
+        input_path: PathBuf,
+        start: f64,
+        end: f64,
+        /// Extract as lossless intermediate instead of stream copy
+        lossless: bool,
+    },
+    /// Frames of VapourSynth script, `end` is exclusive
+    Script {
+        script_path: PathBuf,
+        start: usize,
+        end: usize,
+        fps: f64,
+    },
+    /// Images of numbered sequence, `end` is exclusive
+    Sequence {
+        pattern: PathBuf,
+        start: usize,
+        end: usize,
+        fps: f64,
+    },
+}
+
+impl SourceRange {
+    /// Same source with different frames, for ranges of frames
+    fn with_frames(&self, start: usize, end: usize) -> SourceRange {
+        match self {
+            SourceRange::Script {
+                script_path, fps, ..
+            } => SourceRange::Script {
+                script_path: script_path.clone(),
+                start,
+                end,
+                fps: *fps,
+            },
+            SourceRange::Sequence { pattern, fps, .. } => SourceRange::Sequence {
+                pattern: pattern.clone(),
+                start,
+                end,
+                fps: *fps,
+            },
+            SourceRange::Video { .. } => unreachable!("video is split by time"),
+        }
+    }
 }
 
 impl Chunk {
@@ -31,31 +114,202 @@ impl Chunk {
             source_path,
             encoded_path: None,
             index,
+            position: vec![index],
             encoder_parameters,
+            range: None,
+            metadata: None,
+        }
+    }
+
+    /// Identifies the source the chunk is read from, which renditions of the chunk share
+    pub fn source_key(&self) -> String {
+        match &self.range {
+            Some(range) => format!("{:?}", range),
+            None => self.source_path.to_string_lossy().to_string(),
         }
     }
 
-    #[instrument(skip(self))]
-    pub fn encode(&self, output_path: PathBuf) -> Result<Chunk, VideoEncodeError> {
+    /// Duration of the chunk in seconds, if its metadata is known
+    pub fn duration(&self) -> Option<f64> {
+        self.metadata.as_ref().map(|metadata| metadata.duration)
+    }
+
+    /// Reads source of the chunk. Chunk with a range is extracted from the input first,
+    /// and extracted file is removed once it's read.
+    #[instrument(skip(self), fields(chunk_index = self.index))]
+    pub async fn read_source(&self) -> Result<Vec<u8>, VideoEncodeError> {
+        let Some(range) = &self.range else {
+            return Ok(tokio::fs::read(&self.source_path).await?);
+        };
+
+        match range {
+            SourceRange::Video {
+                input_path,
+                start,
+                end,
+                lossless,
+            } => extract_segment(input_path, *start, *end, &self.source_path, *lossless).await?,
+            SourceRange::Script {
+                script_path,
+                start,
+                end,
+                ..
+            } => extract_frames(script_path, *start, *end, &self.source_path).await?,
+            SourceRange::Sequence {
+                pattern,
+                start,
+                end,
+                fps,
+            } => extract_images(pattern, *fps, *start, *end, &self.source_path).await?,
+        }
+
+        let data = tokio::fs::read(&self.source_path).await;
+        if let Err(e) = tokio::fs::remove_file(&self.source_path).await {
+            error!("Failed to remove {:?}: {}", self.source_path, e);
+        }
+
+        Ok(data?)
+    }
+
+    /// Splits chunk into up to `parts` smaller chunks, with indices starting from `first_index`.
+    /// Video is split at source keyframes, or exactly through lossless intermediate
+    /// when there are not enough keyframes in the chunk.
+    #[instrument(skip(self), fields(chunk_index = self.index))]
+    pub fn split(&self, parts: usize, first_index: usize) -> Result<Vec<Chunk>, VideoEncodeError> {
+        let ranges = match &self.range {
+            Some(
+                range @ (SourceRange::Script {
+                    start, end, fps, ..
+                }
+                | SourceRange::Sequence {
+                    start, end, fps, ..
+                }),
+            ) => {
+                let frames = end - start;
+                let parts = parts.min(frames);
+                (0..parts)
+                    .map(|part| {
+                        let (part_start, part_end) =
+                            (frames * part / parts, frames * (part + 1) / parts);
+                        let range = range.with_frames(start + part_start, start + part_end);
+                        let frames = (part_end - part_start) as u64;
+                        (range, frames, frames as f64 / fps)
+                    })
+                    .collect()
+            }
+            Some(SourceRange::Video {
+                input_path,
+                start,
+                end,
+                lossless,
+            }) => split_range(input_path, *start, *end, *lossless, parts)?,
+            // Segments start at 0, because their timestamps are reset when they are written
+            None => {
+                let duration = match self.duration() {
+                    Some(duration) => duration,
+                    None => probe_duration(&self.source_path)?,
+                };
+                split_range(&self.source_path, 0.0, duration, false, parts)?
+            }
+        };
+
+        if ranges.len() < 2 {
+            return Err(VideoEncodeError::ChunkProcessing(format!(
+                "Chunk {} is too short to be split",
+                self.index
+            )));
+        }
+
+        let (width, height) = self
+            .metadata
+            .as_ref()
+            .map_or((0, 0), |metadata| (metadata.width, metadata.height));
+        let stem = self
+            .source_path
+            .file_stem()
+            .unwrap_or_default()
+            .to_string_lossy();
+
+        let chunks: Vec<Chunk> = ranges
+            .into_iter()
+            .enumerate()
+            .map(|(part, (range, frames, duration))| {
+                let mut position = self.position.clone();
+                position.push(part);
+                Chunk {
+                    // Renditions split the same chunk, so every part gets its own file
+                    source_path: self.source_path.with_file_name(format!(
+                        "{}_{}.mp4",
+                        stem,
+                        first_index + part
+                    )),
+                    encoded_path: None,
+                    index: first_index + part,
+                    position,
+                    encoder_parameters: self.encoder_parameters.clone(),
+                    range: Some(range),
+                    metadata: Some(MediaInfo {
+                        frames,
+                        duration,
+                        width,
+                        height,
+                        size: None,
+                    }),
+                }
+            })
+            .collect();
+
+        info!("Split chunk {} into {} chunks", self.index, chunks.len());
+        Ok(chunks)
+    }
+
+    /// Encodes chunk into `output_path` with `encoder` in `passes`.
+    /// Encoder process is killed if returned future is dropped before it completes.
+    pub async fn encode(
+        &self,
+        encoder: &dyn Encoder,
+        passes: u32,
+        output_path: PathBuf,
+    ) -> Result<Chunk, VideoEncodeError> {
+        self.encode_with_progress(encoder, passes, output_path, |_| {})
+            .await
+    }
+
+    /// Encodes chunk into `output_path` with `encoder` in `passes`, calling `on_progress`
+    /// with every progress report of the encoder.
+    /// Encoder process is killed if returned future is dropped before it completes.
+    #[instrument(skip(self, on_progress))]
+    pub async fn encode_with_progress<F>(
+        &self,
+        encoder: &dyn Encoder,
+        passes: u32,
+        output_path: PathBuf,
+        mut on_progress: F,
+    ) -> Result<Chunk, VideoEncodeError>
+    where
+        F: FnMut(&Progress) + Send,
+    {
         debug!(
-            "Encoding chunk {}: source={:?}, output={:?}, encoder_parameters={:?} ",
-            self.index, self.source_path, output_path, self.encoder_parameters
+            "Encoding chunk {} with {} in {} passes: source={:?}, output={:?}, encoder_parameters={:?} ",
+            self.index,
+            encoder.name(),
+            passes,
+            self.source_path,
+            output_path,
+            self.encoder_parameters
         );
 
-        let command = Command::new("ffmpeg")
-            .arg("-hide_banner")
-            .arg("-i")
-            .arg(&self.source_path)
-            .args(&self.encoder_parameters)
-            .arg(&output_path)
-            .output()?;
-
-        if !command.status.success() {
-            let error_msg = format!(
-                "Failed to encode chunk {}: {:?}",
-                self.index,
-                String::from_utf8_lossy(&command.stderr)
-            );
+        if let Err(e) = encode_passes(
+            encoder,
+            &self.source_path,
+            &output_path,
+            &self.encoder_parameters,
+            passes,
+            &mut on_progress,
+        )
+        .await
+        {
+            let error_msg = format!("Failed to encode chunk {}: {}", self.index, e);
             error!("{}", error_msg);
             return Err(VideoEncodeError::Encoding(error_msg));
         }
@@ -65,7 +319,10 @@ impl Chunk {
             source_path: self.source_path.clone(),
             encoded_path: Some(output_path),
             index: self.index,
+            position: self.position.clone(),
             encoder_parameters: self.encoder_parameters.clone(),
+            range: self.range.clone(),
+            metadata: self.metadata.clone(),
         })
     }
 }
@@ -85,7 +342,11 @@ pub fn convert_files_to_chunks(
                 error!("Segment file does not exist: {:?}", path);
                 panic!("Segment file does not exist");
             }
-            Chunk::new(path, index, encoder_params.clone())
+            let mut chunk = Chunk::new(path, index, encoder_params.clone());
+            chunk.metadata = probe_media(&chunk.source_path)
+                .map_err(|e| warn!("Failed to probe chunk {}: {}", index, e))
+                .ok();
+            chunk
         })
         .collect();
 
@@ -96,17 +357,44 @@ pub fn convert_files_to_chunks(
 #[instrument(skip(encoder_params))]
 pub fn split_video(
     input_path: &Path,
-    segment_duration: f64,
+    processing: &ProcessingSettings,
     segment_dir: &Path,
     encoder_params: &[String],
     encode_dir: &Path,
 ) -> Result<Vec<PathBuf>, VideoEncodeError> {
     debug!(
-        "Splitting video: input={:?}, duration={}, segment_dir={:?}, params={:?}, encode_dir={:?}",
-        input_path, segment_duration, segment_dir, encoder_params, encode_dir
+        "Splitting video: input={:?}, method={:?}, duration={}, segment_dir={:?}, params={:?}, encode_dir={:?}",
+        input_path, processing.split_method, processing.segment_duration, segment_dir, encoder_params, encode_dir
     );
 
-    let segmented_files = segment_video(input_path, segment_duration, segment_dir)?;
+    let jobs = processing.segment_jobs.unwrap_or_else(|| {
+        std::thread::available_parallelism()
+            .map_or(1, |jobs| jobs.get())
+            .min(MAX_SEGMENT_JOBS)
+    });
+
+    let segmented_files = match (processing.split_method, processing.segment_frames) {
+        (SplitMethod::Time, None)
+            if jobs <= 1
+                && processing.import_splits.is_none()
+                && processing.export_splits.is_none()
+                && processing.zones.is_none() =>
+        {
+            segment_video(
+                input_path,
+                processing.segment_duration,
+                segment_dir,
+                processing.lossless_intermediate,
+            )?
+        }
+        _ => segment_video_parallel(
+            input_path,
+            &plan_splits(input_path, processing)?,
+            segment_dir,
+            processing.lossless_intermediate,
+            jobs,
+        )?,
+    };
 
     info!(
         "Video segmentation complete: {} files",
@@ -116,6 +404,367 @@ pub fn split_video(
     Ok(segmented_files)
 }
 
+/// Plans chunks without writing them to disk. Every chunk is extracted from
+/// the source when it's dispatched, so the source isn't stored twice.
+#[instrument(skip(encoder_params))]
+pub fn index_chunks(
+    input_path: &Path,
+    processing: &ProcessingSettings,
+    segment_dir: &Path,
+    encoder_params: Vec<String>,
+) -> Result<Vec<Chunk>, VideoEncodeError> {
+    std::fs::create_dir_all(segment_dir)?;
+
+    let total_duration = probe_duration(input_path)?;
+    let mut splits = plan_splits(input_path, processing)?;
+
+    // Stream copy can only start at keyframes, so chunk starts at the first keyframe
+    // after its split point, same as with segment muxer
+    if !processing.lossless_intermediate {
+        let keyframes = probe_keyframe_times(input_path)?;
+        splits = splits
+            .iter()
+            .filter_map(|&split| keyframes.iter().copied().find(|&time| time >= split))
+            .filter(|&time| time > 0.0 && time < total_duration)
+            .collect();
+        splits.dedup();
+    }
+
+    let boundaries: Vec<f64> = std::iter::once(0.0)
+        .chain(splits)
+        .chain(std::iter::once(total_duration))
+        .collect();
+
+    let (width, height) = probe_resolution(input_path)?;
+    let frame_times = probe_frame_times(input_path)?;
+
+    let chunks: Vec<Chunk> = boundaries
+        .windows(2)
+        .enumerate()
+        .map(|(index, window)| Chunk {
+            source_path: segment_dir.join(format!("chunk_{:04}.mp4", index)),
+            encoded_path: None,
+            index,
+            position: vec![index],
+            encoder_parameters: encoder_params.clone(),
+            range: Some(SourceRange::Video {
+                input_path: input_path.to_path_buf(),
+                start: window[0],
+                end: window[1],
+                lossless: processing.lossless_intermediate,
+            }),
+            metadata: Some(MediaInfo {
+                frames: frame_times
+                    .iter()
+                    .filter(|&&time| time >= window[0] && time < window[1])
+                    .count() as u64,
+                duration: window[1] - window[0],
+                width,
+                height,
+                size: None,
+            }),
+        })
+        .collect();
+
+    info!("Indexed {} chunks", chunks.len());
+    Ok(chunks)
+}
+
+/// Splits part of video between `start` and `end` into up to `parts` ranges of similar
+/// duration, returned with their frame count and duration
+fn split_range(
+    input_path: &Path,
+    start: f64,
+    end: f64,
+    lossless: bool,
+    parts: usize,
+) -> Result<Vec<(SourceRange, u64, f64)>, VideoEncodeError> {
+    let frame_times: Vec<f64> = probe_frame_times(input_path)?
+        .into_iter()
+        .filter(|&time| time >= start && time < end)
+        .collect();
+    let targets = (1..parts).map(|part| start + (end - start) * part as f64 / parts as f64);
+
+    let keyframes: Vec<f64> = if lossless {
+        Vec::new()
+    } else {
+        probe_keyframe_times(input_path)?
+            .into_iter()
+            .filter(|&time| time > start && time < end)
+            .collect()
+    };
+
+    // Stream copy can only start at keyframes, without them chunk is split exactly
+    let lossless = lossless || keyframes.is_empty();
+    let mut splits: Vec<f64> = if lossless {
+        targets
+            .filter_map(|target| frame_times.iter().copied().find(|&time| time >= target))
+            .filter(|&time| time > start)
+            .collect()
+    } else {
+        targets
+            .filter_map(|target| {
+                keyframes
+                    .iter()
+                    .copied()
+                    .min_by(|a, b| (a - target).abs().total_cmp(&(b - target).abs()))
+            })
+            .collect()
+    };
+    splits.dedup();
+
+    let boundaries: Vec<f64> = std::iter::once(start)
+        .chain(splits)
+        .chain(std::iter::once(end))
+        .collect();
+
+    Ok(boundaries
+        .windows(2)
+        .map(|window| {
+            let range = SourceRange::Video {
+                input_path: input_path.to_path_buf(),
+                start: window[0],
+                end: window[1],
+                lossless,
+            };
+            let frames = frame_times
+                .iter()
+                .filter(|&&time| time >= window[0] && time < window[1])
+                .count() as u64;
+            (range, frames, window[1] - window[0])
+        })
+        .collect())
+}
+
+/// Plans chunks of VapourSynth script by frame ranges.
+/// Chunks are rendered by the script when they are dispatched.
+#[instrument(skip(encoder_params))]
+pub fn index_script_chunks(
+    script_path: &Path,
+    processing: &ProcessingSettings,
+    segment_dir: &Path,
+    encoder_params: Vec<String>,
+) -> Result<Vec<Chunk>, VideoEncodeError> {
+    let info = probe_script(script_path)?;
+    let range = SourceRange::Script {
+        script_path: script_path.to_path_buf(),
+        start: 0,
+        end: info.frames,
+        fps: info.fps(),
+    };
+
+    index_frame_chunks(
+        range,
+        (info.width, info.height),
+        processing,
+        segment_dir,
+        encoder_params,
+    )
+}
+
+/// Plans chunks of numbered image sequence by frame ranges, at `fps` frames per second.
+/// Chunks are encoded from images when they are dispatched.
+#[instrument(skip(encoder_params))]
+pub fn index_sequence_chunks(
+    pattern: &Path,
+    fps: f64,
+    processing: &ProcessingSettings,
+    segment_dir: &Path,
+    encoder_params: Vec<String>,
+) -> Result<Vec<Chunk>, VideoEncodeError> {
+    let info = probe_sequence(pattern)?;
+    let range = SourceRange::Sequence {
+        pattern: pattern.to_path_buf(),
+        start: info.first,
+        end: info.first + info.frames,
+        fps,
+    };
+
+    index_frame_chunks(
+        range,
+        (info.width, info.height),
+        processing,
+        segment_dir,
+        encoder_params,
+    )
+}
+
+/// Splits frame range of the whole input into chunks of `segment_frames`,
+/// or of `segment_duration` when frame count isn't set
+fn index_frame_chunks(
+    input: SourceRange,
+    (width, height): (u32, u32),
+    processing: &ProcessingSettings,
+    segment_dir: &Path,
+    encoder_params: Vec<String>,
+) -> Result<Vec<Chunk>, VideoEncodeError> {
+    let (SourceRange::Script {
+        start: first,
+        end: last,
+        fps,
+        ..
+    }
+    | SourceRange::Sequence {
+        start: first,
+        end: last,
+        fps,
+        ..
+    }) = input
+    else {
+        unreachable!("video is split by time");
+    };
+
+    std::fs::create_dir_all(segment_dir)?;
+
+    if processing.split_method != SplitMethod::Time {
+        info!("Input is split by frame count, ignoring split method");
+    }
+
+    let frames_per_chunk = processing
+        .segment_frames
+        .unwrap_or_else(|| (processing.segment_duration * fps).round() as usize)
+        .max(1);
+
+    // Zones start and end at chunk boundaries, so every chunk is in a single zone
+    let mut boundaries: Vec<usize> = (first..last).step_by(frames_per_chunk).collect();
+    if let Some(path) = &processing.zones {
+        boundaries.extend(
+            zone_boundaries(&read_zones(path)?)
+                .into_iter()
+                .map(|frame| first + frame)
+                .filter(|&frame| frame < last),
+        );
+        boundaries.sort_unstable();
+        boundaries.dedup();
+    }
+    boundaries.push(last);
+
+    let chunks: Vec<Chunk> = boundaries
+        .windows(2)
+        .enumerate()
+        .map(|(index, window)| {
+            let (start, end) = (window[0], window[1]);
+            Chunk {
+                source_path: segment_dir.join(format!("chunk_{:04}.mp4", index)),
+                encoded_path: None,
+                index,
+                position: vec![index],
+                encoder_parameters: encoder_params.clone(),
+                range: Some(input.with_frames(start, end)),
+                metadata: Some(MediaInfo {
+                    frames: (end - start) as u64,
+                    duration: (end - start) as f64 / fps,
+                    width,
+                    height,
+                    size: None,
+                }),
+            }
+        })
+        .collect();
+
+    info!(
+        "Indexed {} chunks of up to {} frames",
+        chunks.len(),
+        frames_per_chunk
+    );
+    Ok(chunks)
+}
+
+/// Picks timestamps in seconds at which chunks should start, according to split method
+#[instrument]
+fn plan_splits(
+    input_path: &Path,
+    processing: &ProcessingSettings,
+) -> Result<Vec<f64>, VideoEncodeError> {
+    let mut splits = match &processing.import_splits {
+        Some(path) => {
+            let file: SplitFile = serde_json::from_slice(&std::fs::read(path)?)?;
+            if file.input.file_name() != input_path.file_name() {
+                warn!(
+                    "Split points in {:?} were computed for {:?}, not {:?}",
+                    path, file.input, input_path
+                );
+            }
+            info!(
+                "Imported {} split points from {:?}",
+                file.splits.len(),
+                path
+            );
+            file.splits
+        }
+        None => compute_splits(input_path, processing)?,
+    };
+
+    // Zones start and end at split points, so every chunk is in a single zone
+    if let Some(path) = &processing.zones {
+        splits.extend(zone_splits(
+            &read_zones(path)?,
+            &probe_frame_times(input_path)?,
+        ));
+        splits.sort_by(f64::total_cmp);
+        splits.dedup();
+    }
+
+    if let Some(path) = &processing.export_splits {
+        let file = SplitFile {
+            input: input_path.to_path_buf(),
+            splits: splits.clone(),
+        };
+        std::fs::write(path, serde_json::to_string_pretty(&file)?)?;
+        info!("Exported {} split points to {:?}", splits.len(), path);
+    }
+
+    Ok(splits)
+}
+
+fn compute_splits(
+    input_path: &Path,
+    processing: &ProcessingSettings,
+) -> Result<Vec<f64>, VideoEncodeError> {
+    let splits = match (processing.split_method, processing.segment_frames) {
+        (SplitMethod::Time, Some(frames)) => frame_splits(&probe_frame_times(input_path)?, frames),
+        (SplitMethod::Time, None) => {
+            let total_duration = probe_duration(input_path)?;
+            let segment_duration = processing.segment_duration;
+            (1..)
+                .map(|segment| segment as f64 * segment_duration)
+                .take_while(|&time| segment_duration > 0.0 && time < total_duration)
+                .collect()
+        }
+        (method, _) => {
+            let total_duration = probe_duration(input_path)?;
+            let keyframes = probe_keyframe_times(input_path)?;
+
+            if method == SplitMethod::Scene {
+                // Lossless intermediate gets keyframes at every split point,
+                // otherwise extra splits are moved to source keyframes where possible
+                let snap_to: &[f64] = if processing.lossless_intermediate {
+                    &[]
+                } else {
+                    &keyframes
+                };
+                scene_splits(
+                    &detect_scenes(input_path, processing.scene_threshold)?,
+                    snap_to,
+                    total_duration,
+                    processing.min_segment_duration,
+                    processing.segment_duration,
+                )
+            } else {
+                keyframe_splits(
+                    &keyframes,
+                    total_duration,
+                    processing.min_segment_duration,
+                    processing.segment_duration,
+                )
+            }
+        }
+    };
+
+    debug!("Splitting at {:?}", splits);
+    Ok(splits)
+}
+
 /// Verifies that FFmpeg is installed and accessible
 ///
 /// # Returns
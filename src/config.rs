@@ -1,5 +1,8 @@
 use sha2::{Digest, Sha256};
-use std::{fs, path::PathBuf};
+use std::{
+    fs,
+    path::{Path, PathBuf},
+};
 use tracing::{debug, instrument};
 
 use crate::{error::VideoEncodeError, settings::Settings};
@@ -54,6 +57,11 @@ impl TempConfig {
         self.temp_dir.join("encoded")
     }
 
+    /// Get the path for caching received chunks
+    pub fn cache_dir(&self) -> PathBuf {
+        self.temp_dir.join("cache")
+    }
+
     pub fn delete(self) -> Result<(), VideoEncodeError> {
         // Delete the base temp_dir
         if self.temp_dir.exists() {
@@ -78,7 +86,7 @@ pub fn create_temp_config(
     )
 }
 
-fn generate_hash(input_file: &PathBuf, output_file: &str) -> String {
+fn generate_hash(input_file: &Path, output_file: &str) -> String {
     let mut hasher = Sha256::new();
     hasher.update(input_file.to_string_lossy().as_bytes());
     hasher.update(output_file.as_bytes());
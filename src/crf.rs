@@ -0,0 +1,381 @@
+/// This module selects constant rate factor of an output per title, or of every chunk.
+/// Per title, sampled chunks are encoded at candidate CRFs, and the highest CRF whose
+/// average quality meets the target is selected, or a higher one when bitrate of the
+/// samples exceeds the limit. Per chunk, the chunk is encoded at a few CRFs, and CRF
+/// at which its quality falls to the target is interpolated between them.
+use std::{
+    collections::BTreeMap,
+    path::{Path, PathBuf},
+};
+
+use futures::future::try_join_all;
+use tracing::{debug, info, instrument, warn};
+
+use crate::chunk::Chunk;
+use crate::encoder::{with_crf, Encoder};
+use crate::error::VideoEncodeError;
+use crate::ffmpeg::quality::{measure_quality, Measurement};
+use crate::settings::CrfSettings;
+use crate::zones::override_params;
+
+/// Chunk of the input that is encoded at every probed CRF
+#[derive(Debug)]
+pub struct Sample {
+    path: PathBuf,
+    duration: f64,
+    params: Vec<String>,
+}
+
+/// Average quality and bitrate in kbit/s of samples encoded at a CRF
+#[derive(Debug, Clone, Copy)]
+struct Probe {
+    score: f64,
+    bitrate: f64,
+}
+
+/// Picks up to `count` chunks spread evenly over `chunks`, the one in the middle of
+/// every equal part. Chunks of unknown duration aren't picked.
+pub fn representative_chunks(chunks: &[Chunk], count: usize) -> Vec<&Chunk> {
+    let candidates: Vec<&Chunk> = chunks
+        .iter()
+        .filter(|chunk| chunk.duration().is_some_and(|duration| duration > 0.0))
+        .collect();
+    let count = count.min(candidates.len());
+    (0..count)
+        .map(|part| candidates[(2 * part + 1) * candidates.len() / (2 * count)])
+        .collect()
+}
+
+/// Picks up to `count` chunks spread evenly over the input, and extracts sources
+/// of chunks that are extracted on demand into `dir`. Chunks of unknown duration
+/// aren't sampled.
+#[instrument(skip(chunks))]
+pub async fn sample_chunks(
+    chunks: &[Chunk],
+    count: usize,
+    dir: &Path,
+) -> Result<Vec<Sample>, VideoEncodeError> {
+    let chunks = representative_chunks(chunks, count);
+
+    let mut samples = Vec::with_capacity(chunks.len());
+    for chunk in chunks {
+        let path = match chunk.range {
+            None => chunk.source_path.clone(),
+            Some(_) => {
+                let path = dir.join(chunk.source_path.file_name().unwrap_or_default());
+                tokio::fs::write(&path, chunk.read_source().await?).await?;
+                path
+            }
+        };
+        samples.push(Sample {
+            path,
+            duration: chunk.duration().unwrap_or_default(),
+            params: chunk.encoder_parameters.clone(),
+        });
+    }
+
+    Ok(samples)
+}
+
+/// Selects CRF that `samples` are encoded at with `encoder`, when they are filtered
+/// with `filter`. Quality is measured as `measurement` says. Probe encodes are written
+/// into `dir`, and removed once measured.
+#[instrument(skip(samples, encoder, settings))]
+pub async fn select_crf(
+    samples: &[Sample],
+    encoder: &dyn Encoder,
+    settings: &CrfSettings,
+    measurement: &Measurement,
+    filter: &str,
+    dir: &Path,
+) -> Result<u32, VideoEncodeError> {
+    let target = target(settings)?;
+    let first = samples.first().ok_or_else(|| {
+        VideoEncodeError::Encoding("There are no chunks of known duration to sample".to_string())
+    })?;
+    let (lowest, highest) = crf_range(encoder, &first.params, settings)?;
+
+    let mut prober = Prober {
+        samples,
+        encoder,
+        settings,
+        measurement,
+        filter,
+        dir,
+        probes: BTreeMap::new(),
+    };
+
+    // Quality falls as CRF grows, so the highest CRF that meets the target is bisected
+    let (mut low, mut high) = (lowest, highest);
+    let mut selected = None;
+    while low <= high {
+        let crf = low + (high - low) / 2;
+        if settings
+            .metric
+            .meets(prober.probe(crf).await?.score, target)
+        {
+            selected = Some(crf);
+            low = crf + 1;
+        } else if crf == lowest {
+            break;
+        } else {
+            high = crf - 1;
+        }
+    }
+    let mut crf = selected.unwrap_or_else(|| {
+        warn!(
+            "{:?} of {} isn't reached even at CRF {}",
+            settings.metric, target, lowest
+        );
+        lowest
+    });
+
+    // Bitrate falls as CRF grows too, so the lowest CRF within the limit is bisected
+    if let Some(max_bitrate) = settings.max_bitrate {
+        let max_bitrate = max_bitrate as f64;
+        if prober.probe(crf).await?.bitrate > max_bitrate {
+            let (mut low, mut high) = (crf + 1, highest);
+            let mut fitting = highest;
+            while low <= high {
+                let candidate = low + (high - low) / 2;
+                if prober.probe(candidate).await?.bitrate <= max_bitrate {
+                    fitting = candidate;
+                    high = candidate - 1;
+                } else {
+                    low = candidate + 1;
+                }
+            }
+            warn!(
+                "CRF {} is raised to {} to stay within {} kbit/s, below target quality",
+                crf, fitting, max_bitrate
+            );
+            crf = fitting;
+        }
+    }
+
+    let probe = prober.probe(crf).await?;
+    info!(
+        "Selected CRF {}, samples have {:?} of {:.2} at {:.0} kbit/s",
+        crf, settings.metric, probe.score, probe.bitrate
+    );
+    Ok(crf)
+}
+
+/// Selects CRF that `source` of a single chunk reaches target quality at. It's encoded
+/// at `settings.probes` CRFs spread over the range, and CRF at which quality falls to
+/// the target is interpolated between the two probes around it. `params` are parameters
+/// the chunk is encoded with, which `filter` is applied to already. Quality is measured
+/// as `measurement` says. Probe encodes are written into `dir`, and removed once measured.
+#[instrument(skip(source, encoder, params, settings))]
+pub async fn search_chunk_crf(
+    source: &Path,
+    encoder: &dyn Encoder,
+    params: &[String],
+    settings: &CrfSettings,
+    measurement: &Measurement,
+    filter: &str,
+    dir: &Path,
+) -> Result<u32, VideoEncodeError> {
+    let target = target(settings)?;
+    let (lowest, highest) = crf_range(encoder, params, settings)?;
+
+    let count = settings.probes.max(2) as u32;
+    let mut crfs: Vec<u32> = (0..count)
+        .map(|probe| lowest + (highest - lowest) * probe / (count - 1))
+        .collect();
+    crfs.dedup();
+
+    // Probes share the slot of the encode, so they run one after another
+    let mut probes = Vec::with_capacity(crfs.len());
+    for crf in crfs {
+        let output = dir.join(format!("probe_crf_{}.mkv", crf));
+        let (score, _) = probe_encode(
+            source,
+            encoder,
+            params,
+            crf,
+            settings,
+            measurement,
+            filter,
+            &output,
+        )
+        .await?;
+        debug!("CRF {} gives {:?} of {:.2}", crf, settings.metric, score);
+        probes.push((crf, score));
+    }
+
+    // Quality falls as CRF grows, so the target is between the last probe that meets it
+    // and the first one that doesn't
+    let crf = match probes
+        .iter()
+        .position(|&(_, score)| !settings.metric.meets(score, target))
+    {
+        None => highest,
+        Some(0) => {
+            warn!(
+                "{:?} of {} isn't reached even at CRF {}",
+                settings.metric, target, lowest
+            );
+            lowest
+        }
+        Some(below) => {
+            let (met_crf, met_score) = probes[below - 1];
+            let (missed_crf, missed_score) = probes[below];
+            let share = (met_score - target) / (met_score - missed_score);
+            met_crf + (share * (missed_crf - met_crf) as f64).floor() as u32
+        }
+    };
+
+    debug!(
+        "Selected CRF {} for {:?} of {}",
+        crf, settings.metric, target
+    );
+    Ok(crf)
+}
+
+/// Score that encodes have to reach
+fn target(settings: &CrfSettings) -> Result<f64, VideoEncodeError> {
+    settings
+        .target
+        .ok_or_else(|| VideoEncodeError::Encoding("Target quality is not set".to_string()))
+}
+
+/// Lowest and highest CRF that can be selected, range of `settings` within range of encoder
+fn crf_range(
+    encoder: &dyn Encoder,
+    params: &[String],
+    settings: &CrfSettings,
+) -> Result<(u32, u32), VideoEncodeError> {
+    let (_, range) = encoder.crf_option(params).ok_or_else(|| {
+        VideoEncodeError::Encoding(format!(
+            "{} can't encode at constant rate factor",
+            encoder.name()
+        ))
+    })?;
+    let lowest = settings.min.unwrap_or(0).max(*range.start());
+    let highest = settings.max.unwrap_or(u32::MAX).min(*range.end());
+    if lowest > highest {
+        return Err(VideoEncodeError::Encoding(format!(
+            "CRF range {}-{} is outside of range {}-{} of {}",
+            lowest,
+            highest,
+            range.start(),
+            range.end(),
+            encoder.name()
+        )));
+    }
+    Ok((lowest, highest))
+}
+
+/// Encodes `source` with `params` at `crf` into `output`, returns its quality and size
+/// in kbit. `params` have `filter` applied already. Output is removed once it's measured.
+#[allow(clippy::too_many_arguments)]
+async fn probe_encode(
+    source: &Path,
+    encoder: &dyn Encoder,
+    params: &[String],
+    crf: u32,
+    settings: &CrfSettings,
+    measurement: &Measurement,
+    filter: &str,
+    output: &Path,
+) -> Result<(f64, f64), VideoEncodeError> {
+    let params = override_params(params, &settings.probe_params);
+    let params = with_crf(encoder, &params, crf).ok_or_else(|| {
+        VideoEncodeError::Encoding(format!(
+            "{} can't encode at constant rate factor",
+            encoder.name()
+        ))
+    })?;
+
+    let result = async {
+        encoder.encode(source, output, &params, &mut |_| {}).await?;
+        let size = tokio::fs::metadata(output).await?.len();
+        let score = measure_quality(output, source, filter, settings.metric, measurement).await?;
+        Ok((score, size as f64 * 8.0 / 1000.0))
+    }
+    .await;
+
+    let _ = tokio::fs::remove_file(output).await;
+    result
+}
+
+/// Encodes samples at CRFs, remembering results of CRFs that were probed
+struct Prober<'a> {
+    samples: &'a [Sample],
+    encoder: &'a dyn Encoder,
+    settings: &'a CrfSettings,
+    measurement: &'a Measurement,
+    filter: &'a str,
+    dir: &'a Path,
+    probes: BTreeMap<u32, Probe>,
+}
+
+impl Prober<'_> {
+    async fn probe(&mut self, crf: u32) -> Result<Probe, VideoEncodeError> {
+        if let Some(probe) = self.probes.get(&crf) {
+            return Ok(*probe);
+        }
+
+        let this = &*self;
+        let results = try_join_all(
+            this.samples
+                .iter()
+                .enumerate()
+                .map(|(number, sample)| this.probe_sample(number, sample, crf)),
+        )
+        .await?;
+
+        // Every sample counts by its duration
+        let duration: f64 = this.samples.iter().map(|sample| sample.duration).sum();
+        let score = results
+            .iter()
+            .zip(this.samples)
+            .map(|((score, _), sample)| score * sample.duration)
+            .sum::<f64>()
+            / duration;
+        let bitrate = results.iter().map(|(_, kbit)| kbit).sum::<f64>() / duration;
+
+        info!(
+            "CRF {} gives {:?} of {:.2} at {:.0} kbit/s",
+            crf, self.settings.metric, score, bitrate
+        );
+        let probe = Probe { score, bitrate };
+        self.probes.insert(crf, probe);
+        Ok(probe)
+    }
+
+    /// Encodes sample at `crf`, returns its quality and size in kbit
+    async fn probe_sample(
+        &self,
+        number: usize,
+        sample: &Sample,
+        crf: u32,
+    ) -> Result<(f64, f64), VideoEncodeError> {
+        let params = if self.filter.is_empty() {
+            sample.params.clone()
+        } else {
+            self.encoder
+                .with_filter(&sample.params, self.filter)
+                .ok_or_else(|| {
+                    VideoEncodeError::Encoding(format!(
+                        "Encoder {} can't filter frames",
+                        self.encoder.name()
+                    ))
+                })?
+        };
+
+        let output = self.dir.join(format!("sample_{}_crf_{}.mkv", number, crf));
+        probe_encode(
+            &sample.path,
+            self.encoder,
+            &params,
+            crf,
+            self.settings,
+            self.measurement,
+            self.filter,
+            &output,
+        )
+        .await
+    }
+}
@@ -0,0 +1,191 @@
+/// This module encrypts chunk payloads, so they can't be read by anything
+/// between client and node, even when transport itself is not encrypted.
+/// Client and nodes share a master key, every job uses its own key derived from it.
+use std::{fs, path::Path};
+
+use chacha20poly1305::{
+    aead::{Aead, AeadCore, KeyInit, OsRng, Payload},
+    XChaCha20Poly1305, XNonce,
+};
+use hkdf::Hkdf;
+use sha2::Sha256;
+use tracing::instrument;
+
+use crate::error::VideoEncodeError;
+
+const KEY_SIZE: usize = 32;
+const NONCE_SIZE: usize = 24;
+
+/// Direction of payload, so request payload can't be passed off as response
+#[derive(Debug, Clone, Copy)]
+pub enum Direction {
+    Request,
+    Response,
+}
+
+/// Master key shared by client and nodes
+#[derive(Clone)]
+pub struct MasterKey([u8; KEY_SIZE]);
+
+impl std::fmt::Debug for MasterKey {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str("MasterKey(..)")
+    }
+}
+
+impl MasterKey {
+    /// Loads hex encoded 32 byte key from file
+    #[instrument]
+    pub fn from_file(path: &Path) -> Result<Self, VideoEncodeError> {
+        let content = fs::read_to_string(path)?;
+        let bytes = hex::decode(content.trim()).map_err(|e| {
+            VideoEncodeError::Encryption(format!("Invalid key in {:?}: {}", path, e))
+        })?;
+
+        let key = bytes.try_into().map_err(|_| {
+            VideoEncodeError::Encryption(format!(
+                "Key in {:?} has to be {} bytes long",
+                path, KEY_SIZE
+            ))
+        })?;
+
+        Ok(MasterKey(key))
+    }
+
+    /// Derives cipher for a single job
+    pub fn job_cipher(&self, job_id: &str) -> JobCipher {
+        let hkdf = Hkdf::<Sha256>::new(Some(job_id.as_bytes()), &self.0);
+        let mut key = [0u8; KEY_SIZE];
+        hkdf.expand(b"rav1an chunk payload", &mut key)
+            .expect("key size is valid for hkdf");
+
+        JobCipher {
+            cipher: XChaCha20Poly1305::new(&key.into()),
+        }
+    }
+}
+
+/// Encrypts and decrypts payloads of a single job
+pub struct JobCipher {
+    cipher: XChaCha20Poly1305,
+}
+
+impl JobCipher {
+    /// Encrypts payload of chunk, result is nonce followed by ciphertext
+    pub fn encrypt(
+        &self,
+        data: &[u8],
+        chunk_index: i32,
+        direction: Direction,
+    ) -> Result<Vec<u8>, VideoEncodeError> {
+        let nonce = XChaCha20Poly1305::generate_nonce(&mut OsRng);
+        let aad = associated_data(chunk_index, direction);
+
+        let ciphertext = self
+            .cipher
+            .encrypt(
+                &nonce,
+                Payload {
+                    msg: data,
+                    aad: &aad,
+                },
+            )
+            .map_err(|e| VideoEncodeError::Encryption(e.to_string()))?;
+
+        let mut payload = Vec::with_capacity(NONCE_SIZE + ciphertext.len());
+        payload.extend_from_slice(&nonce);
+        payload.extend_from_slice(&ciphertext);
+
+        Ok(payload)
+    }
+
+    /// Decrypts payload produced by `encrypt`, failing if it was tampered with
+    pub fn decrypt(
+        &self,
+        payload: &[u8],
+        chunk_index: i32,
+        direction: Direction,
+    ) -> Result<Vec<u8>, VideoEncodeError> {
+        if payload.len() < NONCE_SIZE {
+            return Err(VideoEncodeError::Encryption(
+                "Encrypted payload is too short".to_string(),
+            ));
+        }
+
+        let (nonce, ciphertext) = payload.split_at(NONCE_SIZE);
+        let aad = associated_data(chunk_index, direction);
+
+        self.cipher
+            .decrypt(
+                XNonce::from_slice(nonce),
+                Payload {
+                    msg: ciphertext,
+                    aad: &aad,
+                },
+            )
+            .map_err(|_| {
+                VideoEncodeError::Encryption(format!(
+                    "Failed to decrypt payload of chunk {}",
+                    chunk_index
+                ))
+            })
+    }
+}
+
+/// Binds payload to its chunk and direction
+fn associated_data(chunk_index: i32, direction: Direction) -> Vec<u8> {
+    let mut aad = chunk_index.to_le_bytes().to_vec();
+    aad.push(direction as u8);
+    aad
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const KEY: MasterKey = MasterKey([7; KEY_SIZE]);
+
+    #[test]
+    fn payload_round_trips() {
+        let cipher = KEY.job_cipher("job");
+        let payload = cipher
+            .encrypt(b"chunk data", 3, Direction::Request)
+            .unwrap();
+
+        assert_ne!(&payload[NONCE_SIZE..], b"chunk data");
+        assert_eq!(
+            cipher.decrypt(&payload, 3, Direction::Request).unwrap(),
+            b"chunk data"
+        );
+    }
+
+    #[test]
+    fn tampered_payload_is_rejected() {
+        let cipher = KEY.job_cipher("job");
+        let mut payload = cipher
+            .encrypt(b"chunk data", 3, Direction::Request)
+            .unwrap();
+
+        let last = payload.len() - 1;
+        payload[last] ^= 1;
+        assert!(cipher.decrypt(&payload, 3, Direction::Request).is_err());
+        assert!(cipher
+            .decrypt(&payload[..10], 3, Direction::Request)
+            .is_err());
+    }
+
+    #[test]
+    fn payload_is_bound_to_its_job_chunk_and_direction() {
+        let cipher = KEY.job_cipher("job");
+        let payload = cipher
+            .encrypt(b"chunk data", 3, Direction::Request)
+            .unwrap();
+
+        assert!(cipher.decrypt(&payload, 4, Direction::Request).is_err());
+        assert!(cipher.decrypt(&payload, 3, Direction::Response).is_err());
+        assert!(KEY
+            .job_cipher("other job")
+            .decrypt(&payload, 3, Direction::Request)
+            .is_err());
+    }
+}
@@ -0,0 +1,180 @@
+/// This module draws a dashboard of an encode in the terminal, in place of log lines:
+/// progress of the whole job, load and traffic of every node, and chunks that are
+/// being encoded. Terminal is left as it was when the dashboard is dropped.
+use std::io::{self, Stdout};
+use std::time::Duration;
+
+use ratatui::backend::CrosstermBackend;
+use ratatui::crossterm::execute;
+use ratatui::crossterm::terminal::{EnterAlternateScreen, LeaveAlternateScreen};
+use ratatui::layout::{Constraint, Layout};
+use ratatui::style::{Color, Style, Stylize};
+use ratatui::widgets::{Block, Gauge, Row, Table};
+use ratatui::{Frame, Terminal};
+
+use crate::progress::format_duration;
+
+/// Node as the dashboard shows it
+#[derive(Debug, Clone)]
+pub struct NodeView {
+    pub address: String,
+    pub slots: usize,
+    /// Chunks that are being sent, encoded or received
+    pub active: usize,
+    pub completed: usize,
+    pub failed: usize,
+    /// Bytes per second sent to the node
+    pub upload_rate: f64,
+    /// Bytes per second received from the node
+    pub download_rate: f64,
+}
+
+/// Chunk that is being encoded
+#[derive(Debug, Clone)]
+pub struct ChunkView {
+    pub index: usize,
+    pub node: String,
+    pub fps: f64,
+    /// Part of the chunk that is encoded, from 0 to 1
+    pub fraction: f64,
+    /// Estimated time until the chunk is encoded, at its current speed
+    pub eta: Option<Duration>,
+}
+
+/// State of the encode at one moment
+#[derive(Debug, Clone, Default)]
+pub struct DashboardView {
+    pub nodes: Vec<NodeView>,
+    pub chunks: Vec<ChunkView>,
+    pub completed: usize,
+    pub pending: usize,
+    pub total: usize,
+    /// Part of the job that is encoded, from 0 to 1
+    pub fraction: f64,
+    pub fps: f64,
+    pub eta: Option<Duration>,
+    pub elapsed: Duration,
+    /// Failed attempts of chunks
+    pub retries: usize,
+}
+
+/// Dashboard drawn on the alternate screen of the terminal
+pub struct Dashboard {
+    terminal: Terminal<CrosstermBackend<Stdout>>,
+}
+
+impl Dashboard {
+    /// Switches the terminal to the alternate screen, which the dashboard is drawn on.
+    /// Input isn't captured, so Ctrl+C interrupts the encode as without it
+    pub fn start() -> io::Result<Self> {
+        let mut stdout = io::stdout();
+        execute!(stdout, EnterAlternateScreen)?;
+        let mut terminal = Terminal::new(CrosstermBackend::new(stdout))?;
+        terminal.hide_cursor()?;
+        terminal.clear()?;
+        Ok(Dashboard { terminal })
+    }
+
+    pub fn draw(&mut self, view: &DashboardView) -> io::Result<()> {
+        self.terminal.draw(|frame| render(frame, view))?;
+        Ok(())
+    }
+}
+
+impl Drop for Dashboard {
+    fn drop(&mut self) {
+        let _ = self.terminal.show_cursor();
+        let _ = execute!(self.terminal.backend_mut(), LeaveAlternateScreen);
+    }
+}
+
+fn render(frame: &mut Frame, view: &DashboardView) {
+    let [progress_area, nodes_area, chunks_area] = Layout::vertical([
+        Constraint::Length(3),
+        Constraint::Length(view.nodes.len() as u16 + 3),
+        Constraint::Min(3),
+    ])
+    .areas(frame.area());
+
+    let title = format!(
+        " {}/{} chunks, {} pending, {:.1} fps, elapsed {}, ETA {} ",
+        view.completed,
+        view.total,
+        view.pending,
+        view.fps,
+        format_duration(view.elapsed),
+        view.eta
+            .map(format_duration)
+            .unwrap_or_else(|| "unknown".to_string())
+    );
+    let progress = Gauge::default()
+        .block(Block::bordered().title(title))
+        .gauge_style(Style::default().fg(Color::Green))
+        .ratio(view.fraction.clamp(0.0, 1.0))
+        .label(format!("{:.1}%", view.fraction * 100.0));
+    frame.render_widget(progress, progress_area);
+
+    let nodes = view.nodes.iter().map(|node| {
+        Row::new([
+            node.address.clone(),
+            format!("{}/{}", node.active, node.slots),
+            node.completed.to_string(),
+            node.failed.to_string(),
+            format_rate(node.upload_rate),
+            format_rate(node.download_rate),
+        ])
+    });
+    let nodes = Table::new(
+        nodes,
+        [
+            Constraint::Fill(1),
+            Constraint::Length(7),
+            Constraint::Length(7),
+            Constraint::Length(7),
+            Constraint::Length(12),
+            Constraint::Length(12),
+        ],
+    )
+    .header(Row::new(["NODE", "SLOTS", "DONE", "FAILED", "UPLOAD", "DOWNLOAD"]).bold())
+    .block(Block::bordered().title(" Nodes "));
+    frame.render_widget(nodes, nodes_area);
+
+    let chunks = view.chunks.iter().map(|chunk| {
+        Row::new([
+            chunk.index.to_string(),
+            chunk.node.clone(),
+            format!("{:.1}", chunk.fps),
+            format!("{:.0}%", chunk.fraction * 100.0),
+            chunk
+                .eta
+                .map(format_duration)
+                .unwrap_or_else(|| "unknown".to_string()),
+        ])
+    });
+    let chunks = Table::new(
+        chunks,
+        [
+            Constraint::Length(7),
+            Constraint::Fill(1),
+            Constraint::Length(8),
+            Constraint::Length(6),
+            Constraint::Length(9),
+        ],
+    )
+    .header(Row::new(["CHUNK", "NODE", "FPS", "DONE", "ETA"]).bold())
+    .block(Block::bordered().title(" Chunks being encoded "));
+    frame.render_widget(chunks, chunks_area);
+}
+
+/// Formats bytes per second like `1.5 MiB/s`
+fn format_rate(rate: f64) -> String {
+    const UNITS: [&str; 4] = ["B/s", "KiB/s", "MiB/s", "GiB/s"];
+
+    let mut value = rate;
+    let mut unit = 0;
+    while value >= 1024.0 && unit < UNITS.len() - 1 {
+        value /= 1024.0;
+        unit += 1;
+    }
+    format!("{:.1} {}", value, UNITS[unit])
+}
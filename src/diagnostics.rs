@@ -0,0 +1,77 @@
+/// This module writes a diagnostics bundle into the temporary directory of a failed
+/// encode, which is kept: chunks that failed with errors nodes returned, outputs that
+/// failed, settings of the encode, and the log with messages of ffmpeg and encoders.
+/// Everything needed to tell why the encode failed is in one place.
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
+
+use serde::Serialize;
+use tracing::{info, instrument, warn};
+
+use crate::error::VideoEncodeError;
+use crate::logging::current_log_file;
+
+/// Directory of the bundle in the temporary directory
+const DIAGNOSTICS_DIR: &str = "diagnostics";
+
+/// Attempt of a chunk that failed
+#[derive(Debug, Clone, Serialize)]
+pub struct ChunkError {
+    pub node: String,
+    /// Error of the attempt, with output of the encoder the node returned
+    pub message: String,
+}
+
+/// What went wrong in a failed encode, written as `diagnostics.json`
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct Diagnostics {
+    /// Why the encode failed
+    pub error: String,
+    /// Errors of failed attempts, by chunk index
+    pub failed_chunks: BTreeMap<usize, Vec<ChunkError>>,
+    /// Chunks that were never encoded
+    pub pending_chunks: Vec<usize>,
+    /// Errors of outputs that weren't finished, by output
+    pub failed_outputs: BTreeMap<PathBuf, String>,
+}
+
+impl Diagnostics {
+    /// Records failed attempt of chunk `index` on `node`
+    pub fn chunk_failed(&mut self, index: usize, node: &str, message: String) {
+        self.failed_chunks
+            .entry(index)
+            .or_default()
+            .push(ChunkError {
+                node: node.to_string(),
+                message,
+            });
+    }
+
+    /// Writes the bundle into `temp_dir`, with `settings` of the encode as a config file
+    /// and a copy of the log. Returns directory of the bundle.
+    #[instrument(skip(self, settings))]
+    pub fn write(&self, temp_dir: &Path, settings: &str) -> Result<PathBuf, VideoEncodeError> {
+        let dir = temp_dir.join(DIAGNOSTICS_DIR);
+        std::fs::create_dir_all(&dir)?;
+
+        let json = serde_json::to_string_pretty(self)
+            .map_err(|e| VideoEncodeError::Encoding(e.to_string()))?;
+        std::fs::write(dir.join("diagnostics.json"), json)?;
+        std::fs::write(dir.join("settings.toml"), settings)?;
+        // Log has stderr of every ffmpeg and encoder that failed
+        match current_log_file() {
+            Some(log) => {
+                if let Err(e) = std::fs::copy(&log, dir.join("application.log")) {
+                    warn!("Failed to copy log {:?}: {}", log, e);
+                }
+            }
+            None => warn!("No log file to copy into diagnostics"),
+        }
+
+        info!(
+            "Diagnostics of the failed encode are written into {:?}",
+            dir
+        );
+        Ok(dir)
+    }
+}
@@ -0,0 +1,814 @@
+/// This module sends chunks of an encode to nodes and receives them back encoded. Every
+/// node takes chunks from one queue as its slots free up, chunks that fail are queued
+/// again and split after repeated failures, and chunks below the quality floor are
+/// encoded again with stronger parameters.
+use anyhow::{Context, Result};
+use std::collections::{HashMap, HashSet};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use tokio::sync::Mutex;
+use tokio::task::JoinSet;
+use tonic::Code;
+use tracing::{debug, error, info, instrument, warn};
+
+use crate::cache::hash_chunk;
+use crate::chunk::Chunk;
+use crate::crypto::{Direction, JobCipher};
+use crate::diagnostics::Diagnostics;
+use crate::encoder::{crf_value, with_crf, Encoder, EncoderKind};
+use crate::ffmpeg::progress::Progress;
+use crate::ffmpeg::verify::{count_frames, hash_packets, verify_decode};
+use crate::nodes::{NodeConnection, UploadedChunks, MAX_CHUNK_SIZE};
+use crate::progress::JobProgress;
+use crate::proto::video_encoding_service_client::VideoEncodingServiceClient;
+use crate::proto::{
+    EncodeCachedChunkRequest, EncodeChunkRequest, EncodeChunkResponse, TargetQuality, Vmaf,
+    WatchProgressRequest,
+};
+use crate::report::ChunkResult;
+use crate::settings::{QualityFloor, QualityMetric, VerifySettings};
+use crate::telemetry::inject_context;
+use crate::timing::ChunkTiming;
+use crate::transport::NodeChannel;
+use crate::web::RecentError;
+use crate::zones::override_params;
+
+/// Number of chunks that failed chunk is split into
+const RESPLIT_PARTS: usize = 2;
+
+/// Number of the latest errors of chunks the web UI shows
+const RECENT_ERRORS: usize = 20;
+
+/// Time a slot of a node that is low on disk space gets no chunks for
+const DISK_FULL_BACKOFF: Duration = Duration::from_secs(60);
+
+/// What a node has done in this job, as the dashboard shows it
+#[derive(Debug, Default)]
+pub struct NodeActivity {
+    pub slots: usize,
+    /// Indices of chunks that are being sent, encoded or received
+    pub active: HashSet<usize>,
+    pub completed: usize,
+    pub failed: usize,
+    /// Bytes of chunks sent to the node, shared with its connection
+    pub uploaded: Arc<AtomicU64>,
+    /// Bytes of encoded chunks received from the node
+    pub downloaded: u64,
+}
+
+/// Represents the state of the encoding process
+pub struct EncodingState {
+    /// Identifies this job on nodes
+    pub job_id: String,
+    /// Encrypts chunk payloads of this job, if encryption is enabled
+    pub cipher: Option<Arc<JobCipher>>,
+    /// Encoder that nodes encode chunks with
+    pub encoder: EncoderKind,
+    /// Number of passes every chunk is encoded in
+    pub passes: u32,
+    /// Directory encoded chunks of all inputs are written to
+    pub encode_dir: PathBuf,
+    /// Chunks waiting to be encoded
+    pub pending_chunks: Vec<Chunk>,
+    /// Chunks that have been successfully encoded
+    pub completed_chunks: Vec<Chunk>,
+    /// Progress of the job, updated by nodes as chunks are encoded
+    pub progress: JobProgress,
+    /// Number of failed attempts of chunks, by chunk index
+    pub failures: HashMap<usize, usize>,
+    /// Index of the next chunk split from a failed one
+    pub next_index: usize,
+    /// Number of failed attempts after which chunk is split, 0 disables it
+    pub resplit_after: usize,
+    /// Number of the input every chunk belongs to, by chunk index
+    pub chunk_jobs: HashMap<usize, usize>,
+    /// Position of every chunk in its output, by chunk index
+    pub positions: HashMap<usize, Vec<usize>>,
+    /// Film grain tables of inputs, by input number
+    pub grain_tables: HashMap<usize, String>,
+    /// Filter chain applied to chunks of all inputs
+    pub video_filter: String,
+    /// Quality nodes select CRF of every chunk for
+    pub target_quality: Option<TargetQuality>,
+    /// Metrics nodes measure quality of every chunk with
+    pub quality_metrics: Vec<QualityMetric>,
+    /// Model nodes compute VMAF with, their default when not set
+    pub vmaf: Option<Vmaf>,
+    /// Nodes measure quality of every this many frames
+    pub quality_subsample: u32,
+    /// Checks of encoded chunks before they're accepted
+    pub verify: VerifySettings,
+    /// Whether encoded chunks are hashed
+    pub reproducible: bool,
+    /// Results of encoded chunks, by chunk index
+    pub results: HashMap<usize, ChunkResult>,
+    /// Score chunks are encoded again to reach
+    pub quality_floor: Option<QualityFloor>,
+    /// Number of times chunks were encoded again below the floor, by chunk index
+    pub floor_retries: HashMap<usize, usize>,
+    /// What every node has done, by address of the node
+    pub node_activity: HashMap<String, NodeActivity>,
+    /// Errors of failed attempts of chunks
+    pub diagnostics: Diagnostics,
+    /// When chunks were queued again after a failed attempt, by chunk index. Other
+    /// chunks were queued when encoding started
+    pub queued_at: HashMap<usize, Instant>,
+    /// Times of chunks that were encoded
+    pub timings: Vec<ChunkTiming>,
+    /// Latest failed attempts of chunks, oldest first
+    pub recent_errors: Vec<RecentError>,
+}
+
+/// Options of encode requests, shared by chunks of the same input
+#[derive(Clone)]
+pub struct RequestOptions {
+    pub job_id: String,
+    pub cipher: Option<Arc<JobCipher>>,
+    pub encoder: EncoderKind,
+    pub passes: u32,
+    /// Film grain table of the input, none when empty
+    pub film_grain_table: String,
+    /// Filter chain applied to chunks, none when empty
+    pub video_filter: String,
+    /// Quality nodes select CRF of the chunk for
+    pub target_quality: Option<TargetQuality>,
+    /// Metrics node measures quality of the encoded chunk with
+    pub quality_metrics: Vec<QualityMetric>,
+    /// Model node computes VMAF with
+    pub vmaf: Option<Vmaf>,
+    /// Node measures quality of every this many frames
+    pub quality_subsample: u32,
+    /// Checks of the encoded chunk before it's accepted
+    pub verify: VerifySettings,
+    /// Whether the encoded chunk is hashed
+    pub reproducible: bool,
+}
+
+/// Chunk that a node rejected because its temp disk is nearly full, so it isn't counted
+/// as a failed attempt
+#[derive(Debug, thiserror::Error)]
+#[error("Node is low on disk space: {0}")]
+struct NodeDiskFull(String);
+
+/// Error of a request to a node, with context of the request
+fn request_error(status: tonic::Status, context: &'static str) -> anyhow::Error {
+    match status.code() {
+        Code::ResourceExhausted => NodeDiskFull(status.message().to_string()).into(),
+        _ => anyhow::Error::new(status).context(context),
+    }
+}
+
+/// Chunk that doesn't fit into a single request
+#[derive(Debug, thiserror::Error)]
+#[error("Chunk {index} has {size} bytes, which exceeds transfer size limit")]
+struct ChunkTooLarge {
+    index: usize,
+    size: usize,
+}
+
+/// Records chunks that failed and chunks that were never encoded into `diagnostics`
+pub fn record_chunks(state: &EncodingState, diagnostics: &mut Diagnostics) {
+    diagnostics.failed_chunks = state.diagnostics.failed_chunks.clone();
+    diagnostics.pending_chunks = state
+        .pending_chunks
+        .iter()
+        .map(|chunk| chunk.index)
+        .collect();
+}
+
+/// Chunk to encode again with stronger settings, when its score in `result` is below
+/// the quality floor and it has retries left
+fn retry_below_floor(
+    state: &mut EncodingState,
+    chunk: &Chunk,
+    result: &ChunkResult,
+) -> Option<Chunk> {
+    let floor = state.quality_floor.as_ref()?;
+    let &(_, score) = result
+        .scores
+        .iter()
+        .find(|(metric, _)| *metric == floor.metric)?;
+    if floor.metric.meets(score, floor.score) {
+        return None;
+    }
+
+    let retries = state.floor_retries.entry(chunk.index).or_default();
+    if *retries >= floor.retries {
+        warn!(
+            "Chunk {} has {:?} of {:.2} below floor of {} after {} retries",
+            chunk.index, floor.metric, score, floor.score, retries
+        );
+        return None;
+    }
+    *retries += 1;
+
+    let encoder = state.encoder.encoder();
+    let params = stronger_params(encoder.as_ref(), &chunk.encoder_parameters, floor);
+    info!(
+        "Chunk {} has {:?} of {:.2} below floor of {}, encoding it again with {:?}",
+        chunk.index, floor.metric, score, floor.score, params
+    );
+    Some(Chunk {
+        encoder_parameters: params,
+        ..chunk.clone()
+    })
+}
+
+/// Parameters of a retry below the quality floor, with `params` of the floor applied
+/// and CRF lowered by its step, within range of the encoder
+fn stronger_params(encoder: &dyn Encoder, params: &[String], floor: &QualityFloor) -> Vec<String> {
+    let params = override_params(params, &floor.params);
+    let (Some((_, range)), Some(crf)) = (encoder.crf_option(&params), crf_value(encoder, &params))
+    else {
+        return params;
+    };
+    let lowered = crf.saturating_sub(floor.crf_step).max(*range.start());
+    with_crf(encoder, &params, lowered).unwrap_or(params)
+}
+
+/// Names of metrics, as nodes expect them
+fn metric_names(metrics: &[QualityMetric]) -> Vec<String> {
+    metrics
+        .iter()
+        .map(|metric| metric.name().to_string())
+        .collect()
+}
+
+/// File that chunk `index` is written to once it's encoded
+fn encoded_chunk_path(encode_dir: &Path, index: usize) -> PathBuf {
+    encode_dir.join(format!("encoded_chunk_{}.mkv", index))
+}
+
+/// Takes chunks of `pending` that an interrupted encode wrote into `encode_dir`
+pub fn take_encoded(pending: &mut Vec<Chunk>, encode_dir: &Path) -> Vec<Chunk> {
+    let (encoded, rest): (Vec<Chunk>, Vec<Chunk>) = std::mem::take(pending)
+        .into_iter()
+        .partition(|chunk| encoded_chunk_path(encode_dir, chunk.index).exists());
+    *pending = rest;
+    let encoded: Vec<Chunk> = encoded
+        .into_iter()
+        .map(|mut chunk| {
+            chunk.encoded_path = Some(encoded_chunk_path(encode_dir, chunk.index));
+            chunk
+        })
+        .collect();
+    info!(
+        "Resuming with {} chunks encoded, {} left",
+        encoded.len(),
+        pending.len()
+    );
+    encoded
+}
+
+#[instrument(skip(node, encoding_state))]
+pub async fn encode_chunks_on_node(
+    node: NodeConnection,
+    encoding_state: Arc<Mutex<EncodingState>>,
+) -> Result<()> {
+    let mut chunk_futures = JoinSet::new();
+
+    loop {
+        // Try to acquire a permit
+        if let Ok(permit) = node.semaphore.clone().acquire_owned().await {
+            let chunk = {
+                let mut state = encoding_state.lock().await;
+                let chunk = state.pending_chunks.pop();
+                if let (Some(chunk), Some(activity)) =
+                    (&chunk, state.node_activity.get_mut(&node.address))
+                {
+                    activity.active.insert(chunk.index);
+                }
+                chunk.map(|chunk| {
+                    let queue_wait = match state.queued_at.remove(&chunk.index) {
+                        Some(queued) => queued.elapsed(),
+                        None => state.progress.elapsed(),
+                    };
+                    (chunk, queue_wait)
+                })
+            };
+
+            match chunk {
+                Some((chunk, queue_wait)) => {
+                    let client_clone = node.client.clone();
+                    let address = node.address.clone();
+                    let uploaded_chunks = Arc::clone(&node.uploaded_chunks);
+                    let state_clone = Arc::clone(&encoding_state);
+                    let (options, encode_dir) = {
+                        let state = encoding_state.lock().await;
+                        let film_grain_table = state
+                            .chunk_jobs
+                            .get(&chunk.index)
+                            .and_then(|job| state.grain_tables.get(job))
+                            .cloned()
+                            .unwrap_or_default();
+                        let options = RequestOptions {
+                            job_id: state.job_id.clone(),
+                            cipher: state.cipher.clone(),
+                            encoder: state.encoder,
+                            passes: state.passes,
+                            film_grain_table,
+                            video_filter: state.video_filter.clone(),
+                            target_quality: state.target_quality.clone(),
+                            quality_metrics: state.quality_metrics.clone(),
+                            vmaf: state.vmaf.clone(),
+                            quality_subsample: state.quality_subsample,
+                            verify: state.verify.clone(),
+                            reproducible: state.reproducible,
+                        };
+                        (options, state.encode_dir.clone())
+                    };
+
+                    let uploaded_bytes = Arc::clone(&node.uploaded_bytes);
+                    chunk_futures.spawn(async move {
+                        let started = Instant::now();
+                        let result = send_chunk(
+                            chunk.clone(),
+                            options,
+                            encode_dir,
+                            client_clone,
+                            uploaded_chunks,
+                            &uploaded_bytes,
+                        )
+                        .await;
+                        // Slot of a node low on disk space is held while it backs off,
+                        // other slots are released after processing
+                        let disk_full = matches!(&result, Err(e) if e.is::<NodeDiskFull>());
+                        let held_permit = disk_full.then_some(permit);
+                        let request_time = started.elapsed();
+                        let duration_ms = request_time.as_millis() as u64;
+
+                        let download_size = match &result {
+                            Ok((encoded_chunk, _)) => encoded_chunk
+                                .encoded_path
+                                .as_ref()
+                                .and_then(|path| std::fs::metadata(path).ok())
+                                .map(|metadata| metadata.len())
+                                .unwrap_or_default(),
+                            Err(_) => 0,
+                        };
+                        if let Some(activity) =
+                            state_clone.lock().await.node_activity.get_mut(&address)
+                        {
+                            activity.active.remove(&chunk.index);
+                            activity.downloaded += download_size;
+                            match &result {
+                                Ok(_) => activity.completed += 1,
+                                Err(_) => activity.failed += 1,
+                            }
+                        }
+
+                        match result {
+                            Ok((encoded_chunk, result)) => {
+                                let mut state = state_clone.lock().await;
+                                if let Some(retry) = retry_below_floor(&mut state, &chunk, &result)
+                                {
+                                    if let Some(path) = &encoded_chunk.encoded_path {
+                                        let _ = std::fs::remove_file(path);
+                                    }
+                                    state.progress.reset(chunk.index);
+                                    state.queued_at.insert(retry.index, Instant::now());
+                                    state.pending_chunks.push(retry);
+                                    return;
+                                }
+                                state.progress.complete(chunk.index);
+                                state.timings.push(ChunkTiming {
+                                    index: chunk.index,
+                                    node: address.clone(),
+                                    queue_wait: queue_wait.as_secs_f64(),
+                                    transfer_time: (request_time.as_secs_f64()
+                                        - result.encode_time)
+                                        .max(0.0),
+                                    encode_time: result.encode_time,
+                                    size: download_size,
+                                    frames: chunk.metadata.as_ref().map(|info| info.frames),
+                                });
+                                state.results.insert(
+                                    chunk.index,
+                                    ChunkResult {
+                                        node: address.clone(),
+                                        ..result
+                                    },
+                                );
+                                state.completed_chunks.push(encoded_chunk);
+                                info!(
+                                    chunk_index = chunk.index,
+                                    node = %address,
+                                    duration_ms,
+                                    bytes = download_size,
+                                    "Chunk {} encoded successfully on node {}",
+                                    chunk.index,
+                                    address
+                                );
+                            }
+                            Err(e) => {
+                                error!(
+                                    chunk_index = chunk.index,
+                                    node = %address,
+                                    duration_ms,
+                                    "Failed to encode chunk {} on node {}: {}",
+                                    chunk.index,
+                                    address,
+                                    e
+                                );
+                                record_error(
+                                    &mut *state_clone.lock().await,
+                                    chunk.index,
+                                    &address,
+                                    format!("{:#}", e),
+                                );
+                                reschedule_chunk(chunk, e, &state_clone).await;
+                                if held_permit.is_some() {
+                                    tokio::time::sleep(DISK_FULL_BACKOFF).await;
+                                }
+                            }
+                        }
+                    });
+                }
+                None => {
+                    drop(permit);
+                    // Chunks in flight may fail and be returned to the queue
+                    if chunk_futures.join_next().await.is_none() {
+                        break;
+                    }
+                }
+            }
+        } else {
+            // If we can't acquire a permit, wait for some ongoing tasks to complete
+            if !chunk_futures.is_empty() {
+                chunk_futures.join_next().await;
+            } else {
+                // If there are no chunk futures and we can't acquire permits, we're done
+                break;
+            }
+        }
+    }
+
+    // Wait for all remaining chunk futures to complete
+    while chunk_futures.join_next().await.is_some() {}
+
+    Ok(())
+}
+
+/// Encodes chunk on node, returns encoded chunk with its result, which has no node set
+#[instrument(
+    parent = None,
+    skip(options, client, uploaded_chunks, uploaded_bytes),
+    fields(chunk_index = chunk.index)
+)]
+pub async fn send_chunk(
+    chunk: Chunk,
+    options: RequestOptions,
+    encode_dir: PathBuf,
+    mut client: VideoEncodingServiceClient<NodeChannel>,
+    uploaded_chunks: UploadedChunks,
+    uploaded_bytes: &AtomicU64,
+) -> Result<(Chunk, ChunkResult)> {
+    let cipher = &options.cipher;
+    let response = match send_cached_chunk(&chunk, &options, &mut client, &uploaded_chunks).await? {
+        Some(response) => response,
+        None => {
+            let chunk_data = chunk
+                .read_source()
+                .await
+                .context("Failed to read chunk data")?;
+
+            if chunk_data.len() > MAX_CHUNK_SIZE {
+                return Err(ChunkTooLarge {
+                    index: chunk.index,
+                    size: chunk_data.len(),
+                }
+                .into());
+            }
+
+            // Remember what was uploaded, so retries on this node can reuse it
+            uploaded_chunks
+                .lock()
+                .unwrap()
+                .insert(chunk.source_key(), hash_chunk(&chunk_data));
+
+            let (chunk_data, encrypted) = match cipher {
+                Some(cipher) => (
+                    cipher.encrypt(&chunk_data, chunk.index as i32, Direction::Request)?,
+                    true,
+                ),
+                None => (chunk_data, false),
+            };
+            uploaded_bytes.fetch_add(chunk_data.len() as u64, Ordering::Relaxed);
+
+            let mut request = tonic::Request::new(EncodeChunkRequest {
+                chunk_data,
+                chunk_index: chunk.index as i32,
+                encoder_parameters: chunk.encoder_parameters.clone(),
+                job_id: options.job_id.clone(),
+                encrypted,
+                encoder: options.encoder.name().to_string(),
+                passes: options.passes,
+                film_grain_table: options.film_grain_table.clone(),
+                video_filter: options.video_filter.clone(),
+                target_quality: options.target_quality.clone(),
+                quality_metrics: metric_names(&options.quality_metrics),
+                vmaf: options.vmaf.clone(),
+                quality_subsample: options.quality_subsample,
+            });
+            inject_context(request.metadata_mut());
+
+            debug!("Sending encode request for chunk {}", chunk.index);
+            client
+                .encode_chunk(request)
+                .await
+                .map_err(|status| request_error(status, "Failed to send encode request"))?
+                .into_inner()
+        }
+    };
+
+    if response.success {
+        debug!("Successfully encoded chunk {}", chunk.index);
+        if options.target_quality.is_some() {
+            info!(
+                "Node selected CRF {} for chunk {}",
+                response.crf, chunk.index
+            );
+        }
+
+        // Node with a key always encrypts, so plaintext response means it was tampered with
+        let encoded_data = match (cipher, response.encrypted) {
+            (Some(cipher), true) => cipher.decrypt(
+                &response.encoded_chunk_data,
+                chunk.index as i32,
+                Direction::Response,
+            )?,
+            (None, false) => response.encoded_chunk_data,
+            (Some(_), false) => {
+                anyhow::bail!("Node returned unencrypted chunk {}", chunk.index)
+            }
+            (None, true) => anyhow::bail!(
+                "Node returned encrypted chunk {}, but no encryption key is configured",
+                chunk.index
+            ),
+        };
+
+        // Chunk is only found by resumed encodes once it's written whole
+        let encoded_path = encoded_chunk_path(&encode_dir, chunk.index);
+        let written = encoded_path.with_extension("mkv.part");
+        std::fs::write(&written, encoded_data).context("Failed to write encoded chunk data")?;
+        std::fs::rename(&written, &encoded_path).context("Failed to write encoded chunk data")?;
+
+        // Rejected chunk is encoded again
+        if let Err(e) = verify_chunk(&chunk, &encoded_path, &options.verify).await {
+            let _ = std::fs::remove_file(&encoded_path);
+            return Err(e);
+        }
+        let hash = if options.reproducible {
+            match hash_packets(&encoded_path).await {
+                Ok(hash) => Some(hash),
+                Err(e) => {
+                    let _ = std::fs::remove_file(&encoded_path);
+                    return Err(e.into());
+                }
+            }
+        } else {
+            None
+        };
+
+        // Node only returns scores of requested metrics
+        let scores = options
+            .quality_metrics
+            .iter()
+            .filter_map(|metric| Some((*metric, *response.quality_scores.get(metric.name())?)))
+            .collect();
+        let mut frame_scores = response.frame_scores;
+        let frames = options
+            .quality_metrics
+            .iter()
+            .filter_map(|metric| Some((*metric, frame_scores.remove(metric.name())?.scores)))
+            .collect();
+
+        let result = ChunkResult {
+            node: String::new(),
+            encode_time: response.encode_time,
+            crf: options.target_quality.is_some().then_some(response.crf),
+            scores,
+            frames,
+            hash,
+        };
+        Ok((
+            Chunk {
+                encoded_path: Some(encoded_path),
+                ..chunk
+            },
+            result,
+        ))
+    } else {
+        error!(
+            "Failed to encode chunk {}: {}",
+            chunk.index, response.error_message
+        );
+        Err(anyhow::anyhow!(
+            "Failed to encode chunk {}: {}",
+            chunk.index,
+            response.error_message
+        ))
+    }
+}
+
+/// Checks encoded chunk as `settings` say
+async fn verify_chunk(chunk: &Chunk, encoded_path: &Path, settings: &VerifySettings) -> Result<()> {
+    if settings.decode {
+        verify_decode(encoded_path)
+            .await
+            .with_context(|| format!("Encoded chunk {} is corrupted", chunk.index))?;
+    }
+
+    let expected = chunk
+        .metadata
+        .as_ref()
+        .map(|metadata| metadata.frames)
+        .filter(|&frames| frames > 0);
+    if let (true, Some(expected)) = (settings.frame_count, expected) {
+        let frames = count_frames(encoded_path).await?;
+        if frames != expected {
+            anyhow::bail!(
+                "Encoded chunk {} has {} frames instead of {}",
+                chunk.index,
+                frames,
+                expected
+            );
+        }
+    }
+    Ok(())
+}
+
+/// Returns failed chunk to the queue. Chunk that failed repeatedly or is too large
+/// to be uploaded is split into smaller chunks, which are queued instead.
+async fn reschedule_chunk(chunk: Chunk, error: anyhow::Error, state: &Mutex<EncodingState>) {
+    // Node low on disk space didn't try the chunk, so it isn't a failed attempt
+    let first_index = if error.is::<NodeDiskFull>() {
+        None
+    } else {
+        let mut state = state.lock().await;
+        let failures = state.failures.entry(chunk.index).or_default();
+        *failures += 1;
+        let failures = *failures;
+
+        let resplit = error.is::<ChunkTooLarge>()
+            || (state.resplit_after > 0 && failures >= state.resplit_after);
+        if resplit {
+            let first_index = state.next_index;
+            state.next_index += RESPLIT_PARTS;
+            Some(first_index)
+        } else {
+            None
+        }
+    };
+
+    // Splitting probes the source, so state isn't locked meanwhile
+    let parts = first_index.and_then(|first_index| {
+        chunk
+            .split(RESPLIT_PARTS, first_index)
+            .map_err(|e| warn!("Failed to split chunk {}: {}", chunk.index, e))
+            .ok()
+    });
+
+    let mut state = state.lock().await;
+    match parts {
+        Some(parts) => {
+            info!(
+                "Rescheduling chunk {} as chunks {:?}",
+                chunk.index,
+                parts.iter().map(|part| part.index).collect::<Vec<_>>()
+            );
+            if let Some(&job) = state.chunk_jobs.get(&chunk.index) {
+                state
+                    .chunk_jobs
+                    .extend(parts.iter().map(|part| (part.index, job)));
+            }
+            state
+                .positions
+                .extend(parts.iter().map(|part| (part.index, part.position.clone())));
+            state.progress.replace(
+                chunk.index,
+                parts
+                    .iter()
+                    .map(|part| (part.index, part.duration().unwrap_or_default())),
+            );
+            let now = Instant::now();
+            state
+                .queued_at
+                .extend(parts.iter().map(|part| (part.index, now)));
+            state.pending_chunks.extend(parts);
+        }
+        None => {
+            state.progress.reset(chunk.index);
+            state.queued_at.insert(chunk.index, Instant::now());
+            state.pending_chunks.push(chunk);
+        }
+    }
+}
+
+/// Asks node to encode chunk from its cache, if this chunk was uploaded to it before.
+/// Returns `None` if chunk has to be uploaded.
+async fn send_cached_chunk(
+    chunk: &Chunk,
+    options: &RequestOptions,
+    client: &mut VideoEncodingServiceClient<NodeChannel>,
+    uploaded_chunks: &UploadedChunks,
+) -> Result<Option<EncodeChunkResponse>> {
+    let Some(chunk_hash) = uploaded_chunks
+        .lock()
+        .unwrap()
+        .get(&chunk.source_key())
+        .cloned()
+    else {
+        return Ok(None);
+    };
+
+    let mut request = tonic::Request::new(EncodeCachedChunkRequest {
+        chunk_hash,
+        chunk_index: chunk.index as i32,
+        encoder_parameters: chunk.encoder_parameters.clone(),
+        job_id: options.job_id.clone(),
+        encoder: options.encoder.name().to_string(),
+        passes: options.passes,
+        film_grain_table: options.film_grain_table.clone(),
+        video_filter: options.video_filter.clone(),
+        target_quality: options.target_quality.clone(),
+        quality_metrics: metric_names(&options.quality_metrics),
+        vmaf: options.vmaf.clone(),
+        quality_subsample: options.quality_subsample,
+    });
+    inject_context(request.metadata_mut());
+
+    debug!("Sending cached encode request for chunk {}", chunk.index);
+    match client.encode_cached_chunk(request).await {
+        Ok(response) => Ok(Some(response.into_inner())),
+        Err(status) if status.code() == Code::NotFound => {
+            debug!("Chunk {} is no longer cached on node", chunk.index);
+            uploaded_chunks.lock().unwrap().remove(&chunk.source_key());
+            Ok(None)
+        }
+        Err(status) => Err(request_error(
+            status,
+            "Failed to send cached encode request",
+        )),
+    }
+}
+
+/// Receives progress of chunks encoded on node, until the job is done
+#[instrument(skip(client, encoding_state))]
+pub async fn watch_node_progress(
+    mut client: VideoEncodingServiceClient<NodeChannel>,
+    address: String,
+    encoding_state: Arc<Mutex<EncodingState>>,
+) {
+    let job_id = encoding_state.lock().await.job_id.clone();
+
+    let mut updates = match client.watch_progress(WatchProgressRequest { job_id }).await {
+        Ok(response) => response.into_inner(),
+        Err(e) => {
+            warn!("Can't watch progress on node {}: {}", address, e);
+            return;
+        }
+    };
+
+    loop {
+        match updates.message().await {
+            Ok(Some(update)) => {
+                let progress = Progress {
+                    frame: update.frame,
+                    fps: update.fps,
+                    bitrate_kbps: update.bitrate_kbps,
+                    out_time: update.out_time,
+                    speed: update.speed,
+                    done: update.done,
+                };
+                let mut state = encoding_state.lock().await;
+                state.progress.update(update.chunk_index as usize, progress);
+            }
+            Ok(None) => break,
+            Err(e) => {
+                warn!("Lost progress updates from node {}: {}", address, e);
+                break;
+            }
+        }
+    }
+}
+
+/// Records failed attempt of chunk `index` on `node`, for diagnostics and the web UI
+fn record_error(state: &mut EncodingState, index: usize, node: &str, message: String) {
+    let time = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs();
+    if state.recent_errors.len() == RECENT_ERRORS {
+        state.recent_errors.remove(0);
+    }
+    state.recent_errors.push(RecentError {
+        time,
+        chunk: index,
+        node: node.to_string(),
+        message: message.clone(),
+    });
+    state.diagnostics.chunk_failed(index, node, message);
+}
@@ -0,0 +1,145 @@
+/// This module downloads inputs given as HTTP(S) URLs into the temp directory,
+/// so they can be split like local files. Interrupted downloads are resumed.
+use std::path::{Path, PathBuf};
+
+use reqwest::{header, Response, StatusCode};
+use sha2::{Digest, Sha256};
+use tokio::{fs, io::AsyncWriteExt};
+use tracing::{debug, info, instrument, warn};
+
+use crate::error::VideoEncodeError;
+
+/// Downloaded bytes between progress reports
+const PROGRESS_INTERVAL: u64 = 256 * 1024 * 1024;
+
+/// Whether input is an HTTP(S) URL instead of a path
+pub fn is_url(input: &Path) -> bool {
+    input
+        .to_str()
+        .is_some_and(|input| input.starts_with("http://") || input.starts_with("https://"))
+}
+
+/// Downloads `url` into `dir` and returns path of the downloaded file.
+/// Partial download is kept as `.part` file and resumed on the next run,
+/// if the server supports range requests. Completed download of the same URL is reused.
+#[instrument]
+pub async fn download(url: &str, dir: &Path) -> Result<PathBuf, VideoEncodeError> {
+    fs::create_dir_all(dir).await?;
+
+    let path = dir.join(file_name(url));
+    if fs::try_exists(&path).await? {
+        info!("Using already downloaded {:?}", path);
+        return Ok(path);
+    }
+
+    let mut part_path = path.clone().into_os_string();
+    part_path.push(".part");
+    let part_path = PathBuf::from(part_path);
+
+    let downloaded = match fs::metadata(&part_path).await {
+        Ok(metadata) => metadata.len(),
+        Err(_) => 0,
+    };
+
+    let mut request = reqwest::Client::new().get(url);
+    if downloaded > 0 {
+        request = request.header(header::RANGE, format!("bytes={}-", downloaded));
+    }
+    let mut response = request.send().await.map_err(download_error)?;
+
+    let mut file = fs::OpenOptions::new();
+    file.create(true);
+    let mut written = match response.status() {
+        StatusCode::PARTIAL_CONTENT => {
+            let start = range_start(&response);
+            if start != Some(downloaded) {
+                // Part can't be trusted to be the start of this file anymore
+                fs::remove_file(&part_path).await?;
+                return Err(VideoEncodeError::Download(format!(
+                    "Server resumed download of {} at {:?} instead of {} bytes, partial \
+                     download was removed, download it again",
+                    url, start, downloaded
+                )));
+            }
+            info!("Resuming download of {} from {} bytes", url, downloaded);
+            file.append(true);
+            downloaded
+        }
+        // Whole file was already downloaded, only renaming was interrupted
+        StatusCode::RANGE_NOT_SATISFIABLE if downloaded > 0 => {
+            fs::rename(&part_path, &path).await?;
+            return Ok(path);
+        }
+        status if status.is_success() => {
+            if downloaded > 0 {
+                warn!("Server doesn't support resuming, downloading {} again", url);
+            }
+            info!("Downloading {} into {:?}", url, path);
+            file.write(true).truncate(true);
+            0
+        }
+        status => {
+            return Err(VideoEncodeError::Download(format!(
+                "Failed to download {}: {}",
+                url, status
+            )))
+        }
+    };
+    let total = response.content_length().map(|length| length + written);
+
+    let mut file = file.open(&part_path).await?;
+    let mut next_report = written + PROGRESS_INTERVAL;
+    while let Some(bytes) = response.chunk().await.map_err(download_error)? {
+        file.write_all(&bytes).await?;
+        written += bytes.len() as u64;
+
+        if written >= next_report {
+            info!("Downloaded {} of {:?} bytes", written, total);
+            next_report += PROGRESS_INTERVAL;
+        }
+    }
+    file.flush().await?;
+
+    if total.is_some_and(|total| written < total) {
+        return Err(VideoEncodeError::Download(format!(
+            "Download of {} ended after {} of {:?} bytes",
+            url, written, total
+        )));
+    }
+
+    fs::rename(&part_path, &path).await?;
+    info!("Downloaded {} bytes into {:?}", written, path);
+    Ok(path)
+}
+
+/// Start of the range of a partial response, from its `Content-Range` header
+fn range_start(response: &Response) -> Option<u64> {
+    let range = response
+        .headers()
+        .get(header::CONTENT_RANGE)?
+        .to_str()
+        .ok()?;
+    let (start, _) = range.strip_prefix("bytes ")?.split_once('-')?;
+    start.trim().parse().ok()
+}
+
+/// Name of the downloaded file: hash of the whole URL, so URLs that end the same
+/// aren't taken for one another, and last segment of URL path without query
+fn file_name(url: &str) -> String {
+    let path = url.split(['?', '#']).next().unwrap_or_default();
+    let name = path
+        .trim_end_matches('/')
+        .rsplit('/')
+        .next()
+        .filter(|name| !name.is_empty() && !name.contains(':'))
+        .unwrap_or("input");
+
+    let hash = hex::encode(Sha256::digest(url.as_bytes()));
+    let name = format!("{}_{}", &hash[..16], name);
+    debug!("Downloading {} as {}", url, name);
+    name
+}
+
+fn download_error(error: reqwest::Error) -> VideoEncodeError {
+    VideoEncodeError::Download(error.to_string())
+}
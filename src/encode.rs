@@ -0,0 +1,986 @@
+/// This module runs an encode from inputs to outputs: inputs are prepared, chunks are
+/// encoded on nodes, joined into outputs and checked, and the result is reported.
+/// Failed encodes leave diagnostics in their temporary directory.
+use anyhow::{Context, Result};
+use indicatif::MultiProgress;
+use std::collections::{HashMap, HashSet};
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tokio::sync::{watch, Mutex};
+use tokio::task::JoinSet;
+use tracing::{debug, error, info, warn};
+use uuid::Uuid;
+
+use crate::chunk::{verify_ffmpeg, Chunk};
+use crate::crf::representative_chunks;
+use crate::crypto::MasterKey;
+use crate::diagnostics::Diagnostics;
+use crate::dispatch::{
+    encode_chunks_on_node, record_chunks, send_chunk, take_encoded, watch_node_progress,
+    EncodingState, NodeActivity, RequestOptions,
+};
+use crate::download::{download, is_url};
+use crate::encoder::check_passes;
+use crate::error::VideoEncodeError;
+use crate::ffmpeg::audio::transcode_audio;
+use crate::ffmpeg::concat::concatenate_videos_and_copy_streams;
+use crate::ffmpeg::container::{check_specifiers, is_stdout};
+use crate::ffmpeg::grain::denoise_filter;
+use crate::ffmpeg::package::package_output;
+use crate::ffmpeg::preview::preview_path;
+use crate::ffmpeg::quality::Measurement;
+use crate::ffmpeg::scene::detect_scenes;
+use crate::ffmpeg::sync::measure_sync;
+use crate::ffmpeg::trim::Position;
+use crate::metrics::MetricsServer;
+use crate::monitor::{
+    report_progress, show_dashboard, show_progress_bars, update_metrics, update_previews,
+    update_web_status,
+};
+use crate::nodes::{initialize_nodes, select_capable_nodes, NodeConnection, ESTIMATE_SAMPLES};
+use crate::notify::{notify, JobSummary};
+use crate::prepare::{
+    allocate_bitrates, apply_content_params, check_bitrate, check_content, check_crf, check_floor,
+    check_renditions, check_stdout, check_temp_space, collect_inputs, lossless_params,
+    output_paths, prepare_job, prepare_temp_dir, print_plan, remove_created, rendition_jobs,
+    resolve_output, select_job_crf, target_quality, vmaf_model, Job,
+};
+use crate::progress::{format_duration, JobProgress};
+use crate::progress_bars::ProgressBars;
+use crate::report::{
+    output_report, report_bitrates, report_lossless, report_path, report_quality, report_scenes,
+    report_timing, report_vbv, ChunkResult,
+};
+use crate::reproduce::{compare, manifest_path, read_manifest, write_manifest, Hashes};
+use crate::settings::{CrfSearch, ExistingTemp, Settings, SyncCheck, SyncSettings};
+use crate::timing::TimingReport;
+use crate::transport::ThrottleFactory;
+use crate::web::WebServer;
+use crate::zones::{apply_zones, read_zones};
+
+/// Options of an encode that aren't settings, given on the command line
+#[derive(Debug, Clone, Default)]
+pub struct EncodeOptions {
+    /// Input files, directories of inputs or URLs
+    pub input_file: Vec<PathBuf>,
+    /// Output file, output directory of multiple inputs, or `-` for stdout
+    pub output_file: Option<String>,
+    /// Slots of every node, in order of node addresses
+    pub slots: Vec<usize>,
+    /// Part of the input that is encoded
+    pub start: Option<Position>,
+    pub end: Option<Position>,
+    /// Print the plan of the encode without encoding anything
+    pub dry_run: bool,
+    /// Encode a few chunks and print estimated size and time
+    pub estimate: bool,
+    /// Dashboard is shown instead of log lines
+    pub tui: bool,
+}
+
+/// Encodes inputs of `options`, and sends summary of the encode as settings say.
+/// Resumed encode keeps files of the interrupted one, and takes chunks it encoded as
+/// they are. Progress is shown as bars of `bars` when set.
+pub async fn encode(
+    options: &EncodeOptions,
+    settings: Settings,
+    resume: bool,
+    bars: Option<MultiProgress>,
+) -> Result<()> {
+    let notify_settings = settings.client.notify.clone();
+    let temp_dir = settings.processing.temp_dir.clone();
+    let settings_snapshot = settings
+        .redacted_toml()
+        .unwrap_or_else(|e| format!("# Failed to write settings: {}", e));
+    let started = Instant::now();
+    let mut summary = JobSummary::default();
+    let mut diagnostics = Diagnostics::default();
+    let result = run_encode(
+        options,
+        settings,
+        resume,
+        bars,
+        &mut summary,
+        &mut diagnostics,
+    )
+    .await;
+
+    // Temporary directory of a failed encode is kept, with what went wrong in it
+    if let (Err(e), true) = (&result, temp_dir.exists() && !options.dry_run) {
+        diagnostics.error = format!("{:#}", e);
+        if let Err(e) = diagnostics.write(&temp_dir, &settings_snapshot) {
+            warn!("Failed to write diagnostics: {}", e);
+        }
+    }
+
+    if notify_settings.enabled() && !options.dry_run && !options.estimate {
+        summary.elapsed = started.elapsed().as_secs_f64();
+        summary.error = result.as_ref().err().map(|e| format!("{:#}", e));
+        notify(&notify_settings, &summary).await;
+    }
+    result
+}
+
+/// Encodes inputs of `options`, recording what was encoded in `summary`, and what failed
+/// in `diagnostics`
+async fn run_encode(
+    options: &EncodeOptions,
+    mut settings: Settings,
+    resume: bool,
+    bars: Option<MultiProgress>,
+    summary: &mut JobSummary,
+    diagnostics: &mut Diagnostics,
+) -> Result<()> {
+    if resume {
+        if options.estimate {
+            anyhow::bail!("Resumed encode can't be estimated");
+        }
+        settings.processing.existing_temp = ExistingTemp::Reuse;
+    }
+
+    // Clap requires both files when no job is given
+    let output_file = options
+        .output_file
+        .clone()
+        .context("Output file is required")?;
+    let (input_files, batch) = collect_inputs(&options.input_file)?;
+    summary.inputs = input_files.clone();
+    if is_stdout(Path::new(&output_file)) {
+        check_stdout(&settings, batch)?;
+        if options.tui {
+            anyhow::bail!("Dashboard can't be shown while output is written to stdout");
+        }
+    }
+    check_specifiers(&settings.client.output.map)?;
+
+    verify_ffmpeg()?;
+    let encoder = settings.client.encoder.encoder();
+    encoder.validate(&settings.client.encoder_params)?;
+    if settings.client.lossless {
+        settings.client.encoder_params = lossless_params(encoder.as_ref(), &settings)?;
+    }
+    if settings.client.reproducible.enabled {
+        settings.client.encoder_params = encoder
+            .reproducible_params(&settings.client.encoder_params)
+            .with_context(|| {
+                format!(
+                    "{} with these parameters can't encode reproducibly",
+                    encoder.name()
+                )
+            })?;
+    }
+    check_passes(encoder.as_ref(), settings.client.passes)?;
+    let vbv = check_bitrate(encoder.as_ref(), &settings.client.bitrate)?;
+    check_crf(encoder.as_ref(), &settings)?;
+    check_floor(encoder.as_ref(), &settings)?;
+    if settings.client.quality.bitrate_outlier <= 1.0 {
+        anyhow::bail!("Bitrate of outliers has to be over the median");
+    }
+    if settings.client.quality.scenes && settings.client.quality.metrics.is_empty() {
+        anyhow::bail!("Quality can only be broken down by scene when metrics are measured");
+    }
+    check_content(encoder.as_ref(), &settings)?;
+    // Denoiser runs before other filters, decoders synthesize the grain it removes
+    let video_filter = settings
+        .client
+        .grain
+        .denoise
+        .map(denoise_filter)
+        .into_iter()
+        .chain(settings.client.video_filters.iter().cloned())
+        .collect::<Vec<_>>()
+        .join(",");
+    if !video_filter.is_empty()
+        && encoder
+            .with_filter(&settings.client.encoder_params, &video_filter)
+            .is_none()
+    {
+        anyhow::bail!("Encoder {} can't filter frames", encoder.name());
+    }
+    if let Some(strength) = settings
+        .client
+        .grain
+        .denoise
+        .filter(|s| !s.is_finite() || *s <= 0.0)
+    {
+        anyhow::bail!("Denoise strength has to be positive, not {}", strength);
+    }
+    if settings.client.grain.enabled()
+        && encoder
+            .with_grain_table(&settings.client.encoder_params, Path::new("grain.tbl"))
+            .is_none()
+    {
+        anyhow::bail!(
+            "Encoder {} with these parameters can't apply film grain table",
+            encoder.name()
+        );
+    }
+    let renditions = &settings.client.renditions;
+    check_renditions(renditions, encoder.as_ref(), &settings)?;
+    let metrics_address = settings
+        .client
+        .metrics_address
+        .as_deref()
+        .map(|address| {
+            address
+                .parse::<SocketAddr>()
+                .with_context(|| format!("Invalid metrics address {}", address))
+        })
+        .transpose()?;
+    let web_address = settings
+        .client
+        .web_address
+        .as_deref()
+        .map(|address| {
+            address
+                .parse::<SocketAddr>()
+                .with_context(|| format!("Invalid web UI address {}", address))
+        })
+        .transpose()?;
+
+    if options.dry_run {
+        let params = encoder
+            .with_filter(&settings.client.encoder_params, &video_filter)
+            .unwrap_or_else(|| settings.client.encoder_params.clone());
+        return print_plan(
+            &input_files,
+            Path::new(&output_file),
+            batch,
+            &settings,
+            encoder.as_ref(),
+            &params,
+        )
+        .await;
+    }
+
+    // Address that is taken fails the encode before anything is split
+    let metrics_server = match metrics_address {
+        Some(address) if !options.estimate => Some(MetricsServer::bind(address)?),
+        _ => None,
+    };
+    let web_server = match web_address {
+        Some(address) if !options.estimate => Some(WebServer::bind(address)?),
+        _ => None,
+    };
+
+    // With multiple inputs output is a directory, with one output for every input
+    let output_files = output_paths(&input_files, Path::new(&output_file), batch)?;
+    if batch {
+        std::fs::create_dir_all(&output_file).context("Failed to create output directory")?;
+    }
+    let output_files = output_files
+        .into_iter()
+        .map(|output_file| resolve_output(output_file, settings.client.output.overwrite))
+        .collect::<Result<Vec<_>>>()?;
+    let kept_files = prepare_temp_dir(
+        &settings.processing.temp_dir,
+        settings.processing.existing_temp,
+    )?;
+    // Resumed encode has files of the interrupted one already
+    if settings.processing.check_free_space && !resume {
+        check_temp_space(&input_files, &settings)?;
+    }
+
+    let mut jobs = Vec::new();
+    let mut next_index = 0;
+    for (number, (input_file, output_file)) in input_files.iter().zip(output_files).enumerate() {
+        let temp_dir = if batch {
+            settings
+                .processing
+                .temp_dir
+                .join(format!("job_{:03}", number))
+        } else {
+            settings.processing.temp_dir.clone()
+        };
+        // Remote inputs are downloaded first, download is resumed if it was interrupted
+        let input_file = match input_file.to_str() {
+            Some(url) if is_url(input_file) => {
+                download(url, &settings.processing.temp_dir.join("downloads")).await?
+            }
+            _ => input_file.clone(),
+        };
+        let mut job = prepare_job(&input_file, output_file, temp_dir, &settings, options)?;
+
+        // Indices identify chunks on nodes, so they are unique across all inputs
+        for chunk in &mut job.chunks {
+            chunk.index += next_index;
+            chunk.position = vec![chunk.index];
+        }
+        next_index += job.chunks.len();
+
+        // Tuned parameters are applied first, so CRF is selected with them
+        if settings.client.content.enabled() {
+            apply_content_params(&mut job, &settings.client.content).await;
+        }
+        if settings.client.crf.target.is_some() && settings.client.crf.search == CrfSearch::Title {
+            select_job_crf(
+                &mut job,
+                &settings.client.crf,
+                &Measurement {
+                    vmaf: settings.client.vmaf.clone(),
+                    subsample: settings.client.quality.subsample,
+                },
+                encoder.as_ref(),
+                &video_filter,
+            )
+            .await?;
+        }
+        // Zones are applied after CRF is selected, so CRF they set wins, unless nodes
+        // select CRF of every chunk
+        if let Some(path) = &settings.processing.zones {
+            apply_zones(&mut job.chunks, &read_zones(path)?)?;
+        }
+
+        if !renditions.is_empty() {
+            jobs.extend(rendition_jobs(job, renditions, &mut next_index));
+            continue;
+        }
+
+        if let Some(target) = settings.client.bitrate.target {
+            allocate_bitrates(
+                &mut job,
+                target,
+                vbv,
+                &settings.client.bitrate,
+                encoder.as_ref(),
+            )
+            .await;
+        }
+        jobs.push(job);
+    }
+
+    let throttles = ThrottleFactory::new(&settings.client.bandwidth);
+    let nodes = initialize_nodes(
+        &settings.client.node_addresses,
+        &options.slots,
+        &settings,
+        &throttles,
+    )
+    .await?;
+    let nodes = select_capable_nodes(nodes, &settings).await?;
+
+    // Chunks that couldn't be probed are assumed to be of requested duration
+    let chunk_durations = jobs
+        .iter()
+        .flat_map(|job| &job.chunks)
+        .map(|chunk| {
+            let duration = chunk
+                .duration()
+                .unwrap_or(settings.processing.segment_duration);
+            (chunk.index, duration)
+        })
+        .collect();
+    let total_frames = jobs
+        .iter()
+        .flat_map(|job| &job.chunks)
+        .filter_map(|chunk| chunk.metadata.as_ref())
+        .map(|metadata| metadata.frames)
+        .sum();
+
+    let job_id = Uuid::new_v4().to_string();
+    let cipher = match &settings.encryption.key_file {
+        Some(key_file) => {
+            info!("Chunk payloads will be encrypted");
+            Some(Arc::new(
+                MasterKey::from_file(key_file)?.job_cipher(&job_id),
+            ))
+        }
+        None => None,
+    };
+
+    if options.estimate {
+        let options = RequestOptions {
+            job_id: job_id.clone(),
+            cipher: cipher.clone(),
+            encoder: settings.client.encoder,
+            passes: settings.client.passes,
+            film_grain_table: String::new(),
+            video_filter: video_filter.clone(),
+            target_quality: target_quality(&settings.client.crf),
+            quality_metrics: settings.client.quality.metrics.clone(),
+            vmaf: vmaf_model(&settings.client.vmaf)?,
+            quality_subsample: settings.client.quality.subsample,
+            verify: settings.client.verify.clone(),
+            reproducible: settings.client.reproducible.enabled,
+        };
+        let estimate_dir = settings.processing.temp_dir.join("estimate");
+        let result = print_estimate(&jobs, &nodes, &chunk_durations, options, &estimate_dir).await;
+        // Files of an earlier encode that are reused are kept for it
+        if kept_files.is_empty() {
+            std::fs::remove_dir_all(&settings.processing.temp_dir)?;
+        } else {
+            if estimate_dir.exists() {
+                std::fs::remove_dir_all(&estimate_dir)?;
+            }
+            remove_created(&settings.processing.temp_dir, &kept_files)?;
+        }
+        return result;
+    }
+
+    let encode_dir = settings.processing.temp_dir.join("encoded");
+    std::fs::create_dir_all(&encode_dir).context("Failed to create encoded chunks directory")?;
+
+    // Chunks of all inputs share one queue, so nodes stay busy between inputs
+    let mut chunk_jobs = HashMap::new();
+    let mut positions = HashMap::new();
+    let mut grain_tables = HashMap::new();
+    let mut pending_chunks = Vec::new();
+    for (number, job) in jobs.iter_mut().enumerate() {
+        chunk_jobs.extend(job.chunks.iter().map(|chunk| (chunk.index, number)));
+        positions.extend(
+            job.chunks
+                .iter()
+                .map(|chunk| (chunk.index, chunk.position.clone())),
+        );
+        if let Some(table) = &job.film_grain_table {
+            grain_tables.insert(number, table.clone());
+        }
+        // Chunks are taken from the end of the queue, so the first input is encoded first
+        pending_chunks.splice(0..0, job.chunks.drain(..));
+    }
+
+    // Chunks the interrupted encode wrote are taken as they are
+    let resumed = if resume {
+        take_encoded(&mut pending_chunks, &encode_dir)
+    } else {
+        Vec::new()
+    };
+    let mut progress = JobProgress::new(chunk_durations);
+    for chunk in &resumed {
+        progress.complete(chunk.index);
+    }
+
+    // Initializing client state
+    let encoding_state = Arc::new(Mutex::new(EncodingState {
+        job_id,
+        cipher,
+        encoder: settings.client.encoder,
+        passes: settings.client.passes,
+        encode_dir,
+        pending_chunks,
+        completed_chunks: resumed,
+        progress,
+        failures: HashMap::new(),
+        next_index,
+        resplit_after: settings.client.resplit_after,
+        chunk_jobs,
+        positions,
+        grain_tables,
+        video_filter,
+        target_quality: target_quality(&settings.client.crf),
+        quality_metrics: settings.client.quality.metrics.clone(),
+        vmaf: vmaf_model(&settings.client.vmaf)?,
+        quality_subsample: settings.client.quality.subsample,
+        verify: settings.client.verify.clone(),
+        reproducible: settings.client.reproducible.enabled,
+        results: HashMap::new(),
+        quality_floor: settings.client.quality.floor.clone(),
+        floor_retries: HashMap::new(),
+        node_activity: nodes
+            .iter()
+            .map(|node| {
+                let activity = NodeActivity {
+                    slots: node.semaphore.available_permits(),
+                    uploaded: Arc::clone(&node.uploaded_bytes),
+                    ..Default::default()
+                };
+                (node.address.clone(), activity)
+            })
+            .collect(),
+        diagnostics: Diagnostics::default(),
+        queued_at: HashMap::new(),
+        timings: Vec::new(),
+        recent_errors: Vec::new(),
+    }));
+
+    // Tasks are aborted when set is dropped, which cancels all in-flight requests
+    let mut node_tasks = JoinSet::new();
+    let mut progress_tasks = JoinSet::new();
+
+    // Audio is transcoded while nodes encode video, once for all renditions of an input
+    let mut audio_tasks = JoinSet::new();
+    if settings.client.audio.enabled() {
+        let streams: HashSet<PathBuf> = jobs
+            .iter()
+            .filter_map(|job| job.non_video_streams.clone())
+            .collect();
+        for streams in streams {
+            let audio = settings.client.audio.clone();
+            audio_tasks.spawn(async move {
+                let transcoded = streams.with_file_name("transcoded.mkv");
+                let result = transcode_audio(&streams, &audio, &transcoded)
+                    .await
+                    .map(|()| transcoded);
+                (streams, result)
+            });
+        }
+    }
+
+    let addresses: Vec<String> = nodes.iter().map(|node| node.address.clone()).collect();
+    // Start encoding tasks for each node
+    for node in nodes {
+        let state_clone = Arc::clone(&encoding_state);
+        progress_tasks.spawn(watch_node_progress(
+            node.client.clone(),
+            node.address.clone(),
+            Arc::clone(&encoding_state),
+        ));
+        node_tasks.spawn(encode_chunks_on_node(node, state_clone));
+    }
+    // Bars show what progress lines would
+    match bars {
+        Some(multi) => {
+            let bars = ProgressBars::new(&multi, total_frames, &addresses);
+            progress_tasks.spawn(show_progress_bars(Arc::clone(&encoding_state), bars));
+        }
+        None => {
+            progress_tasks.spawn(report_progress(Arc::clone(&encoding_state)));
+        }
+    }
+    if options.tui {
+        progress_tasks.spawn(show_dashboard(Arc::clone(&encoding_state)));
+    }
+    if let Some(server) = metrics_server {
+        let (sender, receiver) = watch::channel(String::new());
+        progress_tasks.spawn(update_metrics(Arc::clone(&encoding_state), sender));
+        progress_tasks.spawn(server.serve(receiver));
+    }
+    if let Some(server) = web_server {
+        let (sender, receiver) = watch::channel(String::new());
+        progress_tasks.spawn(update_web_status(Arc::clone(&encoding_state), sender));
+        progress_tasks.spawn(server.serve(receiver));
+    }
+    if settings.client.output.preview {
+        let previews = jobs
+            .iter()
+            .enumerate()
+            .map(|(number, job)| {
+                let preview = preview_path(&job.output_file);
+                (number, preview, job.config.temp_dir.clone())
+            })
+            .collect();
+        progress_tasks.spawn(update_previews(
+            Arc::clone(&encoding_state),
+            previews,
+            Duration::from_secs(settings.client.output.preview_interval),
+        ));
+    }
+
+    // Wait for all encoding tasks to complete
+    tokio::select! {
+        _ = async {
+            while let Some(result) = node_tasks.join_next().await {
+                if let Err(e) = result {
+                    error!("node task failed: {}", e);
+                }
+            }
+        } => {}
+        _ = tokio::signal::ctrl_c() => {
+            warn!("Interrupted, cancelling chunks that are being encoded");
+            node_tasks.shutdown().await;
+            // Dashboard gives the terminal back before the error is printed
+            progress_tasks.shutdown().await;
+            record_chunks(&*encoding_state.lock().await, diagnostics);
+            return Err(anyhow::anyhow!("Encoding was interrupted"));
+        }
+    }
+    progress_tasks.shutdown().await;
+
+    let encoding_state = encoding_state.lock().await;
+    if !encoding_state.pending_chunks.is_empty() {
+        warn!("Some chunks were not encoded successfully");
+    }
+    summary.chunks = encoding_state.completed_chunks.len();
+    record_chunks(&encoding_state, diagnostics);
+    if !encoding_state.timings.is_empty() {
+        report_timing(
+            TimingReport::new(encoding_state.timings.clone()),
+            Path::new(&output_file),
+            batch,
+        );
+    }
+
+    let mut transcoded = HashMap::new();
+    while let Some(result) = audio_tasks.join_next().await {
+        match result {
+            Ok((streams, result)) => {
+                transcoded.insert(streams, result);
+            }
+            Err(e) => error!("audio task failed: {}", e),
+        }
+    }
+
+    let total = jobs.len();
+    let mut failed = 0;
+    let mut done = Vec::new();
+    // Renditions of an input share its temporary files, which are kept when one fails
+    let mut kept_dirs = HashSet::new();
+    for (number, job) in jobs.into_iter().enumerate() {
+        let mut encoded_chunks: Vec<&Chunk> = encoding_state
+            .completed_chunks
+            .iter()
+            .filter(|chunk| encoding_state.chunk_jobs.get(&chunk.index) == Some(&number))
+            .collect();
+        encoded_chunks.sort_by(|a, b| a.position.cmp(&b.position));
+
+        info!("Concatenating encoded chunks into {:?}", job.output_file);
+
+        let encoded_paths: Vec<PathBuf> = encoded_chunks
+            .iter()
+            .map(|chunk| chunk.encoded_path.clone().unwrap())
+            .collect();
+
+        let mut metadata = job.metadata.clone();
+        let tags = &settings.client.metadata;
+        // Parameters of the first chunk, others may differ by zones or selected CRF
+        if let (true, Some(chunk)) = (tags.encoder_settings, encoded_chunks.first()) {
+            metadata.global.insert(
+                "ENCODER_SETTINGS".to_string(),
+                format!(
+                    "{} {}",
+                    settings.client.encoder.name(),
+                    chunk.encoder_parameters.join(" ")
+                ),
+            );
+        }
+        if tags.job_id {
+            metadata
+                .global
+                .insert("JOB_ID".to_string(), encoding_state.job_id.clone());
+        }
+
+        // Transcoded audio replaces streams extracted from the input
+        let streams = match &job.non_video_streams {
+            Some(streams) => match transcoded.get(streams) {
+                Some(Ok(transcoded)) => Ok(Some(transcoded)),
+                Some(Err(e)) => Err(VideoEncodeError::Encoding(format!(
+                    "Audio was not transcoded: {}",
+                    e
+                ))),
+                None => Ok(Some(streams)),
+            },
+            None => Ok(None),
+        };
+        let result = streams.and_then(|streams| {
+            concatenate_videos_and_copy_streams(
+                encoded_paths,
+                streams.map(PathBuf::as_path),
+                job.chapters.as_deref(),
+                &job.output_file,
+                &job.config.temp_dir,
+                encoded_chunks.len(),
+                job.timecodes.as_deref(),
+                settings.client.concat,
+                &settings.client.output,
+                &metadata,
+            )
+        });
+
+        // Output written to stdout can't be read back
+        let stdout = is_stdout(&job.output_file);
+        if let (Ok(()), Some(vbv), false) = (&result, vbv, stdout) {
+            report_vbv(&job.output_file, vbv);
+        }
+        if let (Ok(()), Some(format), false) = (&result, job.lossless_format, stdout) {
+            report_lossless(&job.output_file, format);
+        }
+        if result.is_ok() {
+            let quality = &settings.client.quality;
+            let mut report = output_report(
+                &job.output_file,
+                &encoded_chunks,
+                &encoding_state.results,
+                &quality.metrics,
+                settings.processing.segment_duration,
+                quality.bitrate_outlier,
+            );
+            if quality.scenes {
+                match detect_scenes(&job.output_file, settings.processing.scene_threshold) {
+                    Ok(scene_changes) => report.break_down(&scene_changes, &quality.metrics),
+                    Err(e) => warn!("Failed to detect scenes of {:?}: {}", job.output_file, e),
+                }
+            }
+            for &metric in &quality.metrics {
+                report_quality(&report, metric);
+                report_scenes(&report, metric);
+            }
+            report_bitrates(&report);
+            if let Some(format) = quality.report {
+                if let Err(e) = report.write(&report_path(&job.output_file, format), format) {
+                    warn!("Failed to write report of {:?}: {}", job.output_file, e);
+                }
+            }
+        }
+        let result = match (result, &job.source) {
+            (Ok(()), Some(source)) if settings.client.sync.check != SyncCheck::Off && !stdout => {
+                check_sync(source, &job.output_file, &settings.client.sync)
+            }
+            (result, _) => result,
+        };
+        let result = match result {
+            Ok(()) if settings.client.reproducible.enabled => check_reproduction(
+                &job.output_file,
+                &encoded_chunks,
+                &encoding_state.results,
+                settings.client.reproducible.verify,
+            ),
+            result => result,
+        };
+        let result = match (result, settings.client.package.format) {
+            (Ok(()), Some(format)) => {
+                package_output(&job.output_file, format, &settings.client.package).map(|_| ())
+            }
+            (result, _) => result,
+        };
+
+        if result.is_ok() && settings.client.output.preview {
+            let _ = std::fs::remove_file(preview_path(&job.output_file));
+        }
+
+        match result {
+            Ok(()) => {
+                summary.output_size += std::fs::metadata(&job.output_file)
+                    .map(|metadata| metadata.len())
+                    .unwrap_or_default();
+                summary.outputs.push(job.output_file);
+                done.push(job.config);
+            }
+            Err(e) => {
+                error!("Failed to finish {:?}: {}", job.output_file, e);
+                kept_dirs.insert(job.config.temp_dir.clone());
+                failed += 1;
+                summary.failed_outputs += 1;
+                diagnostics
+                    .failed_outputs
+                    .insert(job.output_file, format!("{:#}", e));
+            }
+        }
+    }
+
+    // Remove temp config folders recursively
+    for config in done {
+        if !kept_dirs.contains(&config.temp_dir) {
+            config.delete()?;
+        }
+    }
+
+    if failed > 0 {
+        return Err(anyhow::anyhow!(
+            "{} of {} outputs failed, their temporary files are kept",
+            failed,
+            total
+        ));
+    }
+
+    // Encoded chunks of all inputs are stored in the base temp directory
+    if settings.processing.temp_dir.exists() {
+        std::fs::remove_dir_all(&settings.processing.temp_dir)?;
+    }
+
+    info!("Video encoding completed successfully");
+
+    Ok(())
+}
+
+/// Measures drift of audio and video of `output_file` from `source`. Output fails when
+/// it exceeds the limit and `settings` say so.
+fn check_sync(
+    source: &Path,
+    output_file: &Path,
+    settings: &SyncSettings,
+) -> Result<(), VideoEncodeError> {
+    let drift = measure_sync(source, output_file)?;
+    if drift.max() <= settings.max_drift {
+        debug!(
+            "Audio and video of {:?} are in sync: {:?}",
+            output_file, drift
+        );
+        return Ok(());
+    }
+
+    let message = format!(
+        "Audio and video of {:?} drift {:.3}s from the source, at start {:.3}s, \
+         at end {:.3}s, in video duration {:.3}s",
+        output_file,
+        drift.max(),
+        drift.start.unwrap_or_default(),
+        drift.end.unwrap_or_default(),
+        drift.duration
+    );
+    match settings.check {
+        SyncCheck::Fail => Err(VideoEncodeError::Encoding(message)),
+        _ => {
+            warn!("{}", message);
+            Ok(())
+        }
+    }
+}
+
+/// Records hashes of chunks of the output in its manifest, or compares them with
+/// recorded ones when `verify` is set. Output fails when any chunk isn't reproduced.
+fn check_reproduction(
+    output_file: &Path,
+    chunks: &[&Chunk],
+    results: &HashMap<usize, ChunkResult>,
+    verify: bool,
+) -> Result<(), VideoEncodeError> {
+    let hashes: Hashes = chunks
+        .iter()
+        .filter_map(|chunk| {
+            let hash = results.get(&chunk.index)?.hash.clone()?;
+            Some((chunk.index, hash))
+        })
+        .collect();
+    let manifest = manifest_path(output_file);
+
+    if !verify {
+        return write_manifest(&manifest, &hashes);
+    }
+    if !manifest.exists() {
+        warn!(
+            "{:?} has no recorded hashes to compare with, recording them",
+            output_file
+        );
+        return write_manifest(&manifest, &hashes);
+    }
+
+    let comparison = compare(&read_manifest(&manifest)?, &hashes);
+    if comparison.unrecorded > 0 {
+        warn!(
+            "{} chunks of {:?} have no recorded hash",
+            comparison.unrecorded, output_file
+        );
+    }
+    if !comparison.reproduced() {
+        return Err(VideoEncodeError::Encoding(format!(
+            "{} chunks aren't reproduced exactly: {:?}",
+            comparison.mismatched.len(),
+            comparison.mismatched
+        )));
+    }
+    info!(
+        "All {} recorded chunks of {:?} are reproduced exactly",
+        comparison.matched, output_file
+    );
+    Ok(())
+}
+
+/// Encodes chunks spread over `jobs` on `nodes` into `dir`, and prints size and time of
+/// the whole encode they extrapolate to. `durations` are seconds of every chunk.
+async fn print_estimate(
+    jobs: &[Job],
+    nodes: &[NodeConnection],
+    durations: &HashMap<usize, f64>,
+    options: RequestOptions,
+    dir: &Path,
+) -> Result<()> {
+    std::fs::create_dir_all(dir)?;
+    let chunks: Vec<Chunk> = jobs
+        .iter()
+        .flat_map(|job| job.chunks.iter().cloned())
+        .collect();
+    let samples = representative_chunks(&chunks, ESTIMATE_SAMPLES.max(nodes.len()));
+    if samples.is_empty() {
+        anyhow::bail!("No chunk of known duration to estimate the encode with");
+    }
+    info!("Encoding {} chunks to estimate the encode", samples.len());
+
+    // Samples are spread over nodes, and take slots like chunks of the encode
+    let results = futures::future::join_all(samples.iter().enumerate().map(|(number, &chunk)| {
+        let node = &nodes[number % nodes.len()];
+        let film_grain_table = jobs
+            .iter()
+            .find(|job| job.chunks.iter().any(|other| other.index == chunk.index))
+            .and_then(|job| job.film_grain_table.clone())
+            .unwrap_or_default();
+        let options = RequestOptions {
+            film_grain_table,
+            ..options.clone()
+        };
+        async move {
+            let _permit = node.semaphore.acquire().await;
+            let result = send_chunk(
+                chunk.clone(),
+                options,
+                dir.to_path_buf(),
+                node.client.clone(),
+                Arc::clone(&node.uploaded_chunks),
+                &node.uploaded_bytes,
+            )
+            .await;
+            (node, chunk, result)
+        }
+    }))
+    .await;
+
+    let (mut size, mut encoded) = (0, 0.0);
+    // Seconds of input encoded and seconds spent encoding them, by node
+    let mut speeds: HashMap<&str, (f64, f64)> = HashMap::new();
+    for (node, chunk, result) in &results {
+        let duration = chunk.duration().unwrap_or_default();
+        match result {
+            Ok((encoded_chunk, result)) => {
+                size += encoded_chunk
+                    .encoded_path
+                    .as_ref()
+                    .and_then(|path| std::fs::metadata(path).ok())
+                    .map(|metadata| metadata.len())
+                    .unwrap_or_default();
+                encoded += duration;
+                let speed = speeds.entry(node.address.as_str()).or_default();
+                speed.0 += duration;
+                speed.1 += result.encode_time;
+            }
+            Err(e) => warn!(
+                "Failed to encode chunk {} on node {}: {:#}",
+                chunk.index, node.address, e
+            ),
+        }
+    }
+    if encoded <= 0.0 {
+        anyhow::bail!("No chunk could be encoded to estimate the encode");
+    }
+
+    let (input, time) = speeds.values().fold((0.0, 0.0), |(input, time), speed| {
+        (input + speed.0, time + speed.1)
+    });
+    let mean_speed = input / f64::max(time, f64::EPSILON);
+    println!("{:<32} {:>6} {:>10}", "NODE", "SLOTS", "SPEED");
+    // Seconds of input all nodes encode in a second
+    let mut capacity = 0.0;
+    for node in nodes {
+        let slots = node.semaphore.available_permits();
+        // Nodes that encoded no chunk are assumed to be as fast as the others
+        let speed = speeds
+            .get(node.address.as_str())
+            .map(|(input, time)| input / time.max(f64::EPSILON))
+            .unwrap_or(mean_speed);
+        capacity += slots as f64 * speed;
+        println!("{:<32} {:>6} {:>9.2}x", node.address, slots, speed);
+    }
+
+    let total: f64 = durations.values().sum();
+    let bytes = size as f64 / encoded * total;
+    println!();
+    println!(
+        "Input:           {} in {} chunks, {} of it encoded",
+        format_duration(Duration::from_secs_f64(total)),
+        durations.len(),
+        format_duration(Duration::from_secs_f64(encoded))
+    );
+    println!(
+        "Estimated size:  {:.1} MiB, {:.0} kbit/s",
+        bytes / (1024.0 * 1024.0),
+        bytes * 8.0 / 1000.0 / total.max(f64::EPSILON)
+    );
+    // Transfers of chunks and joining them take time on top
+    println!(
+        "Estimated time:  {}",
+        format_duration(Duration::from_secs_f64(
+            total / f64::max(capacity, f64::EPSILON)
+        ))
+    );
+    Ok(())
+}
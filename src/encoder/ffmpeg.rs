@@ -0,0 +1,149 @@
+/// Encoder that passes parameters to ffmpeg as they are,
+/// like `-c:v libx264 -crf 23`
+use std::{ops::RangeInclusive, path::Path};
+
+use tokio::process::Command;
+
+use crate::chunk::verify_ffmpeg;
+use crate::encoder::{
+    add_option, add_private_option, ffmpeg_rate_params, reject_reserved, remove_options,
+    selected_codec, Encoder, ParseProgress, Pass, Vbv, REPRODUCIBLE_THREADS,
+};
+use crate::error::VideoEncodeError;
+use crate::ffmpeg::progress::{Progress, ProgressParser};
+
+/// Options that are set by the encoder itself, and would break input or progress
+const RESERVED_OPTIONS: [&str; 5] = ["-i", "-progress", "-nostats", "-pass", "-passlogfile"];
+/// Rate options of ffmpeg encoder wrappers, which lossless encoding replaces
+const RATE_OPTIONS: [&str; 7] = ["-crf", "-qp", "-q:v", "-b:v", "-maxrate", "-bufsize", "-cq"];
+
+#[derive(Debug, Default, Clone, Copy)]
+pub struct FfmpegEncoder;
+
+impl ParseProgress for ProgressParser {
+    fn push_line(&mut self, line: &str) -> Option<Progress> {
+        ProgressParser::push_line(self, line)
+    }
+}
+
+impl Encoder for FfmpegEncoder {
+    fn name(&self) -> &'static str {
+        "ffmpeg"
+    }
+
+    fn probe(&self) -> Result<(), VideoEncodeError> {
+        verify_ffmpeg()
+    }
+
+    fn validate(&self, params: &[String]) -> Result<(), VideoEncodeError> {
+        if params.is_empty() {
+            return Err(VideoEncodeError::Encoding(
+                "Encoder parameters are empty".to_string(),
+            ));
+        }
+
+        reject_reserved(params, &RESERVED_OPTIONS)
+    }
+
+    fn command(&self, input: &Path, output: &Path, params: &[String]) -> Command {
+        let mut command = Command::new("ffmpeg");
+        command
+            .arg("-hide_banner")
+            .args(["-nostats", "-progress", "pipe:1"])
+            .arg("-i")
+            .arg(input)
+            .args(params)
+            .arg(output);
+        command
+    }
+
+    fn progress_parser(&self) -> Box<dyn ParseProgress> {
+        Box::new(ProgressParser::default())
+    }
+
+    fn pass_params(&self, pass: Pass, stats: &Path) -> Option<Vec<String>> {
+        Some(vec![
+            "-pass".to_string(),
+            pass.number().to_string(),
+            "-passlogfile".to_string(),
+            stats.to_string_lossy().to_string(),
+        ])
+    }
+
+    fn rate_params(&self, bitrate: u64, vbv: Option<Vbv>) -> Option<Vec<String>> {
+        Some(ffmpeg_rate_params(bitrate, vbv))
+    }
+
+    /// Lossless mode of every wrapper, codecs that are always lossless are kept as they are
+    fn lossless_params(&self, params: &[String]) -> Option<Vec<String>> {
+        let rest = remove_options(params, &RATE_OPTIONS);
+        let params = match selected_codec(params)? {
+            "libx264" | "librav1e" => add_option(&rest, "-qp", "0"),
+            "libx265" => add_private_option(&rest, "-x265-params", "lossless=1"),
+            "libaom-av1" => add_private_option(&rest, "-aom-params", "lossless=1"),
+            "libsvtav1" => add_private_option(&rest, "-svtav1-params", "lossless=1"),
+            "libvpx-vp9" => add_option(&rest, "-lossless", "1"),
+            "ffv1" | "utvideo" | "huffyuv" | "ffvhuff" => params.to_vec(),
+            _ => return None,
+        };
+        Some(params)
+    }
+
+    fn crf_option(&self, params: &[String]) -> Option<(&'static str, RangeInclusive<u32>)> {
+        match selected_codec(params)? {
+            "libx264" | "libx265" => Some(("-crf", 0..=51)),
+            "libaom-av1" | "libvpx-vp9" => Some(("-crf", 0..=63)),
+            "libsvtav1" => Some(("-crf", 1..=63)),
+            _ => None,
+        }
+    }
+
+    /// Table is set through private options of AV1 encoder wrappers,
+    /// and merged with private options that parameters already have
+    fn with_grain_table(&self, params: &[String], table: &Path) -> Option<Vec<String>> {
+        let (option, key) = match selected_codec(params)? {
+            "libaom-av1" => ("-aom-params", "film-grain-table"),
+            "libsvtav1" => ("-svtav1-params", "fgs-table"),
+            _ => return None,
+        };
+        let value = format!("{}={}", key, table.to_string_lossy());
+        Some(add_private_option(params, option, &value))
+    }
+
+    /// `-threads` keeps nodes from limiting threads, and wrappers that ignore it get
+    /// their own options. Only software codecs are reproducible.
+    fn reproducible_params(&self, params: &[String]) -> Option<Vec<String>> {
+        let threads = REPRODUCIBLE_THREADS;
+        let pinned = add_option(params, "-threads", &threads.to_string());
+        let params = match selected_codec(params)? {
+            "libx265" => add_private_option(
+                &pinned,
+                "-x265-params",
+                &format!("frame-threads={}:pools={}", threads, threads),
+            ),
+            "libsvtav1" => {
+                add_private_option(&pinned, "-svtav1-params", &format!("lp={}", threads))
+            }
+            "libx264" | "libaom-av1" | "libvpx-vp9" | "librav1e" | "ffv1" | "utvideo"
+            | "huffyuv" | "ffvhuff" => pinned,
+            _ => return None,
+        };
+        Some(params)
+    }
+
+    /// Wrappers of x265 and SVT-AV1 ignore `-threads`, so their own options are set
+    fn with_threads(&self, params: &[String], threads: usize) -> Vec<String> {
+        if params.iter().any(|param| param == "-threads") {
+            return params.to_vec();
+        }
+        match selected_codec(params) {
+            Some("libx265") => {
+                add_private_option(params, "-x265-params", &format!("pools={}", threads))
+            }
+            Some("libsvtav1") => {
+                add_private_option(params, "-svtav1-params", &format!("lp={}", threads))
+            }
+            _ => add_option(params, "-threads", &threads.to_string()),
+        }
+    }
+}
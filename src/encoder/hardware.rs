@@ -0,0 +1,215 @@
+/// Encoders that use hardware encoding APIs through ffmpeg, like `-c:v hevc_nvenc -cq 24`.
+/// Encoder wrappers are compiled into ffmpeg even without the hardware, so nodes
+/// detect which codecs actually work by encoding a few test frames.
+use std::{path::Path, process::Command as StdCommand};
+
+use tokio::process::Command;
+use tracing::{debug, info};
+
+use crate::chunk::verify_ffmpeg;
+use crate::encoder::pixel_format::{take_pixel_format, PIX_FMT_OPTION};
+use crate::encoder::{
+    add_filter, ffmpeg_rate_params, reject_reserved, selected_codec, Encoder, ParseProgress,
+    PixelFormat, Vbv,
+};
+use crate::error::VideoEncodeError;
+use crate::ffmpeg::progress::ProgressParser;
+
+/// Options that are set by the encoder itself, and would break input or progress
+const RESERVED_OPTIONS: [&str; 6] = [
+    "-i",
+    "-progress",
+    "-nostats",
+    "-hwaccel",
+    "-vaapi_device",
+    "-init_hw_device",
+];
+
+/// Render node VAAPI encodes with
+const VAAPI_DEVICE: &str = "/dev/dri/renderD128";
+
+/// Pixel formats hardware encoders take frames in
+const DEVICE_FORMAT_8BIT: &str = "nv12";
+const DEVICE_FORMAT_10BIT: &str = "p010le";
+
+/// Hardware encoding APIs
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum HwAccel {
+    /// NVIDIA NVENC
+    Nvenc,
+    /// Intel Quick Sync Video
+    Qsv,
+    /// VA-API, on Intel and AMD GPUs on Linux
+    Vaapi,
+    /// AMD Advanced Media Framework
+    Amf,
+}
+
+impl HwAccel {
+    pub const ALL: [HwAccel; 4] = [HwAccel::Nvenc, HwAccel::Qsv, HwAccel::Vaapi, HwAccel::Amf];
+
+    pub fn name(self) -> &'static str {
+        match self {
+            HwAccel::Nvenc => "nvenc",
+            HwAccel::Qsv => "qsv",
+            HwAccel::Vaapi => "vaapi",
+            HwAccel::Amf => "amf",
+        }
+    }
+
+    /// ffmpeg encoders of this API, like `hevc_nvenc`
+    pub fn codecs(self) -> Vec<String> {
+        ["h264", "hevc", "av1"]
+            .iter()
+            .map(|codec| format!("{}_{}", codec, self.name()))
+            .collect()
+    }
+
+    /// Finds API that ffmpeg encoder uses, `None` for software encoders
+    pub fn of_codec(codec: &str) -> Option<HwAccel> {
+        HwAccel::ALL
+            .into_iter()
+            .find(|accel| accel.codecs().iter().any(|known| known == codec))
+    }
+
+    /// Options that open the device, they go before input
+    fn device_options(self) -> &'static [&'static str] {
+        match self {
+            HwAccel::Vaapi => &["-vaapi_device", VAAPI_DEVICE],
+            _ => &[],
+        }
+    }
+
+    /// Filter that uploads decoded frames of `format` to the device,
+    /// when encoder can't do it itself
+    fn upload_filter(self, format: &str) -> Option<String> {
+        match self {
+            HwAccel::Vaapi => Some(format!("format={},hwupload", format)),
+            _ => None,
+        }
+    }
+}
+
+/// Returns codecs of `accel` that work on this machine. Every codec encodes
+/// a few generated frames, which fails when there's no device or driver for it.
+pub fn detect_codecs(accel: HwAccel) -> Vec<String> {
+    accel
+        .codecs()
+        .into_iter()
+        .filter(|codec| {
+            let mut command = StdCommand::new("ffmpeg");
+            command
+                .args(["-hide_banner", "-loglevel", "error"])
+                .args(accel.device_options())
+                .args(["-f", "lavfi", "-i", "color=size=256x256:duration=0.2"]);
+            if let Some(filter) = accel.upload_filter(DEVICE_FORMAT_8BIT) {
+                command.args(["-vf", &filter]);
+            }
+            command.args(["-c:v", codec, "-f", "null", "-"]);
+
+            match command.output() {
+                Ok(output) if output.status.success() => true,
+                Ok(output) => {
+                    debug!(
+                        "{} doesn't work: {}",
+                        codec,
+                        String::from_utf8_lossy(&output.stderr).trim()
+                    );
+                    false
+                }
+                Err(e) => {
+                    debug!("Failed to test {}: {}", codec, e);
+                    false
+                }
+            }
+        })
+        .collect()
+}
+
+/// Returns hardware codec that parameters select, so chunks encoded with them
+/// can only go to nodes where the codec works
+pub fn hardware_codec(params: &[String]) -> Option<&str> {
+    selected_codec(params).filter(|codec| HwAccel::of_codec(codec).is_some())
+}
+
+/// Encoder that passes parameters to ffmpeg, and sets up the device of hardware API
+#[derive(Debug, Clone, Copy)]
+pub struct HardwareEncoder(pub HwAccel);
+
+impl Encoder for HardwareEncoder {
+    fn name(&self) -> &'static str {
+        self.0.name()
+    }
+
+    fn probe(&self) -> Result<(), VideoEncodeError> {
+        verify_ffmpeg()?;
+        let codecs = detect_codecs(self.0);
+        if codecs.is_empty() {
+            return Err(VideoEncodeError::Encoding(format!(
+                "None of {} codecs work",
+                self.0.name()
+            )));
+        }
+
+        info!("Hardware codecs available: {}", codecs.join(", "));
+        Ok(())
+    }
+
+    fn validate(&self, params: &[String]) -> Result<(), VideoEncodeError> {
+        reject_reserved(params, &RESERVED_OPTIONS)?;
+
+        match selected_codec(params) {
+            Some(codec) if HwAccel::of_codec(codec) == Some(self.0) => Ok(()),
+            Some(codec) => Err(VideoEncodeError::Encoding(format!(
+                "Encoder parameters select {}, which is not a {} codec",
+                codec,
+                self.0.name()
+            ))),
+            None => Err(VideoEncodeError::Encoding(format!(
+                "Encoder parameters have to select {} codec, like -c:v {}",
+                self.0.name(),
+                self.0.codecs()[1]
+            ))),
+        }
+    }
+
+    /// Output of hardware encoders depends on the device and its driver
+    fn reproducible_params(&self, _params: &[String]) -> Option<Vec<String>> {
+        None
+    }
+
+    /// Pixel format is converted to semi-planar format of the device with the same depth
+    fn command(&self, input: &Path, output: &Path, params: &[String]) -> Command {
+        let (pix_fmt, mut params) = take_pixel_format(params);
+        let device_format = match pix_fmt.as_deref().and_then(PixelFormat::parse) {
+            Some(format) if format.bit_depth > 8 => DEVICE_FORMAT_10BIT,
+            _ => DEVICE_FORMAT_8BIT,
+        };
+
+        // Upload follows filters of the parameters, which run on decoded frames
+        if let Some(upload) = self.0.upload_filter(device_format) {
+            params = add_filter(&params, &upload, false);
+        } else if pix_fmt.is_some() {
+            params.extend([PIX_FMT_OPTION.to_string(), device_format.to_string()]);
+        }
+
+        let mut command = Command::new("ffmpeg");
+        command
+            .arg("-hide_banner")
+            .args(["-nostats", "-progress", "pipe:1"])
+            .args(self.0.device_options())
+            .arg("-i")
+            .arg(input)
+            .args(params)
+            .arg(output);
+        command
+    }
+
+    fn progress_parser(&self) -> Box<dyn ParseProgress> {
+        Box::new(ProgressParser::default())
+    }
+
+    fn rate_params(&self, bitrate: u64, vbv: Option<Vbv>) -> Option<Vec<String>> {
+        Some(ffmpeg_rate_params(bitrate, vbv))
+    }
+}
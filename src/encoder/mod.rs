@@ -0,0 +1,728 @@
+/// This module abstracts encoders that chunks are encoded with. Every encoder
+/// builds its own command line and parses its own progress output.
+use std::{
+    fmt::Debug,
+    ops::RangeInclusive,
+    path::Path,
+    process::{ExitStatus, Stdio},
+};
+
+use futures::future::BoxFuture;
+use serde::{Deserialize, Serialize};
+use tokio::{
+    io::{AsyncBufReadExt, AsyncRead, AsyncReadExt, BufReader},
+    process::Command,
+};
+use tracing::{debug, error};
+
+use crate::cgroup;
+use crate::error::VideoEncodeError;
+use crate::ffmpeg::progress::Progress;
+use crate::zones::override_params;
+
+pub mod ffmpeg;
+pub mod hardware;
+pub mod pixel_format;
+pub mod profile;
+#[cfg(feature = "rav1e")]
+pub mod rav1e;
+pub mod svt_av1;
+pub mod warm;
+pub mod x26x;
+
+pub use self::ffmpeg::FfmpegEncoder;
+pub use self::hardware::{HardwareEncoder, HwAccel};
+pub use self::pixel_format::{PixelFormat, PixelFormats};
+
+use self::pixel_format::PIX_FMT_OPTION;
+#[cfg(feature = "rav1e")]
+pub use self::rav1e::Rav1eEncoder;
+pub use self::svt_av1::SvtAv1Encoder;
+pub use self::x26x::X26xEncoder;
+
+/// Encoders that chunks can be encoded with
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Deserialize, Serialize, clap::ValueEnum)]
+#[serde(rename_all = "kebab-case")]
+pub enum EncoderKind {
+    /// ffmpeg with parameters passed as they are
+    #[default]
+    Ffmpeg,
+    /// SvtAv1EncApp fed by ffmpeg through y4m pipe
+    SvtAv1,
+    /// rav1e library, built with `rav1e` feature
+    #[cfg(feature = "rav1e")]
+    Rav1e,
+    /// x264 fed by ffmpeg through y4m pipe
+    X264,
+    /// x265 fed by ffmpeg through y4m pipe
+    X265,
+    /// ffmpeg with NVIDIA NVENC codecs
+    Nvenc,
+    /// ffmpeg with Intel Quick Sync Video codecs
+    Qsv,
+    /// ffmpeg with VA-API codecs
+    Vaapi,
+    /// ffmpeg with AMD AMF codecs
+    Amf,
+}
+
+impl EncoderKind {
+    pub const ALL: &'static [EncoderKind] = &[
+        EncoderKind::Ffmpeg,
+        EncoderKind::SvtAv1,
+        #[cfg(feature = "rav1e")]
+        EncoderKind::Rav1e,
+        EncoderKind::X264,
+        EncoderKind::X265,
+        EncoderKind::Nvenc,
+        EncoderKind::Qsv,
+        EncoderKind::Vaapi,
+        EncoderKind::Amf,
+    ];
+
+    pub fn encoder(self) -> Box<dyn Encoder> {
+        match self {
+            EncoderKind::Ffmpeg => Box::new(FfmpegEncoder),
+            EncoderKind::SvtAv1 => Box::new(SvtAv1Encoder),
+            #[cfg(feature = "rav1e")]
+            EncoderKind::Rav1e => Box::new(Rav1eEncoder),
+            EncoderKind::X264 => Box::new(X26xEncoder::X264),
+            EncoderKind::X265 => Box::new(X26xEncoder::X265),
+            EncoderKind::Nvenc => Box::new(HardwareEncoder(HwAccel::Nvenc)),
+            EncoderKind::Qsv => Box::new(HardwareEncoder(HwAccel::Qsv)),
+            EncoderKind::Vaapi => Box::new(HardwareEncoder(HwAccel::Vaapi)),
+            EncoderKind::Amf => Box::new(HardwareEncoder(HwAccel::Amf)),
+        }
+    }
+
+    /// Hardware API of the encoder, `None` for software encoders
+    pub fn hardware(self) -> Option<HwAccel> {
+        match self {
+            EncoderKind::Nvenc => Some(HwAccel::Nvenc),
+            EncoderKind::Qsv => Some(HwAccel::Qsv),
+            EncoderKind::Vaapi => Some(HwAccel::Vaapi),
+            EncoderKind::Amf => Some(HwAccel::Amf),
+            _ => None,
+        }
+    }
+
+    /// Name sent to nodes, same as name of the encoder
+    pub fn name(self) -> &'static str {
+        self.encoder().name()
+    }
+
+    /// Finds encoder by its name, empty name is ffmpeg for older clients
+    pub fn from_name(name: &str) -> Option<EncoderKind> {
+        if name.is_empty() {
+            return Some(EncoderKind::Ffmpeg);
+        }
+        EncoderKind::ALL
+            .iter()
+            .copied()
+            .find(|kind| kind.name() == name)
+    }
+}
+
+/// Pass of two-pass encoding
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Pass {
+    /// Analyzes the chunk and writes statistics
+    First,
+    /// Encodes the chunk with statistics of the first pass
+    Second,
+}
+
+impl Pass {
+    /// Number of the pass, as encoders expect it
+    pub fn number(self) -> &'static str {
+        match self {
+            Pass::First => "1",
+            Pass::Second => "2",
+        }
+    }
+}
+
+/// Video buffering verifier constraints, so the stream plays over a channel of limited
+/// rate with a decoder buffer of limited size
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Vbv {
+    /// Rate the buffer is filled at, in kbit/s
+    pub maxrate: u64,
+    /// Size of the buffer in kbit
+    pub bufsize: u64,
+}
+
+/// Threads that reproducible encodes are pinned to, so output doesn't depend on cores of nodes
+pub const REPRODUCIBLE_THREADS: usize = 8;
+
+/// Fullness of the buffer that encoders assume at the start of a chunk. Previous chunk
+/// can leave the buffer emptier than the usual 0.9, so chunks start more conservatively.
+pub const VBV_INIT: f64 = 0.5;
+
+/// Parses progress from output of encoder, line by line
+pub trait ParseProgress: Send {
+    /// Feeds a single line of output, returns progress once it's complete
+    fn push_line(&mut self, line: &str) -> Option<Progress>;
+}
+
+/// Encoder that chunks are encoded with
+pub trait Encoder: Debug + Send + Sync {
+    /// Name of the encoder, used in logs and errors
+    fn name(&self) -> &'static str;
+
+    /// Checks that encoder is installed
+    fn probe(&self) -> Result<(), VideoEncodeError>;
+
+    /// Exact version of the encoder, output of the same version differs less across
+    /// nodes. Version of ffmpeg by default, `None` when it can't be determined.
+    fn version(&self) -> Option<String> {
+        binary_version("ffmpeg")
+    }
+
+    /// Checks parameters before they are used, so invalid ones are rejected upfront
+    fn validate(&self, params: &[String]) -> Result<(), VideoEncodeError>;
+
+    /// Builds command that encodes `input` into `output`, reporting progress to stdout
+    fn command(&self, input: &Path, output: &Path, params: &[String]) -> Command;
+
+    /// Creates parser of progress that command reports
+    fn progress_parser(&self) -> Box<dyn ParseProgress>;
+
+    /// Parameters that select `pass` of two-pass encoding, with statistics stored
+    /// in `stats`. `None` when encoder can't encode in two passes.
+    fn pass_params(&self, _pass: Pass, _stats: &Path) -> Option<Vec<String>> {
+        None
+    }
+
+    /// Parameters that encode at average `bitrate` in kbit/s, within `vbv` constraints.
+    /// `None` when encoder can't target bitrate, or can't follow constraints.
+    fn rate_params(&self, _bitrate: u64, _vbv: Option<Vbv>) -> Option<Vec<String>> {
+        None
+    }
+
+    /// Option that sets constant rate factor with `params`, and range of its values
+    /// from the best quality. `None` when encoder can't encode at constant rate factor.
+    fn crf_option(&self, _params: &[String]) -> Option<(&'static str, RangeInclusive<u32>)> {
+        None
+    }
+
+    /// Parameters that encode losslessly, with rate options of `params` replaced.
+    /// `None` when encoder can't encode losslessly.
+    fn lossless_params(&self, _params: &[String]) -> Option<Vec<String>> {
+        None
+    }
+
+    /// Parameters that apply AV1 film grain table at `table` on top of `params`.
+    /// `None` when encoder can't apply grain tables.
+    fn with_grain_table(&self, _params: &[String], _table: &Path) -> Option<Vec<String>> {
+        None
+    }
+
+    /// Parameters that limit the encoder to `threads` threads, unless `params`
+    /// set threads themselves
+    fn with_threads(&self, params: &[String], _threads: usize) -> Vec<String> {
+        params.to_vec()
+    }
+
+    /// Parameters that make output depend only on `params` and the source, so a chunk
+    /// encodes bit-exactly again on any node with the same version of the encoder.
+    /// Threads are pinned, since nodes would limit them otherwise. `None` when encoder
+    /// can't encode reproducibly.
+    fn reproducible_params(&self, params: &[String]) -> Option<Vec<String>> {
+        Some(self.with_threads(params, REPRODUCIBLE_THREADS))
+    }
+
+    /// Parameters that apply ffmpeg filter chain `filter` to frames before they're
+    /// encoded with `params`. `None` when encoder can't filter frames.
+    fn with_filter(&self, params: &[String], filter: &str) -> Option<Vec<String>> {
+        Some(add_filter(params, filter, true))
+    }
+
+    /// Pixel formats encoder can encode with `params`, `None` when they aren't known
+    fn pixel_formats(&self, params: &[String]) -> Option<PixelFormats> {
+        pixel_format::ffmpeg_pixel_formats(params)
+    }
+
+    /// Whether encoder encodes AV1 with `params`
+    fn encodes_av1(&self, params: &[String]) -> bool {
+        selected_codec(params).is_some_and(pixel_format::is_av1_codec)
+    }
+
+    /// Encodes `input` into `output`, calling `on_progress` with every progress report.
+    /// Encoder process is killed if returned future is dropped before it completes.
+    fn encode<'a>(
+        &'a self,
+        input: &'a Path,
+        output: &'a Path,
+        params: &'a [String],
+        on_progress: &'a mut (dyn FnMut(&Progress) + Send),
+    ) -> BoxFuture<'a, Result<(), VideoEncodeError>> {
+        let command = self.command(input, output, params);
+        let parser = self.progress_parser();
+        Box::pin(async move {
+            let (status, stderr) = run(command, parser, on_progress).await?;
+            if !status.success() {
+                return Err(VideoEncodeError::Encoding(format!(
+                    "{} failed: {:?}",
+                    self.name(),
+                    String::from_utf8_lossy(&stderr)
+                )));
+            }
+            Ok(())
+        })
+    }
+}
+
+/// Parameters that encode at constant rate factor `crf`, replacing the one `params` set.
+/// `None` when encoder can't encode at constant rate factor.
+pub fn with_crf(encoder: &dyn Encoder, params: &[String], crf: u32) -> Option<Vec<String>> {
+    let (option, _) = encoder.crf_option(params)?;
+    Some(override_params(
+        params,
+        &[option.to_string(), crf.to_string()],
+    ))
+}
+
+/// Constant rate factor `params` set, the last one when it's set more than once.
+/// `None` when it isn't set, or encoder can't encode at constant rate factor.
+pub fn crf_value(encoder: &dyn Encoder, params: &[String]) -> Option<u32> {
+    let (option, _) = encoder.crf_option(params)?;
+    params
+        .windows(2)
+        .rev()
+        .find(|pair| pair[0] == option)
+        .and_then(|pair| pair[1].parse().ok())
+}
+
+/// Checks that encoder can encode in given number of passes
+pub fn check_passes(encoder: &dyn Encoder, passes: u32) -> Result<(), VideoEncodeError> {
+    match passes {
+        1 => Ok(()),
+        2 if encoder.pass_params(Pass::First, Path::new("")).is_some() => Ok(()),
+        2 => Err(VideoEncodeError::Encoding(format!(
+            "{} can't encode in two passes",
+            encoder.name()
+        ))),
+        _ => Err(VideoEncodeError::Encoding(format!(
+            "Chunks are encoded in 1 or 2 passes, not {}",
+            passes
+        ))),
+    }
+}
+
+/// Encodes `input` into `output` in `passes`. Statistics of the first pass are written
+/// next to the output, and removed once the second pass is done or has failed.
+/// Progress of each pass covers half of the chunk, so it doesn't go back between them.
+pub async fn encode_passes(
+    encoder: &dyn Encoder,
+    input: &Path,
+    output: &Path,
+    params: &[String],
+    passes: u32,
+    on_progress: &mut (dyn FnMut(&Progress) + Send),
+) -> Result<(), VideoEncodeError> {
+    check_passes(encoder, passes)?;
+    if passes == 1 {
+        return encoder.encode(input, output, params, on_progress).await;
+    }
+
+    let stats = output.with_extension("stats");
+    let pass_params = |pass| {
+        let mut pass_params = params.to_vec();
+        pass_params.extend(encoder.pass_params(pass, &stats).unwrap_or_default());
+        pass_params
+    };
+
+    let result = async {
+        // Output time at the end of the first pass is duration of the chunk
+        let mut duration = 0.0;
+        let mut first_progress = |progress: &Progress| {
+            if !progress.done {
+                duration = f64::max(duration, progress.out_time);
+                on_progress(&Progress {
+                    out_time: progress.out_time / 2.0,
+                    ..progress.clone()
+                });
+            }
+        };
+        encoder
+            .encode(
+                input,
+                output,
+                &pass_params(Pass::First),
+                &mut first_progress,
+            )
+            .await?;
+        // Output of the first pass is not needed, and encoders may refuse to overwrite it
+        let _ = tokio::fs::remove_file(output).await;
+
+        let mut second_progress = |progress: &Progress| {
+            on_progress(&Progress {
+                out_time: (duration + progress.out_time) / 2.0,
+                ..progress.clone()
+            });
+        };
+        encoder
+            .encode(
+                input,
+                output,
+                &pass_params(Pass::Second),
+                &mut second_progress,
+            )
+            .await
+    }
+    .await;
+
+    remove_stats(&stats).await;
+    result
+}
+
+/// Removes statistics file, and files that encoders write next to it,
+/// like `.mbtree` of x264 or `-0.log` of ffmpeg
+async fn remove_stats(stats: &Path) {
+    let (Some(dir), Some(name)) = (stats.parent(), stats.file_name()) else {
+        return;
+    };
+    let Ok(mut entries) = tokio::fs::read_dir(dir).await else {
+        return;
+    };
+
+    while let Ok(Some(entry)) = entries.next_entry().await {
+        if entry
+            .file_name()
+            .to_string_lossy()
+            .starts_with(&*name.to_string_lossy())
+        {
+            debug!("Removing {:?}", entry.path());
+            let _ = tokio::fs::remove_file(entry.path()).await;
+        }
+    }
+}
+
+/// Runs command, feeding its stdout to progress `parser`. Returns exit status and stderr.
+/// Process is killed if returned future is dropped before it completes.
+pub async fn run(
+    mut command: Command,
+    mut parser: Box<dyn ParseProgress>,
+    on_progress: &mut (dyn FnMut(&Progress) + Send),
+) -> Result<(ExitStatus, Vec<u8>), VideoEncodeError> {
+    debug!("Encoder command: {:?}", command);
+    cgroup::attach(&mut command);
+
+    let mut child = command
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .kill_on_drop(true)
+        .spawn()?;
+
+    // Stderr is drained concurrently, so encoder doesn't block on full pipe
+    let mut stderr = child.stderr.take().expect("stderr is piped");
+    let stderr_task = tokio::spawn(async move {
+        let mut output = Vec::new();
+        stderr.read_to_end(&mut output).await.map(|_| output)
+    });
+
+    let stdout = child.stdout.take().expect("stdout is piped");
+    let mut lines = BufReader::new(stdout).lines();
+    while let Some(line) = lines.next_line().await? {
+        if let Some(progress) = parser.push_line(&line) {
+            on_progress(&progress);
+        }
+    }
+
+    let status = child.wait().await?;
+    let stderr = stderr_task
+        .await
+        .map_err(|e| VideoEncodeError::Encoding(e.to_string()))??;
+
+    Ok((status, stderr))
+}
+
+/// First line of version of encoder binary, which goes to stdout or stderr,
+/// depending on the encoder and its release
+pub(crate) fn binary_version(binary: &str) -> Option<String> {
+    let output = std::process::Command::new(binary)
+        .arg(if binary == "ffmpeg" {
+            "-version"
+        } else {
+            "--version"
+        })
+        .output()
+        .ok()?;
+    let version = String::from_utf8_lossy(&output.stdout).to_string()
+        + &String::from_utf8_lossy(&output.stderr);
+    version
+        .lines()
+        .map(str::trim)
+        .find(|line| !line.is_empty())
+        .map(str::to_string)
+}
+
+/// Options of ffmpeg that select the codec
+pub(crate) const CODEC_OPTIONS: [&str; 4] = ["-c:v", "-codec:v", "-vcodec", "-vc"];
+
+/// Option of ffmpeg that sets video filter chain
+pub(crate) const FILTER_OPTION: &str = "-vf";
+
+/// Options of ffmpeg that apply to decoded frames
+const DECODER_OPTIONS: [&str; 2] = [FILTER_OPTION, PIX_FMT_OPTION];
+
+/// Returns codec that ffmpeg parameters select, the last one wins like in ffmpeg
+pub fn selected_codec(params: &[String]) -> Option<&str> {
+    params
+        .windows(2)
+        .rev()
+        .find(|pair| CODEC_OPTIONS.contains(&pair[0].as_str()))
+        .map(|pair| pair[1].as_str())
+}
+
+/// Rate parameters of ffmpeg, which its wrappers of encoders map to their own options
+pub(crate) fn ffmpeg_rate_params(bitrate: u64, vbv: Option<Vbv>) -> Vec<String> {
+    let mut params = vec!["-b:v".to_string(), format!("{}k", bitrate)];
+    if let Some(vbv) = vbv {
+        params.extend([
+            "-maxrate".to_string(),
+            format!("{}k", vbv.maxrate),
+            "-bufsize".to_string(),
+            format!("{}k", vbv.bufsize),
+            // Occupancy is in bits
+            "-rc_init_occupancy".to_string(),
+            ((vbv.bufsize * 1000) as f64 * VBV_INIT).round().to_string(),
+        ]);
+    }
+    params
+}
+
+/// Adds `filter` to the filter chain of ffmpeg parameters, before filters they have
+/// when `first` is set and after them otherwise
+pub fn add_filter(params: &[String], filter: &str, first: bool) -> Vec<String> {
+    let mut params = params.to_vec();
+    match params.iter().rposition(|param| param == FILTER_OPTION) {
+        Some(i) if i + 1 < params.len() => {
+            params[i + 1] = if first {
+                format!("{},{}", filter, params[i + 1])
+            } else {
+                format!("{},{}", params[i + 1], filter)
+            };
+        }
+        _ => params.extend([FILTER_OPTION.to_string(), filter.to_string()]),
+    }
+    params
+}
+
+/// Adds `key=value` to private options of ffmpeg encoder wrapper, like `-x265-params`
+pub(crate) fn add_private_option(params: &[String], option: &str, value: &str) -> Vec<String> {
+    let mut params = params.to_vec();
+    match params.iter().rposition(|param| param == option) {
+        Some(i) if i + 1 < params.len() => {
+            params[i + 1] = format!("{}:{}", params[i + 1], value);
+        }
+        _ => params.extend([option.to_string(), value.to_string()]),
+    }
+    params
+}
+
+/// Removes options and their values from parameters
+pub(crate) fn remove_options(params: &[String], options: &[&str]) -> Vec<String> {
+    let mut rest = Vec::with_capacity(params.len());
+    let mut params = params.iter();
+    while let Some(param) = params.next() {
+        if options.contains(&param.as_str()) {
+            params.next();
+        } else {
+            rest.push(param.clone());
+        }
+    }
+    rest
+}
+
+/// Adds `option value` to parameters, unless they already have the option
+pub(crate) fn add_option(params: &[String], option: &str, value: &str) -> Vec<String> {
+    let mut params = params.to_vec();
+    if !params.iter().any(|param| param == option) {
+        params.extend([option.to_string(), value.to_string()]);
+    }
+    params
+}
+
+/// Splits ffmpeg options that apply to decoded frames from parameters of encoder
+/// that reads frames from ffmpeg, so they are applied by the decoder.
+/// Returns these options and the rest of parameters.
+pub(crate) fn take_decoder_options(params: &[String]) -> (Vec<String>, Vec<String>) {
+    let mut decoder_options = Vec::new();
+    let mut rest = Vec::with_capacity(params.len());
+    let mut params = params.iter();
+
+    while let Some(param) = params.next() {
+        if DECODER_OPTIONS.contains(&param.as_str()) {
+            decoder_options.push(param.clone());
+            decoder_options.extend(params.next().cloned());
+        } else {
+            rest.push(param.clone());
+        }
+    }
+
+    (decoder_options, rest)
+}
+
+/// Rejects parameters that contain any of `reserved` options, which are set by
+/// the encoder for every chunk
+pub(crate) fn reject_reserved(
+    params: &[String],
+    reserved: &[&str],
+) -> Result<(), VideoEncodeError> {
+    match params
+        .iter()
+        .find(|param| reserved.contains(&param.as_str()))
+    {
+        Some(param) => Err(VideoEncodeError::Encoding(format!(
+            "Encoder parameters can't contain {}, it's set for every chunk",
+            param
+        ))),
+        None => Ok(()),
+    }
+}
+
+/// Decodes `input` with ffmpeg and pipes it as y4m into standalone `encoder` writing
+/// `output`, which reports progress to stderr. Returns the rest of stderr, which has
+/// statistics printed at the end. Decoder options of `params`, like filters and pixel
+/// format, are applied by ffmpeg. Encoder is a warm process when node keeps them.
+/// Both processes are killed if returned future is dropped before it completes.
+pub(crate) async fn pipe_y4m(
+    name: &str,
+    encoder: &dyn Encoder,
+    input: &Path,
+    output: &Path,
+    params: &[String],
+    parser: &mut dyn ParseProgress,
+    on_progress: &mut (dyn FnMut(&Progress) + Send),
+) -> Result<String, VideoEncodeError> {
+    let (decoder_options, _) = take_decoder_options(params);
+
+    // Frames are passed through as they are, so none are dropped or duplicated
+    let mut decoder = Command::new("ffmpeg");
+    decoder
+        .args(["-hide_banner", "-loglevel", "error", "-i"])
+        .arg(input)
+        .args(["-map", "0:v:0", "-fps_mode", "passthrough"])
+        .args(&decoder_options)
+        .args(["-f", "yuv4mpegpipe", "-strict", "-1", "-"]);
+    cgroup::attach(&mut decoder);
+    let mut decoder = decoder
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .kill_on_drop(true)
+        .spawn()?;
+    let mut frames = decoder.stdout.take().expect("stdout is piped");
+
+    let (mut encoder, warm_output) = warm::start(encoder, input, output, params)?;
+    let mut stdin = encoder.stdin.take().expect("stdin is piped");
+    let feed = async move {
+        // Encoder that exits early closes the pipe, its status tells why
+        if let Err(e) = tokio::io::copy(&mut frames, &mut stdin).await {
+            debug!("Failed to pipe frames into {}: {}", name, e);
+        }
+        // Closing stdin ends the encode
+        drop(stdin);
+    };
+
+    // Progress lines are separated by carriage returns, and stats follow them
+    let stderr = encoder.stderr.take().expect("stderr is piped");
+    let ((), stats) = tokio::join!(feed, read_progress(stderr, parser, on_progress));
+    let stats = stats?;
+
+    let (decoder, status) = tokio::join!(decoder.wait_with_output(), encoder.wait());
+    let (decoder, status) = (decoder?, status?);
+
+    let success = decoder.status.success() && status.success();
+    warm::finish(warm_output, output, success).await?;
+    if !success {
+        error!(
+            "Failed to encode {:?}: ffmpeg: {}, {}: {}",
+            input,
+            String::from_utf8_lossy(&decoder.stderr),
+            name,
+            stats
+        );
+        return Err(VideoEncodeError::Encoding(format!(
+            "{} failed: {}",
+            name,
+            stats.lines().last().unwrap_or_default()
+        )));
+    }
+    debug!("{} stats: {}", name, stats);
+
+    Ok(stats)
+}
+
+/// Feeds progress lines to parser, and returns the rest of the output
+async fn read_progress(
+    mut stderr: impl AsyncRead + Unpin,
+    parser: &mut dyn ParseProgress,
+    on_progress: &mut (dyn FnMut(&Progress) + Send),
+) -> Result<String, VideoEncodeError> {
+    let mut stats = String::new();
+    let mut line = Vec::new();
+    let mut buffer = [0; 4096];
+
+    loop {
+        let read = stderr.read(&mut buffer).await?;
+        if read == 0 {
+            break;
+        }
+        for &byte in &buffer[..read] {
+            if byte != b'\r' && byte != b'\n' {
+                line.push(byte);
+                continue;
+            }
+
+            let text = String::from_utf8_lossy(&line);
+            match parser.push_line(&text) {
+                Some(progress) => on_progress(&progress),
+                None if !text.trim().is_empty() => {
+                    stats.push_str(text.trim_end());
+                    stats.push('\n');
+                }
+                None => {}
+            }
+            line.clear();
+        }
+    }
+    stats.push_str(&String::from_utf8_lossy(&line));
+
+    Ok(stats)
+}
+
+/// Remuxes bitstream written by standalone encoder into `output`, so it can be
+/// concatenated like output of ffmpeg. Raw bitstreams have no timestamps,
+/// so their `frame_rate` has to be given. Bitstream file is removed afterwards.
+pub(crate) async fn remux(
+    bitstream: &Path,
+    frame_rate: Option<f64>,
+    output: &Path,
+) -> Result<(), VideoEncodeError> {
+    let mut command = Command::new("ffmpeg");
+    command.args(["-hide_banner", "-y"]);
+    if let Some(frame_rate) = frame_rate {
+        command.args(["-r", &frame_rate.to_string()]);
+    }
+    let remux = command
+        .arg("-i")
+        .arg(bitstream)
+        .args(["-c", "copy"])
+        .arg(output)
+        .kill_on_drop(true)
+        .output()
+        .await?;
+    let _ = tokio::fs::remove_file(bitstream).await;
+
+    if !remux.status.success() {
+        return Err(VideoEncodeError::Encoding(format!(
+            "Failed to remux {:?}: {}",
+            bitstream,
+            String::from_utf8_lossy(&remux.stderr)
+        )));
+    }
+
+    Ok(())
+}
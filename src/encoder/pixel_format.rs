@@ -0,0 +1,269 @@
+/// Pixel formats chunks are encoded in, like `yuv420p10le`. Format is passed to every
+/// encoder as ffmpeg `-pix_fmt` option: ffmpeg applies it itself, encoders that read
+/// frames from ffmpeg get frames converted to it.
+use std::fmt;
+
+use tracing::{info, warn};
+
+use crate::encoder::{selected_codec, Encoder};
+use crate::error::VideoEncodeError;
+
+/// Option of ffmpeg that sets pixel format
+pub const PIX_FMT_OPTION: &str = "-pix_fmt";
+
+/// Chroma subsampling
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Chroma {
+    Yuv420,
+    Yuv422,
+    Yuv444,
+}
+
+impl Chroma {
+    pub fn name(self) -> &'static str {
+        match self {
+            Chroma::Yuv420 => "420",
+            Chroma::Yuv422 => "422",
+            Chroma::Yuv444 => "444",
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PixelFormat {
+    pub chroma: Chroma,
+    pub bit_depth: u32,
+}
+
+impl PixelFormat {
+    /// Parses planar YUV formats of ffmpeg, like `yuv420p` or `yuv422p10le`, and
+    /// semi-planar formats of hardware encoders. `None` for other formats, like RGB.
+    pub fn parse(name: &str) -> Option<PixelFormat> {
+        match name {
+            "nv12" => {
+                return Some(PixelFormat {
+                    chroma: Chroma::Yuv420,
+                    bit_depth: 8,
+                })
+            }
+            "p010le" | "p010" => {
+                return Some(PixelFormat {
+                    chroma: Chroma::Yuv420,
+                    bit_depth: 10,
+                })
+            }
+            _ => {}
+        }
+
+        // Full range formats like `yuvj420p` are deprecated aliases of 8-bit formats
+        let rest = name
+            .strip_prefix("yuvj")
+            .or_else(|| name.strip_prefix("yuv"))?;
+        let chroma = [Chroma::Yuv420, Chroma::Yuv422, Chroma::Yuv444]
+            .into_iter()
+            .find(|chroma| rest.starts_with(chroma.name()))?;
+        let depth = rest[chroma.name().len()..].strip_prefix('p')?;
+
+        let bit_depth = match depth.strip_suffix("le") {
+            Some(depth) if !name.starts_with("yuvj") => depth.parse().ok()?,
+            None if depth.is_empty() => 8,
+            _ => return None,
+        };
+        Some(PixelFormat { chroma, bit_depth })
+    }
+
+    /// Bits of a pixel of uncompressed frame, with samples of its bit depth
+    pub fn bits_per_pixel(self) -> u32 {
+        let samples_per_two_pixels = match self.chroma {
+            Chroma::Yuv420 => 3,
+            Chroma::Yuv422 => 4,
+            Chroma::Yuv444 => 6,
+        };
+        samples_per_two_pixels * self.bit_depth / 2
+    }
+
+    pub fn with_bit_depth(self, bit_depth: u32) -> PixelFormat {
+        PixelFormat { bit_depth, ..self }
+    }
+
+    /// Name of the format in ffmpeg
+    pub fn name(self) -> String {
+        match self.bit_depth {
+            8 => format!("yuv{}p", self.chroma.name()),
+            depth => format!("yuv{}p{}le", self.chroma.name(), depth),
+        }
+    }
+}
+
+impl fmt::Display for PixelFormat {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.name())
+    }
+}
+
+/// Pixel formats that encoder supports
+#[derive(Debug, Clone, Copy)]
+pub struct PixelFormats {
+    pub chroma: &'static [Chroma],
+    pub bit_depths: &'static [u32],
+}
+
+const ALL_CHROMA: &[Chroma] = &[Chroma::Yuv420, Chroma::Yuv422, Chroma::Yuv444];
+
+impl PixelFormats {
+    pub const X264: PixelFormats = PixelFormats {
+        chroma: ALL_CHROMA,
+        bit_depths: &[8, 10],
+    };
+    pub const X265: PixelFormats = PixelFormats {
+        chroma: ALL_CHROMA,
+        bit_depths: &[8, 10, 12],
+    };
+    pub const SVT_AV1: PixelFormats = PixelFormats {
+        chroma: &[Chroma::Yuv420],
+        bit_depths: &[8, 10],
+    };
+    /// Hardware encoders only encode 4:2:0
+    pub const HARDWARE: PixelFormats = PixelFormats {
+        chroma: &[Chroma::Yuv420],
+        bit_depths: &[8, 10],
+    };
+
+    pub fn contains(&self, format: PixelFormat) -> bool {
+        self.chroma.contains(&format.chroma) && self.bit_depths.contains(&format.bit_depth)
+    }
+}
+
+/// Pixel formats of ffmpeg encoder, `None` for encoders that aren't known
+pub fn codec_pixel_formats(codec: &str) -> Option<PixelFormats> {
+    match codec {
+        "libx264" => Some(PixelFormats::X264),
+        "libx265" => Some(PixelFormats::X265),
+        "libsvtav1" => Some(PixelFormats::SVT_AV1),
+        "libaom-av1" | "librav1e" => Some(PixelFormats {
+            chroma: ALL_CHROMA,
+            bit_depths: &[8, 10, 12],
+        }),
+        // Hardware H.264 encoders are 8-bit only
+        _ if codec.starts_with("h264_") => Some(PixelFormats {
+            chroma: &[Chroma::Yuv420],
+            bit_depths: &[8],
+        }),
+        _ if codec.starts_with("hevc_") || codec.starts_with("av1_") => {
+            Some(PixelFormats::HARDWARE)
+        }
+        _ => None,
+    }
+}
+
+/// Whether ffmpeg encoder encodes AV1
+pub fn is_av1_codec(codec: &str) -> bool {
+    matches!(codec, "libsvtav1" | "libaom-av1" | "librav1e") || codec.starts_with("av1_")
+}
+
+/// Default pixel formats of encoders that pass parameters to ffmpeg
+pub(crate) fn ffmpeg_pixel_formats(params: &[String]) -> Option<PixelFormats> {
+    selected_codec(params).and_then(codec_pixel_formats)
+}
+
+/// Removes pixel format options from parameters, and returns the format they set.
+/// The last one wins like in ffmpeg.
+pub fn take_pixel_format(params: &[String]) -> (Option<String>, Vec<String>) {
+    let mut pix_fmt = None;
+    let mut rest = Vec::with_capacity(params.len());
+    let mut params = params.iter();
+
+    while let Some(param) = params.next() {
+        if param == PIX_FMT_OPTION {
+            pix_fmt = params.next().cloned();
+        } else {
+            rest.push(param.clone());
+        }
+    }
+
+    (pix_fmt, rest)
+}
+
+/// Chooses pixel format chunks are encoded in. `pix_fmt` is used as it is, `bit_depth`
+/// changes bit depth of the source format, and 8-bit sources are promoted to 10-bit
+/// when encoder encodes AV1 and neither is set. Returns `None` when source format is kept.
+pub fn resolve_pixel_format(
+    source: Option<&str>,
+    pix_fmt: Option<&str>,
+    bit_depth: Option<u32>,
+    encoder: &dyn Encoder,
+    params: &[String],
+) -> Result<Option<PixelFormat>, VideoEncodeError> {
+    let source_format = source.and_then(PixelFormat::parse);
+    let supported = encoder.pixel_formats(params);
+
+    let format = match (pix_fmt, bit_depth) {
+        (Some(pix_fmt), _) => {
+            let format = PixelFormat::parse(pix_fmt).ok_or_else(|| {
+                VideoEncodeError::Encoding(format!("Unsupported pixel format {}", pix_fmt))
+            })?;
+            if bit_depth.is_some_and(|depth| depth != format.bit_depth) {
+                return Err(VideoEncodeError::Encoding(format!(
+                    "Pixel format {} doesn't have bit depth {}",
+                    pix_fmt,
+                    bit_depth.unwrap_or_default()
+                )));
+            }
+            Some(format)
+        }
+        // Formats like RGB of image sequences are converted to 4:2:0
+        (None, Some(depth)) => Some(
+            source_format
+                .unwrap_or(PixelFormat {
+                    chroma: Chroma::Yuv420,
+                    bit_depth: 8,
+                })
+                .with_bit_depth(depth),
+        ),
+        (None, None) => {
+            // 10-bit AV1 has less banding, and is barely slower or larger
+            let promoted = source_format
+                .filter(|format| format.bit_depth == 8 && encoder.encodes_av1(params))
+                .map(|format| format.with_bit_depth(10))
+                .filter(|format| supported.is_none_or(|supported| supported.contains(*format)));
+            if let Some(format) = promoted {
+                info!("Promoting 8-bit source to {} for AV1", format);
+            }
+            promoted
+        }
+    };
+
+    match (format, supported) {
+        (Some(format), Some(supported)) if !supported.contains(format) => {
+            Err(VideoEncodeError::Encoding(format!(
+                "Encoder {} can't encode {}, it supports {} chroma at {} bits",
+                encoder.name(),
+                format,
+                supported
+                    .chroma
+                    .iter()
+                    .map(|chroma| chroma.name())
+                    .collect::<Vec<_>>()
+                    .join(", "),
+                supported
+                    .bit_depths
+                    .iter()
+                    .map(|depth| depth.to_string())
+                    .collect::<Vec<_>>()
+                    .join(", ")
+            )))
+        }
+        (None, Some(supported)) => {
+            if let Some(source_format) = source_format.filter(|format| !supported.contains(*format))
+            {
+                warn!(
+                    "Encoder {} may not encode source format {}, set pixel format to convert it",
+                    encoder.name(),
+                    source_format
+                );
+            }
+            Ok(None)
+        }
+        _ => Ok(format),
+    }
+}
@@ -0,0 +1,85 @@
+/// Built-in profiles, curated parameters of every software encoder from fast to archival
+/// encodes, so encodes don't need tuned encoder_params to start with. ffmpeg encodes
+/// AV1 with libsvtav1. Options of encoder_params given on the command line override
+/// options of the profile.
+use serde::{Deserialize, Serialize};
+
+use crate::encoder::EncoderKind;
+use crate::error::VideoEncodeError;
+
+/// Trade-off between speed and quality of a built-in profile
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize, Serialize, clap::ValueEnum)]
+#[serde(rename_all = "kebab-case")]
+pub enum Profile {
+    /// Quick encodes and previews
+    Fast,
+    /// Good quality at reasonable speed
+    Balanced,
+    /// Visually transparent for most sources
+    Quality,
+    /// Slowest, for masters that are kept
+    Archive,
+}
+
+/// Parameters of `profile` for `encoder`
+pub fn profile_params(
+    encoder: EncoderKind,
+    profile: Profile,
+) -> Result<Vec<String>, VideoEncodeError> {
+    let params: Vec<&str> = match encoder {
+        EncoderKind::Ffmpeg => {
+            let (preset, crf) = svt_av1_settings(profile);
+            vec!["-c:v", "libsvtav1", "-preset", preset, "-crf", crf]
+        }
+        EncoderKind::SvtAv1 => {
+            let (preset, crf) = svt_av1_settings(profile);
+            vec!["--preset", preset, "--crf", crf]
+        }
+        #[cfg(feature = "rav1e")]
+        EncoderKind::Rav1e => {
+            let (speed, quantizer) = match profile {
+                Profile::Fast => ("10", "120"),
+                Profile::Balanced => ("6", "100"),
+                Profile::Quality => ("4", "80"),
+                Profile::Archive => ("2", "60"),
+            };
+            vec!["--speed", speed, "--quantizer", quantizer]
+        }
+        EncoderKind::X264 => {
+            let (preset, crf) = match profile {
+                Profile::Fast => ("veryfast", "23"),
+                Profile::Balanced => ("medium", "20"),
+                Profile::Quality => ("slow", "18"),
+                Profile::Archive => ("veryslow", "16"),
+            };
+            vec!["--preset", preset, "--crf", crf]
+        }
+        EncoderKind::X265 => {
+            let (preset, crf) = match profile {
+                Profile::Fast => ("veryfast", "26"),
+                Profile::Balanced => ("medium", "23"),
+                Profile::Quality => ("slow", "20"),
+                Profile::Archive => ("veryslow", "18"),
+            };
+            vec!["--preset", preset, "--crf", crf]
+        }
+        // Options of hardware codecs differ between codecs and generations of devices
+        EncoderKind::Nvenc | EncoderKind::Qsv | EncoderKind::Vaapi | EncoderKind::Amf => {
+            return Err(VideoEncodeError::Encoding(format!(
+                "Encoder {} has no built-in profiles, set its encoder_params",
+                encoder.name()
+            )))
+        }
+    };
+    Ok(params.into_iter().map(String::from).collect())
+}
+
+/// Preset and CRF of SVT-AV1 for `profile`
+fn svt_av1_settings(profile: Profile) -> (&'static str, &'static str) {
+    match profile {
+        Profile::Fast => ("10", "35"),
+        Profile::Balanced => ("6", "30"),
+        Profile::Quality => ("4", "26"),
+        Profile::Archive => ("2", "20"),
+    }
+}
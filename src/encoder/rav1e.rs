@@ -0,0 +1,445 @@
+/// Encoder that runs rav1e in-process, with frames decoded by ffmpeg as raw video.
+/// There's no encoder process to spawn for every chunk, and progress is reported
+/// for every frame. Parameters are options of rav1e, like `--speed 6 --quantizer 100`.
+use std::{
+    fs::File,
+    io::{self, BufWriter, Read, Seek, SeekFrom, Write},
+    ops::RangeInclusive,
+    path::{Path, PathBuf},
+    process::{Command as StdCommand, Stdio},
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+    time::Instant,
+};
+
+use futures::future::BoxFuture;
+use rav1e::prelude::*;
+use tokio::{process::Command, sync::mpsc};
+use tracing::{debug, info};
+
+use crate::cgroup;
+use crate::chunk::verify_ffmpeg;
+use crate::encoder::pixel_format::{Chroma, PIX_FMT_OPTION};
+use crate::encoder::{
+    add_option, remove_options, remux, Encoder, ParseProgress, PixelFormats, Vbv,
+};
+use crate::error::VideoEncodeError;
+use crate::ffmpeg::probe::{probe_frame_rate, probe_resolution};
+use crate::ffmpeg::progress::{Progress, ProgressParser};
+
+/// Offset of frame count in IVF header
+const IVF_FRAME_COUNT_OFFSET: u64 = 24;
+
+#[derive(Debug, Default, Clone, Copy)]
+pub struct Rav1eEncoder;
+
+/// Options of rav1e, parsed from encoder parameters
+#[derive(Debug, Clone)]
+struct Options {
+    speed: u8,
+    quantizer: usize,
+    min_quantizer: u8,
+    /// Target bitrate in kbit/s, constant quantizer when 0
+    bitrate: i32,
+    keyint: u64,
+    min_keyint: u64,
+    tiles: usize,
+    /// Threads of rav1e, 0 uses all CPUs
+    threads: usize,
+}
+
+impl Default for Options {
+    fn default() -> Self {
+        let config = EncoderConfig::default();
+        Options {
+            speed: 6,
+            quantizer: config.quantizer,
+            min_quantizer: config.min_quantizer,
+            bitrate: 0,
+            keyint: config.max_key_frame_interval,
+            min_keyint: config.min_key_frame_interval,
+            tiles: 0,
+            threads: 0,
+        }
+    }
+}
+
+impl Options {
+    fn parse(params: &[String]) -> Result<Options, VideoEncodeError> {
+        let mut options = Options::default();
+        let mut params = params.iter();
+
+        while let Some(name) = params.next() {
+            let value = params.next().ok_or_else(|| {
+                VideoEncodeError::Encoding(format!("Missing value of rav1e option {}", name))
+            })?;
+            let invalid =
+                || VideoEncodeError::Encoding(format!("Invalid value of {}: {}", name, value));
+
+            match name.as_str() {
+                "--speed" | "-s" => options.speed = value.parse().map_err(|_| invalid())?,
+                "--quantizer" => options.quantizer = value.parse().map_err(|_| invalid())?,
+                "--min-quantizer" => {
+                    options.min_quantizer = value.parse().map_err(|_| invalid())?
+                }
+                "--bitrate" | "-b" => options.bitrate = value.parse().map_err(|_| invalid())?,
+                "--keyint" | "-I" => options.keyint = value.parse().map_err(|_| invalid())?,
+                "--min-keyint" | "-i" => {
+                    options.min_keyint = value.parse().map_err(|_| invalid())?
+                }
+                "--tiles" => options.tiles = value.parse().map_err(|_| invalid())?,
+                "--threads" => options.threads = value.parse().map_err(|_| invalid())?,
+                // Frames are decoded as yuv420p, which is the only format encoded here
+                PIX_FMT_OPTION if value == "yuv420p" => {}
+                _ => {
+                    return Err(VideoEncodeError::Encoding(format!(
+                        "Unsupported rav1e option {}",
+                        name
+                    )))
+                }
+            }
+        }
+
+        if options.speed > 10 {
+            return Err(VideoEncodeError::Encoding(format!(
+                "Speed of rav1e is from 0 to 10, not {}",
+                options.speed
+            )));
+        }
+
+        Ok(options)
+    }
+}
+
+/// Sets flag when encode future is dropped, so blocking encoder stops
+struct CancelOnDrop(Arc<AtomicBool>);
+
+impl Drop for CancelOnDrop {
+    fn drop(&mut self) {
+        self.0.store(true, Ordering::Relaxed);
+    }
+}
+
+/// Properties of decoded video
+#[derive(Debug, Clone, Copy)]
+struct VideoFormat {
+    width: usize,
+    height: usize,
+    /// Frame rate as fraction
+    frame_rate: (u64, u64),
+}
+
+impl VideoFormat {
+    fn fps(&self) -> f64 {
+        self.frame_rate.0 as f64 / self.frame_rate.1 as f64
+    }
+
+    fn chroma_size(&self) -> (usize, usize) {
+        (self.width.div_ceil(2), self.height.div_ceil(2))
+    }
+
+    /// Size of a yuv420p frame in bytes
+    fn frame_size(&self) -> usize {
+        let (chroma_width, chroma_height) = self.chroma_size();
+        self.width * self.height + 2 * chroma_width * chroma_height
+    }
+}
+
+impl Encoder for Rav1eEncoder {
+    fn name(&self) -> &'static str {
+        "rav1e"
+    }
+
+    /// rav1e is built in, but frames are decoded by ffmpeg
+    fn probe(&self) -> Result<(), VideoEncodeError> {
+        verify_ffmpeg()
+    }
+
+    fn validate(&self, params: &[String]) -> Result<(), VideoEncodeError> {
+        Options::parse(params).map(|_| ())
+    }
+
+    /// Encoder is linked in, so its version is the version of the crate
+    fn version(&self) -> Option<String> {
+        Some(format!("rav1e {}", rav1e::version::long()))
+    }
+
+    /// Command that decodes `input` into raw frames, which are read by rav1e
+    fn command(&self, input: &Path, _output: &Path, _params: &[String]) -> Command {
+        Command::from(decoder_command(input))
+    }
+
+    /// Progress is reported by the encoder itself, not parsed from output
+    fn progress_parser(&self) -> Box<dyn ParseProgress> {
+        Box::new(ProgressParser::default())
+    }
+
+    fn with_threads(&self, params: &[String], threads: usize) -> Vec<String> {
+        add_option(params, "--threads", &threads.to_string())
+    }
+
+    fn pixel_formats(&self, _params: &[String]) -> Option<PixelFormats> {
+        Some(PixelFormats {
+            chroma: &[Chroma::Yuv420],
+            bit_depths: &[8],
+        })
+    }
+
+    fn encodes_av1(&self, _params: &[String]) -> bool {
+        true
+    }
+
+    /// Frames are read at resolution of the source, which filters could change
+    fn with_filter(&self, _params: &[String], _filter: &str) -> Option<Vec<String>> {
+        None
+    }
+
+    /// rav1e is lossless at quantizer 0, without a target bitrate
+    fn lossless_params(&self, params: &[String]) -> Option<Vec<String>> {
+        let mut params = remove_options(
+            params,
+            &["--quantizer", "--min-quantizer", "--bitrate", "-b"],
+        );
+        params.extend(["--quantizer".to_string(), "0".to_string()]);
+        Some(params)
+    }
+
+    /// Quantizer of rav1e is its constant quality mode
+    fn crf_option(&self, _params: &[String]) -> Option<(&'static str, RangeInclusive<u32>)> {
+        Some(("--quantizer", 0..=255))
+    }
+
+    /// Bitrate mode of rav1e has no VBV buffer
+    fn rate_params(&self, bitrate: u64, vbv: Option<Vbv>) -> Option<Vec<String>> {
+        vbv.is_none()
+            .then(|| vec!["--bitrate".to_string(), bitrate.to_string()])
+    }
+
+    fn encode<'a>(
+        &'a self,
+        input: &'a Path,
+        output: &'a Path,
+        params: &'a [String],
+        on_progress: &'a mut (dyn FnMut(&Progress) + Send),
+    ) -> BoxFuture<'a, Result<(), VideoEncodeError>> {
+        Box::pin(async move {
+            let options = Options::parse(params)?;
+            let (width, height) = probe_resolution(input)?;
+            let format = VideoFormat {
+                width: width as usize,
+                height: height as usize,
+                frame_rate: to_fraction(probe_frame_rate(input)?),
+            };
+            let ivf_path = output.with_extension("ivf");
+            // Decoder is started on another thread, where cgroup of the encode isn't known
+            let mut decoder = decoder_command(input);
+            cgroup::attach_std(&mut decoder);
+
+            let cancelled = Arc::new(AtomicBool::new(false));
+            let _cancel = CancelOnDrop(Arc::clone(&cancelled));
+            let (sender, mut receiver) = mpsc::unbounded_channel();
+
+            let task = tokio::task::spawn_blocking({
+                let ivf_path = ivf_path.clone();
+                move || encode_frames(decoder, ivf_path, format, options, sender, cancelled)
+            });
+
+            while let Some(progress) = receiver.recv().await {
+                on_progress(&progress);
+            }
+            let frames = task
+                .await
+                .map_err(|e| VideoEncodeError::Encoding(e.to_string()))??;
+            info!("rav1e encoded {} frames of {:?}", frames, input);
+
+            remux(&ivf_path, None, output).await?;
+
+            on_progress(&Progress {
+                done: true,
+                ..Progress::default()
+            });
+            Ok(())
+        })
+    }
+}
+
+/// Decodes `input` into raw yuv420p frames on stdout.
+/// Frames are passed through as they are, so none are dropped or duplicated.
+fn decoder_command(input: &Path) -> StdCommand {
+    let mut command = StdCommand::new("ffmpeg");
+    command
+        .args(["-hide_banner", "-loglevel", "error", "-i"])
+        .arg(input)
+        .args(["-map", "0:v:0", "-fps_mode", "passthrough"])
+        .args(["-f", "rawvideo", "-pix_fmt", "yuv420p", "-"]);
+    command
+}
+
+/// Reads frames from decoder and encodes them into IVF file, until decoder ends
+/// or `cancelled` is set. Returns number of encoded frames.
+fn encode_frames(
+    mut decoder: StdCommand,
+    ivf_path: PathBuf,
+    format: VideoFormat,
+    options: Options,
+    progress: mpsc::UnboundedSender<Progress>,
+    cancelled: Arc<AtomicBool>,
+) -> Result<u64, VideoEncodeError> {
+    let (fps_num, fps_den) = format.frame_rate;
+    let config = Config::new()
+        .with_encoder_config(EncoderConfig {
+            width: format.width,
+            height: format.height,
+            time_base: Rational::new(fps_den, fps_num),
+            speed_settings: SpeedSettings::from_preset(options.speed),
+            quantizer: options.quantizer,
+            min_quantizer: options.min_quantizer,
+            bitrate: options.bitrate,
+            max_key_frame_interval: options.keyint,
+            min_key_frame_interval: options.min_keyint,
+            tiles: options.tiles,
+            ..Default::default()
+        })
+        .with_threads(options.threads);
+    let mut context: Context<u8> = config
+        .new_context()
+        .map_err(|e| VideoEncodeError::Encoding(format!("Invalid rav1e configuration: {}", e)))?;
+
+    debug!("Decoder command: {:?}", decoder);
+    let mut decoder = decoder
+        .stdout(Stdio::piped())
+        .stderr(Stdio::inherit())
+        .spawn()?;
+    let mut frames = decoder.stdout.take().expect("stdout is piped");
+
+    let mut ivf = BufWriter::new(File::create(&ivf_path)?);
+    write_ivf_header(&mut ivf, &format)?;
+
+    let (chroma_width, chroma_height) = format.chroma_size();
+    let luma_size = format.width * format.height;
+    let chroma_size = chroma_width * chroma_height;
+    let mut buffer = vec![0; format.frame_size()];
+
+    let started = Instant::now();
+    let mut encoded = 0;
+    let mut bytes = 0;
+    let mut flushed = false;
+
+    loop {
+        if cancelled.load(Ordering::Relaxed) {
+            let _ = decoder.kill();
+            return Err(VideoEncodeError::Encoding(
+                "Encoding was cancelled".to_string(),
+            ));
+        }
+
+        if !flushed {
+            if read_frame(&mut frames, &mut buffer)? {
+                let mut frame = context.new_frame();
+                frame.planes[0].copy_from_raw_u8(&buffer[..luma_size], format.width, 1);
+                frame.planes[1].copy_from_raw_u8(
+                    &buffer[luma_size..luma_size + chroma_size],
+                    chroma_width,
+                    1,
+                );
+                frame.planes[2].copy_from_raw_u8(
+                    &buffer[luma_size + chroma_size..],
+                    chroma_width,
+                    1,
+                );
+                context.send_frame(frame).map_err(encoder_error)?;
+            } else {
+                context.flush();
+                flushed = true;
+            }
+        }
+
+        match context.receive_packet() {
+            Ok(packet) => {
+                write_ivf_frame(&mut ivf, &packet.data, packet.input_frameno)?;
+                encoded += 1;
+                bytes += packet.data.len();
+
+                let elapsed = started.elapsed().as_secs_f64();
+                let out_time = encoded as f64 / format.fps();
+                let fps = if elapsed > 0.0 {
+                    encoded as f64 / elapsed
+                } else {
+                    0.0
+                };
+                // Sending only fails when encode future was dropped
+                let _ = progress.send(Progress {
+                    frame: encoded,
+                    fps,
+                    bitrate_kbps: bytes as f64 * 8.0 / out_time / 1000.0,
+                    out_time,
+                    speed: fps / format.fps(),
+                    done: false,
+                });
+            }
+            Err(EncoderStatus::Encoded) | Err(EncoderStatus::NeedMoreData) => {}
+            Err(EncoderStatus::LimitReached) => break,
+            Err(e) => return Err(encoder_error(e)),
+        }
+    }
+
+    let status = decoder.wait()?;
+    if !status.success() {
+        return Err(VideoEncodeError::Encoding(format!(
+            "Failed to decode frames for rav1e: {}",
+            status
+        )));
+    }
+
+    // Frame count isn't known until the end, so it's written into the header last
+    let mut ivf = ivf.into_inner().map_err(|e| e.into_error())?;
+    ivf.seek(SeekFrom::Start(IVF_FRAME_COUNT_OFFSET))?;
+    ivf.write_all(&(encoded as u32).to_le_bytes())?;
+
+    Ok(encoded)
+}
+
+/// Fills buffer with the next frame, returns `false` when there are no more frames
+fn read_frame(reader: &mut impl Read, buffer: &mut [u8]) -> io::Result<bool> {
+    match reader.read_exact(buffer) {
+        Ok(()) => Ok(true),
+        Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => Ok(false),
+        Err(e) => Err(e),
+    }
+}
+
+fn write_ivf_header(writer: &mut impl Write, format: &VideoFormat) -> io::Result<()> {
+    let (fps_num, fps_den) = format.frame_rate;
+    writer.write_all(b"DKIF")?;
+    writer.write_all(&0u16.to_le_bytes())?; // version
+    writer.write_all(&32u16.to_le_bytes())?; // header size
+    writer.write_all(b"AV01")?;
+    writer.write_all(&(format.width as u16).to_le_bytes())?;
+    writer.write_all(&(format.height as u16).to_le_bytes())?;
+    writer.write_all(&(fps_num as u32).to_le_bytes())?;
+    writer.write_all(&(fps_den as u32).to_le_bytes())?;
+    writer.write_all(&0u32.to_le_bytes())?; // frame count
+    writer.write_all(&0u32.to_le_bytes())
+}
+
+fn write_ivf_frame(writer: &mut impl Write, data: &[u8], pts: u64) -> io::Result<()> {
+    writer.write_all(&(data.len() as u32).to_le_bytes())?;
+    writer.write_all(&pts.to_le_bytes())?;
+    writer.write_all(data)
+}
+
+/// Converts frame rate into fraction, keeping NTSC rates like 24000/1001 exact
+fn to_fraction(fps: f64) -> (u64, u64) {
+    let ntsc = fps * 1.001;
+    if (ntsc - ntsc.round()).abs() < 1e-3 && (fps - fps.round()).abs() > 1e-3 {
+        ((ntsc.round() * 1000.0) as u64, 1001)
+    } else {
+        ((fps * 1000.0).round() as u64, 1000)
+    }
+}
+
+fn encoder_error(status: EncoderStatus) -> VideoEncodeError {
+    VideoEncodeError::Encoding(format!("rav1e failed: {}", status))
+}
@@ -0,0 +1,212 @@
+/// Encoder that runs SvtAv1EncApp directly, with frames decoded by ffmpeg and piped
+/// to it as y4m. Parameters are SvtAv1EncApp options, like `--preset 6 --crf 30`,
+/// which follow upstream releases unlike options of ffmpeg libsvtav1 wrapper.
+use std::{ops::RangeInclusive, path::Path};
+
+use futures::future::BoxFuture;
+use tokio::process::Command;
+use tracing::{debug, info};
+
+use crate::encoder::{
+    add_option, binary_version, pipe_y4m, reject_reserved, remove_options, remux,
+    take_decoder_options, Encoder, ParseProgress, Pass, PixelFormats, Vbv,
+};
+use crate::error::VideoEncodeError;
+use crate::ffmpeg::probe::probe_frame_rate;
+use crate::ffmpeg::progress::Progress;
+
+const ENCODER_BINARY: &str = "SvtAv1EncApp";
+
+/// Options that are set by the encoder itself
+const RESERVED_OPTIONS: [&str; 7] = [
+    "-i",
+    "-b",
+    "--progress",
+    "--no-progress",
+    "--pass",
+    "--passes",
+    "--stats",
+];
+
+/// Rate options, which lossless encoding replaces
+const RATE_OPTIONS: [&str; 6] = ["--crf", "--qp", "-q", "--rc", "--tbr", "--mbr"];
+
+#[derive(Debug, Default, Clone, Copy)]
+pub struct SvtAv1Encoder;
+
+/// Parses progress lines of SvtAv1EncApp,
+/// like `Encoding frame  120 12.34 kbps 45.67 fps`
+#[derive(Debug, Default)]
+pub struct SvtAv1ProgressParser {
+    /// Frame rate of the source, to convert frames to duration
+    frame_rate: Option<f64>,
+}
+
+impl ParseProgress for SvtAv1ProgressParser {
+    fn push_line(&mut self, line: &str) -> Option<Progress> {
+        let (_, rest) = line.split_once("Encoding frame")?;
+        let words: Vec<&str> = rest.split_whitespace().collect();
+
+        // Values are followed by their unit
+        let value = |unit: &str| {
+            words
+                .windows(2)
+                .find(|pair| pair[1].starts_with(unit))
+                .and_then(|pair| pair[0].trim_matches(['(', ',']).parse().ok())
+        };
+
+        let frame: u64 = words.first()?.parse().ok()?;
+        let fps: f64 = value("fps").unwrap_or_default();
+        let out_time = self
+            .frame_rate
+            .map_or(0.0, |frame_rate| frame as f64 / frame_rate);
+
+        Some(Progress {
+            frame,
+            fps,
+            bitrate_kbps: value("kbps").unwrap_or_default(),
+            out_time,
+            speed: self.frame_rate.map_or(0.0, |frame_rate| fps / frame_rate),
+            done: false,
+        })
+    }
+}
+
+impl Encoder for SvtAv1Encoder {
+    fn name(&self) -> &'static str {
+        "svt-av1"
+    }
+
+    fn probe(&self) -> Result<(), VideoEncodeError> {
+        let path = which::which(ENCODER_BINARY).map_err(|e| {
+            VideoEncodeError::Encoding(format!("{} not found: {}", ENCODER_BINARY, e))
+        })?;
+
+        info!(
+            "{} found at {:?}: {}",
+            ENCODER_BINARY,
+            path,
+            self.version().unwrap_or_default()
+        );
+        Ok(())
+    }
+
+    fn version(&self) -> Option<String> {
+        binary_version(ENCODER_BINARY)
+    }
+
+    fn validate(&self, params: &[String]) -> Result<(), VideoEncodeError> {
+        reject_reserved(params, &RESERVED_OPTIONS)
+    }
+
+    /// Command reads y4m from stdin and writes IVF into `output`.
+    /// Bit depth is read from y4m, so filters and pixel format only apply to decoding.
+    fn command(&self, _input: &Path, output: &Path, params: &[String]) -> Command {
+        let (_, params) = take_decoder_options(params);
+        let mut command = Command::new(ENCODER_BINARY);
+        command
+            .args(["-i", "stdin", "--progress", "2", "-b"])
+            .arg(output)
+            .args(params);
+        command
+    }
+
+    fn progress_parser(&self) -> Box<dyn ParseProgress> {
+        Box::new(SvtAv1ProgressParser::default())
+    }
+
+    fn pass_params(&self, pass: Pass, stats: &Path) -> Option<Vec<String>> {
+        Some(vec![
+            "--pass".to_string(),
+            pass.number().to_string(),
+            "--stats".to_string(),
+            stats.to_string_lossy().to_string(),
+        ])
+    }
+
+    /// Level of parallelism of SvtAv1EncApp, which is about the number of threads
+    fn with_threads(&self, params: &[String], threads: usize) -> Vec<String> {
+        add_option(params, "--lp", &threads.to_string())
+    }
+
+    fn pixel_formats(&self, _params: &[String]) -> Option<PixelFormats> {
+        Some(PixelFormats::SVT_AV1)
+    }
+
+    fn encodes_av1(&self, _params: &[String]) -> bool {
+        true
+    }
+
+    fn with_grain_table(&self, params: &[String], table: &Path) -> Option<Vec<String>> {
+        let mut params = params.to_vec();
+        params.extend([
+            "--fgs-table".to_string(),
+            table.to_string_lossy().to_string(),
+        ]);
+        Some(params)
+    }
+
+    fn crf_option(&self, _params: &[String]) -> Option<(&'static str, RangeInclusive<u32>)> {
+        Some(("--crf", 1..=63))
+    }
+
+    /// Lossless mode was added in SVT-AV1 2.3, older releases reject the option
+    fn lossless_params(&self, params: &[String]) -> Option<Vec<String>> {
+        let mut params = remove_options(params, &RATE_OPTIONS);
+        params.extend(["--lossless".to_string(), "1".to_string()]);
+        Some(params)
+    }
+
+    /// Variable bitrate mode, buffer of SvtAv1EncApp doesn't follow VBV model
+    fn rate_params(&self, bitrate: u64, vbv: Option<Vbv>) -> Option<Vec<String>> {
+        vbv.is_none().then(|| {
+            vec![
+                "--rc".to_string(),
+                "1".to_string(),
+                "--tbr".to_string(),
+                bitrate.to_string(),
+            ]
+        })
+    }
+
+    /// Decodes `input` with ffmpeg into SvtAv1EncApp, and remuxes its IVF output
+    /// into `output`, so it can be concatenated like output of other encoders
+    fn encode<'a>(
+        &'a self,
+        input: &'a Path,
+        output: &'a Path,
+        params: &'a [String],
+        on_progress: &'a mut (dyn FnMut(&Progress) + Send),
+    ) -> BoxFuture<'a, Result<(), VideoEncodeError>> {
+        Box::pin(async move {
+            let frame_rate = probe_frame_rate(input)
+                .map_err(|e| debug!("Progress won't have duration: {}", e))
+                .ok();
+            let mut parser = SvtAv1ProgressParser { frame_rate };
+            let ivf_path = output.with_extension("ivf");
+
+            if let Err(e) = pipe_y4m(
+                ENCODER_BINARY,
+                self,
+                input,
+                &ivf_path,
+                params,
+                &mut parser,
+                on_progress,
+            )
+            .await
+            {
+                let _ = tokio::fs::remove_file(&ivf_path).await;
+                return Err(e);
+            }
+
+            remux(&ivf_path, None, output).await?;
+
+            on_progress(&Progress {
+                done: true,
+                ..Progress::default()
+            });
+            Ok(())
+        })
+    }
+}
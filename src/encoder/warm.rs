@@ -0,0 +1,211 @@
+/// Encoder processes started ahead of chunks, so jobs with many tiny chunks don't wait
+/// for an encoder to start for every chunk. Standalone encoders read y4m from stdin,
+/// so they can be started with parameters of a job before its next chunk arrives.
+/// Every warm process writes into its own file, which is moved to output of the chunk.
+use std::{
+    collections::HashMap,
+    path::{Path, PathBuf},
+    process::Stdio,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Mutex, OnceLock,
+    },
+    time::{Duration, Instant},
+};
+
+use tokio::process::{Child, Command};
+use tracing::{debug, info, warn};
+
+use crate::cgroup;
+use crate::encoder::Encoder;
+use crate::error::VideoEncodeError;
+
+/// Processes that waited this long for a chunk are stopped
+const IDLE_TIMEOUT: Duration = Duration::from_secs(60);
+
+static POOL: OnceLock<WarmPool> = OnceLock::new();
+
+/// Encoder and its parameters, warm processes are only used for identical parameters
+type Key = (String, Vec<String>);
+
+struct WarmProcess {
+    child: Child,
+    output: PathBuf,
+    started: Instant,
+}
+
+#[derive(Default)]
+struct State {
+    processes: HashMap<Key, Vec<WarmProcess>>,
+    /// When parameters were last used
+    used: HashMap<Key, Instant>,
+}
+
+struct WarmPool {
+    dir: PathBuf,
+    /// Warm processes kept for every parameters
+    size: usize,
+    next: AtomicUsize,
+    state: Mutex<State>,
+}
+
+/// Keeps `size` encoder processes started for parameters of recent chunks, writing into
+/// `dir`. Only parameters that were used by a previous chunk get warm processes, so
+/// parameters of a single chunk, like stats of two-pass encodes, don't start any.
+pub fn enable(dir: PathBuf, size: usize) -> Result<(), VideoEncodeError> {
+    // Outputs of warm processes of previous run are never used
+    if dir.exists() {
+        std::fs::remove_dir_all(&dir)?;
+    }
+    std::fs::create_dir_all(&dir)?;
+
+    let pool = WarmPool {
+        dir,
+        size,
+        next: AtomicUsize::new(0),
+        state: Mutex::new(State::default()),
+    };
+    if POOL.set(pool).is_err() {
+        return Err(VideoEncodeError::Encoding(
+            "Warm encoder processes are already enabled".to_string(),
+        ));
+    }
+    info!("Keeping {} warm encoder processes per parameters", size);
+    Ok(())
+}
+
+/// Stops processes that waited too long for a chunk
+pub fn evict_idle() {
+    let Some(pool) = POOL.get() else {
+        return;
+    };
+    let mut state = pool.state.lock().expect("warm pool lock is poisoned");
+
+    state.used.retain(|_, used| used.elapsed() < IDLE_TIMEOUT);
+    for processes in state.processes.values_mut() {
+        processes.retain(|process| {
+            let idle = process.started.elapsed() >= IDLE_TIMEOUT;
+            if idle {
+                debug!("Stopping idle encoder process writing {:?}", process.output);
+                // Child is killed when it's dropped
+                let _ = std::fs::remove_file(&process.output);
+            }
+            !idle
+        });
+    }
+    state.processes.retain(|_, processes| !processes.is_empty());
+}
+
+/// Starts encoder that reads y4m from stdin and writes into `output`, or takes a warm
+/// one started with the same parameters. Warm process writes into returned path,
+/// which has to be moved to `output` when it succeeds.
+pub(crate) fn start(
+    encoder: &dyn Encoder,
+    input: &Path,
+    output: &Path,
+    params: &[String],
+) -> Result<(Child, Option<PathBuf>), VideoEncodeError> {
+    if let Some(warm) = POOL
+        .get()
+        .and_then(|pool| pool.take(encoder, output, params))
+    {
+        debug!("Using warm encoder process writing {:?}", warm.output);
+        return Ok((warm.child, Some(warm.output)));
+    }
+
+    let mut command = encoder.command(input, output, params);
+    cgroup::attach(&mut command);
+    Ok((spawn(command)?, None))
+}
+
+impl WarmPool {
+    /// Takes a live warm process for parameters, and starts processes
+    /// for the following chunks
+    fn take(&self, encoder: &dyn Encoder, output: &Path, params: &[String]) -> Option<WarmProcess> {
+        let key = (encoder.name().to_string(), params.to_vec());
+        let mut state = self.state.lock().expect("warm pool lock is poisoned");
+
+        let used_before = state.used.insert(key.clone(), Instant::now()).is_some();
+        let processes = state.processes.entry(key).or_default();
+        let mut warm = None;
+        while let Some(mut process) = processes.pop() {
+            // Processes that exited on their own can't encode anything
+            match process.child.try_wait() {
+                Ok(None) => {
+                    warm = Some(process);
+                    break;
+                }
+                _ => {
+                    let _ = std::fs::remove_file(&process.output);
+                }
+            }
+        }
+
+        if used_before || warm.is_some() {
+            while processes.len() < self.size {
+                match self.start_warm(encoder, output, params) {
+                    Ok(process) => processes.push(process),
+                    Err(e) => {
+                        warn!("Failed to start warm encoder process: {}", e);
+                        break;
+                    }
+                }
+            }
+        }
+
+        warm
+    }
+
+    fn start_warm(
+        &self,
+        encoder: &dyn Encoder,
+        output: &Path,
+        params: &[String],
+    ) -> Result<WarmProcess, VideoEncodeError> {
+        // Encoders like x264 choose format of the output by its extension
+        let mut warm_output = self.dir.join(format!(
+            "warm_{}",
+            self.next.fetch_add(1, Ordering::Relaxed)
+        ));
+        if let Some(extension) = output.extension() {
+            warm_output.set_extension(extension);
+        }
+
+        // Warm processes outlive the encode that starts them, so they aren't
+        // placed in its cgroup
+        let command = encoder.command(Path::new("-"), &warm_output, params);
+        Ok(WarmProcess {
+            child: spawn(command)?,
+            output: warm_output,
+            started: Instant::now(),
+        })
+    }
+}
+
+/// Moves output of warm process to `output` after the encode succeeded,
+/// or removes it when it failed
+pub(crate) async fn finish(
+    warm_output: Option<PathBuf>,
+    output: &Path,
+    success: bool,
+) -> Result<(), VideoEncodeError> {
+    let Some(warm_output) = warm_output else {
+        return Ok(());
+    };
+    if success {
+        tokio::fs::rename(&warm_output, output).await?;
+    } else {
+        let _ = tokio::fs::remove_file(&warm_output).await;
+    }
+    Ok(())
+}
+
+fn spawn(mut command: Command) -> Result<Child, VideoEncodeError> {
+    debug!("Encoder command: {:?}", command);
+    Ok(command
+        .stdin(Stdio::piped())
+        .stdout(Stdio::null())
+        .stderr(Stdio::piped())
+        .kill_on_drop(true)
+        .spawn()?)
+}
@@ -0,0 +1,422 @@
+/// Encoders that run x264 or x265 directly, with frames decoded by ffmpeg and piped
+/// to them as y4m. Parameters are options of the encoder, like `--preset slow --crf 20`.
+/// Options of ffmpeg libx264 and libx265 wrappers, like `-preset slow -crf 20`,
+/// are translated, so the same parameters work with ffmpeg and standalone encoders.
+use std::{ops::RangeInclusive, path::Path};
+
+use futures::future::BoxFuture;
+use tokio::process::Command;
+use tracing::{debug, info, warn};
+
+use crate::encoder::pixel_format::PIX_FMT_OPTION;
+use crate::encoder::{
+    add_option, binary_version, pipe_y4m, reject_reserved, remove_options, remux, Encoder,
+    ParseProgress, Pass, PixelFormat, PixelFormats, Vbv, CODEC_OPTIONS, FILTER_OPTION,
+    REPRODUCIBLE_THREADS, VBV_INIT,
+};
+use crate::error::VideoEncodeError;
+use crate::ffmpeg::probe::probe_frame_rate;
+use crate::ffmpeg::progress::Progress;
+
+/// Options of ffmpeg wrappers, and options of encoders they map to
+const TRANSLATED_OPTIONS: [(&str, &str); 13] = [
+    ("-preset", "--preset"),
+    ("-tune", "--tune"),
+    ("-profile:v", "--profile"),
+    ("-crf", "--crf"),
+    ("-qp", "--qp"),
+    ("-maxrate", "--vbv-maxrate"),
+    ("-bufsize", "--vbv-bufsize"),
+    ("-g", "--keyint"),
+    ("-keyint_min", "--min-keyint"),
+    ("-bf", "--bframes"),
+    ("-refs", "--ref"),
+    ("-threads", "--threads"),
+    ("-b:v", "--bitrate"),
+];
+
+/// Rate options of encoders and their ffmpeg wrappers, which lossless encoding replaces
+const RATE_OPTIONS: [&str; 10] = [
+    "-crf",
+    "-qp",
+    "-b:v",
+    "-maxrate",
+    "-bufsize",
+    "--crf",
+    "--qp",
+    "--bitrate",
+    "--vbv-maxrate",
+    "--vbv-bufsize",
+];
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum X26xEncoder {
+    X264,
+    X265,
+}
+
+impl X26xEncoder {
+    fn binary(self) -> &'static str {
+        match self {
+            X26xEncoder::X264 => "x264",
+            X26xEncoder::X265 => "x265",
+        }
+    }
+
+    /// Name of the ffmpeg wrapper of the encoder
+    fn ffmpeg_codec(self) -> &'static str {
+        match self {
+            X26xEncoder::X264 => "libx264",
+            X26xEncoder::X265 => "libx265",
+        }
+    }
+
+    /// Extension of raw bitstream written by the encoder
+    fn bitstream_extension(self) -> &'static str {
+        match self {
+            X26xEncoder::X264 => "264",
+            X26xEncoder::X265 => "hevc",
+        }
+    }
+
+    /// Options that are set by the encoder itself
+    fn reserved_options(self) -> &'static [&'static str] {
+        match self {
+            X26xEncoder::X264 => &[
+                "-o",
+                "--output",
+                "--demuxer",
+                "--stats",
+                "-p",
+                "--pass",
+                "--no-progress",
+                "--quiet",
+            ],
+            X26xEncoder::X265 => &[
+                "-o",
+                "--output",
+                "--input",
+                "--y4m",
+                "--stats",
+                "--pass",
+                "--no-progress",
+                "--log-level",
+            ],
+        }
+    }
+
+    /// Translates options of ffmpeg wrapper into options of the encoder.
+    /// Native options, which start with `--` or are single letters, are kept as they are.
+    /// Pixel format becomes output depth, and output colorspace of x264, which don't
+    /// follow y4m input. Filters are applied by the decoder, so they're left out.
+    pub fn translate(self, params: &[String]) -> Result<Vec<String>, VideoEncodeError> {
+        let private_options = format!("-{}-params", self.binary());
+        let mut translated = Vec::with_capacity(params.len());
+        let mut params = params.iter();
+
+        while let Some(param) = params.next() {
+            let name = param.as_str();
+            let mut value = || {
+                params.next().ok_or_else(|| {
+                    VideoEncodeError::Encoding(format!("Option {} is missing its value", name))
+                })
+            };
+
+            if CODEC_OPTIONS.contains(&name) {
+                let codec = value()?;
+                if codec != self.ffmpeg_codec() {
+                    return Err(VideoEncodeError::Encoding(format!(
+                        "Encoder parameters select {}, but chunks are encoded with {}",
+                        codec,
+                        self.binary()
+                    )));
+                }
+            } else if name == FILTER_OPTION {
+                value()?;
+            } else if name == PIX_FMT_OPTION {
+                let value = value()?;
+                let format = PixelFormat::parse(value).ok_or_else(|| {
+                    VideoEncodeError::Encoding(format!("Unsupported pixel format {}", value))
+                })?;
+                translated.push("--output-depth".to_string());
+                translated.push(format.bit_depth.to_string());
+                if self == X26xEncoder::X264 {
+                    translated.push("--output-csp".to_string());
+                    translated.push(format!("i{}", format.chroma.name()));
+                }
+            } else if name == private_options {
+                // Options of the encoder are passed to wrapper like `key=value:flag`
+                for option in value()?.split(':').filter(|option| !option.is_empty()) {
+                    match option.split_once('=') {
+                        Some((key, value)) => {
+                            translated.push(format!("--{}", key));
+                            translated.push(value.to_string());
+                        }
+                        None => translated.push(format!("--{}", option)),
+                    }
+                }
+            } else if let Some((_, option)) = TRANSLATED_OPTIONS
+                .iter()
+                .find(|(ffmpeg_option, _)| *ffmpeg_option == name)
+            {
+                let value = value()?;
+                translated.push(option.to_string());
+                // Rates and buffer sizes are in kbits for encoders, but in bits for ffmpeg
+                if matches!(*option, "--bitrate" | "--vbv-maxrate" | "--vbv-bufsize") {
+                    translated.push(to_kbps(value)?);
+                } else {
+                    translated.push(value.clone());
+                }
+            } else {
+                translated.push(param.clone());
+            }
+        }
+
+        Ok(translated)
+    }
+}
+
+/// Converts bitrate like `5M`, `800k` or `64000` into kbit/s
+fn to_kbps(bitrate: &str) -> Result<String, VideoEncodeError> {
+    let invalid = || VideoEncodeError::Encoding(format!("Invalid bitrate: {}", bitrate));
+    let (number, multiplier) = match bitrate.char_indices().last().ok_or_else(invalid)? {
+        (i, 'k' | 'K') => (&bitrate[..i], 1.0),
+        (i, 'M') => (&bitrate[..i], 1000.0),
+        _ => (bitrate, 0.001),
+    };
+    let kbps = number.parse::<f64>().map_err(|_| invalid())? * multiplier;
+
+    Ok(kbps.round().to_string())
+}
+
+/// Parses progress lines of x264 and x265, like `120 frames: 45.67 fps, 1234.56 kb/s`,
+/// or `[12.5%] 120/960 frames, 45.67 fps, 1234.56 kb/s, eta 0:00:18` when frame count
+/// is known
+#[derive(Debug, Default)]
+pub struct X26xProgressParser {
+    /// Frame rate of the source, to convert frames to duration
+    frame_rate: Option<f64>,
+}
+
+impl ParseProgress for X26xProgressParser {
+    fn push_line(&mut self, line: &str) -> Option<Progress> {
+        // Summary at the end looks similar, but it's part of the stats
+        if line.trim_start().starts_with("encoded") {
+            return None;
+        }
+        let words: Vec<&str> = line.split_whitespace().collect();
+
+        // Values are followed by their unit
+        let value = |unit: &str| {
+            words
+                .windows(2)
+                .find(|pair| pair[1].starts_with(unit))
+                .map(|pair| pair[0])
+        };
+
+        let frame: u64 = value("frames")?.split('/').next()?.parse().ok()?;
+        let fps: f64 = value("fps")?.parse().ok()?;
+        let out_time = self
+            .frame_rate
+            .map_or(0.0, |frame_rate| frame as f64 / frame_rate);
+
+        Some(Progress {
+            frame,
+            fps,
+            bitrate_kbps: value("kb/s")
+                .and_then(|bitrate| bitrate.parse().ok())
+                .unwrap_or_default(),
+            out_time,
+            speed: self.frame_rate.map_or(0.0, |frame_rate| fps / frame_rate),
+            done: false,
+        })
+    }
+}
+
+impl Encoder for X26xEncoder {
+    fn name(&self) -> &'static str {
+        self.binary()
+    }
+
+    fn probe(&self) -> Result<(), VideoEncodeError> {
+        let binary = self.binary();
+        let path = which::which(binary)
+            .map_err(|e| VideoEncodeError::Encoding(format!("{} not found: {}", binary, e)))?;
+
+        info!(
+            "{} found at {:?}: {}",
+            binary,
+            path,
+            self.version().unwrap_or_default()
+        );
+        Ok(())
+    }
+
+    /// x265 prints version to stderr, x264 to stdout
+    fn version(&self) -> Option<String> {
+        binary_version(self.binary())
+    }
+
+    fn validate(&self, params: &[String]) -> Result<(), VideoEncodeError> {
+        reject_reserved(params, self.reserved_options())?;
+        let translated = self.translate(params)?;
+        reject_reserved(&translated, self.reserved_options())
+    }
+
+    /// Command reads y4m from stdin and writes raw bitstream into `output`
+    fn command(&self, _input: &Path, output: &Path, params: &[String]) -> Command {
+        // Parameters are validated before they're sent, so translation doesn't fail here
+        let params = self.translate(params).unwrap_or_else(|e| {
+            warn!("Passing parameters as they are: {}", e);
+            params.to_vec()
+        });
+
+        let mut command = Command::new(self.binary());
+        match self {
+            X26xEncoder::X264 => {
+                command
+                    .args(["--demuxer", "y4m"])
+                    .args(params)
+                    .arg("-o")
+                    .arg(output)
+                    .arg("-");
+            }
+            X26xEncoder::X265 => {
+                command
+                    .args(["--input", "-", "--y4m"])
+                    .args(params)
+                    .arg("--output")
+                    .arg(output);
+            }
+        }
+        command
+    }
+
+    fn progress_parser(&self) -> Box<dyn ParseProgress> {
+        Box::new(X26xProgressParser::default())
+    }
+
+    /// x264 is deterministic unless told otherwise, output of x265 depends on number
+    /// of frame threads, which is derived from cores when it's not set
+    fn reproducible_params(&self, params: &[String]) -> Option<Vec<String>> {
+        let params: Vec<String> = params
+            .iter()
+            .filter(|param| *param != "--non-deterministic")
+            .cloned()
+            .collect();
+        let params = match self {
+            X26xEncoder::X264 => params,
+            X26xEncoder::X265 => add_option(
+                &params,
+                "--frame-threads",
+                &REPRODUCIBLE_THREADS.to_string(),
+            ),
+        };
+        Some(self.with_threads(&params, REPRODUCIBLE_THREADS))
+    }
+
+    /// Threads of x265 are in thread pools, which are sized with `--pools`
+    fn with_threads(&self, params: &[String], threads: usize) -> Vec<String> {
+        // Translated `-threads` sets threads as well
+        if params.iter().any(|param| param == "-threads") {
+            return params.to_vec();
+        }
+        match self {
+            X26xEncoder::X264 => add_option(params, "--threads", &threads.to_string()),
+            X26xEncoder::X265 => add_option(params, "--pools", &threads.to_string()),
+        }
+    }
+
+    fn pixel_formats(&self, _params: &[String]) -> Option<PixelFormats> {
+        match self {
+            X26xEncoder::X264 => Some(PixelFormats::X264),
+            X26xEncoder::X265 => Some(PixelFormats::X265),
+        }
+    }
+
+    fn encodes_av1(&self, _params: &[String]) -> bool {
+        false
+    }
+
+    /// Statistics are written next to the output, with mbtree or cutree data of x264
+    /// and x265, so chunks encoded at once don't share them
+    fn pass_params(&self, pass: Pass, stats: &Path) -> Option<Vec<String>> {
+        Some(vec![
+            "--pass".to_string(),
+            pass.number().to_string(),
+            "--stats".to_string(),
+            stats.to_string_lossy().to_string(),
+        ])
+    }
+
+    fn crf_option(&self, _params: &[String]) -> Option<(&'static str, RangeInclusive<u32>)> {
+        Some(("--crf", 0..=51))
+    }
+
+    /// x264 is lossless at quantizer 0, x265 has its own mode
+    fn lossless_params(&self, params: &[String]) -> Option<Vec<String>> {
+        let mut params = remove_options(params, &RATE_OPTIONS);
+        match self {
+            X26xEncoder::X264 => params.extend(["--qp".to_string(), "0".to_string()]),
+            X26xEncoder::X265 => params.push("--lossless".to_string()),
+        }
+        Some(params)
+    }
+
+    fn rate_params(&self, bitrate: u64, vbv: Option<Vbv>) -> Option<Vec<String>> {
+        let mut params = vec!["--bitrate".to_string(), bitrate.to_string()];
+        if let Some(vbv) = vbv {
+            params.extend([
+                "--vbv-maxrate".to_string(),
+                vbv.maxrate.to_string(),
+                "--vbv-bufsize".to_string(),
+                vbv.bufsize.to_string(),
+                "--vbv-init".to_string(),
+                VBV_INIT.to_string(),
+            ]);
+        }
+        Some(params)
+    }
+
+    /// Decodes `input` with ffmpeg into the encoder, and remuxes its raw bitstream
+    /// into `output` with frame rate of the source
+    fn encode<'a>(
+        &'a self,
+        input: &'a Path,
+        output: &'a Path,
+        params: &'a [String],
+        on_progress: &'a mut (dyn FnMut(&Progress) + Send),
+    ) -> BoxFuture<'a, Result<(), VideoEncodeError>> {
+        Box::pin(async move {
+            let frame_rate = probe_frame_rate(input)
+                .map_err(|e| warn!("Remuxed chunk will have default frame rate: {}", e))
+                .ok();
+            let mut parser = X26xProgressParser { frame_rate };
+            let bitstream_path = output.with_extension(self.bitstream_extension());
+
+            if let Err(e) = pipe_y4m(
+                self.binary(),
+                self,
+                input,
+                &bitstream_path,
+                params,
+                &mut parser,
+                on_progress,
+            )
+            .await
+            {
+                let _ = tokio::fs::remove_file(&bitstream_path).await;
+                return Err(e);
+            }
+
+            debug!("Remuxing {:?} at {:?} fps", bitstream_path, frame_rate);
+            remux(&bitstream_path, frame_rate, output).await?;
+
+            on_progress(&Progress {
+                done: true,
+                ..Progress::default()
+            });
+            Ok(())
+        })
+    }
+}
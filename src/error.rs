@@ -28,6 +28,15 @@ pub enum VideoEncodeError {
 
     #[error("Chunk processing error: {0}")]
     ChunkProcessing(String),
+
+    #[error("QUIC error: {0}")]
+    Quic(String),
+
+    #[error("Encryption error: {0}")]
+    Encryption(String),
+
+    #[error("Download error: {0}")]
+    Download(String),
 }
 
 pub type VideoEncodeResult<T> = Result<T, VideoEncodeError>;
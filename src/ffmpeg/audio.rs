@@ -0,0 +1,73 @@
+/// This module transcodes audio tracks of streams extracted from the input, so they
+/// can be transcoded while nodes encode the video. Other streams are copied.
+use std::path::Path;
+use std::process::Stdio;
+
+use tokio::process::Command;
+use tracing::{debug, error, info, instrument};
+
+use crate::error::VideoEncodeError;
+use crate::ffmpeg::probe::probe_streams;
+use crate::settings::AudioSettings;
+
+/// Writes streams of `streams_path` into `output_path`, with audio tracks transcoded
+/// as `settings` say. ffmpeg is killed if returned future is dropped before it completes.
+#[instrument(skip(settings))]
+pub async fn transcode_audio(
+    streams_path: &Path,
+    settings: &AudioSettings,
+    output_path: &Path,
+) -> Result<(), VideoEncodeError> {
+    let tracks = probe_streams(streams_path)?
+        .into_iter()
+        .filter(|stream| stream.codec_type == "audio")
+        .count();
+
+    let mut command = Command::new("ffmpeg");
+    command
+        .args(["-hide_banner", "-loglevel", "error", "-y", "-i"])
+        .arg(streams_path)
+        .args(["-map", "0", "-c", "copy"]);
+
+    let mut transcoded = 0;
+    for track in 0..tracks {
+        let (Some(codec), bitrate) = settings.track(track) else {
+            continue;
+        };
+        transcoded += 1;
+        command.args([format!("-c:a:{}", track), codec.to_string()]);
+        if let Some(bitrate) = bitrate {
+            command.args([format!("-b:a:{}", track), format!("{}k", bitrate)]);
+        }
+        debug!(
+            "Transcoding audio track {} to {} at {:?} kbit/s",
+            track, codec, bitrate
+        );
+    }
+    command.arg(output_path);
+
+    let output = command
+        .stdout(Stdio::null())
+        .stderr(Stdio::piped())
+        .kill_on_drop(true)
+        .output()
+        .await?;
+
+    if !output.status.success() {
+        error!(
+            "Failed to transcode audio of {:?}: {}",
+            streams_path,
+            String::from_utf8_lossy(&output.stderr)
+        );
+        return Err(VideoEncodeError::Encoding(format!(
+            "Failed to transcode audio of {:?}",
+            streams_path
+        )));
+    }
+
+    info!(
+        "Transcoded {} of {} audio tracks into {:?}",
+        transcoded, tracks, output_path
+    );
+    Ok(())
+}
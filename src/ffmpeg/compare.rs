@@ -0,0 +1,195 @@
+/// This module extracts matching frames of the source and the encoded output, so they
+/// can be compared by eye. Frames are picked at points spread over the source, in
+/// the darkest and most moving part around every point, where encodes fail first.
+use std::{
+    path::{Path, PathBuf},
+    process::Stdio,
+};
+
+use tokio::process::Command;
+use tracing::{debug, error, info, instrument};
+
+use crate::error::VideoEncodeError;
+use crate::ffmpeg::probe::probe_resolution;
+
+/// Only every this many frames of the source are analyzed
+const ANALYZED_INTERVAL: u32 = 6;
+/// Difference of luma between analyzed frames that counts as full motion
+const FULL_MOTION: f64 = 32.0;
+const YAVG_KEY: &str = "lavfi.signalstats.YAVG=";
+const YDIF_KEY: &str = "lavfi.signalstats.YDIF=";
+
+/// How frames of the source and output are written
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, clap::ValueEnum)]
+pub enum ComparisonLayout {
+    /// Source and output next to each other in one image
+    #[default]
+    SideBySide,
+    /// Source and output in separate images that follow each other, to flip between
+    Interleaved,
+}
+
+/// Frame of the source that was analyzed
+#[derive(Debug, Clone, Copy)]
+struct AnalyzedFrame {
+    time: f64,
+    /// Average luma from 0 to 255
+    brightness: f64,
+    /// Average luma difference from the previous analyzed frame
+    motion: f64,
+}
+
+impl AnalyzedFrame {
+    /// Dark and moving frames score higher, both count the same
+    fn score(&self) -> f64 {
+        (255.0 - self.brightness) / 255.0 + (self.motion / FULL_MOTION).min(1.0)
+    }
+}
+
+/// Picks timestamps of `count` frames of `source`, one in every equal part of it,
+/// the darkest and most moving frame of the part
+#[instrument]
+pub async fn pick_frames(source: &Path, count: usize) -> Result<Vec<f64>, VideoEncodeError> {
+    // Frames are scaled down, statistics of the whole frame barely change
+    let filter = format!(
+        "select='not(mod(n\\,{}))',scale=320:-2,format=yuv420p,signalstats,\
+         metadata=mode=print:key=lavfi.signalstats.YAVG,\
+         metadata=mode=print:key=lavfi.signalstats.YDIF",
+        ANALYZED_INTERVAL
+    );
+    let output = Command::new("ffmpeg")
+        .args(["-hide_banner", "-nostats", "-i"])
+        .arg(source)
+        .args(["-map", "0:v:0", "-vf", &filter, "-f", "null", "-"])
+        .stdin(Stdio::null())
+        .kill_on_drop(true)
+        .output()
+        .await?;
+
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    if !output.status.success() {
+        error!("Failed to analyze frames of {:?}: {}", source, stderr);
+        return Err(VideoEncodeError::Encoding(format!(
+            "Failed to analyze frames of {:?}",
+            source
+        )));
+    }
+
+    // Every frame is printed as `frame:3 pts:... pts_time:0.125`, followed by its keys
+    let mut frames: Vec<AnalyzedFrame> = Vec::new();
+    for line in stderr.lines() {
+        if let Some((_, time)) = line.split_once("pts_time:") {
+            let Ok(time) = time.trim().parse::<f64>() else {
+                continue;
+            };
+            if frames.last().is_none_or(|frame| frame.time != time) {
+                frames.push(AnalyzedFrame {
+                    time,
+                    brightness: 255.0,
+                    motion: 0.0,
+                });
+            }
+        } else if let (Some(frame), Some((_, value))) =
+            (frames.last_mut(), line.split_once(YAVG_KEY))
+        {
+            frame.brightness = value.trim().parse().unwrap_or(frame.brightness);
+        } else if let (Some(frame), Some((_, value))) =
+            (frames.last_mut(), line.split_once(YDIF_KEY))
+        {
+            frame.motion = value.trim().parse().unwrap_or_default();
+        }
+    }
+    if frames.is_empty() {
+        return Err(VideoEncodeError::Encoding(format!(
+            "Analysis of {:?} has no frames",
+            source
+        )));
+    }
+
+    let count = count.min(frames.len());
+    let times: Vec<f64> = (0..count)
+        .filter_map(|part| {
+            let start = part * frames.len() / count;
+            let end = (part + 1) * frames.len() / count;
+            frames[start..end]
+                .iter()
+                .max_by(|a, b| a.score().total_cmp(&b.score()))
+                .map(|frame| frame.time)
+        })
+        .collect();
+
+    debug!("Picked frames at {:?}", times);
+    Ok(times)
+}
+
+/// Writes frame at `time` of `source` and `output` into `dir` as PNG, named by `number`.
+/// Source is scaled to resolution of the output, so frames match pixel for pixel.
+/// Returns written images.
+#[instrument]
+pub async fn write_comparison(
+    source: &Path,
+    output: &Path,
+    time: f64,
+    number: usize,
+    layout: ComparisonLayout,
+    dir: &Path,
+) -> Result<Vec<PathBuf>, VideoEncodeError> {
+    let (width, height) = probe_resolution(output)?;
+    let source_chain = format!("[0:v:0]scale={}:{},format=rgb24", width, height);
+    let output_chain = "[1:v:0]format=rgb24";
+
+    let name = |suffix: &str| dir.join(format!("{:03}_{:.3}s_{}.png", number, time, suffix));
+    let mut command = Command::new("ffmpeg");
+    command
+        .args(["-hide_banner", "-loglevel", "error", "-y"])
+        .args(["-ss", &time.to_string(), "-i"])
+        .arg(source)
+        .args(["-ss", &time.to_string(), "-i"])
+        .arg(output);
+
+    let images = match layout {
+        ComparisonLayout::SideBySide => {
+            let image = name("comparison");
+            let graph = format!(
+                "{}[source];{}[output];[source][output]hstack",
+                source_chain, output_chain
+            );
+            command
+                .args(["-filter_complex", &graph, "-frames:v", "1"])
+                .arg(&image);
+            vec![image]
+        }
+        // Names sort source first, so viewers flip between them in order
+        ComparisonLayout::Interleaved => {
+            let (source_image, output_image) = (name("a_source"), name("b_output"));
+            let graph = format!("{}[source];{}[output]", source_chain, output_chain);
+            command
+                .args(["-filter_complex", &graph])
+                .args(["-map", "[source]", "-frames:v", "1"])
+                .arg(&source_image)
+                .args(["-map", "[output]", "-frames:v", "1"])
+                .arg(&output_image);
+            vec![source_image, output_image]
+        }
+    };
+
+    let result = command
+        .stdin(Stdio::null())
+        .kill_on_drop(true)
+        .output()
+        .await?;
+    if !result.status.success() {
+        error!(
+            "Failed to extract frame at {:.3}s: {}",
+            time,
+            String::from_utf8_lossy(&result.stderr)
+        );
+        return Err(VideoEncodeError::Encoding(format!(
+            "Failed to extract frame at {:.3}s",
+            time
+        )));
+    }
+
+    info!("Wrote comparison of frame at {:.3}s", time);
+    Ok(images)
+}
@@ -1,17 +1,35 @@
 use crate::error::VideoEncodeError;
+use crate::settings::{ConcatMethod, OutputSettings};
 use std::fs;
 use std::path::{Path, PathBuf};
-use std::process::Command;
+use std::process::{Command, Stdio};
 use tracing::{debug, error, info, instrument};
 
-/// Concatenates video segments and adds back non-video streams.
+use super::container::{output_container, stream_args, Container};
+use super::ivf::join_ivf;
+use super::metadata::Metadata;
+use super::mp4box::join_mp4;
+use super::timestamps::apply_timecodes;
+
+/// Concatenates video segments with `method` and adds back non-video streams, and chapters
+/// of FFMETADATA file `chapters`.
+/// When `timecodes` file is given, its timestamps replace timestamps of concatenated video.
+/// Output is muxed into container of its extension as `output` settings say, or written
+/// to stdout when it's `-`, with streams converted or dropped where the container
+/// doesn't allow them, and with tags of `metadata`.
+#[allow(clippy::too_many_arguments)]
 #[instrument(skip(segment_paths))]
 pub fn concatenate_videos_and_copy_streams(
     segment_paths: Vec<PathBuf>,
-    original_input: &Path,
+    original_input: Option<&Path>,
+    chapters: Option<&Path>,
     output_file: &Path,
     temp_dir: &PathBuf,
     expected_segments: usize,
+    timecodes: Option<&Path>,
+    method: ConcatMethod,
+    output: &OutputSettings,
+    metadata: &Metadata,
 ) -> Result<(), VideoEncodeError> {
     // Verify that all segments exist and match the expected count
     if segment_paths.len() != expected_segments {
@@ -31,49 +49,119 @@ pub fn concatenate_videos_and_copy_streams(
         }
     }
 
-    // Create a temporary file list for FFmpeg
-    // Unfortunately due to current implementation path of the files inside
-    // is relative to the file
-    let temp_file_list = PathBuf::from("file_list.txt");
-    let file_list_content: String = segment_paths
-        .iter()
-        .map(|path| format!("file '{}'\n", path.to_str().unwrap()))
-        .collect();
-    fs::write(&temp_file_list, file_list_content)?;
+    // Create a temporary file list for FFmpeg. Renditions share the temporary directory,
+    // so lists are named after their outputs
+    let output_name = output_file
+        .file_name()
+        .unwrap_or_default()
+        .to_string_lossy();
+    let temp_file_list = temp_dir.join(format!("{}.list.txt", output_name));
+    fs::write(&temp_file_list, concat_list(&segment_paths)?)?;
+
+    // Bitstreams are joined first, and muxed once with other streams
+    let joined = match method {
+        ConcatMethod::Ffmpeg => None,
+        ConcatMethod::Ivf => {
+            let joined = temp_dir.join("joined.ivf");
+            join_ivf(&segment_paths, &joined, temp_dir)?;
+            Some(joined)
+        }
+        ConcatMethod::Mp4box => {
+            let joined = temp_dir.join("joined.mp4");
+            join_mp4(&segment_paths, &joined, temp_dir)?;
+            Some(joined)
+        }
+    };
+
+    // Video is concatenated on its own first, so its frames can be retimed
+    let retimed = match (timecodes, &joined) {
+        (Some(timecodes), Some(joined)) => {
+            let retimed = temp_dir.join("retimed.mkv");
+            apply_timecodes(joined, timecodes, &retimed)?;
+            Some(retimed)
+        }
+        (Some(timecodes), None) => {
+            let concatenated = temp_dir.join("concatenated.mkv");
+            let output = Command::new("ffmpeg")
+                .arg("-hide_banner")
+                .args(["-y", "-f", "concat", "-safe", "0", "-i"])
+                .arg(&temp_file_list)
+                .args(["-map", "0:v", "-c", "copy"])
+                .arg(&concatenated)
+                .output()?;
+
+            if !output.status.success() {
+                error!(
+                    "Failed to concatenate videos: {}",
+                    String::from_utf8_lossy(&output.stderr)
+                );
+                return Err(VideoEncodeError::Concatenation(
+                    "Failed to concatenate videos".to_string(),
+                ));
+            }
+
+            let retimed = temp_dir.join("retimed.mkv");
+            apply_timecodes(&concatenated, timecodes, &retimed)?;
+            Some(retimed)
+        }
+        (None, _) => joined,
+    };
+
+    // Original input is the second input, after video
+    let container = output_container(output_file, output)?;
+    let (stream_maps, stream_codecs) = match original_input {
+        Some(original_input) => stream_args(container, original_input, 1, &output.map)?,
+        None => (Vec::new(), Vec::new()),
+    };
+    let muxer_args = muxer_args(output, container);
 
     let temp_st = temp_file_list.to_string_lossy();
-    let original_input = original_input.to_string_lossy();
+    let retimed = retimed.as_ref().map(|path| path.to_string_lossy());
+    let original_input = original_input.map(|input| input.to_string_lossy());
+    let chapters = chapters.map(|chapters| chapters.to_string_lossy());
     let output_file = output_file.to_string_lossy();
 
     // Prepare FFmpeg command
-    let ffmpeg_args = vec![
-        "-f",
-        "concat",
-        "-safe",
-        "0",
-        "-i",
-        &temp_st,
-        "-i",
-        &original_input,
-        "-map",
-        "0:v", // map video from concatenated segments
-        "-map",
-        "1", // map all streams from original input
-        "-c",
-        "copy",
-        &output_file,
-    ];
+    let mut ffmpeg_args = match &retimed {
+        Some(retimed) => vec!["-i", retimed],
+        None => vec!["-f", "concat", "-safe", "0", "-i", &temp_st],
+    };
+    if let Some(original_input) = &original_input {
+        ffmpeg_args.extend(["-i", original_input]);
+    }
+    // Chapters file is the last input, output has no chapters without it
+    if let Some(chapters) = &chapters {
+        ffmpeg_args.extend(["-i", chapters]);
+    }
+    ffmpeg_args.extend(["-map", "0:v"]); // map video from concatenated segments
+    ffmpeg_args.extend(stream_maps.iter().map(String::as_str));
+    let chapters_input = match (&chapters, &original_input) {
+        (Some(_), Some(_)) => "2",
+        (Some(_), None) => "1",
+        (None, _) => "-1",
+    };
+    ffmpeg_args.extend(["-map_chapters", chapters_input]);
+    let metadata_args = metadata.args();
+    ffmpeg_args.extend(metadata_args.iter().map(String::as_str));
+    ffmpeg_args.extend(muxer_args.iter().map(String::as_str));
+    ffmpeg_args.extend(["-c", "copy"]);
+    ffmpeg_args.extend(stream_codecs.iter().map(String::as_str));
+    ffmpeg_args.extend(["-f", container.muxer(), &output_file]);
 
     debug!("FFmpeg command: ffmpeg {:?}", ffmpeg_args);
 
-    // Execute FFmpeg command
-    let status = Command::new("ffmpeg")
+    // Output may be written to stdout, only messages of ffmpeg are kept from terminal
+    let output = Command::new("ffmpeg")
         .arg("-hide_banner")
         .args(&ffmpeg_args)
-        .status()?;
+        .stdout(Stdio::inherit())
+        .output()?;
 
-    if !status.success() {
-        error!("Failed to concatenate videos and copy streams");
+    if !output.status.success() {
+        error!(
+            "Failed to concatenate videos and copy streams: {}",
+            String::from_utf8_lossy(&output.stderr)
+        );
         return Err(VideoEncodeError::Concatenation(
             "Failed to concatenate videos and copy streams".to_string(),
         ));
@@ -89,3 +177,40 @@ pub fn concatenate_videos_and_copy_streams(
 
     Ok(())
 }
+
+/// Options of the muxer of `container` that `settings` ask for
+fn muxer_args(settings: &OutputSettings, container: Container) -> Vec<String> {
+    let mut args = Vec::new();
+    if container == Container::WebM {
+        // Cues are written before clusters, so players seek without reading to the end
+        args.extend(["-cues_to_front".to_string(), "1".to_string()]);
+    }
+    if settings.fragmented {
+        // Moov without samples comes first, and every fragment has its own moof,
+        // addressed from its start, as CMAF requires
+        args.extend([
+            "-movflags".to_string(),
+            "+frag_keyframe+empty_moov+default_base_moof+cmaf".to_string(),
+        ]);
+        if let Some(duration) = settings.fragment_duration {
+            args.extend([
+                "-frag_duration".to_string(),
+                ((duration * 1_000_000.0) as u64).to_string(),
+            ]);
+        }
+    }
+    args
+}
+
+/// File list of the concat demuxer with `segment_paths`, in this order. Paths are
+/// absolute, because the demuxer resolves relative ones against the list.
+pub fn concat_list(segment_paths: &[PathBuf]) -> Result<String, VideoEncodeError> {
+    let mut list = String::new();
+    for path in segment_paths {
+        // Quotes in paths are closed, escaped and opened again
+        let path = std::path::absolute(path)?;
+        let path = path.to_string_lossy().replace('\'', "'\\''");
+        list.push_str(&format!("file '{}'\n", path));
+    }
+    Ok(list)
+}
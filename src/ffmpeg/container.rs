@@ -0,0 +1,298 @@
+/// This module picks the container of an output by its extension, and checks that
+/// streams kept from the input can be muxed into it before anything is encoded.
+/// Streams are converted where the container only allows other codecs of their kind,
+/// like text subtitles, which MP4 stores as `mov_text`.
+use std::path::Path;
+
+use tracing::{debug, instrument, warn};
+
+use crate::error::VideoEncodeError;
+use crate::ffmpeg::probe::{probe_streams, StreamInfo};
+use crate::ffmpeg::webm::webm_action;
+use crate::settings::{AudioSettings, OutputSettings, StdoutFormat, SubtitlePolicy, TrackSettings};
+
+/// Audio codecs and encoders of ffmpeg that MP4 and QuickTime store
+const MP4_AUDIO_CODECS: [&str; 12] = [
+    "aac",
+    "libfdk_aac",
+    "mp3",
+    "libmp3lame",
+    "mp2",
+    "ac3",
+    "eac3",
+    "dts",
+    "opus",
+    "libopus",
+    "flac",
+    "alac",
+];
+/// Subtitle codecs that are text, and can be converted to `mov_text`
+const TEXT_SUBTITLE_CODECS: [&str; 7] =
+    ["mov_text", "subrip", "srt", "ass", "ssa", "webvtt", "text"];
+
+/// Container of an output
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Container {
+    Matroska,
+    WebM,
+    Mp4,
+    Mov,
+    Nut,
+}
+
+/// Whether output `path` is stdout
+pub fn is_stdout(path: &Path) -> bool {
+    path == Path::new("-")
+}
+
+/// Container of output `path`, by its extension, or as `settings` say for stdout
+pub fn output_container(
+    path: &Path,
+    settings: &OutputSettings,
+) -> Result<Container, VideoEncodeError> {
+    if !is_stdout(path) {
+        return Container::from_path(path);
+    }
+    Ok(match settings.stdout_format {
+        StdoutFormat::Matroska => Container::Matroska,
+        StdoutFormat::Nut => Container::Nut,
+    })
+}
+
+impl Container {
+    /// Container of `path` by its extension
+    pub fn from_path(path: &Path) -> Result<Container, VideoEncodeError> {
+        let extension = path
+            .extension()
+            .and_then(|extension| extension.to_str())
+            .unwrap_or_default()
+            .to_ascii_lowercase();
+        match extension.as_str() {
+            "mkv" => Ok(Container::Matroska),
+            "webm" => Ok(Container::WebM),
+            "mp4" | "m4v" => Ok(Container::Mp4),
+            "mov" => Ok(Container::Mov),
+            "nut" => Ok(Container::Nut),
+            _ => Err(VideoEncodeError::Encoding(format!(
+                "Container of output {:?} isn't known by its extension, \
+                 use .mkv, .webm, .mp4, .m4v, .mov or .nut",
+                path
+            ))),
+        }
+    }
+
+    /// Muxer of ffmpeg that writes the container
+    pub fn muxer(self) -> &'static str {
+        match self {
+            Container::Matroska => "matroska",
+            Container::WebM => "webm",
+            Container::Mp4 => "mp4",
+            Container::Mov => "mov",
+            Container::Nut => "nut",
+        }
+    }
+
+    fn name(self) -> &'static str {
+        match self {
+            Container::Matroska => "Matroska",
+            Container::WebM => "WebM",
+            Container::Mp4 => "MP4",
+            Container::Mov => "QuickTime",
+            Container::Nut => "NUT",
+        }
+    }
+}
+
+/// Checks that audio and subtitle tracks of `input` that are kept as `tracks` say,
+/// with audio transcoded as `audio` says, can be muxed into `container`
+#[instrument(skip(tracks, audio))]
+pub fn check_input_streams(
+    input: &Path,
+    container: Container,
+    tracks: &TrackSettings,
+    audio: &AudioSettings,
+) -> Result<(), VideoEncodeError> {
+    // Matroska and NUT store any audio and subtitles, and WebM outputs convert or drop
+    // streams WebM doesn't allow
+    if matches!(
+        container,
+        Container::Matroska | Container::WebM | Container::Nut
+    ) {
+        return Ok(());
+    }
+
+    let (mut audio_tracks, mut subtitles) = (0, 0);
+    for stream in probe_streams(input)? {
+        let language = stream.tags.language.as_deref();
+        let codec = stream.codec_name.as_deref().unwrap_or_default();
+        match stream.codec_type.as_str() {
+            "audio" => {
+                audio_tracks += 1;
+                if !tracks.keeps_audio(audio_tracks - 1, language) {
+                    continue;
+                }
+                let codec = audio.track(audio_tracks - 1).0.unwrap_or(codec);
+                if !MP4_AUDIO_CODECS.contains(&codec) {
+                    return Err(VideoEncodeError::Encoding(format!(
+                        "{} doesn't store {} audio of stream {}, transcode it or leave it out",
+                        container.name(),
+                        codec,
+                        stream.index
+                    )));
+                }
+            }
+            "subtitle" => {
+                subtitles += 1;
+                let policy = tracks.subtitle_policy(codec);
+                if policy == SubtitlePolicy::Drop || !tracks.keeps_subtitle(subtitles - 1, language)
+                {
+                    continue;
+                }
+                // Converted tracks are text already
+                if policy.codec().is_none() && !TEXT_SUBTITLE_CODECS.contains(&codec) {
+                    return Err(VideoEncodeError::Encoding(format!(
+                        "{} doesn't store {} subtitles of stream {}, drop them with \
+                         subtitle_formats or leave the track out",
+                        container.name(),
+                        codec,
+                        stream.index
+                    )));
+                }
+            }
+            _ => {}
+        }
+    }
+    Ok(())
+}
+
+/// What is done with a stream of the input when it's muxed into a container
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum StreamAction {
+    Copy,
+    /// Stream is converted with this encoder of ffmpeg
+    Convert(&'static str),
+    Drop,
+}
+
+/// Options of ffmpeg that map streams of `streams_path`, input `input` of the command,
+/// into `container` after video, and codec options that follow `-c copy`. Streams are
+/// selected and ordered by `specifiers`, like `a:1`, and all streams but video are
+/// mapped in order when there are none.
+#[instrument]
+pub fn stream_args(
+    container: Container,
+    streams_path: &Path,
+    input: usize,
+    specifiers: &[String],
+) -> Result<(Vec<String>, Vec<String>), VideoEncodeError> {
+    let streams = select_streams(probe_streams(streams_path)?, specifiers)?;
+
+    let mut maps = Vec::new();
+    let mut codecs = Vec::new();
+    // Video of the output is its first stream
+    let mut output_index = 1;
+    for stream in streams {
+        match stream_action(container, &stream) {
+            StreamAction::Copy => {}
+            StreamAction::Convert(encoder) => {
+                debug!("Converting stream {} with {}", stream.index, encoder);
+                codecs.extend([format!("-c:{}", output_index), encoder.to_string()]);
+            }
+            StreamAction::Drop => {
+                warn!(
+                    "{} doesn't store {} {} streams, stream {} is dropped",
+                    container.name(),
+                    stream.codec_name.as_deref().unwrap_or_default(),
+                    stream.codec_type,
+                    stream.index
+                );
+                continue;
+            }
+        }
+        maps.extend(["-map".to_string(), format!("{}:{}", input, stream.index)]);
+        output_index += 1;
+    }
+    Ok((maps, codecs))
+}
+
+/// Checks that `specifiers` of streams are valid
+pub fn check_specifiers(specifiers: &[String]) -> Result<(), VideoEncodeError> {
+    for specifier in specifiers {
+        parse_specifier(specifier)?;
+    }
+    Ok(())
+}
+
+/// Streams of `streams` that `specifiers` select, in their order. Every stream is
+/// selected once, all streams but video are selected when there are no specifiers.
+fn select_streams(
+    streams: Vec<StreamInfo>,
+    specifiers: &[String],
+) -> Result<Vec<StreamInfo>, VideoEncodeError> {
+    if specifiers.is_empty() {
+        return Ok(streams
+            .into_iter()
+            .filter(|stream| stream.codec_type != "video")
+            .collect());
+    }
+
+    let mut selected: Vec<StreamInfo> = Vec::new();
+    for specifier in specifiers {
+        let (codec_type, number) = parse_specifier(specifier)?;
+        let matching = streams
+            .iter()
+            .filter(|stream| stream.codec_type == codec_type)
+            .enumerate()
+            .filter(|&(position, _)| number.is_none_or(|number| number == position))
+            .map(|(_, stream)| stream);
+        let mut found = false;
+        for stream in matching {
+            found = true;
+            if !selected.iter().any(|other| other.index == stream.index) {
+                selected.push(stream.clone());
+            }
+        }
+        if !found {
+            warn!("No stream matches specifier {:?}", specifier);
+        }
+    }
+    Ok(selected)
+}
+
+/// Type of streams and number among streams of the type of specifier like `a:1`,
+/// no number selects all streams of the type
+fn parse_specifier(specifier: &str) -> Result<(&'static str, Option<usize>), VideoEncodeError> {
+    let invalid = || {
+        VideoEncodeError::Encoding(format!(
+            "Stream specifier {:?} isn't valid, use type v, a, s or t, \
+             optionally followed by number among streams of the type, like a:1",
+            specifier
+        ))
+    };
+    let (kind, number) = match specifier.split_once(':') {
+        Some((kind, number)) => (kind, Some(number.parse().map_err(|_| invalid())?)),
+        None => (specifier, None),
+    };
+    let codec_type = match kind {
+        "v" => "video",
+        "a" => "audio",
+        "s" => "subtitle",
+        "t" => "attachment",
+        _ => return Err(invalid()),
+    };
+    Ok((codec_type, number))
+}
+
+/// What is done with `stream` when it's muxed into `container`
+fn stream_action(container: Container, stream: &StreamInfo) -> StreamAction {
+    let codec = stream.codec_name.as_deref().unwrap_or_default();
+    match (container, stream.codec_type.as_str()) {
+        (Container::WebM, _) => webm_action(stream),
+        (Container::Matroska, "video" | "audio" | "subtitle" | "attachment") => StreamAction::Copy,
+        (Container::Nut, "video" | "audio" | "subtitle") => StreamAction::Copy,
+        (Container::Mp4 | Container::Mov, "video" | "audio") => StreamAction::Copy,
+        (Container::Mp4 | Container::Mov, "subtitle") if codec == "mov_text" => StreamAction::Copy,
+        (Container::Mp4 | Container::Mov, "subtitle") => StreamAction::Convert("mov_text"),
+        _ => StreamAction::Drop,
+    }
+}
@@ -0,0 +1,124 @@
+/// This module tells animation from live action by statistics of sampled frames.
+/// Animation is mostly flat areas bounded by sharp edges, while texture and grain
+/// of live action leave few pixels that don't change when they're blurred.
+use std::process::Stdio;
+
+use tokio::process::Command;
+use tracing::{debug, error, instrument};
+
+use crate::chunk::{Chunk, SourceRange};
+use crate::error::VideoEncodeError;
+use crate::settings::ContentType;
+
+/// Number of frames of a chunk that are measured
+const SAMPLE_FRAMES: u64 = 8;
+/// Pixel that differs from its blurred copy by less than this many 8-bit levels is flat
+const FLAT_THRESHOLD: u32 = 3;
+/// Share of flat pixels above which frames are animation
+const ANIMATION_FLATNESS: f64 = 0.6;
+/// Share of edge pixels animation has at least, frames without edges are just dark
+const ANIMATION_EDGES: f64 = 0.005;
+
+/// Statistics of frames, as shares of their pixels
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct ContentStats {
+    /// Pixels in flat areas
+    pub flatness: f64,
+    /// Pixels on edges
+    pub edges: f64,
+}
+
+impl ContentStats {
+    /// Type of content with these statistics
+    pub fn content_type(self) -> ContentType {
+        if self.flatness >= ANIMATION_FLATNESS && self.edges >= ANIMATION_EDGES {
+            ContentType::Animation
+        } else {
+            ContentType::LiveAction
+        }
+    }
+}
+
+/// Measures statistics of frames sampled from the chunk. Returns `None` for chunks
+/// of scripts and image sequences, and chunks of unknown frame count, which aren't measured.
+#[instrument(skip(chunk), fields(chunk_index = chunk.index))]
+pub async fn measure_content(chunk: &Chunk) -> Result<Option<ContentStats>, VideoEncodeError> {
+    let Some(frames) = chunk
+        .metadata
+        .as_ref()
+        .map(|metadata| metadata.frames)
+        .filter(|&frames| frames > 0)
+    else {
+        return Ok(None);
+    };
+
+    let mut command = Command::new("ffmpeg");
+    command.args(["-hide_banner", "-nostats"]);
+    match &chunk.range {
+        None => command.arg("-i").arg(&chunk.source_path),
+        Some(SourceRange::Video {
+            input_path,
+            start,
+            end,
+            ..
+        }) => command
+            .args(["-ss", &start.to_string(), "-to", &end.to_string(), "-i"])
+            .arg(input_path),
+        Some(_) => return Ok(None),
+    };
+
+    // Both chains log share of pixels below threshold, named so they can be told apart
+    let interval = (frames / SAMPLE_FRAMES).max(1);
+    let filter = format!(
+        "[0:v:0]select='not(mod(n\\,{}))',format=gray,split=3[frame][copy][edges];\
+         [copy]boxblur=2:1[blurred];\
+         [frame][blurred]blend=all_mode=difference,blackframe@flat=amount=0:threshold={}[flat];\
+         [edges]edgedetect,blackframe@edges=amount=0:threshold=128[edge]",
+        interval, FLAT_THRESHOLD
+    );
+    let output = command
+        .args(["-filter_complex", &filter])
+        .args(["-map", "[flat]", "-f", "null", "-"])
+        .args(["-map", "[edge]", "-f", "null", "-"])
+        .stdin(Stdio::null())
+        .kill_on_drop(true)
+        .output()
+        .await?;
+
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    if !output.status.success() {
+        error!(
+            "Failed to measure content of chunk {}: {}",
+            chunk.index, stderr
+        );
+        return Err(VideoEncodeError::Encoding(format!(
+            "Failed to measure content of chunk {}",
+            chunk.index
+        )));
+    }
+
+    // Lines look like `[blackframe@flat @ 0x...] frame:3 pblack:87 pts:...`
+    let share = |name: &str| {
+        let values: Vec<f64> = stderr
+            .lines()
+            .filter(|line| line.contains(name))
+            .filter_map(|line| line.split_once("pblack:"))
+            .filter_map(|(_, rest)| rest.split_whitespace().next()?.parse().ok())
+            .collect();
+        (!values.is_empty()).then(|| values.iter().sum::<f64>() / values.len() as f64 / 100.0)
+    };
+    let (Some(flat), Some(not_edges)) = (share("blackframe@flat"), share("blackframe@edges"))
+    else {
+        return Err(VideoEncodeError::Encoding(format!(
+            "Content measurement of chunk {} has no result",
+            chunk.index
+        )));
+    };
+
+    let stats = ContentStats {
+        flatness: flat,
+        edges: 1.0 - not_edges,
+    };
+    debug!("Chunk {} has {:?}", chunk.index, stats);
+    Ok(Some(stats))
+}
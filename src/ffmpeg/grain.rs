@@ -0,0 +1,168 @@
+/// This module generates AV1 film grain tables with photon noise, so grain that is
+/// removed by denoising is synthesized by the decoder instead of being encoded.
+/// Strength of the noise can be measured from grain of the source.
+use std::{path::Path, process::Command};
+
+use av1_grain::{generate_photon_noise_params, write_grain_table, NoiseGenArgs, TransferFunction};
+use tracing::{debug, error, info, instrument};
+
+use crate::error::VideoEncodeError;
+use crate::ffmpeg::probe::{probe_color_transfer, probe_resolution};
+
+/// Every this many frames of the source is measured
+const SAMPLE_INTERVAL: u32 = 24;
+/// Number of frames measured
+const SAMPLE_FRAMES: u32 = 100;
+/// Strengths of hqdn3d at strength 1: luma and chroma spatial, luma and chroma temporal
+const HQDN3D_STRENGTHS: [f64; 4] = [4.0, 3.0, 6.0, 4.5];
+/// Strength of denoiser that grain is measured against, when chunks aren't denoised
+const MEASURE_STRENGTH: f64 = 1.5;
+/// Range of photon noise strengths, in ISO
+const ISO_RANGE: (u32, u32) = (50, 12800);
+const ISO_STEP: usize = 50;
+/// Standard deviation of AV1 grain at 8 bits before it's scaled,
+/// scaling of 256 gives noise of about this deviation
+const GRAIN_DEVIATION: f64 = 32.0;
+
+/// Denoiser that chunks are filtered with before they're encoded with grain synthesis
+pub fn denoise_filter(strength: f64) -> String {
+    let [luma, chroma, luma_temporal, chroma_temporal] = HQDN3D_STRENGTHS.map(|s| s * strength);
+    format!(
+        "hqdn3d={}:{}:{}:{}",
+        luma, chroma, luma_temporal, chroma_temporal
+    )
+}
+
+/// Measures grain of the source as standard deviation of the difference between
+/// source frames and their copies denoised at `strength`, in 8-bit levels
+#[instrument]
+pub fn measure_noise(input_path: &Path, strength: f64) -> Result<f64, VideoEncodeError> {
+    // Temporal denoising is disabled, because sampled frames are far apart
+    let [luma, chroma, _, _] = HQDN3D_STRENGTHS.map(|s| s * strength);
+    let filter = format!(
+        "[0:v:0]select='not(mod(n\\,{}))',split[source][copy];[copy]hqdn3d={}:{}:0:0[denoised];[source][denoised]psnr[out]",
+        SAMPLE_INTERVAL, luma, chroma
+    );
+
+    let output = Command::new("ffmpeg")
+        .arg("-hide_banner")
+        .arg("-i")
+        .arg(input_path)
+        .args(["-filter_complex", &filter, "-map", "[out]"])
+        .args(["-frames:v", &SAMPLE_FRAMES.to_string(), "-f", "null", "-"])
+        .output()?;
+
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    if !output.status.success() {
+        error!("Failed to measure grain: {}", stderr);
+        return Err(VideoEncodeError::Encoding(
+            "Failed to measure grain of the source".to_string(),
+        ));
+    }
+
+    // Summary looks like `[Parsed_psnr_4 @ 0x...] PSNR y:41.23 u:45.10 v:44.98 average:...`
+    let psnr = stderr
+        .lines()
+        .filter_map(|line| line.split_once("PSNR y:"))
+        .filter_map(|(_, rest)| rest.split_whitespace().next())
+        .next_back()
+        .ok_or_else(|| VideoEncodeError::Encoding("Grain measurement has no result".to_string()))?;
+
+    // Identical frames have infinite PSNR, so no grain
+    let noise = match psnr.parse::<f64>() {
+        Ok(psnr) if psnr.is_finite() => 255.0 / 10f64.powf(psnr / 20.0),
+        _ => 0.0,
+    };
+
+    info!("Measured grain of {:?}: {:.2}", input_path, noise);
+    Ok(noise)
+}
+
+/// Finds photon noise strength in ISO that gives noise closest to `noise`
+/// of mid-tones, in 8-bit levels
+pub fn iso_for_noise(noise: f64, width: u32, height: u32, hdr: bool) -> u32 {
+    (ISO_RANGE.0..=ISO_RANGE.1)
+        .step_by(ISO_STEP)
+        .min_by(|&a, &b| {
+            let distance = |iso| (mid_tone_noise(iso, width, height, hdr) - noise).abs();
+            distance(a).total_cmp(&distance(b))
+        })
+        .unwrap_or(ISO_RANGE.0)
+}
+
+/// Noise of mid-tones that photon noise of given strength gives, in 8-bit levels
+fn mid_tone_noise(iso: u32, width: u32, height: u32, hdr: bool) -> f64 {
+    let segment =
+        generate_photon_noise_params(0, u64::MAX, noise_args(iso, width, height, hdr, false));
+    let points = &segment.scaling_points_y;
+
+    // Scaling is interpolated between points, which are sorted by intensity
+    let scaling = points
+        .windows(2)
+        .find(|pair| pair[0][0] <= 128 && 128 <= pair[1][0])
+        .map(|pair| {
+            let (x0, y0) = (f64::from(pair[0][0]), f64::from(pair[0][1]));
+            let (x1, y1) = (f64::from(pair[1][0]), f64::from(pair[1][1]));
+            if x1 == x0 {
+                y0
+            } else {
+                y0 + (y1 - y0) * (128.0 - x0) / (x1 - x0)
+            }
+        })
+        .unwrap_or_default();
+
+    scaling * GRAIN_DEVIATION / 256.0
+}
+
+fn noise_args(iso: u32, width: u32, height: u32, hdr: bool, chroma: bool) -> NoiseGenArgs {
+    NoiseGenArgs {
+        iso_setting: iso,
+        width,
+        height,
+        transfer_function: if hdr {
+            TransferFunction::SMPTE2084
+        } else {
+            TransferFunction::BT1886
+        },
+        chroma_grain: chroma,
+        random_seed: None,
+    }
+}
+
+/// Writes photon noise table for the source into `table_path`. Strength is `iso`,
+/// or measured from the source when it's not set, against denoiser of strength
+/// `denoise` that chunks are filtered with. Noise is applied to chroma when
+/// `chroma` is set. Returns content of the table, which covers any timestamp,
+/// so the same table fits every chunk.
+#[instrument]
+pub fn write_photon_noise_table(
+    input_path: &Path,
+    iso: Option<u32>,
+    chroma: bool,
+    denoise: Option<f64>,
+    table_path: &Path,
+) -> Result<String, VideoEncodeError> {
+    let (width, height) = probe_resolution(input_path)?;
+    let hdr = probe_color_transfer(input_path)?.as_deref() == Some("smpte2084");
+
+    let iso = match iso {
+        Some(iso) => iso,
+        None => {
+            let noise = measure_noise(input_path, denoise.unwrap_or(MEASURE_STRENGTH))?;
+            let iso = iso_for_noise(noise, width, height, hdr);
+            info!("Photon noise strength matching the source is ISO {}", iso);
+            iso
+        }
+    };
+
+    let segment =
+        generate_photon_noise_params(0, u64::MAX, noise_args(iso, width, height, hdr, chroma));
+    write_grain_table(table_path, &[segment])
+        .map_err(|e| VideoEncodeError::Encoding(format!("Failed to write grain table: {}", e)))?;
+
+    debug!(
+        "Wrote photon noise table of ISO {} to {:?}",
+        iso, table_path
+    );
+    Ok(std::fs::read_to_string(table_path)?)
+}
@@ -0,0 +1,136 @@
+/// This module detects interlaced and telecined sources with ffmpeg `idet` filter,
+/// so they can be deinterlaced before chunking instead of encoding combed frames
+use std::{
+    fs,
+    path::Path,
+    process::{Command, Stdio},
+};
+
+use tracing::{debug, error, info, instrument};
+
+use crate::error::VideoEncodeError;
+use crate::ffmpeg::segment::LOSSLESS_INTERMEDIATE;
+
+/// Number of frames analyzed, from the start of the input
+const ANALYZED_FRAMES: usize = 2000;
+
+const MULTI_FRAME_KEY: &str = "Multi frame detection:";
+
+/// Share of interlaced frames above which the whole input is considered interlaced.
+/// Telecine with 3:2 pulldown combs 2 of every 5 frames, so it's found below it.
+const INTERLACED_RATIO: f64 = 0.8;
+/// Share of interlaced frames above which the input is considered telecined
+const TELECINED_RATIO: f64 = 0.2;
+
+/// How frames of the input are scanned
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ScanType {
+    Progressive,
+    /// Every frame is made of two fields
+    Interlaced,
+    /// Progressive film with fields repeated by pulldown, restored by inverse telecine
+    Telecined,
+}
+
+/// Analyzes fields of the first frames of the input
+#[instrument]
+pub fn detect_scan_type(input_path: &Path) -> Result<ScanType, VideoEncodeError> {
+    debug!("Detecting interlacing in {:?}", input_path);
+
+    let output = Command::new("ffmpeg")
+        .arg("-hide_banner")
+        .arg("-i")
+        .arg(input_path)
+        .args(["-an", "-sn", "-dn", "-map", "0:v:0"])
+        .args(["-frames:v", &ANALYZED_FRAMES.to_string()])
+        .args(["-vf", "idet", "-f", "null", "-"])
+        .stdout(Stdio::null())
+        .output()?;
+
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    if !output.status.success() {
+        error!("Failed to detect interlacing: {}", stderr);
+        return Err(VideoEncodeError::Encoding(
+            "Failed to detect interlacing".to_string(),
+        ));
+    }
+
+    // Line looks like `[Parsed_idet_0 @ 0x...] Multi frame detection: TFF: 12 BFF: 0 Progressive: 988 Undetermined: 0`
+    let counts = stderr
+        .lines()
+        .rev()
+        .find_map(|line| line.split_once(MULTI_FRAME_KEY))
+        .map(|(_, counts)| counts.split_whitespace().collect::<Vec<_>>())
+        .ok_or_else(|| {
+            VideoEncodeError::Encoding("idet filter reported no frame counts".to_string())
+        })?;
+    let count = |key: &str| -> f64 {
+        counts
+            .windows(2)
+            .find(|pair| pair[0] == key)
+            .and_then(|pair| pair[1].parse().ok())
+            .unwrap_or_default()
+    };
+
+    let interlaced = count("TFF:") + count("BFF:");
+    let detected = interlaced + count("Progressive:");
+    let ratio = if detected > 0.0 {
+        interlaced / detected
+    } else {
+        0.0
+    };
+
+    let scan_type = if ratio >= INTERLACED_RATIO {
+        ScanType::Interlaced
+    } else if ratio >= TELECINED_RATIO {
+        ScanType::Telecined
+    } else {
+        ScanType::Progressive
+    };
+
+    info!(
+        "Input is {:?}, {:.0}% of analyzed frames are interlaced",
+        scan_type,
+        ratio * 100.0
+    );
+    Ok(scan_type)
+}
+
+/// Applies video `filter` to the input and writes it with all other streams into `output_path`.
+/// Video is re-encoded to lossless intermediate, so chunks are split from filtered frames.
+#[instrument]
+pub fn filter_input(
+    input_path: &Path,
+    filter: &str,
+    output_path: &Path,
+) -> Result<(), VideoEncodeError> {
+    if let Some(parent) = output_path.parent() {
+        fs::create_dir_all(parent)?;
+    }
+
+    let mut command = Command::new("ffmpeg");
+    command
+        .args(["-hide_banner", "-y", "-i"])
+        .arg(input_path)
+        .args(["-map", "0", "-c", "copy", "-vf", filter])
+        .args(LOSSLESS_INTERMEDIATE)
+        .arg(output_path);
+
+    debug!("FFmpeg command: {:?}", command);
+    let output = command.output()?;
+
+    if !output.status.success() {
+        error!(
+            "Failed to filter input with {}: {}",
+            filter,
+            String::from_utf8_lossy(&output.stderr)
+        );
+        return Err(VideoEncodeError::Encoding(format!(
+            "Failed to filter input with {}",
+            filter
+        )));
+    }
+
+    info!("Filtered input with {} into {:?}", filter, output_path);
+    Ok(())
+}
@@ -0,0 +1,197 @@
+/// This module joins AV1 and VP9 chunks as raw bitstreams in IVF, with timestamps of
+/// every chunk continuing from the end of the previous one. Output is muxed once from
+/// the joined stream, instead of the concat demuxer joining containers, which
+/// occasionally leaves stutters at joins.
+use std::{
+    fs,
+    io::{BufWriter, Seek, SeekFrom, Write},
+    path::{Path, PathBuf},
+    process::{Command, Stdio},
+};
+
+use tracing::{debug, error, info, instrument};
+
+use crate::error::VideoEncodeError;
+
+const SIGNATURE: &[u8] = b"DKIF";
+const HEADER_SIZE: usize = 32;
+const FRAME_HEADER_SIZE: usize = 12;
+/// Offset of the frame count in the file header
+const FRAME_COUNT_OFFSET: u64 = 24;
+
+/// Frame of an IVF file, with its timestamp in time base of the file
+struct Frame<'a> {
+    pts: u64,
+    data: &'a [u8],
+}
+
+/// Joins encoded chunks of `segment_paths` into IVF file `output`, in this order.
+/// Chunks are extracted into IVF in `temp_dir` first, and have to share codec,
+/// resolution and time base.
+#[instrument(skip(segment_paths))]
+pub fn join_ivf(
+    segment_paths: &[PathBuf],
+    output: &Path,
+    temp_dir: &Path,
+) -> Result<(), VideoEncodeError> {
+    let mut writer = BufWriter::new(fs::File::create(output)?);
+    let mut first_header: Option<[u8; HEADER_SIZE]> = None;
+    let mut frame_count: u32 = 0;
+    let mut offset: u64 = 0;
+    // Duration of a frame in time base of the file, taken from the last two frames
+    let mut frame_duration: u64 = 1;
+
+    for (number, segment) in segment_paths.iter().enumerate() {
+        let ivf = temp_dir.join(format!("segment_{}.ivf", number));
+        let data = extract_ivf(segment, &ivf).and_then(|()| Ok(fs::read(&ivf)?));
+        let _ = fs::remove_file(&ivf);
+        let data = data?;
+
+        let (header, frames) = parse_ivf(&data).ok_or_else(|| {
+            VideoEncodeError::Concatenation(format!("Chunk {:?} isn't valid IVF", segment))
+        })?;
+        match &first_header {
+            None => {
+                let mut header: [u8; HEADER_SIZE] =
+                    header.try_into().expect("header has fixed size");
+                // Longer headers of other muxers are cut to the standard size
+                header[6..8].copy_from_slice(&(HEADER_SIZE as u16).to_le_bytes());
+                writer.write_all(&header)?;
+                first_header = Some(header);
+            }
+            // Codec, resolution and time base follow the signature, version and size
+            Some(first) if first[8..24] != header[8..24] => {
+                return Err(VideoEncodeError::Concatenation(format!(
+                    "Chunk {:?} differs from the first chunk in codec, resolution or time base",
+                    segment
+                )));
+            }
+            Some(_) => {}
+        }
+
+        let Some(start) = frames.first().map(|frame| frame.pts) else {
+            continue;
+        };
+        for frame in &frames {
+            writer.write_all(&(frame.data.len() as u32).to_le_bytes())?;
+            writer.write_all(&(offset + frame.pts.saturating_sub(start)).to_le_bytes())?;
+            writer.write_all(frame.data)?;
+        }
+        if let [.., previous, last] = frames.as_slice() {
+            frame_duration = last.pts.saturating_sub(previous.pts).max(1);
+        }
+        let last = frames.last().map_or(start, |frame| frame.pts);
+        offset += last.saturating_sub(start) + frame_duration;
+        frame_count += frames.len() as u32;
+    }
+
+    let mut file = writer.into_inner().map_err(|e| e.into_error())?;
+    file.seek(SeekFrom::Start(FRAME_COUNT_OFFSET))?;
+    file.write_all(&frame_count.to_le_bytes())?;
+
+    info!(
+        "Joined {} frames of {} chunks into {:?}",
+        frame_count,
+        segment_paths.len(),
+        output
+    );
+    Ok(())
+}
+
+/// Copies video bitstream of `segment` into IVF file `output`
+fn extract_ivf(segment: &Path, output: &Path) -> Result<(), VideoEncodeError> {
+    debug!("Extracting bitstream of {:?}", segment);
+    let result = Command::new("ffmpeg")
+        .args(["-hide_banner", "-loglevel", "error", "-y", "-i"])
+        .arg(segment)
+        .args(["-map", "0:v:0", "-c", "copy", "-f", "ivf"])
+        .arg(output)
+        .stdin(Stdio::null())
+        .output()?;
+    if !result.status.success() {
+        error!(
+            "Failed to extract bitstream of {:?}: {}",
+            segment,
+            String::from_utf8_lossy(&result.stderr)
+        );
+        return Err(VideoEncodeError::Concatenation(format!(
+            "Failed to extract bitstream of {:?}, only AV1 and VP9 chunks can be joined in IVF",
+            segment
+        )));
+    }
+    Ok(())
+}
+
+/// Header and frames of IVF file, `None` when it's truncated or not IVF
+fn parse_ivf(data: &[u8]) -> Option<(&[u8], Vec<Frame<'_>>)> {
+    if data.len() < HEADER_SIZE || !data.starts_with(SIGNATURE) {
+        return None;
+    }
+    let header_size = u16::from_le_bytes(data[6..8].try_into().ok()?) as usize;
+    let (header, mut rest) = data.split_at_checked(header_size.max(HEADER_SIZE))?;
+
+    let mut frames = Vec::new();
+    while !rest.is_empty() {
+        let (frame_header, after) = rest.split_at_checked(FRAME_HEADER_SIZE)?;
+        let size = u32::from_le_bytes(frame_header[0..4].try_into().ok()?) as usize;
+        let pts = u64::from_le_bytes(frame_header[4..12].try_into().ok()?);
+        let (data, after) = after.split_at_checked(size)?;
+        frames.push(Frame { pts, data });
+        rest = after;
+    }
+    Some((&header[..HEADER_SIZE], frames))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// IVF file of AV1 with `header_size` long header and `frames` of pts and data
+    fn ivf(header_size: u16, frames: &[(u64, &[u8])]) -> Vec<u8> {
+        let mut data = SIGNATURE.to_vec();
+        data.extend(0u16.to_le_bytes());
+        data.extend(header_size.to_le_bytes());
+        data.extend(b"AV01");
+        data.resize(header_size as usize, 0);
+        for (pts, frame) in frames {
+            data.extend((frame.len() as u32).to_le_bytes());
+            data.extend(pts.to_le_bytes());
+            data.extend(*frame);
+        }
+        data
+    }
+
+    fn frames<'a>(frames: &[Frame<'a>]) -> Vec<(u64, &'a [u8])> {
+        frames.iter().map(|frame| (frame.pts, frame.data)).collect()
+    }
+
+    #[test]
+    fn frames_are_parsed_after_the_header() {
+        let data = ivf(32, &[(0, b"key"), (1, b"delta")]);
+        let (header, parsed) = parse_ivf(&data).unwrap();
+
+        assert_eq!(header, &data[..HEADER_SIZE]);
+        assert_eq!(
+            frames(&parsed),
+            vec![(0, b"key".as_slice()), (1, b"delta".as_slice())]
+        );
+    }
+
+    #[test]
+    fn longer_headers_are_skipped() {
+        let data = ivf(40, &[(7, b"frame")]);
+        let (header, parsed) = parse_ivf(&data).unwrap();
+
+        assert_eq!(header.len(), HEADER_SIZE);
+        assert_eq!(frames(&parsed), vec![(7, b"frame".as_slice())]);
+    }
+
+    #[test]
+    fn truncated_or_foreign_files_are_rejected() {
+        let data = ivf(32, &[(0, b"frame")]);
+        assert!(parse_ivf(&data[..data.len() - 1]).is_none());
+        assert!(parse_ivf(&data[..HEADER_SIZE + 4]).is_none());
+        assert!(parse_ivf(&data[..16]).is_none());
+        assert!(parse_ivf(b"RIFF0000000000000000000000000000").is_none());
+    }
+}
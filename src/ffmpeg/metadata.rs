@@ -0,0 +1,106 @@
+/// This module carries tags of the input over to the output, which chunks lose when
+/// they're split and encoded. Global tags, like title, and tags of the video track,
+/// like its name and language, are read from the input and written when the output is
+/// muxed. Tags that describe the encode of the input, like statistics of mkvmerge,
+/// aren't carried over.
+use std::{collections::BTreeMap, path::Path, process::Command};
+
+use serde::Deserialize;
+use tracing::{debug, error, instrument};
+
+use crate::error::VideoEncodeError;
+
+/// Tags that describe the file they're read from rather than its content
+const FILE_TAGS: [&str; 11] = [
+    "encoder",
+    "duration",
+    "number_of_frames",
+    "number_of_bytes",
+    "bps",
+    "major_brand",
+    "minor_version",
+    "compatible_brands",
+    "vendor_id",
+    "handler_name",
+    "creation_time",
+];
+/// Prefix of tags of mkvmerge that list which tags are its statistics
+const STATISTICS_PREFIX: &str = "_statistics";
+
+/// Tags written into the output
+#[derive(Debug, Clone, Default)]
+pub struct Metadata {
+    /// Tags of the file
+    pub global: BTreeMap<String, String>,
+    /// Tags of the video track
+    pub video: BTreeMap<String, String>,
+}
+
+impl Metadata {
+    /// Options of ffmpeg that write the tags, global tags of inputs aren't copied
+    pub fn args(&self) -> Vec<String> {
+        let mut args = vec!["-map_metadata".to_string(), "-1".to_string()];
+        for (key, value) in &self.global {
+            args.extend(["-metadata".to_string(), format!("{}={}", key, value)]);
+        }
+        for (key, value) in &self.video {
+            args.extend(["-metadata:s:v:0".to_string(), format!("{}={}", key, value)]);
+        }
+        args
+    }
+}
+
+#[derive(Debug, Deserialize)]
+struct TagsOutput {
+    #[serde(default)]
+    streams: Vec<Tagged>,
+    format: Option<Tagged>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+struct Tagged {
+    #[serde(default)]
+    tags: BTreeMap<String, String>,
+}
+
+/// Reads global tags and tags of the first video track of `path`
+#[instrument]
+pub fn read_metadata(path: &Path) -> Result<Metadata, VideoEncodeError> {
+    let output = Command::new("ffprobe")
+        .args(["-v", "error", "-select_streams", "v:0", "-of", "json"])
+        .args(["-show_entries", "format_tags:stream_tags"])
+        .arg(path)
+        .output()?;
+
+    if !output.status.success() {
+        error!(
+            "Failed to probe tags of {:?}: {}",
+            path,
+            String::from_utf8_lossy(&output.stderr)
+        );
+        return Err(VideoEncodeError::Encoding(format!(
+            "Failed to probe tags of {:?}",
+            path
+        )));
+    }
+
+    let output: TagsOutput = serde_json::from_slice(&output.stdout)?;
+    let metadata = Metadata {
+        global: content_tags(output.format.unwrap_or_default().tags),
+        video: content_tags(output.streams.into_iter().next().unwrap_or_default().tags),
+    };
+    debug!("Tags of {:?}: {:?}", path, metadata);
+    Ok(metadata)
+}
+
+/// Tags that describe content, without tags of the file
+fn content_tags(tags: BTreeMap<String, String>) -> BTreeMap<String, String> {
+    tags.into_iter()
+        .filter(|(key, _)| {
+            let key = key.to_ascii_lowercase();
+            // Statistics tags of mkvmerge may have language suffix, like `BPS-eng`
+            let name = key.split('-').next().unwrap_or_default();
+            !FILE_TAGS.contains(&name) && !key.starts_with(STATISTICS_PREFIX)
+        })
+        .collect()
+}
@@ -1,2 +1,23 @@
+pub mod audio;
+pub mod compare;
 pub mod concat;
+pub mod container;
+pub mod content;
+pub mod grain;
+pub mod interlace;
+pub mod ivf;
+pub mod metadata;
+pub mod mp4box;
+pub mod package;
+pub mod preview;
+pub mod probe;
+pub mod progress;
+pub mod quality;
+pub mod scene;
 pub mod segment;
+pub mod sequence;
+pub mod sync;
+pub mod timestamps;
+pub mod trim;
+pub mod verify;
+pub mod webm;
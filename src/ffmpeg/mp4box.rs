@@ -0,0 +1,124 @@
+/// This module joins chunks with MP4Box of GPAC, which keeps edit lists and negative
+/// composition time offsets of MP4 that the concat demuxer of ffmpeg mishandles
+use std::{
+    fs,
+    path::{Path, PathBuf},
+    process::{Command, Stdio},
+};
+
+use tracing::{debug, error, info, instrument};
+
+use crate::error::VideoEncodeError;
+
+/// Extensions of outputs that MP4Box joins chunks of
+const MP4_EXTENSIONS: [&str; 3] = ["mp4", "m4v", "mov"];
+
+/// Verifies that MP4Box is installed
+#[instrument]
+pub fn verify_mp4box() -> Result<(), VideoEncodeError> {
+    which::which("MP4Box").map_err(|e| {
+        error!("MP4Box not found: {}", e);
+        VideoEncodeError::Encoding(
+            "MP4Box is required to join chunks with it, but it's not installed".to_string(),
+        )
+    })?;
+
+    Ok(())
+}
+
+/// Whether `path` is an MP4 file by its extension
+pub fn is_mp4(path: &Path) -> bool {
+    path.extension()
+        .and_then(|extension| extension.to_str())
+        .is_some_and(|extension| {
+            MP4_EXTENSIONS
+                .iter()
+                .any(|mp4| mp4.eq_ignore_ascii_case(extension))
+        })
+}
+
+/// Joins video of encoded chunks of `segment_paths` into MP4 file `output`, in this order.
+/// Chunks are remuxed into MP4 in `temp_dir` first, and removed once they're joined.
+#[instrument(skip(segment_paths))]
+pub fn join_mp4(
+    segment_paths: &[PathBuf],
+    output: &Path,
+    temp_dir: &Path,
+) -> Result<(), VideoEncodeError> {
+    let mut remuxed = Vec::with_capacity(segment_paths.len());
+    let result = (|| {
+        for (number, segment) in segment_paths.iter().enumerate() {
+            let mp4 = temp_dir.join(format!("segment_{}.mp4", number));
+            remuxed.push(mp4.clone());
+            remux_mp4(segment, &mp4)?;
+        }
+        cat_mp4(&remuxed, output, temp_dir)
+    })();
+
+    for mp4 in &remuxed {
+        let _ = fs::remove_file(mp4);
+    }
+    result?;
+
+    info!(
+        "Joined {} chunks into {:?} with MP4Box",
+        segment_paths.len(),
+        output
+    );
+    Ok(())
+}
+
+/// Copies video of `segment` into MP4 file `output`
+fn remux_mp4(segment: &Path, output: &Path) -> Result<(), VideoEncodeError> {
+    debug!("Remuxing {:?} into MP4", segment);
+    let result = Command::new("ffmpeg")
+        .args(["-hide_banner", "-loglevel", "error", "-y", "-i"])
+        .arg(segment)
+        .args(["-map", "0:v:0", "-c", "copy", "-f", "mp4"])
+        .arg(output)
+        .stdin(Stdio::null())
+        .output()?;
+    if !result.status.success() {
+        error!(
+            "Failed to remux {:?} into MP4: {}",
+            segment,
+            String::from_utf8_lossy(&result.stderr)
+        );
+        return Err(VideoEncodeError::Concatenation(format!(
+            "Failed to remux {:?} into MP4",
+            segment
+        )));
+    }
+    Ok(())
+}
+
+/// Joins MP4 files `parts` into `output`, the first one is added and others are appended
+fn cat_mp4(parts: &[PathBuf], output: &Path, temp_dir: &Path) -> Result<(), VideoEncodeError> {
+    let Some((first, rest)) = parts.split_first() else {
+        return Err(VideoEncodeError::Concatenation(
+            "There are no chunks to join".to_string(),
+        ));
+    };
+
+    let mut command = Command::new("MP4Box");
+    command.args(["-quiet", "-tmp"]).arg(temp_dir);
+    command.arg("-add").arg(first);
+    for part in rest {
+        command.arg("-cat").arg(part);
+    }
+    let result = command
+        .arg("-new")
+        .arg(output)
+        .stdin(Stdio::null())
+        .output()?;
+    if !result.status.success() {
+        error!(
+            "Failed to join chunks with MP4Box: {}",
+            String::from_utf8_lossy(&result.stderr)
+        );
+        return Err(VideoEncodeError::Concatenation(
+            "Failed to join chunks with MP4Box".to_string(),
+        ));
+    }
+    Ok(())
+}
@@ -0,0 +1,78 @@
+/// This module packages outputs for streaming, into segments with a DASH manifest,
+/// HLS playlists, or both, written by the DASH muxer of ffmpeg. Streams are copied,
+/// so segments start at keyframes of the output.
+use std::{
+    fs,
+    path::{Path, PathBuf},
+    process::{Command, Stdio},
+};
+
+use tracing::{debug, error, info, instrument};
+
+use crate::error::VideoEncodeError;
+use crate::settings::{PackageFormat, PackageSettings};
+
+const MANIFEST: &str = "manifest.mpd";
+/// Master playlist of HLS, which lists playlists of every stream
+const MASTER_PLAYLIST: &str = "master.m3u8";
+
+/// Directory that `output` is packaged into, named after it, like `movie_package`
+pub fn package_dir(output: &Path) -> PathBuf {
+    let stem = output.file_stem().unwrap_or_default().to_string_lossy();
+    output.with_file_name(format!("{}_package", stem))
+}
+
+/// Packages video and audio of `output` into its package directory as `settings` say.
+/// Returns the manifest, or the master playlist when only HLS is written.
+#[instrument(skip(settings))]
+pub fn package_output(
+    output: &Path,
+    format: PackageFormat,
+    settings: &PackageSettings,
+) -> Result<PathBuf, VideoEncodeError> {
+    let dir = package_dir(output);
+    if dir.exists() {
+        fs::remove_dir_all(&dir)?;
+    }
+    fs::create_dir_all(&dir)?;
+
+    let manifest = dir.join(MANIFEST);
+    let hls = matches!(format, PackageFormat::Hls | PackageFormat::Both);
+    let mut command = Command::new("ffmpeg");
+    command
+        .args(["-hide_banner", "-loglevel", "error", "-y", "-i"])
+        .arg(output)
+        // Segments only hold video and audio
+        .args(["-map", "0:v", "-map", "0:a?", "-c", "copy"])
+        .args(["-f", "dash", "-use_template", "1", "-use_timeline", "1"])
+        .args(["-seg_duration", &settings.segment_duration.to_string()])
+        .args(["-init_seg_name", &settings.init_segment])
+        .args(["-media_seg_name", &settings.media_segment]);
+    if hls {
+        command.args(["-hls_playlist", "1", "-hls_master_name", MASTER_PLAYLIST]);
+    }
+    debug!("Packaging command: {:?}", command);
+
+    let result = command.arg(&manifest).stdin(Stdio::null()).output()?;
+    if !result.status.success() {
+        error!(
+            "Failed to package {:?}: {}",
+            output,
+            String::from_utf8_lossy(&result.stderr)
+        );
+        return Err(VideoEncodeError::Encoding(format!(
+            "Failed to package {:?}",
+            output
+        )));
+    }
+
+    // Playlists list the same segments as the manifest, which isn't kept without DASH
+    let entry = if format == PackageFormat::Hls {
+        fs::remove_file(&manifest)?;
+        dir.join(MASTER_PLAYLIST)
+    } else {
+        manifest
+    };
+    info!("Packaged {:?} into {:?}", output, entry);
+    Ok(entry)
+}
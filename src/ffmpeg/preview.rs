@@ -0,0 +1,61 @@
+/// This module joins chunks encoded so far into a preview of the output, so it can be
+/// watched long before the whole input is encoded. Preview only has video, and is
+/// replaced at once when it grows, so players never read it half written.
+use std::{
+    path::{Path, PathBuf},
+    process::Stdio,
+};
+
+use tokio::process::Command;
+use tracing::{debug, error, instrument};
+
+use crate::error::VideoEncodeError;
+use crate::ffmpeg::concat::concat_list;
+
+/// Preview of `output`, next to it, like `movie.preview.mkv`
+pub fn preview_path(output: &Path) -> PathBuf {
+    let stem = output.file_stem().unwrap_or_default().to_string_lossy();
+    output.with_file_name(format!("{}.preview.mkv", stem))
+}
+
+/// Joins video of encoded chunks of `segment_paths` into `preview`, in this order.
+/// List of chunks is written into `temp_dir`.
+#[instrument(skip(segment_paths))]
+pub async fn write_preview(
+    segment_paths: &[PathBuf],
+    preview: &Path,
+    temp_dir: &Path,
+) -> Result<(), VideoEncodeError> {
+    // Renditions share temporary directory, so lists are named after their previews
+    let name = preview.file_stem().unwrap_or_default().to_string_lossy();
+    let list = temp_dir.join(format!("{}.txt", name));
+    tokio::fs::write(&list, concat_list(segment_paths)?).await?;
+
+    // Written next to the preview, so it's renamed over it on the same file system
+    let written = preview.with_extension("mkv.part");
+    let result = Command::new("ffmpeg")
+        .args(["-hide_banner", "-loglevel", "error", "-y"])
+        .args(["-f", "concat", "-safe", "0", "-i"])
+        .arg(&list)
+        .args(["-map", "0:v", "-c", "copy", "-f", "matroska"])
+        .arg(&written)
+        .stdin(Stdio::null())
+        .kill_on_drop(true)
+        .output()
+        .await?;
+    if !result.status.success() {
+        error!(
+            "Failed to write preview {:?}: {}",
+            preview,
+            String::from_utf8_lossy(&result.stderr)
+        );
+        return Err(VideoEncodeError::Concatenation(format!(
+            "Failed to write preview {:?}",
+            preview
+        )));
+    }
+
+    tokio::fs::rename(&written, preview).await?;
+    debug!("Preview {:?} has {} chunks", preview, segment_paths.len());
+    Ok(())
+}
@@ -0,0 +1,391 @@
+/// This module queries properties of media files with ffprobe
+use std::{path::Path, process::Command};
+
+use serde::{Deserialize, Serialize};
+use tracing::{debug, error, instrument};
+
+use crate::error::VideoEncodeError;
+
+/// Properties of the first video stream of a file
+#[derive(Debug, Default, Clone, PartialEq, Serialize, Deserialize)]
+pub struct MediaInfo {
+    pub frames: u64,
+    /// Duration in seconds
+    pub duration: f64,
+    pub width: u32,
+    pub height: u32,
+    /// Size of the file in bytes, not known for parts of a file
+    pub size: Option<u64>,
+}
+
+#[derive(Deserialize)]
+struct ProbeOutput {
+    #[serde(default)]
+    streams: Vec<ProbeStream>,
+    format: Option<ProbeFormat>,
+}
+
+#[derive(Deserialize)]
+struct ProbeStream {
+    width: Option<u32>,
+    height: Option<u32>,
+    nb_read_packets: Option<String>,
+    avg_frame_rate: Option<String>,
+    color_transfer: Option<String>,
+    pix_fmt: Option<String>,
+}
+
+/// Stream of any type, as listed by `probe_streams`
+#[derive(Debug, Clone, Deserialize)]
+pub struct StreamInfo {
+    /// Index of the stream among all streams of the file
+    pub index: usize,
+    /// Type of the stream, like `audio` or `subtitle`
+    pub codec_type: String,
+    pub codec_name: Option<String>,
+    pub channels: Option<u32>,
+    #[serde(default)]
+    pub tags: StreamTags,
+}
+
+#[derive(Debug, Clone, Default, Deserialize)]
+pub struct StreamTags {
+    /// Language of the stream, like `eng`
+    pub language: Option<String>,
+    pub title: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct StreamsOutput {
+    #[serde(default)]
+    streams: Vec<StreamInfo>,
+}
+
+#[derive(Deserialize)]
+struct ProbeFormat {
+    duration: Option<String>,
+    size: Option<String>,
+}
+
+/// Returns properties of the file. Frames are counted, so whole file is read.
+#[instrument]
+pub fn probe_media(path: &Path) -> Result<MediaInfo, VideoEncodeError> {
+    let probe = run_json_probe(
+        path,
+        &[
+            "-count_packets",
+            "-show_entries",
+            "stream=width,height,nb_read_packets:format=duration,size",
+        ],
+    )?;
+
+    let stream = probe.streams.first();
+    let format = probe.format.as_ref();
+    let info = MediaInfo {
+        frames: stream
+            .and_then(|stream| stream.nb_read_packets.as_ref()?.parse().ok())
+            .unwrap_or_default(),
+        duration: format
+            .and_then(|format| format.duration.as_ref()?.parse().ok())
+            .unwrap_or_default(),
+        width: stream.and_then(|stream| stream.width).unwrap_or_default(),
+        height: stream.and_then(|stream| stream.height).unwrap_or_default(),
+        size: format.and_then(|format| format.size.as_ref()?.parse().ok()),
+    };
+
+    debug!("Probed {:?}: {:?}", path, info);
+    Ok(info)
+}
+
+/// Returns width and height of the first video stream
+#[instrument]
+pub fn probe_resolution(path: &Path) -> Result<(u32, u32), VideoEncodeError> {
+    let probe = run_json_probe(path, &["-show_entries", "stream=width,height"])?;
+
+    probe
+        .streams
+        .first()
+        .and_then(|stream| Some((stream.width?, stream.height?)))
+        .ok_or_else(|| VideoEncodeError::Encoding(format!("No video stream found in {:?}", path)))
+}
+
+/// Returns average frame rate of the first video stream
+#[instrument]
+pub fn probe_frame_rate(path: &Path) -> Result<f64, VideoEncodeError> {
+    let probe = run_json_probe(path, &["-show_entries", "stream=avg_frame_rate"])?;
+
+    // Rate is a fraction like `24000/1001`, or `0/0` when it's not known
+    probe
+        .streams
+        .first()
+        .and_then(|stream| {
+            let (num, den) = stream.avg_frame_rate.as_ref()?.split_once('/')?;
+            let rate = num.parse::<f64>().ok()? / den.parse::<f64>().ok()?;
+            (rate.is_finite() && rate > 0.0).then_some(rate)
+        })
+        .ok_or_else(|| VideoEncodeError::Encoding(format!("Frame rate of {:?} is not known", path)))
+}
+
+/// Returns transfer characteristics of the first video stream, like `bt709`
+/// or `smpte2084`, `None` when they are not tagged
+#[instrument]
+pub fn probe_color_transfer(path: &Path) -> Result<Option<String>, VideoEncodeError> {
+    let probe = run_json_probe(path, &["-show_entries", "stream=color_transfer"])?;
+
+    Ok(probe
+        .streams
+        .into_iter()
+        .next()
+        .and_then(|stream| stream.color_transfer))
+}
+
+/// Returns pixel format of the first video stream, like `yuv420p`
+#[instrument]
+pub fn probe_pixel_format(path: &Path) -> Result<Option<String>, VideoEncodeError> {
+    let probe = run_json_probe(path, &["-show_entries", "stream=pix_fmt"])?;
+
+    Ok(probe
+        .streams
+        .into_iter()
+        .next()
+        .and_then(|stream| stream.pix_fmt))
+}
+
+/// Returns all streams of the file, in order of their indices
+#[instrument]
+pub fn probe_streams(path: &Path) -> Result<Vec<StreamInfo>, VideoEncodeError> {
+    let output = Command::new("ffprobe")
+        .args(["-v", "error", "-of", "json", "-show_entries"])
+        .arg("stream=index,codec_type,codec_name,channels:stream_tags=language,title")
+        .arg(path)
+        .output()?;
+
+    if !output.status.success() {
+        error!(
+            "Failed to probe streams of {:?}: {}",
+            path,
+            String::from_utf8_lossy(&output.stderr)
+        );
+        return Err(VideoEncodeError::Encoding(format!(
+            "Failed to probe streams of {:?}",
+            path
+        )));
+    }
+
+    let streams = serde_json::from_slice::<StreamsOutput>(&output.stdout)?.streams;
+    debug!("Streams of {:?}: {:?}", path, streams);
+    Ok(streams)
+}
+
+fn run_json_probe(path: &Path, args: &[&str]) -> Result<ProbeOutput, VideoEncodeError> {
+    let output = Command::new("ffprobe")
+        .args(["-v", "error", "-select_streams", "v:0", "-of", "json"])
+        .args(args)
+        .arg(path)
+        .output()?;
+
+    if !output.status.success() {
+        error!(
+            "Failed to probe {:?}: {}",
+            path,
+            String::from_utf8_lossy(&output.stderr)
+        );
+        return Err(VideoEncodeError::Encoding(format!(
+            "Failed to probe {:?}",
+            path
+        )));
+    }
+
+    Ok(serde_json::from_slice(&output.stdout)?)
+}
+
+/// Returns duration of the file in seconds
+#[instrument]
+pub fn probe_duration(path: &Path) -> Result<f64, VideoEncodeError> {
+    let output = Command::new("ffprobe")
+        .args([
+            "-v",
+            "error",
+            "-show_entries",
+            "format=duration",
+            "-of",
+            "default=noprint_wrappers=1:nokey=1",
+        ])
+        .arg(path)
+        .output()?;
+
+    if !output.status.success() {
+        error!(
+            "Failed to probe {:?}: {}",
+            path,
+            String::from_utf8_lossy(&output.stderr)
+        );
+        return Err(VideoEncodeError::Encoding(format!(
+            "Failed to probe duration of {:?}",
+            path
+        )));
+    }
+
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    let duration = stdout.trim().parse().map_err(|_| {
+        VideoEncodeError::Encoding(format!(
+            "Unexpected duration of {:?}: {:?}",
+            path,
+            stdout.trim()
+        ))
+    })?;
+
+    debug!("Duration of {:?} is {}s", path, duration);
+    Ok(duration)
+}
+
+/// Returns presentation timestamps of all frames of the first video stream
+/// in seconds, in ascending order
+#[instrument]
+pub fn probe_frame_times(path: &Path) -> Result<Vec<f64>, VideoEncodeError> {
+    probe_packet_times(path, false)
+}
+
+/// Returns presentation timestamps of keyframes of the first video stream
+/// in seconds, in ascending order
+#[instrument]
+pub fn probe_keyframe_times(path: &Path) -> Result<Vec<f64>, VideoEncodeError> {
+    probe_packet_times(path, true)
+}
+
+fn probe_packet_times(path: &Path, keyframes_only: bool) -> Result<Vec<f64>, VideoEncodeError> {
+    // Packets are in decoding order, which differs from presentation order with B-frames
+    let mut times: Vec<f64> = probe_packets(path, None)?
+        .into_iter()
+        .filter(|(_, keyframe)| !keyframes_only || *keyframe)
+        .map(|(time, _)| time)
+        .collect();
+    times.sort_by(f64::total_cmp);
+
+    debug!(
+        "{:?} has {} {}",
+        path,
+        times.len(),
+        if keyframes_only {
+            "keyframes"
+        } else {
+            "frames"
+        }
+    );
+    Ok(times)
+}
+
+/// Whether the first `seconds` of the file contain open GOPs, where frames following
+/// a keyframe in decoding order are shown before it. Such frames reference the previous
+/// GOP, so they can't be decoded when the file is split at that keyframe.
+#[instrument]
+pub fn probe_open_gop(path: &Path, seconds: u32) -> Result<bool, VideoEncodeError> {
+    let packets = probe_packets(path, Some(&format!("%+{}", seconds)))?;
+
+    // Leading frames of the very first GOP have nothing to reference, so they're skipped
+    let mut keyframe = None;
+    for (time, is_keyframe) in packets {
+        if is_keyframe {
+            keyframe = Some(keyframe.map_or(f64::NEG_INFINITY, |_| time));
+        } else if keyframe.is_some_and(|keyframe| time < keyframe) {
+            debug!("Frame at {}s leads keyframe at {:?}s", time, keyframe);
+            return Ok(true);
+        }
+    }
+
+    Ok(false)
+}
+
+/// Returns decoding timestamp in seconds and size in bytes of packets
+/// of the first video stream, in decoding order
+#[instrument]
+pub fn probe_packet_sizes(path: &Path) -> Result<Vec<(f64, u64)>, VideoEncodeError> {
+    // Lines look like `1.234000,5678`
+    Ok(run_packet_probe(path, "v:0", "packet=dts_time,size", None)?
+        .lines()
+        .filter_map(|line| line.trim().split_once(','))
+        .filter_map(|(time, size)| Some((time.parse().ok()?, size.parse().ok()?)))
+        .collect())
+}
+
+/// Returns timestamp and keyframe flag of packets of the first video stream,
+/// in decoding order. `interval` limits which part of the file is read.
+fn probe_packets(
+    path: &Path,
+    interval: Option<&str>,
+) -> Result<Vec<(f64, bool)>, VideoEncodeError> {
+    // Lines look like `1.234000,K__`, where K marks keyframes
+    Ok(
+        run_packet_probe(path, "v:0", "packet=pts_time,flags", interval)?
+            .lines()
+            .filter_map(|line| line.trim().split_once(','))
+            .filter_map(|(time, flags)| Some((time.parse().ok()?, flags.contains('K'))))
+            .collect(),
+    )
+}
+
+/// Returns start and end in seconds of stream `stream` of the file, like `a:0`, from
+/// timestamps of its packets. `None` when the file has no such stream.
+#[instrument]
+pub fn probe_stream_span(
+    path: &Path,
+    stream: &str,
+) -> Result<Option<(f64, f64)>, VideoEncodeError> {
+    // Lines look like `1.234000,0.041000`, duration is missing in some containers
+    let mut span: Option<(f64, f64)> = None;
+    for line in run_packet_probe(path, stream, "packet=pts_time,duration_time", None)?.lines() {
+        let mut values = line.trim().split(',');
+        let Some(Ok(pts)) = values.next().map(str::parse::<f64>) else {
+            continue;
+        };
+        let end = pts
+            + values
+                .next()
+                .and_then(|value| value.parse().ok())
+                .unwrap_or(0.0);
+        span = Some(match span {
+            Some((start, last)) => (start.min(pts), last.max(end)),
+            None => (pts, end),
+        });
+    }
+    Ok(span)
+}
+
+/// Returns `entries` of packets of stream `stream` as CSV lines
+fn run_packet_probe(
+    path: &Path,
+    stream: &str,
+    entries: &str,
+    interval: Option<&str>,
+) -> Result<String, VideoEncodeError> {
+    // Packets are read without decoding, which is much faster than probing frames
+    let mut command = Command::new("ffprobe");
+    command.args([
+        "-v",
+        "error",
+        "-select_streams",
+        stream,
+        "-show_entries",
+        entries,
+        "-of",
+        "csv=p=0",
+    ]);
+    if let Some(interval) = interval {
+        command.args(["-read_intervals", interval]);
+    }
+    let output = command.arg(path).output()?;
+
+    if !output.status.success() {
+        error!(
+            "Failed to probe frames of {:?}: {}",
+            path,
+            String::from_utf8_lossy(&output.stderr)
+        );
+        return Err(VideoEncodeError::Encoding(format!(
+            "Failed to probe frames of {:?}",
+            path
+        )));
+    }
+
+    Ok(String::from_utf8_lossy(&output.stdout).to_string())
+}
@@ -0,0 +1,121 @@
+/// This module parses output of ffmpeg `-progress` option,
+/// which is a stream of `key=value` lines, with each block of values
+/// terminated by `progress=continue` or `progress=end`
+use serde::{Deserialize, Serialize};
+
+/// Snapshot of encoding progress reported by ffmpeg
+#[derive(Debug, Default, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Progress {
+    /// Number of frames encoded so far
+    pub frame: u64,
+    /// Current encoding speed in frames per second
+    pub fps: f64,
+    /// Current bitrate of the output in kbit/s
+    pub bitrate_kbps: f64,
+    /// Duration of encoded output in seconds
+    pub out_time: f64,
+    /// Encoding speed relative to realtime
+    pub speed: f64,
+    /// Whether this is the last report of the encode
+    pub done: bool,
+}
+
+/// Accumulates `-progress` lines into `Progress` reports
+#[derive(Debug, Default)]
+pub struct ProgressParser {
+    current: Progress,
+}
+
+impl ProgressParser {
+    /// Feeds a single line of output, returns progress once block is complete
+    pub fn push_line(&mut self, line: &str) -> Option<Progress> {
+        let (key, value) = line.trim().split_once('=')?;
+        let value = value.trim();
+
+        match key {
+            "frame" => self.current.frame = value.parse().unwrap_or(self.current.frame),
+            "fps" => self.current.fps = value.parse().unwrap_or(self.current.fps),
+            "bitrate" => {
+                if let Ok(bitrate) = value.trim_end_matches("kbits/s").parse() {
+                    self.current.bitrate_kbps = bitrate;
+                }
+            }
+            // Despite the name, value is in microseconds
+            "out_time_ms" | "out_time_us" => {
+                if let Ok(us) = value.parse::<i64>() {
+                    self.current.out_time = us.max(0) as f64 / 1_000_000.0;
+                }
+            }
+            "speed" => {
+                if let Ok(speed) = value.trim_end_matches('x').parse() {
+                    self.current.speed = speed;
+                }
+            }
+            "progress" => {
+                self.current.done = value == "end";
+                return Some(self.current.clone());
+            }
+            _ => {}
+        }
+
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn push_block(parser: &mut ProgressParser, block: &str) -> Vec<Progress> {
+        block
+            .lines()
+            .filter_map(|line| parser.push_line(line))
+            .collect()
+    }
+
+    #[test]
+    fn reports_progress_of_every_block() {
+        let mut parser = ProgressParser::default();
+        let reports = push_block(
+            &mut parser,
+            "frame=120\nfps=24.5\nbitrate= 812.4kbits/s\nout_time_us=5000000\n\
+             speed=1.02x\nprogress=continue\nframe=240\nprogress=end\n",
+        );
+
+        assert_eq!(
+            reports,
+            vec![
+                Progress {
+                    frame: 120,
+                    fps: 24.5,
+                    bitrate_kbps: 812.4,
+                    out_time: 5.0,
+                    speed: 1.02,
+                    done: false,
+                },
+                Progress {
+                    frame: 240,
+                    fps: 24.5,
+                    bitrate_kbps: 812.4,
+                    out_time: 5.0,
+                    speed: 1.02,
+                    done: true,
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn keeps_last_values_when_they_are_unknown() {
+        let mut parser = ProgressParser::default();
+        let reports = push_block(
+            &mut parser,
+            "frame=10\nspeed=2x\nprogress=continue\nframe=N/A\nbitrate=N/A\n\
+             speed=N/A\nout_time_us=-5\nnot a value\nprogress=continue\n",
+        );
+
+        assert_eq!(reports[1].frame, 10);
+        assert_eq!(reports[1].speed, 2.0);
+        assert_eq!(reports[1].out_time, 0.0);
+    }
+}
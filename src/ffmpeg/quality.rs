@@ -0,0 +1,374 @@
+/// This module measures quality of encoded video against its source, with ffmpeg
+/// filters of VMAF, SSIM or PSNR, or with butteraugli of libjxl on sampled frames.
+/// Any metric can be measured on every Nth frame only, averaged over measured frames.
+/// Scores of measured frames are kept along with the score of the video.
+use std::{
+    path::{Path, PathBuf},
+    process::Stdio,
+};
+
+use tokio::process::Command;
+use tracing::{debug, error, instrument};
+
+use crate::error::VideoEncodeError;
+use crate::settings::{QualityMetric, VmafModel, VmafSettings};
+
+/// Format both videos are compared in, so sources of any bit depth match encodes
+const COMPARED_FORMAT: &str = "yuv420p10le";
+/// PSNR of identical frames is infinite, it's reported as this many dB
+const MAX_PSNR: f64 = 100.0;
+/// Butteraugli compares single images slowly, so at most every this many frames are compared
+const BUTTERAUGLI_INTERVAL: u32 = 24;
+
+/// How quality is measured, whatever the metric
+#[derive(Debug, Clone, Default)]
+pub struct Measurement {
+    /// Model VMAF is computed with
+    pub vmaf: VmafSettings,
+    /// Only every this many frames are measured, every frame when 0 or 1
+    pub subsample: u32,
+}
+
+/// Score of a video and scores of its measured frames, in order
+#[derive(Debug, Clone, Default)]
+pub struct Scores {
+    pub score: f64,
+    pub frames: Vec<f64>,
+}
+
+/// Measures quality of `encoded` against `reference`, which is filtered with `filter`
+/// first, so its frames match frames the encoder was given. Empty filter leaves
+/// reference as it is. ffmpeg is killed if returned future is dropped before it completes.
+pub async fn measure_quality(
+    encoded: &Path,
+    reference: &Path,
+    filter: &str,
+    metric: QualityMetric,
+    measurement: &Measurement,
+) -> Result<f64, VideoEncodeError> {
+    Ok(
+        measure_frames(encoded, reference, filter, metric, measurement)
+            .await?
+            .score,
+    )
+}
+
+/// Measures quality of `encoded` like [`measure_quality`], along with scores of
+/// measured frames
+#[instrument]
+pub async fn measure_frames(
+    encoded: &Path,
+    reference: &Path,
+    filter: &str,
+    metric: QualityMetric,
+    measurement: &Measurement,
+) -> Result<Scores, VideoEncodeError> {
+    // libvmaf subsamples itself, so motion between neighbouring frames is still measured
+    let mut compared = format!("format={},setpts=PTS-STARTPTS", COMPARED_FORMAT);
+    if measurement.subsample > 1 && metric != QualityMetric::Vmaf {
+        compared.push_str(&format!(
+            ",select='not(mod(n\\,{}))'",
+            measurement.subsample
+        ));
+    }
+    let reference_chain = reference_chain(filter, &compared);
+    // Summaries look like `VMAF score: 93.1`, `SSIM Y:... All:0.98 (17.2)`
+    // and `PSNR y:... average:41.2 min:...`. Scores of SSIM and PSNR frames are printed
+    // from their metadata, libvmaf logs them into a file.
+    let vmaf_log = encoded.with_extension("vmaf.json");
+    let (metric_filter, marker, frame_key) = match metric {
+        QualityMetric::Vmaf => (vmaf_filter(measurement, &vmaf_log), "VMAF score:", None),
+        QualityMetric::Ssim => (
+            "ssim,metadata=mode=print:key=lavfi.ssim.All".to_string(),
+            " All:",
+            Some("lavfi.ssim.All="),
+        ),
+        QualityMetric::Psnr => (
+            "psnr,metadata=mode=print:key=lavfi.psnr.psnr_avg".to_string(),
+            " average:",
+            Some("lavfi.psnr.psnr_avg="),
+        ),
+        QualityMetric::ButteraugliMax | QualityMetric::ButteraugliPnorm => {
+            let interval = BUTTERAUGLI_INTERVAL.max(measurement.subsample);
+            return measure_butteraugli(encoded, reference, filter, metric, interval).await;
+        }
+    };
+    let graph = format!(
+        "[0:v:0]{}[encoded];[1:v:0]{}[reference];[encoded][reference]{}",
+        compared, reference_chain, metric_filter
+    );
+
+    let output = Command::new("ffmpeg")
+        .args(["-hide_banner", "-nostats", "-i"])
+        .arg(encoded)
+        .arg("-i")
+        .arg(reference)
+        .args(["-lavfi", &graph, "-f", "null", "-"])
+        .stdin(Stdio::null())
+        .kill_on_drop(true)
+        .output()
+        .await;
+    let vmaf_frames = match metric {
+        QualityMetric::Vmaf => {
+            let frames = read_vmaf_log(&vmaf_log).await;
+            let _ = tokio::fs::remove_file(&vmaf_log).await;
+            frames
+        }
+        _ => Vec::new(),
+    };
+    let output = output?;
+
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    if !output.status.success() {
+        error!("Failed to measure quality of {:?}: {}", encoded, stderr);
+        return Err(VideoEncodeError::Encoding(format!(
+            "Failed to measure quality of {:?}",
+            encoded
+        )));
+    }
+
+    let score = stderr
+        .lines()
+        .filter_map(|line| line.split_once(marker))
+        .filter_map(|(_, rest)| rest.split_whitespace().next())
+        .next_back()
+        .and_then(|score| score.parse::<f64>().ok())
+        .ok_or_else(|| {
+            VideoEncodeError::Encoding(format!(
+                "Quality measurement of {:?} has no result",
+                encoded
+            ))
+        })?;
+    let frames = match frame_key {
+        Some(key) => stderr
+            .lines()
+            .filter_map(|line| line.split_once(key))
+            .filter_map(|(_, value)| value.trim().parse::<f64>().ok())
+            .collect(),
+        None => vmaf_frames,
+    };
+    let (score, frames) = match metric {
+        QualityMetric::Psnr => (
+            score.min(MAX_PSNR),
+            frames
+                .into_iter()
+                .map(|frame| frame.min(MAX_PSNR))
+                .collect(),
+        ),
+        _ => (score, frames),
+    };
+
+    debug!(
+        "{:?} has {:?} of {:.3} in {} frames",
+        encoded,
+        metric,
+        score,
+        frames.len()
+    );
+    Ok(Scores { score, frames })
+}
+
+/// Scores of frames in JSON log of libvmaf, which looks like
+/// `{"frames": [{"frameNum": 0, "metrics": {"vmaf": 93.1, ...}}, ...]}`.
+/// Empty when log can't be read, frames are reported only.
+async fn read_vmaf_log(path: &Path) -> Vec<f64> {
+    let Ok(content) = tokio::fs::read(path).await else {
+        return Vec::new();
+    };
+    let Ok(log) = serde_json::from_slice::<serde_json::Value>(&content) else {
+        return Vec::new();
+    };
+    log["frames"]
+        .as_array()
+        .into_iter()
+        .flatten()
+        .filter_map(|frame| frame["metrics"]["vmaf"].as_f64())
+        .collect()
+}
+
+/// libvmaf filter of `measurement`, which logs scores of frames into `log`.
+/// Options of the model are separated by colons, which are escaped within options of the filter.
+fn vmaf_filter(measurement: &Measurement, log: &Path) -> String {
+    let settings = &measurement.vmaf;
+    let model = match (&settings.model_path, settings.model) {
+        (Some(path), _) => Some(format!("path={}", path.display())),
+        (None, VmafModel::Hd) => None,
+        (None, VmafModel::Uhd) => Some("version=vmaf_4k_v0.6.1".to_string()),
+        (None, VmafModel::Phone) => Some("version=vmaf_v0.6.1\\:enable_transform=true".to_string()),
+        (None, VmafModel::Neg) => Some("version=vmaf_v0.6.1neg".to_string()),
+    };
+
+    let mut options: Vec<String> = model
+        .map(|model| format!("model={}", model))
+        .into_iter()
+        .collect();
+    if measurement.subsample > 1 {
+        options.push(format!("n_subsample={}", measurement.subsample));
+    }
+    options.push("log_fmt=json".to_string());
+    options.push(format!("log_path={}", log.display()));
+    format!("libvmaf={}", options.join(":"))
+}
+
+/// Filter chain of reference frames, `filter` followed by `chain`
+fn reference_chain(filter: &str, chain: &str) -> String {
+    if filter.is_empty() {
+        chain.to_string()
+    } else {
+        format!("{},{}", filter, chain)
+    }
+}
+
+/// Measures butteraugli distance of frames sampled from `encoded` against the same frames
+/// of `reference`, with `butteraugli_main` of libjxl. Maximum is the largest distance
+/// of all frames, p-norm is the average of their 3-norms. Every `interval` frames are
+/// compared. Frames are extracted next to `encoded`, and removed once they're compared.
+async fn measure_butteraugli(
+    encoded: &Path,
+    reference: &Path,
+    filter: &str,
+    metric: QualityMetric,
+    interval: u32,
+) -> Result<Scores, VideoEncodeError> {
+    let dir = encoded.with_extension("butteraugli");
+    tokio::fs::create_dir_all(&dir).await?;
+    let result = compare_frames(encoded, reference, filter, metric, interval, &dir).await;
+    let _ = tokio::fs::remove_dir_all(&dir).await;
+    result
+}
+
+async fn compare_frames(
+    encoded: &Path,
+    reference: &Path,
+    filter: &str,
+    metric: QualityMetric,
+    interval: u32,
+    dir: &Path,
+) -> Result<Scores, VideoEncodeError> {
+    // Frames are selected by their number, so both videos give the same ones
+    let sampled = format!(
+        "setpts=PTS-STARTPTS,select='not(mod(n\\,{}))',format=rgb24",
+        interval
+    );
+    let graph = format!(
+        "[0:v:0]{}[encoded];[1:v:0]{}[reference]",
+        sampled,
+        reference_chain(filter, &sampled)
+    );
+    let frames = |name: &str| dir.join(format!("{}_%06d.png", name));
+    let output = Command::new("ffmpeg")
+        .args(["-hide_banner", "-loglevel", "error", "-i"])
+        .arg(encoded)
+        .arg("-i")
+        .arg(reference)
+        .args(["-filter_complex", &graph])
+        .args(["-map", "[encoded]", "-fps_mode", "passthrough"])
+        .arg(frames("encoded"))
+        .args(["-map", "[reference]", "-fps_mode", "passthrough"])
+        .arg(frames("reference"))
+        .stdin(Stdio::null())
+        .kill_on_drop(true)
+        .output()
+        .await?;
+    if !output.status.success() {
+        error!(
+            "Failed to extract frames of {:?}: {}",
+            encoded,
+            String::from_utf8_lossy(&output.stderr)
+        );
+        return Err(VideoEncodeError::Encoding(format!(
+            "Failed to extract frames of {:?} for butteraugli",
+            encoded
+        )));
+    }
+
+    let mut references: Vec<PathBuf> = std::fs::read_dir(dir)?
+        .filter_map(|entry| Some(entry.ok()?.path()))
+        .filter(|path| {
+            path.file_name()
+                .is_some_and(|name| name.to_string_lossy().starts_with("reference_"))
+        })
+        .collect();
+    references.sort();
+
+    let mut distances = Vec::with_capacity(references.len());
+    for reference_frame in references {
+        let name = reference_frame
+            .file_name()
+            .unwrap_or_default()
+            .to_string_lossy();
+        let encoded_frame = dir.join(name.replacen("reference_", "encoded_", 1));
+        if !encoded_frame.exists() {
+            continue;
+        }
+        distances.push(butteraugli(&reference_frame, &encoded_frame).await?);
+    }
+    if distances.is_empty() {
+        return Err(VideoEncodeError::Encoding(format!(
+            "Butteraugli measurement of {:?} has no frames",
+            encoded
+        )));
+    }
+
+    let frames: Vec<f64> = match metric {
+        QualityMetric::ButteraugliMax => distances.iter().map(|(max, _)| *max).collect(),
+        _ => distances.iter().map(|(_, pnorm)| *pnorm).collect(),
+    };
+    let score = match metric {
+        QualityMetric::ButteraugliMax => frames.iter().copied().fold(0.0, f64::max),
+        _ => frames.iter().sum::<f64>() / frames.len() as f64,
+    };
+    debug!(
+        "{:?} has {:?} of {:.3} in {} frames",
+        encoded,
+        metric,
+        score,
+        distances.len()
+    );
+    Ok(Scores { score, frames })
+}
+
+/// Maximum distance and 3-norm of distances between two images. Output of
+/// `butteraugli_main` looks like `1.2345` on the first line and `3-norm: 0.5678`.
+async fn butteraugli(reference: &Path, distorted: &Path) -> Result<(f64, f64), VideoEncodeError> {
+    let output = Command::new("butteraugli_main")
+        .arg(reference)
+        .arg(distorted)
+        .args(["--pnorm", "3"])
+        .stdin(Stdio::null())
+        .kill_on_drop(true)
+        .output()
+        .await
+        .map_err(|e| {
+            VideoEncodeError::Encoding(format!("Failed to run butteraugli_main of libjxl: {}", e))
+        })?;
+
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    if !output.status.success() {
+        error!(
+            "Failed to compare {:?} with butteraugli: {}",
+            distorted,
+            String::from_utf8_lossy(&output.stderr)
+        );
+        return Err(VideoEncodeError::Encoding(format!(
+            "Failed to compare {:?} with butteraugli",
+            distorted
+        )));
+    }
+
+    let max = stdout
+        .lines()
+        .next()
+        .and_then(|line| line.trim().parse().ok());
+    let pnorm = stdout
+        .lines()
+        .filter_map(|line| line.split_once("-norm:"))
+        .find_map(|(_, value)| value.trim().parse().ok());
+    match (max, pnorm) {
+        (Some(max), Some(pnorm)) => Ok((max, pnorm)),
+        _ => Err(VideoEncodeError::Encoding(format!(
+            "Butteraugli comparison of {:?} has no result",
+            distorted
+        ))),
+    }
+}
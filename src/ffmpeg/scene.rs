@@ -0,0 +1,52 @@
+/// This module detects scene changes with ffmpeg `scdet` filter,
+/// so chunk boundaries can fall on cuts
+use std::{
+    path::Path,
+    process::{Command, Stdio},
+};
+
+use tracing::{debug, error, info, instrument};
+
+use crate::error::VideoEncodeError;
+
+const SCENE_TIME_KEY: &str = "lavfi.scd.time:";
+
+/// Returns timestamps of scene changes in seconds, in ascending order.
+/// `threshold` is the scdet score from 0 to 100 above which frame starts a new scene.
+#[instrument]
+pub fn detect_scenes(input_path: &Path, threshold: f64) -> Result<Vec<f64>, VideoEncodeError> {
+    debug!("Detecting scene changes in {:?}", input_path);
+
+    let output = Command::new("ffmpeg")
+        .arg("-hide_banner")
+        .arg("-i")
+        .arg(input_path)
+        .args(["-an", "-sn", "-dn", "-map", "0:v:0"])
+        .arg("-vf")
+        .arg(format!("scdet=threshold={}", threshold))
+        .args(["-f", "null", "-"])
+        .stdout(Stdio::null())
+        .output()?;
+
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    if !output.status.success() {
+        error!("Failed to detect scene changes: {}", stderr);
+        return Err(VideoEncodeError::Encoding(
+            "Failed to detect scene changes".to_string(),
+        ));
+    }
+
+    // Lines look like `[scdet @ 0x...] lavfi.scd.score: 25.1, lavfi.scd.time: 4.12`
+    let mut scenes: Vec<f64> = stderr
+        .lines()
+        .filter_map(|line| {
+            let (_, time) = line.split_once(SCENE_TIME_KEY)?;
+            time.trim().parse().ok()
+        })
+        .collect();
+    scenes.sort_by(f64::total_cmp);
+    scenes.dedup();
+
+    info!("Detected {} scene changes", scenes.len());
+    Ok(scenes)
+}
@@ -7,71 +7,486 @@ use std::{
 };
 
 use crate::error::VideoEncodeError;
-use tracing::{debug, error, info, instrument};
+use tracing::{debug, error, info, instrument, warn};
 
 use crate::chunk::verify_ffmpeg;
+use crate::ffmpeg::probe::{probe_keyframe_times, probe_streams};
+use crate::settings::{SubtitlePolicy, TrackSettings};
+
+/// Encoder settings of lossless intermediate, fast to produce and to decode on nodes.
+/// Frames are passed through as they are, so variable frame rate sources keep all frames.
+pub const LOSSLESS_INTERMEDIATE: [&str; 8] = [
+    "-c:v",
+    "libx264",
+    "-preset",
+    "ultrafast",
+    "-qp",
+    "0",
+    "-fps_mode",
+    "passthrough",
+];
 
 /// Due to the nature of method -segment_time
 /// Getting expected number of segments is not
 /// guaranteed as splitting can only be done at keyframes
 /// Which means that split time will be inconsistent
 /// and exact on file keyframe structure.
+/// With `lossless` video is re-encoded to lossless intermediate
+/// with keyframes at split points, so every chunk has exact duration.
 #[instrument]
 pub fn segment_video(
     input_path: &Path,
     segment_duration: f64,
     segment_dir: &Path,
+    lossless: bool,
 ) -> Result<Vec<PathBuf>, VideoEncodeError> {
     debug!(
         "Starting video segmentation: input={:?}, duration={}, segment_dir={:?}",
         input_path, segment_duration, segment_dir
     );
 
+    let keyframes = format!("expr:gte(t,n_forced*{})", segment_duration);
+    run_segmenter(
+        input_path,
+        segment_dir,
+        "-segment_time",
+        &segment_duration.to_string(),
+        lossless.then_some(keyframes.as_str()),
+        None,
+        "chunk",
+    )?;
+
+    list_segments(segment_dir)
+}
+
+/// Splits video at given timestamps in seconds.
+/// As with `segment_video`, split can only happen at keyframes,
+/// so each chunk starts at the first keyframe after its timestamp,
+/// unless video is re-encoded to `lossless` intermediate.
+#[instrument(skip(split_times))]
+pub fn segment_video_at(
+    input_path: &Path,
+    split_times: &[f64],
+    segment_dir: &Path,
+    lossless: bool,
+) -> Result<Vec<PathBuf>, VideoEncodeError> {
+    debug!(
+        "Starting video segmentation: input={:?}, split_times={:?}, segment_dir={:?}",
+        input_path, split_times, segment_dir
+    );
+
+    segment_range(
+        input_path,
+        split_times,
+        segment_dir,
+        lossless,
+        None,
+        "chunk",
+    )?;
+
+    list_segments(segment_dir)
+}
+
+/// Splits video at given timestamps like `segment_video_at`, but with `jobs` ffmpeg
+/// processes in parallel, each segmenting a coarse range of the input.
+/// Ranges start at split points, so chunks are the same as with a single process.
+#[instrument(skip(split_times))]
+pub fn segment_video_parallel(
+    input_path: &Path,
+    split_times: &[f64],
+    segment_dir: &Path,
+    lossless: bool,
+    jobs: usize,
+) -> Result<Vec<PathBuf>, VideoEncodeError> {
+    if jobs <= 1 || split_times.len() < jobs {
+        return segment_video_at(input_path, split_times, segment_dir, lossless);
+    }
+
+    // Stream copy can only start at keyframe, so range starts at the first keyframe
+    // after its split point, which is also where segment muxer would split
+    let keyframes = if lossless {
+        Vec::new()
+    } else {
+        probe_keyframe_times(input_path)?
+    };
+    let range_start = |split: f64| {
+        if lossless {
+            Some(split)
+        } else {
+            keyframes.iter().copied().find(|&time| time >= split)
+        }
+    };
+
+    let mut boundaries: Vec<f64> = (1..jobs)
+        .filter_map(|job| range_start(split_times[split_times.len() * job / jobs]))
+        .collect();
+    boundaries.dedup();
+
+    let starts = std::iter::once(0.0).chain(boundaries.iter().copied());
+    let ends = boundaries.iter().copied().map(Some).chain([None]);
+    let ranges: Vec<(f64, Option<f64>)> = starts.zip(ends).collect();
+
+    info!(
+        "Segmenting {:?} in {} parallel ranges",
+        input_path,
+        ranges.len()
+    );
+
+    std::thread::scope(|scope| {
+        let handles: Vec<_> = ranges
+            .iter()
+            .enumerate()
+            .map(|(range_index, &(start, end))| {
+                // Split points are relative to the start of the range
+                let local_splits: Vec<f64> = split_times
+                    .iter()
+                    .filter(|&&time| time > start && end.is_none_or(|end| time < end))
+                    .map(|time| time - start)
+                    .collect();
+                let name = format!("chunk_{:03}", range_index);
+
+                scope.spawn(move || {
+                    segment_range(
+                        input_path,
+                        &local_splits,
+                        segment_dir,
+                        lossless,
+                        Some((start, end)),
+                        &name,
+                    )
+                })
+            })
+            .collect();
+
+        handles
+            .into_iter()
+            .try_for_each(|handle| handle.join().expect("segmenter thread panicked"))
+    })?;
+
+    list_segments(segment_dir)
+}
+
+/// Segments part of the input, `range` limits it to timestamps from start to end
+fn segment_range(
+    input_path: &Path,
+    split_times: &[f64],
+    segment_dir: &Path,
+    lossless: bool,
+    range: Option<(f64, Option<f64>)>,
+    name: &str,
+) -> Result<(), VideoEncodeError> {
+    if split_times.is_empty() {
+        // Segment muxer needs at least one split point, single segment covers whole video
+        return run_segmenter(
+            input_path,
+            segment_dir,
+            "-segment_time",
+            "86400",
+            None,
+            range,
+            name,
+        );
+    }
+
+    let split_times = split_times
+        .iter()
+        .map(|time| format!("{:.3}", time))
+        .collect::<Vec<_>>()
+        .join(",");
+
+    run_segmenter(
+        input_path,
+        segment_dir,
+        "-segment_times",
+        &split_times,
+        lossless.then_some(split_times.as_str()),
+        range,
+        name,
+    )
+}
+
+/// Extracts part of the video between `start` and `end` seconds into `output_path`.
+/// Stream copy has to start at a keyframe, lossless intermediate can start anywhere.
+/// FFmpeg process is killed if returned future is dropped before it completes.
+#[instrument]
+pub async fn extract_segment(
+    input_path: &Path,
+    start: f64,
+    end: f64,
+    output_path: &Path,
+    lossless: bool,
+) -> Result<(), VideoEncodeError> {
+    let codec_args: &[&str] = if lossless {
+        &LOSSLESS_INTERMEDIATE
+    } else {
+        &["-c", "copy"]
+    };
+
+    let output = tokio::process::Command::new("ffmpeg")
+        .args(["-hide_banner", "-y"])
+        .args(["-ss", &start.to_string(), "-to", &end.to_string()])
+        .arg("-i")
+        .arg(input_path)
+        .args(["-an", "-sn", "-dn", "-map", "0"])
+        .args(codec_args)
+        .arg(output_path)
+        .kill_on_drop(true)
+        .output()
+        .await?;
+
+    if !output.status.success() {
+        error!(
+            "Failed to extract segment: {}",
+            String::from_utf8_lossy(&output.stderr)
+        );
+        return Err(VideoEncodeError::Encoding(format!(
+            "Failed to extract segment {}-{} of {:?}",
+            start, end, input_path
+        )));
+    }
+
+    Ok(())
+}
+
+/// Returns split points that start a new chunk every `frames_per_chunk` frames.
+/// Points lie between two frames, so rounding of timestamps can't move them.
+pub fn frame_splits(frame_times: &[f64], frames_per_chunk: usize) -> Vec<f64> {
+    (frames_per_chunk.max(1)..frame_times.len())
+        .step_by(frames_per_chunk.max(1))
+        .map(|frame| (frame_times[frame - 1] + frame_times[frame]) / 2.0)
+        .collect()
+}
+
+/// Picks split points from scene changes, so every chunk is at least `min_duration`
+/// long, short scenes are merged into the following ones. Scenes longer than
+/// `max_duration` are split evenly, preferring source `keyframes` near even split points.
+pub fn scene_splits(
+    scenes: &[f64],
+    keyframes: &[f64],
+    total_duration: f64,
+    min_duration: f64,
+    max_duration: f64,
+) -> Vec<f64> {
+    let mut splits = Vec::new();
+    let mut last = 0.0;
+
+    let boundaries = scenes
+        .iter()
+        .copied()
+        .filter(|&time| time > 0.0 && time < total_duration)
+        .chain(std::iter::once(total_duration));
+
+    for boundary in boundaries {
+        if boundary - last < min_duration && boundary < total_duration {
+            continue;
+        }
+
+        extra_splits(
+            &mut splits,
+            keyframes,
+            last,
+            boundary,
+            min_duration,
+            max_duration,
+        );
+        if boundary < total_duration {
+            splits.push(boundary);
+        }
+        last = boundary;
+    }
+
+    merge_short_tail(&mut splits, total_duration, min_duration);
+    splits
+}
+
+/// Picks split points from source keyframes, packing as many of them into a chunk
+/// as fit into `max_duration`, but no less than `min_duration`.
+/// Where keyframes are too sparse, chunks are split evenly between them.
+pub fn keyframe_splits(
+    keyframes: &[f64],
+    total_duration: f64,
+    min_duration: f64,
+    max_duration: f64,
+) -> Vec<f64> {
+    let mut splits = Vec::new();
+    let mut last = 0.0;
+
+    if max_duration <= 0.0 {
+        return splits;
+    }
+
+    while total_duration - last > max_duration {
+        let fitting = keyframes
+            .iter()
+            .copied()
+            .rev()
+            // Keyframe at `last` fits when min_duration is 0, and would never move on
+            .find(|&time| {
+                time > last && time - last >= min_duration && time - last <= max_duration
+            });
+
+        match fitting {
+            Some(keyframe) => {
+                splits.push(keyframe);
+                last = keyframe;
+            }
+            None => {
+                let next = keyframes
+                    .iter()
+                    .copied()
+                    .find(|&time| time - last > max_duration)
+                    .unwrap_or(total_duration);
+
+                extra_splits(&mut splits, &[], last, next, min_duration, max_duration);
+                if next < total_duration {
+                    splits.push(next);
+                }
+                last = next;
+            }
+        }
+    }
+
+    merge_short_tail(&mut splits, total_duration, min_duration);
+    splits
+}
+
+/// Splits range from `start` to `end` evenly into parts of at most `max_duration`,
+/// moving each split point to the nearest keyframe within half a part
+fn extra_splits(
+    splits: &mut Vec<f64>,
+    keyframes: &[f64],
+    start: f64,
+    end: f64,
+    min_duration: f64,
+    max_duration: f64,
+) {
+    let length = end - start;
+    if max_duration <= 0.0 || length <= max_duration {
+        return;
+    }
+
+    let parts = (length / max_duration).ceil() as usize;
+    let step = length / parts as f64;
+    let mut previous = start;
+
+    for part in 1..parts {
+        let ideal = start + step * part as f64;
+        let split = nearest(keyframes, ideal)
+            .filter(|&keyframe| (keyframe - ideal).abs() <= step / 2.0)
+            .filter(|&keyframe| keyframe - previous >= min_duration)
+            .filter(|&keyframe| end - keyframe >= min_duration)
+            .unwrap_or(ideal);
+
+        splits.push(split);
+        previous = split;
+    }
+}
+
+/// Returns value from sorted `times` that is the closest to `target`
+fn nearest(times: &[f64], target: f64) -> Option<f64> {
+    let index = times.partition_point(|&time| time < target);
+    let before = index.checked_sub(1).map(|index| times[index]);
+    let after = times.get(index).copied();
+
+    match (before, after) {
+        (Some(before), Some(after)) if target - before <= after - target => Some(before),
+        (_, Some(after)) => Some(after),
+        (before, None) => before,
+    }
+}
+
+/// Merges the last chunk into the previous one when it's too short
+fn merge_short_tail(splits: &mut Vec<f64>, total_duration: f64, min_duration: f64) {
+    if let Some(&split) = splits.last() {
+        if total_duration - split < min_duration {
+            splits.pop();
+        }
+    }
+}
+
+/// Runs segment muxer with given option that defines split points.
+/// When `keyframes` are set, video is re-encoded to lossless intermediate
+/// with keyframes forced at them, otherwise it's copied.
+/// Only part of the input within `range` is segmented, when it's set.
+/// Segments are named `<name>_<number>.mp4`.
+#[allow(clippy::too_many_arguments)]
+fn run_segmenter(
+    input_path: &Path,
+    segment_dir: &Path,
+    split_option: &str,
+    split_value: &str,
+    keyframes: Option<&str>,
+    range: Option<(f64, Option<f64>)>,
+    name: &str,
+) -> Result<(), VideoEncodeError> {
     verify_ffmpeg()?;
     debug!("FFmpeg verification successful");
 
     std::fs::create_dir_all(segment_dir)?;
     debug!("Created segment directory: {:?}", segment_dir);
 
-    let output_pattern = segment_dir.join("chunk_%04d.mp4");
+    let output_pattern = segment_dir.join(format!("{}_%04d.mp4", name));
     debug!("Output pattern: {:?}", output_pattern);
 
-    let binding = segment_duration.to_string();
     let inp = input_path.to_string_lossy();
     let output_pattern = output_pattern.to_string_lossy();
 
-    let ffmpeg_args = vec![
-        "-hide_banner",
-        "-i",
-        &inp,
-        "-y",
-        "-an", // don't copy audio
+    let codec_args = match keyframes {
+        Some(keyframes) => {
+            debug!(
+                "Re-encoding to lossless intermediate, keyframes at {}",
+                keyframes
+            );
+            let mut args = LOSSLESS_INTERMEDIATE.to_vec();
+            args.extend(["-force_key_frames", keyframes]);
+            args
+        }
+        None => vec!["-c", "copy"],
+    };
+
+    let start = range.map(|(start, _)| start.to_string());
+    let end = range.and_then(|(_, end)| end).map(|end| end.to_string());
+
+    let mut ffmpeg_args = vec!["-hide_banner"];
+    if let Some(start) = &start {
+        ffmpeg_args.extend(["-ss", start]);
+    }
+    if let Some(end) = &end {
+        ffmpeg_args.extend(["-to", end]);
+    }
+    ffmpeg_args.extend([
+        "-i", &inp, "-y", "-an", // don't copy audio
         "-sn", // don't copy subtitles
         "-dn", // don't copy other data
-        "-c",
-        "copy",
+    ]);
+    ffmpeg_args.extend(codec_args);
+    ffmpeg_args.extend([
         "-map",
         "0",
-        "-segment_time",
-        &binding,
+        split_option,
+        split_value,
         "-f",
         "segment",
         "-reset_timestamps",
         "1",
         &output_pattern,
-    ];
+    ]);
 
     debug!("FFmpeg command: ffmpeg {:?}", ffmpeg_args);
 
-    // Free line to place your prayers that source don't have bidirectional keyframes
-    //
-    let status = Command::new("ffmpeg")
+    // Stream copy breaks leading frames of open GOPs, client checks input for them
+    // and switches to lossless intermediate before splitting
+    let output = Command::new("ffmpeg")
         .arg("-hide_banner")
         .args(&ffmpeg_args)
-        .status()?;
+        .output()?;
 
-    if !status.success() {
-        error!("Failed to split video. FFmpeg exit status: {}", status);
+    if !output.status.success() {
+        error!(
+            "Failed to split video. FFmpeg exit status: {}: {}",
+            output.status,
+            String::from_utf8_lossy(&output.stderr)
+        );
         return Err(VideoEncodeError::Encoding(
             "Failed to split video".to_string(),
         ));
@@ -79,14 +494,18 @@ pub fn segment_video(
 
     debug!("Video segmentation completed successfully");
 
-    let segmented_files: Vec<PathBuf> = std::fs::read_dir(segment_dir)?
+    Ok(())
+}
+
+/// Lists segments written to `segment_dir`, in order of their position in the video
+fn list_segments(segment_dir: &Path) -> Result<Vec<PathBuf>, VideoEncodeError> {
+    let mut segmented_files: Vec<PathBuf> = std::fs::read_dir(segment_dir)?
         .filter_map(Result::ok)
         .filter(|entry| entry.path().extension().and_then(|s| s.to_str()) == Some("mp4"))
-        .map(|entry| {
-            let path = entry.path();
-            path
-        })
+        .map(|entry| entry.path())
         .collect();
+    // Directory order is arbitrary, chunk index has to follow position in the video
+    segmented_files.sort();
 
     debug!(
         "Segmented files: count={}, files={:?}",
@@ -99,38 +518,167 @@ pub fn segment_video(
     Ok(segmented_files)
 }
 
-/// Extracts audio and other non-video streams from the input file.
-/// Returns paths to the extracted files.
+/// Writes chapters of the input file into FFMETADATA file at `chapters_path`.
+/// Returns `false` when input has no chapters.
 #[instrument]
+pub fn extract_chapters(input_path: &Path, chapters_path: &Path) -> Result<bool, VideoEncodeError> {
+    let output = Command::new("ffmpeg")
+        .args(["-hide_banner", "-loglevel", "error", "-y", "-i"])
+        .arg(input_path)
+        .args(["-map_chapters", "0", "-f", "ffmetadata"])
+        .arg(chapters_path)
+        .output()?;
+
+    if !output.status.success() {
+        error!(
+            "Failed to extract chapters: {}",
+            String::from_utf8_lossy(&output.stderr)
+        );
+        return Err(VideoEncodeError::Encoding(format!(
+            "Failed to extract chapters of {:?}",
+            input_path
+        )));
+    }
+
+    let chapters = std::fs::read_to_string(chapters_path)?
+        .matches("[CHAPTER]")
+        .count();
+    debug!("{:?} has {} chapters", input_path, chapters);
+    Ok(chapters > 0)
+}
+
+/// Extracts audio, subtitles and attachments of the input file, except tracks
+/// that `tracks` don't keep. Returns path to the extracted file, `None` when there
+/// are no such streams.
+#[instrument(skip(tracks))]
 pub fn extract_non_video_streams(
     input_path: &Path,
     temp_dir: &Path,
-) -> Result<PathBuf, VideoEncodeError> {
+    tracks: &TrackSettings,
+) -> Result<Option<PathBuf>, VideoEncodeError> {
     debug!("Extracting non-video streams from: {:?}", input_path);
 
     std::fs::create_dir_all(temp_dir)?;
 
+    // Streams are mapped one by one, ffmpeg would only pick one of every type.
+    // Data streams can't be stored in Matroska
+    let (mut audio, mut subtitles, mut kept_subtitles) = (0, 0, 0);
+    let mut maps = Vec::new();
+    let mut conversions = Vec::new();
+    for stream in probe_streams(input_path)? {
+        let language = stream.tags.language.as_deref();
+        let kept = match stream.codec_type.as_str() {
+            "audio" => {
+                audio += 1;
+                tracks.keeps_audio(audio - 1, language)
+            }
+            "subtitle" => {
+                subtitles += 1;
+                let policy =
+                    tracks.subtitle_policy(stream.codec_name.as_deref().unwrap_or_default());
+                let kept = policy != SubtitlePolicy::Drop
+                    && tracks.keeps_subtitle(subtitles - 1, language);
+                if let Some(codec) = policy.codec().filter(|_| kept) {
+                    conversions.extend([format!("-c:s:{}", kept_subtitles), codec.to_string()]);
+                }
+                kept_subtitles += usize::from(kept);
+                kept
+            }
+            "attachment" => true,
+            _ => false,
+        };
+        if kept {
+            maps.extend(["-map".to_string(), format!("0:{}", stream.index)]);
+        } else if stream.codec_type != "video" {
+            debug!(
+                "Dropping {} stream {} of language {:?}",
+                stream.codec_type, stream.index, language
+            );
+        }
+    }
+    if maps.is_empty() {
+        if audio + subtitles > 0 {
+            warn!(
+                "None of the audio and subtitle tracks of {:?} are kept",
+                input_path
+            );
+        }
+        return Ok(None);
+    }
+
     // Extract audio
     let steams_path = temp_dir.join("audio.mkv");
-    let status = Command::new("ffmpeg")
+    let output = Command::new("ffmpeg")
         .arg("-hide_banner")
-        .args(&[
-            "-i",
-            input_path.to_str().unwrap(),
-            "-y",
-            "-vn",
+        .args(["-i", input_path.to_str().unwrap(), "-y"])
+        .args(&maps)
+        .args([
             "-c", // copy all streams that is not video
             "copy",
-            steams_path.to_str().unwrap(),
         ])
-        .status()?;
+        .args(&conversions)
+        // Chapters are extracted on their own, so they don't depend on kept streams
+        .args(["-map_chapters", "-1", steams_path.to_str().unwrap()])
+        .output()?;
 
-    if !status.success() {
-        error!("Failed to extract audio");
+    if !output.status.success() {
+        error!(
+            "Failed to extract audio: {}",
+            String::from_utf8_lossy(&output.stderr)
+        );
         return Err(VideoEncodeError::Encoding(
             "Failed to extract audio".to_string(),
         ));
     }
 
-    Ok(steams_path)
+    Ok(Some(steams_path))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn frame_splits_fall_between_frames() {
+        let times = [0.0, 1.0, 2.0, 3.0, 4.0, 5.0];
+        assert_eq!(frame_splits(&times, 2), vec![1.5, 3.5]);
+        assert_eq!(frame_splits(&times, 0), vec![0.5, 1.5, 2.5, 3.5, 4.5]);
+    }
+
+    #[test]
+    fn scene_splits_merge_short_scenes() {
+        assert_eq!(
+            scene_splits(&[1.0, 10.0, 12.0], &[], 20.0, 2.0, 100.0),
+            vec![10.0, 12.0]
+        );
+        // Last chunk would be shorter than the minimum
+        assert_eq!(
+            scene_splits(&[10.0, 19.5], &[], 20.0, 2.0, 100.0),
+            vec![10.0]
+        );
+    }
+
+    #[test]
+    fn scene_splits_split_long_scenes_at_nearby_keyframes() {
+        assert_eq!(scene_splits(&[], &[], 30.0, 1.0, 10.0), vec![10.0, 20.0]);
+        assert_eq!(
+            scene_splits(&[], &[9.5, 21.0], 30.0, 1.0, 10.0),
+            vec![9.5, 21.0]
+        );
+    }
+
+    #[test]
+    fn keyframe_splits_pack_keyframes_into_chunks() {
+        let keyframes = [0.0, 4.0, 8.0, 12.0, 16.0];
+        assert_eq!(
+            keyframe_splits(&keyframes, 20.0, 2.0, 10.0),
+            vec![8.0, 16.0]
+        );
+    }
+
+    #[test]
+    fn keyframe_splits_move_past_keyframe_at_last_split() {
+        let splits = keyframe_splits(&[0.0, 20.0, 40.0], 45.0, 0.0, 10.0);
+        assert_eq!(splits, vec![10.0, 20.0, 30.0, 40.0]);
+    }
 }
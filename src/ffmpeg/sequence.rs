@@ -0,0 +1,155 @@
+/// This module reads numbered image sequences like `frames/%06d.png`, which are
+/// split into chunks by frame ranges, like VapourSynth scripts
+use std::{
+    path::{Path, PathBuf},
+    process::Stdio,
+};
+
+use tracing::{debug, error, instrument};
+
+use crate::error::VideoEncodeError;
+use crate::ffmpeg::probe::probe_resolution;
+use crate::ffmpeg::segment::LOSSLESS_INTERMEDIATE;
+
+/// Highest number ffmpeg looks for the first image at, same as its default
+const MAX_START_NUMBER: usize = 4;
+
+/// Properties of an image sequence
+#[derive(Debug, Clone, Copy)]
+pub struct SequenceInfo {
+    /// Number of the first image
+    pub first: usize,
+    /// Number of consecutive images from the first one
+    pub frames: usize,
+    pub width: u32,
+    pub height: u32,
+}
+
+/// Whether input is an image sequence, with a `%d` or `%0Nd` pattern in its file name
+pub fn is_sequence(path: &Path) -> bool {
+    image_path(path, 0).is_some()
+}
+
+/// Path of the image with given number, or `None` if path isn't a pattern
+pub fn image_path(pattern: &Path, number: usize) -> Option<PathBuf> {
+    let name = pattern.file_name()?.to_str()?;
+    let (prefix, rest) = name.split_once('%')?;
+    let (width, suffix) = rest.split_once('d')?;
+    if !width.chars().all(|c| c.is_ascii_digit()) {
+        return None;
+    }
+    let width: usize = width.parse().unwrap_or(0);
+
+    Some(pattern.with_file_name(format!("{prefix}{number:0width$}{suffix}")))
+}
+
+/// Parses frame rate as fraction like `24000/1001` or decimal number like `24`
+pub fn parse_frame_rate(value: &str) -> Option<f64> {
+    let fps = match value.split_once('/') {
+        Some((num, den)) => num.trim().parse::<f64>().ok()? / den.trim().parse::<f64>().ok()?,
+        None => value.trim().parse().ok()?,
+    };
+
+    (fps.is_finite() && fps > 0.0).then_some(fps)
+}
+
+/// Finds the first image and counts consecutive images after it
+#[instrument]
+pub fn probe_sequence(pattern: &Path) -> Result<SequenceInfo, VideoEncodeError> {
+    let exists = |number| image_path(pattern, number).is_some_and(|path| path.is_file());
+
+    let first = (0..=MAX_START_NUMBER)
+        .find(|&number| exists(number))
+        .ok_or_else(|| VideoEncodeError::Encoding(format!("No images found for {:?}", pattern)))?;
+    let frames = (first..).take_while(|&number| exists(number)).count();
+
+    let first_image = image_path(pattern, first).expect("pattern was matched");
+    let (width, height) = probe_resolution(&first_image)?;
+
+    let info = SequenceInfo {
+        first,
+        frames,
+        width,
+        height,
+    };
+    debug!("Sequence {:?} has {:?}", pattern, info);
+    Ok(info)
+}
+
+/// Encodes images from `start` to `end` (exclusive) as lossless intermediate into `output_path`.
+/// FFmpeg process is killed if returned future is dropped before it completes.
+#[instrument]
+pub async fn extract_images(
+    pattern: &Path,
+    fps: f64,
+    start: usize,
+    end: usize,
+    output_path: &Path,
+) -> Result<(), VideoEncodeError> {
+    let output = tokio::process::Command::new("ffmpeg")
+        .args(["-hide_banner", "-y", "-f", "image2"])
+        .args(["-framerate", &fps.to_string()])
+        .args(["-start_number", &start.to_string()])
+        .arg("-i")
+        .arg(pattern)
+        .args(["-frames:v", &(end - start).to_string()])
+        .args(LOSSLESS_INTERMEDIATE)
+        .arg(output_path)
+        .stdout(Stdio::null())
+        .kill_on_drop(true)
+        .output()
+        .await?;
+
+    if !output.status.success() {
+        error!(
+            "Failed to extract images {}-{}: {}",
+            start,
+            end,
+            String::from_utf8_lossy(&output.stderr)
+        );
+        return Err(VideoEncodeError::Encoding(format!(
+            "Failed to extract images {}-{} of {:?}",
+            start, end, pattern
+        )));
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn image_path_fills_number_with_width() {
+        assert_eq!(
+            image_path(Path::new("frames/img_%04d.png"), 7),
+            Some(PathBuf::from("frames/img_0007.png"))
+        );
+        assert_eq!(
+            image_path(Path::new("img_%d.png"), 12),
+            Some(PathBuf::from("img_12.png"))
+        );
+    }
+
+    #[test]
+    fn image_path_rejects_paths_without_pattern() {
+        assert_eq!(image_path(Path::new("video.mkv"), 1), None);
+        assert_eq!(image_path(Path::new("img_%xd.png"), 1), None);
+    }
+
+    #[test]
+    fn parse_frame_rate_reads_fractions_and_numbers() {
+        let ntsc = parse_frame_rate("24000/1001").unwrap();
+        assert!((ntsc - 23.976).abs() < 0.001);
+        assert_eq!(parse_frame_rate(" 25 "), Some(25.0));
+    }
+
+    #[test]
+    fn parse_frame_rate_rejects_invalid_rates() {
+        assert_eq!(parse_frame_rate("0"), None);
+        assert_eq!(parse_frame_rate("1/0"), None);
+        assert_eq!(parse_frame_rate("-24"), None);
+        assert_eq!(parse_frame_rate("fast"), None);
+    }
+}
@@ -0,0 +1,65 @@
+/// This module checks that audio of the output stays in sync with its video. Splitting
+/// at keyframes by stream copy and joining chunks occasionally shifts video against
+/// audio, which shows as offsets between the first and last audio and video packets
+/// that differ from the ones of the source.
+use std::path::Path;
+
+use tracing::{debug, instrument};
+
+use crate::error::VideoEncodeError;
+use crate::ffmpeg::probe::probe_stream_span;
+
+/// Drift of the output from the source in seconds
+#[derive(Debug, Clone, Copy)]
+pub struct SyncDrift {
+    /// Change of the offset between starts of audio and video, none without audio
+    pub start: Option<f64>,
+    /// Change of the offset between ends of audio and video, none without audio
+    pub end: Option<f64>,
+    /// Difference of durations of video
+    pub duration: f64,
+}
+
+impl SyncDrift {
+    /// Largest of the drifts
+    pub fn max(&self) -> f64 {
+        [self.start, self.end, Some(self.duration)]
+            .into_iter()
+            .flatten()
+            .fold(0.0, f64::max)
+    }
+}
+
+/// Measures drift of first video and audio streams of `output` from the ones of `source`
+#[instrument]
+pub fn measure_sync(source: &Path, output: &Path) -> Result<SyncDrift, VideoEncodeError> {
+    let video_span = |path: &Path| {
+        probe_stream_span(path, "v:0")?.ok_or_else(|| {
+            VideoEncodeError::Encoding(format!("{:?} has no video to check sync of", path))
+        })
+    };
+    let (source_video, output_video) = (video_span(source)?, video_span(output)?);
+    let audio = match (
+        probe_stream_span(source, "a:0")?,
+        probe_stream_span(output, "a:0")?,
+    ) {
+        (Some(source_audio), Some(output_audio)) => Some((source_audio, output_audio)),
+        _ => None,
+    };
+    debug!(
+        "Video spans {:?} in source and {:?} in output, audio spans {:?}",
+        source_video, output_video, audio
+    );
+
+    // Output may start at other timestamp than the source, so offsets are compared
+    let drift = SyncDrift {
+        start: audio.map(|(source_audio, output_audio)| {
+            ((output_audio.0 - output_video.0) - (source_audio.0 - source_video.0)).abs()
+        }),
+        end: audio.map(|(source_audio, output_audio)| {
+            ((output_audio.1 - output_video.1) - (source_audio.1 - source_video.1)).abs()
+        }),
+        duration: ((output_video.1 - output_video.0) - (source_video.1 - source_video.0)).abs(),
+    };
+    Ok(drift)
+}
@@ -0,0 +1,71 @@
+/// This module preserves timestamps of variable frame rate sources.
+/// Chunks lose original timestamps when they are split and encoded, so source
+/// timestamps are stored in a timecode file and applied to the concatenated video.
+use std::{fmt::Write, fs, path::Path, process::Command};
+
+use tracing::{debug, error, info, instrument};
+
+use crate::error::VideoEncodeError;
+
+/// Verifies that mkvmerge, which applies timecode files, is installed
+#[instrument]
+pub fn verify_mkvmerge() -> Result<(), VideoEncodeError> {
+    which::which("mkvmerge").map_err(|e| {
+        error!("mkvmerge not found: {}", e);
+        VideoEncodeError::Encoding(
+            "mkvmerge is required to preserve timestamps, but it's not installed".to_string(),
+        )
+    })?;
+
+    Ok(())
+}
+
+/// Writes timecode file in v2 format, with timestamp of every frame in milliseconds
+/// relative to the first frame
+#[instrument(skip(frame_times))]
+pub fn write_timecodes(frame_times: &[f64], path: &Path) -> Result<(), VideoEncodeError> {
+    let first = frame_times.first().copied().unwrap_or_default();
+
+    let mut content = String::from("# timestamp format v2\n");
+    for time in frame_times {
+        writeln!(content, "{:.6}", (time - first) * 1000.0).expect("writing to string");
+    }
+    fs::write(path, content)?;
+
+    debug!("Wrote {} timestamps to {:?}", frame_times.len(), path);
+    Ok(())
+}
+
+/// Remuxes video with timestamps from timecode file into `output_path`
+#[instrument]
+pub fn apply_timecodes(
+    video_path: &Path,
+    timecodes_path: &Path,
+    output_path: &Path,
+) -> Result<(), VideoEncodeError> {
+    let mut timestamps = std::ffi::OsString::from("0:");
+    timestamps.push(timecodes_path);
+
+    let output = Command::new("mkvmerge")
+        .arg("-q")
+        .arg("-o")
+        .arg(output_path)
+        .arg("--timestamps")
+        .arg(timestamps)
+        .arg(video_path)
+        .output()?;
+
+    // mkvmerge exits with 1 when there were only warnings
+    if !matches!(output.status.code(), Some(0) | Some(1)) {
+        error!(
+            "Failed to apply timestamps: {}",
+            String::from_utf8_lossy(&output.stdout)
+        );
+        return Err(VideoEncodeError::Concatenation(
+            "Failed to apply timestamps".to_string(),
+        ));
+    }
+
+    info!("Applied source timestamps to {:?}", output_path);
+    Ok(())
+}
@@ -0,0 +1,165 @@
+/// This module trims input to a part of it, so only that part is split and encoded
+use std::{fs, path::Path, process::Command, str::FromStr};
+
+use tracing::{debug, error, info, instrument};
+
+use crate::error::VideoEncodeError;
+use crate::ffmpeg::probe::{probe_frame_times, probe_keyframe_times};
+use crate::ffmpeg::segment::LOSSLESS_INTERMEDIATE;
+
+/// Position in the input, as timestamp or frame number
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Position {
+    /// Seconds from the start
+    Time(f64),
+    /// Frame number, starting from 0
+    Frame(usize),
+}
+
+impl FromStr for Position {
+    type Err = String;
+
+    /// Parses `1234f` as frame, and `90`, `01:30` or `00:01:30.5` as time
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        let value = value.trim();
+        if let Some(frame) = value.strip_suffix('f') {
+            return frame
+                .parse()
+                .map(Position::Frame)
+                .map_err(|_| format!("Invalid frame number: {}", value));
+        }
+
+        let mut seconds = 0.0;
+        for part in value.split(':') {
+            let part: f64 = part
+                .parse()
+                .map_err(|_| format!("Invalid time: {}", value))?;
+            seconds = seconds * 60.0 + part;
+        }
+
+        if value.split(':').count() > 3 || seconds < 0.0 {
+            return Err(format!("Invalid time: {}", value));
+        }
+
+        Ok(Position::Time(seconds))
+    }
+}
+
+/// Writes part of the input between `start` and `end` into `output_path`, with all streams.
+/// Stream copy can only start at a keyframe, so start is moved to the keyframe before it,
+/// unless video is re-encoded to `lossless` intermediate.
+#[instrument]
+pub fn trim_input(
+    input_path: &Path,
+    start: Option<Position>,
+    end: Option<Position>,
+    output_path: &Path,
+    lossless: bool,
+) -> Result<(), VideoEncodeError> {
+    let needs_frames =
+        matches!(start, Some(Position::Frame(_))) || matches!(end, Some(Position::Frame(_)));
+    let frame_times = if needs_frames {
+        probe_frame_times(input_path)?
+    } else {
+        Vec::new()
+    };
+
+    let to_seconds = |position: Position| match position {
+        Position::Time(seconds) => Ok(seconds),
+        Position::Frame(frame) => frame_times.get(frame).copied().ok_or_else(|| {
+            VideoEncodeError::Encoding(format!(
+                "Frame {} is out of range, input has {} frames",
+                frame,
+                frame_times.len()
+            ))
+        }),
+    };
+
+    let mut start = start.map(to_seconds).transpose()?;
+    let end = end.map(to_seconds).transpose()?;
+
+    if let (Some(start), Some(end)) = (start, end) {
+        if end <= start {
+            return Err(VideoEncodeError::Encoding(format!(
+                "End {} is not after start {}",
+                end, start
+            )));
+        }
+    }
+
+    if let (Some(requested), false) = (start, lossless) {
+        let keyframe = probe_keyframe_times(input_path)?
+            .into_iter()
+            .rev()
+            .find(|&time| time <= requested)
+            .unwrap_or(0.0);
+        if keyframe != requested {
+            info!(
+                "Moving start from {}s to keyframe at {}s, use lossless intermediate to start exactly",
+                requested, keyframe
+            );
+        }
+        start = Some(keyframe);
+    }
+
+    if let Some(parent) = output_path.parent() {
+        fs::create_dir_all(parent)?;
+    }
+
+    let mut command = Command::new("ffmpeg");
+    command.args(["-hide_banner", "-y"]);
+    if let Some(start) = start {
+        command.args(["-ss", &start.to_string()]);
+    }
+    if let Some(end) = end {
+        command.args(["-to", &end.to_string()]);
+    }
+    command
+        .arg("-i")
+        .arg(input_path)
+        .args(["-map", "0", "-c", "copy"]);
+    if lossless {
+        command.args(LOSSLESS_INTERMEDIATE);
+    }
+    command.arg(output_path);
+
+    debug!("FFmpeg command: {:?}", command);
+    let output = command.output()?;
+
+    if !output.status.success() {
+        error!(
+            "Failed to trim input: {}",
+            String::from_utf8_lossy(&output.stderr)
+        );
+        return Err(VideoEncodeError::Encoding(
+            "Failed to trim input".to_string(),
+        ));
+    }
+
+    info!(
+        "Trimmed input to {:?} from {:?} to {:?}",
+        output_path, start, end
+    );
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn position_parses_frames_and_times() {
+        assert_eq!("1234f".parse(), Ok(Position::Frame(1234)));
+        assert_eq!("90".parse(), Ok(Position::Time(90.0)));
+        assert_eq!("01:30".parse(), Ok(Position::Time(90.0)));
+        assert_eq!("00:01:30.5".parse(), Ok(Position::Time(90.5)));
+    }
+
+    #[test]
+    fn position_rejects_invalid_values() {
+        assert!("abcf".parse::<Position>().is_err());
+        assert!("1:2:3:4".parse::<Position>().is_err());
+        assert!("-5".parse::<Position>().is_err());
+        assert!("1:xx".parse::<Position>().is_err());
+    }
+}
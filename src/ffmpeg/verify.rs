@@ -0,0 +1,97 @@
+/// This module verifies encoded chunks returned by nodes before they're accepted,
+/// so corrupted results and dropped or duplicated frames are encoded again instead
+/// of surfacing at concatenation. Hashes of their bitstreams show whether encodes
+/// are reproduced exactly.
+use std::{path::Path, process::Stdio};
+
+use tokio::process::Command;
+use tracing::{debug, instrument};
+
+use crate::error::VideoEncodeError;
+
+/// Decodes video of `path` without writing it anywhere, any error ffmpeg reports
+/// fails the verification. ffmpeg is killed if returned future is dropped before it completes.
+#[instrument]
+pub async fn verify_decode(path: &Path) -> Result<(), VideoEncodeError> {
+    let output = Command::new("ffmpeg")
+        .args(["-hide_banner", "-v", "error", "-i"])
+        .arg(path)
+        .args(["-map", "0:v:0", "-f", "null", "-"])
+        .stdin(Stdio::null())
+        .kill_on_drop(true)
+        .output()
+        .await?;
+
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    if !output.status.success() || !stderr.trim().is_empty() {
+        return Err(VideoEncodeError::Encoding(format!(
+            "{:?} doesn't decode cleanly: {}",
+            path,
+            stderr.lines().next().unwrap_or("ffmpeg failed")
+        )));
+    }
+
+    debug!("{:?} decodes cleanly", path);
+    Ok(())
+}
+
+/// Counts frames of the first video stream of `path` by its packets
+#[instrument]
+pub async fn count_frames(path: &Path) -> Result<u64, VideoEncodeError> {
+    let output = Command::new("ffprobe")
+        .args([
+            "-v",
+            "error",
+            "-select_streams",
+            "v:0",
+            "-count_packets",
+            "-show_entries",
+            "stream=nb_read_packets",
+            "-of",
+            "csv=p=0",
+        ])
+        .arg(path)
+        .stdin(Stdio::null())
+        .kill_on_drop(true)
+        .output()
+        .await?;
+
+    if !output.status.success() {
+        return Err(VideoEncodeError::Encoding(format!(
+            "Failed to count frames of {:?}: {}",
+            path,
+            String::from_utf8_lossy(&output.stderr).trim()
+        )));
+    }
+    String::from_utf8_lossy(&output.stdout)
+        .trim()
+        .parse()
+        .map_err(|_| VideoEncodeError::Encoding(format!("{:?} has no video frames", path)))
+}
+
+/// SHA-256 of video packets of `path`, which doesn't change with container metadata
+/// like muxing application or date, so encodes of the same bitstream hash the same
+#[instrument]
+pub async fn hash_packets(path: &Path) -> Result<String, VideoEncodeError> {
+    let output = Command::new("ffmpeg")
+        .args(["-hide_banner", "-v", "error", "-i"])
+        .arg(path)
+        .args([
+            "-map", "0:v:0", "-c", "copy", "-f", "hash", "-hash", "sha256", "-",
+        ])
+        .stdin(Stdio::null())
+        .kill_on_drop(true)
+        .output()
+        .await?;
+
+    // Hash is printed like `SHA256=1f2e...`
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    match stdout.trim().split_once('=') {
+        Some((_, hash)) if output.status.success() => Ok(hash.to_string()),
+        _ => Err(VideoEncodeError::Encoding(format!(
+            "Failed to hash {:?}: {}",
+            path,
+            String::from_utf8_lossy(&output.stderr).trim()
+        ))),
+    }
+}
@@ -0,0 +1,91 @@
+/// This module keeps WebM outputs within what WebM allows. Video has to be AV1, VP9
+/// or VP8, audio Opus or Vorbis, and subtitles WebVTT. Streams of the input are
+/// converted to what WebM allows where they can be, and dropped where they can't.
+use std::path::Path;
+
+use tracing::warn;
+
+use crate::encoder::{selected_codec, EncoderKind};
+use crate::error::VideoEncodeError;
+use crate::ffmpeg::container::StreamAction;
+use crate::ffmpeg::probe::StreamInfo;
+use crate::settings::AudioSettings;
+
+/// Audio codecs WebM allows, as ffprobe names them
+const AUDIO_CODECS: [&str; 2] = ["opus", "vorbis"];
+/// Encoders of ffmpeg that audio can be transcoded with for WebM
+const AUDIO_ENCODERS: [&str; 4] = ["libopus", "opus", "libvorbis", "vorbis"];
+/// Subtitle codecs that are text, and can be converted to WebVTT
+const TEXT_SUBTITLE_CODECS: [&str; 6] = ["webvtt", "subrip", "ass", "ssa", "mov_text", "text"];
+/// Audio that WebM doesn't allow is transcoded to this
+const FALLBACK_AUDIO_ENCODER: &str = "libopus";
+
+/// Whether `path` is a WebM file by its extension
+pub fn is_webm(path: &Path) -> bool {
+    path.extension()
+        .and_then(|extension| extension.to_str())
+        .is_some_and(|extension| extension.eq_ignore_ascii_case("webm"))
+}
+
+/// Checks that video encoded with `encoder` and `params`, and audio transcoded as
+/// `audio` says, can be stored in WebM
+pub fn check_webm_codecs(
+    encoder: EncoderKind,
+    params: &[String],
+    audio: &AudioSettings,
+) -> Result<(), VideoEncodeError> {
+    let video = match encoder {
+        EncoderKind::SvtAv1 => Some("libsvtav1"),
+        #[cfg(feature = "rav1e")]
+        EncoderKind::Rav1e => Some("librav1e"),
+        EncoderKind::X264 | EncoderKind::X265 => None,
+        _ => selected_codec(params),
+    };
+    // Encoders of ffmpeg are named after their codec, like `libaom-av1` or `vp9_qsv`
+    let webm_video = video.is_some_and(|codec| {
+        codec == "libvpx"
+            || ["av1", "vp8", "vp9"]
+                .iter()
+                .any(|name| codec.contains(name))
+    });
+    if !webm_video {
+        return Err(VideoEncodeError::Encoding(format!(
+            "WebM only stores AV1, VP9 and VP8 video, {} encodes {}",
+            encoder.name(),
+            video.unwrap_or("other codecs")
+        )));
+    }
+
+    let codecs = audio
+        .codec
+        .iter()
+        .chain(audio.tracks.iter().filter_map(|track| track.codec.as_ref()));
+    for codec in codecs {
+        if codec != "copy" && !AUDIO_ENCODERS.contains(&codec.as_str()) {
+            return Err(VideoEncodeError::Encoding(format!(
+                "WebM only stores Opus and Vorbis audio, audio can't be transcoded with {}",
+                codec
+            )));
+        }
+    }
+    Ok(())
+}
+
+/// What is done with `stream` in WebM output. Audio WebM doesn't allow is transcoded
+/// to Opus, text subtitles are converted to WebVTT, and other streams are dropped.
+pub(crate) fn webm_action(stream: &StreamInfo) -> StreamAction {
+    let codec = stream.codec_name.as_deref().unwrap_or_default();
+    match stream.codec_type.as_str() {
+        "audio" if AUDIO_CODECS.contains(&codec) => StreamAction::Copy,
+        "audio" => {
+            warn!(
+                "WebM doesn't store {} audio, stream {} is transcoded to Opus",
+                codec, stream.index
+            );
+            StreamAction::Convert(FALLBACK_AUDIO_ENCODER)
+        }
+        "subtitle" if codec == "webvtt" => StreamAction::Copy,
+        "subtitle" if TEXT_SUBTITLE_CODECS.contains(&codec) => StreamAction::Convert("webvtt"),
+        _ => StreamAction::Drop,
+    }
+}
@@ -0,0 +1,42 @@
+/// This module generates shell completions and man pages of the binaries from their
+/// clap definitions, so packages can install them without keeping copies up to date.
+/// Both binaries have a hidden `generate` subcommand that runs it.
+use std::io;
+use std::path::PathBuf;
+
+use clap::{Command, Subcommand};
+use clap_complete::Shell;
+use tracing::instrument;
+
+use crate::error::VideoEncodeError;
+
+/// What `generate` subcommand writes
+#[derive(Subcommand, Debug, Clone)]
+pub enum Generate {
+    /// Print completions of the shell
+    Completions {
+        #[arg(value_enum)]
+        shell: Shell,
+    },
+    /// Write man pages of the binary and its subcommands into the directory
+    Man {
+        #[arg(default_value = ".")]
+        dir: PathBuf,
+    },
+}
+
+/// Writes what `generate` says for `command`, the clap definition of a binary
+#[instrument(skip(command))]
+pub fn generate(mut command: Command, generate: &Generate) -> Result<(), VideoEncodeError> {
+    match generate {
+        Generate::Completions { shell } => {
+            let name = command.get_name().to_string();
+            clap_complete::generate(*shell, &mut command, name, &mut io::stdout());
+        }
+        Generate::Man { dir } => {
+            std::fs::create_dir_all(dir)?;
+            clap_mangen::generate_to(command, dir)?;
+        }
+    }
+    Ok(())
+}
@@ -0,0 +1,88 @@
+/// This module reads job files, which describe an encode in YAML, TOML or JSON, so
+/// encodes with many options can be kept, rerun and compared as files. Format of the
+/// file is picked by its extension, like `job.yml`. Relative paths in the file are
+/// relative to its directory.
+use std::path::{Path, PathBuf};
+
+use config::{Config, File};
+use serde::Deserialize;
+use tracing::{debug, instrument};
+
+use crate::download::is_url;
+use crate::error::VideoEncodeError;
+use crate::ffmpeg::container::is_stdout;
+
+/// Encode described by a job file. Options of the command line override it.
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct JobSpec {
+    /// Inputs, like `["movie.mkv"]`
+    #[serde(default)]
+    pub input: Vec<PathBuf>,
+    /// Output file, output directory for multiple inputs, or `-` for stdout
+    pub output: Option<PathBuf>,
+    /// Config file with settings of the encode
+    pub config: Option<PathBuf>,
+    /// Named preset of the config file
+    pub preset: Option<String>,
+    /// File of zones that override encoder parameters for frame ranges
+    pub zones: Option<PathBuf>,
+    /// Addresses of nodes that encode the job
+    #[serde(default)]
+    pub nodes: Vec<String>,
+    /// Slots of every node, in order of `nodes`
+    #[serde(default)]
+    pub slots: Vec<usize>,
+    #[serde(default)]
+    pub tracks: JobTracks,
+}
+
+/// Audio and subtitle tracks of the input kept in the output, all when not set
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct JobTracks {
+    #[serde(default)]
+    pub audio_languages: Vec<String>,
+    #[serde(default)]
+    pub audio_tracks: Vec<usize>,
+    #[serde(default)]
+    pub subtitle_languages: Vec<String>,
+    #[serde(default)]
+    pub subtitle_tracks: Vec<usize>,
+}
+
+impl JobSpec {
+    /// Reads job file `path`, with its paths resolved against its directory
+    #[instrument]
+    pub fn from_file(path: &Path) -> Result<Self, VideoEncodeError> {
+        let mut job: JobSpec = Config::builder()
+            .add_source(File::from(path))
+            .build()
+            .and_then(|config| config.try_deserialize())
+            .map_err(|e| {
+                VideoEncodeError::Encoding(format!("Invalid job file {:?}: {}", path, e))
+            })?;
+
+        let dir = path.parent().unwrap_or(Path::new(""));
+        for input in &mut job.input {
+            *input = resolve(dir, input);
+        }
+        for file in [&mut job.output, &mut job.config, &mut job.zones]
+            .into_iter()
+            .flatten()
+        {
+            *file = resolve(dir, file);
+        }
+        debug!("Job of {:?}: {:?}", path, job);
+        Ok(job)
+    }
+}
+
+/// `path` relative to `dir`, unless it's absolute, URL or stdout
+fn resolve(dir: &Path, path: &Path) -> PathBuf {
+    if path.is_absolute() || is_url(path) || is_stdout(path) {
+        path.to_path_buf()
+    } else {
+        dir.join(path)
+    }
+}
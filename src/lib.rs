@@ -1,6 +1,38 @@
+pub mod bitrate;
+pub mod cache;
+pub mod cgroup;
 pub mod chunk;
 pub mod config;
+pub mod crf;
+pub mod crypto;
+pub mod dashboard;
+pub mod diagnostics;
+pub mod dispatch;
+pub mod download;
+pub mod encode;
+pub mod encoder;
 pub mod error;
 pub mod ffmpeg;
+pub mod generate;
+pub mod job;
 pub mod logging;
+pub mod metrics;
+pub mod monitor;
+pub mod nodes;
+pub mod notify;
+pub mod prepare;
+pub mod priority;
+pub mod progress;
+pub mod progress_bars;
+pub mod proto;
+pub mod report;
+pub mod reproduce;
 pub mod settings;
+pub mod status;
+pub mod telemetry;
+pub mod throttle;
+pub mod timing;
+pub mod transport;
+pub mod vapoursynth;
+pub mod web;
+pub mod zones;
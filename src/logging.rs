@@ -1,50 +1,298 @@
 use std::env;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::sync::{Mutex, OnceLock};
+
+use clap::{ArgAction, Args};
+use indicatif::MultiProgress;
+use tracing::Subscriber;
 use tracing_appender::rolling::{RollingFileAppender, Rotation};
-use tracing_subscriber::{fmt, prelude::*, EnvFilter};
+use tracing_subscriber::fmt::writer::BoxMakeWriter;
+use tracing_subscriber::registry::LookupSpan;
+use tracing_subscriber::{fmt, fmt::MakeWriter, prelude::*, EnvFilter, Layer};
+
+use crate::progress_bars::BarsWriter;
+use crate::settings::{LogRotation, LogSettings};
+use crate::telemetry::otlp_layer;
+
+/// Name of the log file. Files rotated by time end with their hour or day, files
+/// rotated by size with their number
+const LOG_FILE: &str = "application.log";
+
+/// Directory and rotation of the log file, once logging is initialized
+static FILE_LOG: OnceLock<(PathBuf, LogRotation)> = OnceLock::new();
+
+/// Log file that is being written, if logs are written into files
+pub fn current_log_file() -> Option<PathBuf> {
+    let (dir, rotation) = FILE_LOG.get()?;
+    match rotation {
+        LogRotation::Size | LogRotation::Never => Some(dir.join(LOG_FILE)),
+        LogRotation::Hourly | LogRotation::Daily => {
+            let prefix = format!("{}.", LOG_FILE);
+            fs::read_dir(dir)
+                .ok()?
+                .filter_map(Result::ok)
+                .filter(|entry| entry.file_name().to_string_lossy().starts_with(&prefix))
+                .max_by_key(|entry| entry.file_name())
+                .map(|entry| entry.path())
+        }
+    }
+}
+
+/// Format of log lines, of the console and the log file
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, clap::ValueEnum)]
+pub enum LogFormat {
+    /// Lines of text, for people
+    #[default]
+    Text,
+    /// JSON object per line, with fields of events and spans like `chunk_index`, `node`,
+    /// `duration_ms` and `bytes`, for Loki or Elasticsearch
+    Json,
+}
+
+/// Verbosity of logs, set by `-v` and `-q` flags of binaries
+#[derive(Args, Debug, Clone, Copy, Default)]
+pub struct Verbosity {
+    /// Log more, debug logs with -v, all logs with -vv
+    #[arg(short, long, action = ArgAction::Count, global = true)]
+    verbose: u8,
+    /// Log less, warnings and errors with -q, only errors with -qq
+    #[arg(short, long, action = ArgAction::Count, global = true, conflicts_with = "verbose")]
+    quiet: u8,
+}
+
+impl Verbosity {
+    /// Filter of logs of the application, with `binary` being the crate name of the
+    /// binary. Logs of libraries are only shown from warnings, and from debug logs
+    /// with -vv.
+    pub fn filter(&self, binary: &str) -> String {
+        let level = match i16::from(self.verbose) - i16::from(self.quiet) {
+            ..=-2 => "error",
+            -1 => "warn",
+            0 => "info",
+            1 => "debug",
+            _ => "trace",
+        };
+        let libraries = match (self.verbose, self.quiet) {
+            (2.., _) => "debug",
+            (_, 2..) => "error",
+            _ => "warn",
+        };
+        format!(
+            "{},{}={},{}={}",
+            libraries,
+            env!("CARGO_CRATE_NAME"),
+            level,
+            binary,
+            level
+        )
+    }
+}
 
 /// Initialize the logging system for the application.
 ///
 /// This function sets up tracing with the following features:
-/// - Reads log level from the RUST_LOG environment variable (defaults to "info")
-/// - Enables logging to both console and a file
-/// - Uses daily log rotation for file logging
+/// - Reads log level from the RUST_LOG environment variable (defaults to `default_filter`,
+///   like the one of [`Verbosity::filter`])
+/// - Enables logging to both console and files of `file`, with their own filter
+/// - Rotates log files by time or size, keeping a bounded number of them
+/// - Writes lines in `format`, text or JSON objects
 /// - Logs the duration of each span
 /// - Includes file and line numbers in log messages
+/// - Exports spans over OTLP when `OTEL_EXPORTER_OTLP_ENDPOINT` is set, see [`otlp_layer`]
 ///
 /// # Panics
 ///
 /// This function will panic if it fails to initialize the global logger.
-pub fn init_logging() {
-    let rust_log = env::var("RUST_LOG").unwrap_or_else(|_| "info".to_string());
+pub fn init_logging(default_filter: &str, format: LogFormat, file: &LogSettings) {
+    init_logging_with(std::io::stdout, default_filter, format, file);
+}
 
-    // Set up daily rotating file appender
-    let file_appender = RollingFileAppender::new(Rotation::DAILY, "logs", "application.log");
+/// Initialize the logging system like [`init_logging`], with console logs written
+/// to stderr, so stdout is left to output the application writes there.
+///
+/// # Panics
+///
+/// This function will panic if it fails to initialize the global logger.
+pub fn init_stderr_logging(default_filter: &str, format: LogFormat, file: &LogSettings) {
+    init_logging_with(std::io::stderr, default_filter, format, file);
+}
 
-    let (non_blocking, _guard) = tracing_appender::non_blocking(file_appender);
+/// Initialize the logging system like [`init_logging`], with logs only written to the
+/// file, so the terminal is left to a dashboard.
+///
+/// # Panics
+///
+/// This function will panic if it fails to initialize the global logger.
+pub fn init_file_logging(default_filter: &str, format: LogFormat, file: &LogSettings) {
+    init_logging_with(std::io::sink, default_filter, format, file);
+}
+
+/// Initialize the logging system like [`init_logging`], with console logs written to
+/// stderr above progress bars of `multi`.
+///
+/// # Panics
+///
+/// This function will panic if it fails to initialize the global logger.
+pub fn init_bars_logging(
+    multi: MultiProgress,
+    default_filter: &str,
+    format: LogFormat,
+    file: &LogSettings,
+) {
+    init_logging_with(BarsWriter::new(multi), default_filter, format, file);
+}
+
+fn init_logging_with<W>(console: W, default_filter: &str, format: LogFormat, file: &LogSettings)
+where
+    W: for<'writer> MakeWriter<'writer> + Send + Sync + 'static,
+{
+    let rust_log = env::var("RUST_LOG").unwrap_or_else(|_| default_filter.to_string());
+
+    let file_layer = file
+        .enabled
+        .then(|| match file_writer(file) {
+            Ok(writer) => {
+                let _ = FILE_LOG.set((file.dir.clone(), file.rotation));
+                let filter = file.level.as_deref().unwrap_or(&rust_log);
+                Some(fmt_layer(writer, false, format).with_filter(EnvFilter::new(filter)))
+            }
+            Err(e) => {
+                // Logging isn't initialized yet
+                eprintln!("Failed to write logs into {:?}: {}", file.dir, e);
+                None
+            }
+        })
+        .flatten();
 
     let subscriber = tracing_subscriber::registry()
-        .with(EnvFilter::new(rust_log))
-        .with(
-            fmt::Layer::new()
-                .with_writer(std::io::stdout)
-                .with_ansi(true)
-                .with_file(true)
-                .with_line_number(true)
-                .with_thread_ids(true)
-                .with_thread_names(true),
-        )
-        .with(
-            fmt::Layer::new()
-                .with_writer(non_blocking)
-                .with_ansi(false)
-                .with_file(true)
-                .with_line_number(true)
-                .with_thread_ids(true)
-                .with_thread_names(true),
-        );
+        .with(fmt_layer(console, true, format).with_filter(EnvFilter::new(&rust_log)))
+        .with(file_layer)
+        .with(otlp_layer().with_filter(EnvFilter::new(&rust_log)));
 
     tracing::subscriber::set_global_default(subscriber)
         .expect("Failed to set global default subscriber");
 
-    tracing::info!("Logging initialized with daily rotation");
+    tracing::info!("Logging initialized");
+}
+
+/// Writer of log files of `settings`. Files are written directly, so lines logged right
+/// before the process exits aren't lost
+fn file_writer(settings: &LogSettings) -> io::Result<BoxMakeWriter> {
+    let rotation = match settings.rotation {
+        LogRotation::Hourly => Rotation::HOURLY,
+        LogRotation::Daily => Rotation::DAILY,
+        LogRotation::Never => Rotation::NEVER,
+        LogRotation::Size => {
+            let file = SizeRollingFile::open(
+                &settings.dir,
+                settings.max_size * 1024 * 1024,
+                settings.max_files,
+            )?;
+            return Ok(BoxMakeWriter::new(Mutex::new(file)));
+        }
+    };
+    let mut builder = RollingFileAppender::builder()
+        .rotation(rotation)
+        .filename_prefix(LOG_FILE);
+    if settings.max_files > 0 {
+        builder = builder.max_log_files(settings.max_files);
+    }
+    let appender = builder.build(&settings.dir).map_err(io::Error::other)?;
+    Ok(BoxMakeWriter::new(appender))
+}
+
+/// Layer writing log lines in `format` to `writer`, with colors when `ansi` is set
+fn fmt_layer<S, W>(writer: W, ansi: bool, format: LogFormat) -> Box<dyn Layer<S> + Send + Sync>
+where
+    S: Subscriber + for<'span> LookupSpan<'span>,
+    W: for<'writer> MakeWriter<'writer> + Send + Sync + 'static,
+{
+    let layer = fmt::Layer::new()
+        .with_writer(writer)
+        .with_file(true)
+        .with_line_number(true)
+        .with_thread_ids(true)
+        .with_thread_names(true);
+    match format {
+        LogFormat::Text => layer.with_ansi(ansi).boxed(),
+        // Fields of events are top-level keys, so they can be queried like labels
+        LogFormat::Json => layer.json().flatten_event(true).boxed(),
+    }
+}
+
+/// Log file that is rotated once it reaches its maximum size. Older files end with their
+/// number, `application.log.1` being the latest
+struct SizeRollingFile {
+    dir: PathBuf,
+    max_size: u64,
+    /// Number of files that are kept, with the current one, 0 keeps all of them
+    max_files: usize,
+    file: File,
+    size: u64,
+}
+
+impl SizeRollingFile {
+    fn open(dir: &Path, max_size: u64, max_files: usize) -> io::Result<Self> {
+        fs::create_dir_all(dir)?;
+        let file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(dir.join(LOG_FILE))?;
+        let size = file.metadata()?.len();
+        Ok(SizeRollingFile {
+            dir: dir.to_path_buf(),
+            max_size,
+            max_files,
+            file,
+            size,
+        })
+    }
+
+    fn rotated(&self, number: usize) -> PathBuf {
+        self.dir.join(format!("{}.{}", LOG_FILE, number))
+    }
+
+    /// Moves every file one number up, removing ones over the limit, and starts a new
+    /// current file
+    fn rotate(&mut self) -> io::Result<()> {
+        let keep = |number: usize| self.max_files == 0 || number < self.max_files;
+        let mut last = 0;
+        while self.rotated(last + 1).exists() {
+            last += 1;
+        }
+        for number in (1..=last).rev() {
+            if keep(number + 1) {
+                fs::rename(self.rotated(number), self.rotated(number + 1))?;
+            } else {
+                fs::remove_file(self.rotated(number))?;
+            }
+        }
+
+        let current = self.dir.join(LOG_FILE);
+        if keep(1) {
+            fs::rename(&current, self.rotated(1))?;
+        } else {
+            fs::remove_file(&current)?;
+        }
+        self.file = File::create(&current)?;
+        self.size = 0;
+        Ok(())
+    }
+}
+
+impl Write for SizeRollingFile {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        if self.size > 0 && self.size + buf.len() as u64 > self.max_size {
+            self.rotate()?;
+        }
+        let written = self.file.write(buf)?;
+        self.size += written as u64;
+        Ok(written)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.file.flush()
+    }
 }
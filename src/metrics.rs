@@ -0,0 +1,177 @@
+/// This module serves metrics of an encode in the text format of Prometheus, so long
+/// encodes can be watched from dashboards and alerted on: chunks by state, retries,
+/// speed and estimated time of the job, and load and throughput of every node.
+use std::convert::Infallible;
+use std::fmt::Write;
+use std::net::SocketAddr;
+
+use hyper::header::CONTENT_TYPE;
+use hyper::server::conn::AddrIncoming;
+use hyper::service::{make_service_fn, service_fn};
+use hyper::{Body, Response, Server};
+use tokio::sync::watch;
+use tracing::{error, info, instrument};
+
+use crate::dashboard::{DashboardView, NodeView};
+use crate::error::VideoEncodeError;
+
+/// Content type of the text format of Prometheus
+const METRICS_CONTENT_TYPE: &str = "text/plain; version=0.0.4";
+
+/// Listener of the metrics endpoint, bound before the encode starts, so an address
+/// that is taken fails the encode right away
+pub struct MetricsServer {
+    incoming: AddrIncoming,
+}
+
+impl MetricsServer {
+    #[instrument]
+    pub fn bind(address: SocketAddr) -> Result<Self, VideoEncodeError> {
+        let incoming = AddrIncoming::bind(&address).map_err(|e| {
+            VideoEncodeError::Encoding(format!("Failed to serve metrics on {}: {}", address, e))
+        })?;
+        info!("Serving metrics on http://{}/metrics", address);
+        Ok(MetricsServer { incoming })
+    }
+
+    /// Answers every request with the latest metrics of `metrics`, until the task is
+    /// aborted
+    pub async fn serve(self, metrics: watch::Receiver<String>) {
+        let make_service = make_service_fn(move |_| {
+            let metrics = metrics.clone();
+            async move {
+                Ok::<_, Infallible>(service_fn(move |_| {
+                    let body = metrics.borrow().clone();
+                    async move {
+                        Ok::<_, Infallible>(
+                            Response::builder()
+                                .header(CONTENT_TYPE, METRICS_CONTENT_TYPE)
+                                .body(Body::from(body))
+                                .expect("Metrics response is valid"),
+                        )
+                    }
+                }))
+            }
+        });
+        if let Err(e) = Server::builder(self.incoming).serve(make_service).await {
+            error!("Metrics server failed: {}", e);
+        }
+    }
+}
+
+/// Metrics of the encode `view` shows, in the text format of Prometheus
+pub fn render_metrics(view: &DashboardView) -> String {
+    let mut text = String::new();
+    let mut gauge = |name: &str, help: &str, samples: &[(String, f64)]| {
+        let _ = writeln!(text, "# HELP rav1an_{} {}", name, help);
+        let _ = writeln!(text, "# TYPE rav1an_{} gauge", name);
+        for (labels, value) in samples {
+            let _ = writeln!(text, "rav1an_{}{} {}", name, labels, value);
+        }
+    };
+
+    gauge(
+        "chunks",
+        "Chunks of the job by state",
+        &[
+            (r#"{state="pending"}"#.to_string(), view.pending as f64),
+            (
+                r#"{state="encoding"}"#.to_string(),
+                view.chunks.len() as f64,
+            ),
+            (r#"{state="completed"}"#.to_string(), view.completed as f64),
+        ],
+    );
+    gauge(
+        "chunks_total",
+        "Chunks of the job",
+        &[(String::new(), view.total as f64)],
+    );
+    gauge(
+        "retries",
+        "Failed attempts of chunks that were encoded again",
+        &[(String::new(), view.retries as f64)],
+    );
+    gauge(
+        "progress_ratio",
+        "Part of the job that is encoded",
+        &[(String::new(), view.fraction)],
+    );
+    gauge(
+        "fps",
+        "Frames per second all nodes encode",
+        &[(String::new(), view.fps)],
+    );
+    gauge(
+        "elapsed_seconds",
+        "Time since the job started",
+        &[(String::new(), view.elapsed.as_secs_f64())],
+    );
+    if let Some(eta) = view.eta {
+        gauge(
+            "eta_seconds",
+            "Estimated time until the job is encoded",
+            &[(String::new(), eta.as_secs_f64())],
+        );
+    }
+
+    let node_samples = |value: &dyn Fn(&NodeView) -> f64| -> Vec<(String, f64)> {
+        view.nodes
+            .iter()
+            .map(|node| {
+                let labels = format!(r#"{{node="{}"}}"#, escape_label(&node.address));
+                (labels, value(node))
+            })
+            .collect()
+    };
+    gauge(
+        "node_slots",
+        "Slots of the node",
+        &node_samples(&|node| node.slots as f64),
+    );
+    gauge(
+        "node_active_chunks",
+        "Chunks the node is sending, encoding or receiving",
+        &node_samples(&|node| node.active as f64),
+    );
+    gauge(
+        "node_completed_chunks",
+        "Chunks the node encoded",
+        &node_samples(&|node| node.completed as f64),
+    );
+    gauge(
+        "node_failed_chunks",
+        "Attempts of chunks that failed on the node",
+        &node_samples(&|node| node.failed as f64),
+    );
+    gauge(
+        "node_fps",
+        "Frames per second the node encodes",
+        &node_samples(&|node| {
+            view.chunks
+                .iter()
+                .filter(|chunk| chunk.node == node.address)
+                .map(|chunk| chunk.fps)
+                .sum()
+        }),
+    );
+    gauge(
+        "node_upload_bytes_per_second",
+        "Average rate chunks are sent to the node at",
+        &node_samples(&|node| node.upload_rate),
+    );
+    gauge(
+        "node_download_bytes_per_second",
+        "Average rate encoded chunks are received from the node at",
+        &node_samples(&|node| node.download_rate),
+    );
+    text
+}
+
+/// `value` escaped for a label of the text format
+fn escape_label(value: &str) -> String {
+    value
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+}
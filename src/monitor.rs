@@ -0,0 +1,286 @@
+/// This module shows progress of a running encode: periodic log lines, the terminal
+/// dashboard or progress bars, Prometheus metrics and the web UI status, and previews
+/// of encoded chunks. Every view is refreshed from the shared state of the encode.
+use std::collections::{HashMap, HashSet};
+use std::path::PathBuf;
+use std::sync::atomic::Ordering;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::{watch, Mutex};
+use tracing::{info, warn};
+
+use crate::dashboard::{ChunkView, Dashboard, DashboardView, NodeView};
+use crate::dispatch::EncodingState;
+use crate::ffmpeg::preview::write_preview;
+use crate::metrics::render_metrics;
+use crate::progress::format_duration;
+use crate::progress_bars::ProgressBars;
+use crate::web::{render_status, ChunkCell, ChunkState};
+
+const PROGRESS_REPORT_INTERVAL: Duration = Duration::from_secs(5);
+
+/// Periodically logs progress of the whole job, and speed and chunks of every node
+pub async fn report_progress(encoding_state: Arc<Mutex<EncodingState>>) {
+    let mut interval = tokio::time::interval(PROGRESS_REPORT_INTERVAL);
+    // First tick completes immediately, when there is nothing to report yet
+    interval.tick().await;
+
+    loop {
+        interval.tick().await;
+
+        let state = encoding_state.lock().await;
+        let progress = &state.progress;
+        info!(
+            "Progress: {:.1}% ({}/{} chunks), {:.1} fps, ETA {}",
+            progress.fraction() * 100.0,
+            progress.completed_chunks(),
+            progress.total_chunks(),
+            progress.fps(),
+            progress
+                .eta()
+                .map(format_duration)
+                .unwrap_or_else(|| "unknown".to_string())
+        );
+
+        let view = dashboard_view(&state);
+        for node in &view.nodes {
+            let chunks: Vec<&ChunkView> = view
+                .chunks
+                .iter()
+                .filter(|chunk| chunk.node == node.address)
+                .collect();
+            if chunks.is_empty() {
+                info!(node = %node.address, "Node {}: idle", node.address);
+                continue;
+            }
+            let fps: f64 = chunks.iter().map(|chunk| chunk.fps).sum();
+            let active = chunks
+                .iter()
+                .map(|chunk| format!("{} ({:.0}%)", chunk.index, chunk.fraction * 100.0))
+                .collect::<Vec<_>>()
+                .join(", ");
+            info!(
+                node = %node.address,
+                fps,
+                "Node {}: {:.1} fps, encoding chunks {}",
+                node.address,
+                fps,
+                active
+            );
+        }
+    }
+}
+
+/// Draws the dashboard of the encode every second, until the task is aborted
+pub async fn show_dashboard(encoding_state: Arc<Mutex<EncodingState>>) {
+    let mut dashboard = match Dashboard::start() {
+        Ok(dashboard) => dashboard,
+        Err(e) => {
+            warn!("Failed to show dashboard: {}", e);
+            return;
+        }
+    };
+    let mut interval = tokio::time::interval(Duration::from_secs(1));
+
+    loop {
+        interval.tick().await;
+
+        let view = dashboard_view(&*encoding_state.lock().await);
+        if let Err(e) = dashboard.draw(&view) {
+            warn!("Failed to draw dashboard: {}", e);
+            return;
+        }
+    }
+}
+
+/// Moves progress bars every second, until the task is aborted
+pub async fn show_progress_bars(encoding_state: Arc<Mutex<EncodingState>>, bars: ProgressBars) {
+    let mut interval = tokio::time::interval(Duration::from_secs(1));
+
+    loop {
+        interval.tick().await;
+
+        bars.update(&dashboard_view(&*encoding_state.lock().await));
+    }
+}
+
+/// Renders metrics of the encode for the metrics server every second, until the task
+/// is aborted
+pub async fn update_metrics(
+    encoding_state: Arc<Mutex<EncodingState>>,
+    metrics: watch::Sender<String>,
+) {
+    let mut interval = tokio::time::interval(Duration::from_secs(1));
+
+    loop {
+        interval.tick().await;
+
+        let view = dashboard_view(&*encoding_state.lock().await);
+        metrics.send_replace(render_metrics(&view));
+    }
+}
+
+/// Renders status of the encode for the web UI every second, until the task is
+/// aborted
+pub async fn update_web_status(
+    encoding_state: Arc<Mutex<EncodingState>>,
+    status: watch::Sender<String>,
+) {
+    let mut interval = tokio::time::interval(Duration::from_secs(1));
+
+    loop {
+        interval.tick().await;
+
+        let state = encoding_state.lock().await;
+        let rendered = render_status(
+            &dashboard_view(&state),
+            &chunk_map(&state),
+            &state.recent_errors,
+        );
+        drop(state);
+        status.send_replace(rendered);
+    }
+}
+
+/// Every chunk of the job with its state, in order of index
+fn chunk_map(state: &EncodingState) -> Vec<ChunkCell> {
+    let encoding: HashMap<usize, &String> = state
+        .node_activity
+        .iter()
+        .flat_map(|(address, activity)| activity.active.iter().map(move |&index| (index, address)))
+        .collect();
+    let completed: HashSet<usize> = state
+        .completed_chunks
+        .iter()
+        .map(|chunk| chunk.index)
+        .collect();
+
+    let mut chunks: Vec<ChunkCell> = state
+        .progress
+        .chunk_indices()
+        .map(|index| {
+            let (chunk_state, node) = if completed.contains(&index) {
+                let node = state.results.get(&index).map(|result| result.node.clone());
+                (ChunkState::Completed, node)
+            } else if let Some(address) = encoding.get(&index) {
+                (ChunkState::Encoding, Some(address.to_string()))
+            } else {
+                (ChunkState::Pending, None)
+            };
+            ChunkCell {
+                index,
+                state: chunk_state,
+                node,
+                failures: state.failures.get(&index).copied().unwrap_or_default(),
+            }
+        })
+        .collect();
+    chunks.sort_by_key(|chunk| chunk.index);
+    chunks
+}
+
+/// State of the encode as the dashboard and progress bars show it
+fn dashboard_view(state: &EncodingState) -> DashboardView {
+    let progress = &state.progress;
+    let elapsed = progress.elapsed();
+    let seconds = elapsed.as_secs_f64().max(1.0);
+
+    let mut nodes: Vec<NodeView> = state
+        .node_activity
+        .iter()
+        .map(|(address, activity)| NodeView {
+            address: address.clone(),
+            slots: activity.slots,
+            active: activity.active.len(),
+            completed: activity.completed,
+            failed: activity.failed,
+            upload_rate: activity.uploaded.load(Ordering::Relaxed) as f64 / seconds,
+            download_rate: activity.downloaded as f64 / seconds,
+        })
+        .collect();
+    nodes.sort_by(|a, b| a.address.cmp(&b.address));
+
+    let fps: HashMap<usize, f64> = progress
+        .in_flight()
+        .map(|(index, progress)| (index, progress.fps))
+        .collect();
+    let mut chunks: Vec<ChunkView> = state
+        .node_activity
+        .iter()
+        .flat_map(|(address, activity)| {
+            activity.active.iter().map(|&index| ChunkView {
+                index,
+                node: address.clone(),
+                fps: fps.get(&index).copied().unwrap_or_default(),
+                fraction: progress.chunk_fraction(index),
+                eta: progress.chunk_eta(index),
+            })
+        })
+        .collect();
+    chunks.sort_by_key(|chunk| chunk.index);
+
+    DashboardView {
+        nodes,
+        chunks,
+        completed: progress.completed_chunks(),
+        pending: state.pending_chunks.len(),
+        total: progress.total_chunks(),
+        fraction: progress.fraction(),
+        fps: progress.fps(),
+        eta: progress.eta(),
+        elapsed,
+        retries: state.failures.values().sum(),
+    }
+}
+
+/// Periodically joins chunks encoded from the start of every output into its preview.
+/// `previews` are number of the input, its preview and temporary directory.
+pub async fn update_previews(
+    encoding_state: Arc<Mutex<EncodingState>>,
+    previews: Vec<(usize, PathBuf, PathBuf)>,
+    period: Duration,
+) {
+    let mut interval = tokio::time::interval(period);
+    // First tick completes immediately, when there is nothing to join yet
+    interval.tick().await;
+    let mut joined = vec![0; previews.len()];
+
+    loop {
+        interval.tick().await;
+
+        for ((job, preview, temp_dir), joined) in previews.iter().zip(&mut joined) {
+            let chunks = encoded_prefix(&*encoding_state.lock().await, *job);
+            if chunks.len() <= *joined {
+                continue;
+            }
+            match write_preview(&chunks, preview, temp_dir).await {
+                Ok(()) => {
+                    info!("Preview {:?} has {} chunks", preview, chunks.len());
+                    *joined = chunks.len();
+                }
+                Err(e) => warn!("Failed to update preview {:?}: {}", preview, e),
+            }
+        }
+    }
+}
+
+/// Encoded chunks of input `job` from its start, up to the first chunk that isn't
+/// encoded yet
+fn encoded_prefix(state: &EncodingState, job: usize) -> Vec<PathBuf> {
+    let encoded: HashMap<usize, &PathBuf> = state
+        .completed_chunks
+        .iter()
+        .filter_map(|chunk| Some((chunk.index, chunk.encoded_path.as_ref()?)))
+        .collect();
+    let mut chunks: Vec<(&Vec<usize>, usize)> = state
+        .progress
+        .chunk_indices()
+        .filter(|index| state.chunk_jobs.get(index) == Some(&job))
+        .filter_map(|index| Some((state.positions.get(&index)?, index)))
+        .collect();
+    chunks.sort();
+    chunks
+        .iter()
+        .map_while(|(_, index)| encoded.get(index).map(|path| path.to_path_buf()))
+        .collect()
+}
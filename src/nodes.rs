@@ -0,0 +1,526 @@
+/// This module connects the client to nodes, and keeps only nodes that can encode the
+/// job: reachable, with the encoder and its version the settings select. Status of
+/// nodes, their checks and benchmarks are printed from the same connections.
+use anyhow::{Context, Result};
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::AtomicU64;
+use std::sync::Arc;
+use std::time::{Duration, UNIX_EPOCH};
+use tokio::sync::Semaphore;
+use tracing::{info, instrument, warn};
+use uuid::Uuid;
+
+use crate::chunk::{verify_ffmpeg, Chunk};
+use crate::crypto::MasterKey;
+use crate::dispatch::{send_chunk, RequestOptions};
+use crate::encoder::hardware::hardware_codec;
+use crate::encoder::EncoderKind;
+use crate::ffmpeg::probe::probe_media;
+use crate::ffmpeg::trim::{trim_input, Position};
+use crate::progress::format_duration;
+use crate::proto::video_encoding_service_client::VideoEncodingServiceClient;
+use crate::proto::{GetStatusRequest, GetStatusResponse};
+use crate::settings::{Settings, VerifySettings, VersionPolicy};
+use crate::transport::{connect, NodeChannel, Throttle, ThrottleFactory};
+
+const MAX_MESSAGE_SIZE: usize = 1024 * 1024 * 1024; // 1 GB
+/// Largest chunk that is uploaded, leaves room for the rest of the request
+pub const MAX_CHUNK_SIZE: usize = MAX_MESSAGE_SIZE - 1024 * 1024;
+
+/// Number of chunks encoded to estimate an encode, at least one for every node
+pub const ESTIMATE_SAMPLES: usize = 5;
+
+/// Represents a node connection with its processing capacity
+#[derive(Clone)]
+pub struct NodeConnection {
+    pub client: VideoEncodingServiceClient<NodeChannel>,
+    pub address: String,
+    pub semaphore: Arc<Semaphore>,
+    /// Hashes of chunks uploaded to this node, by source of the chunk,
+    /// so retries and other renditions of the chunk can reuse them
+    pub uploaded_chunks: UploadedChunks,
+    /// Bytes of chunks sent to this node
+    pub uploaded_bytes: Arc<AtomicU64>,
+}
+
+pub type UploadedChunks = Arc<std::sync::Mutex<HashMap<String, String>>>;
+
+/// Initialize connections to all provided node addresses with their corresponding slots
+#[instrument(skip(addresses, slots, settings, throttles))]
+pub async fn initialize_nodes(
+    addresses: &[String],
+    slots: &[usize],
+    settings: &Settings,
+    throttles: &ThrottleFactory,
+) -> Result<Vec<NodeConnection>> {
+    let mut nodes = Vec::new();
+
+    if addresses.len() != slots.len() {
+        return Err(anyhow::anyhow!(
+            "Number of node addresses does not match the number of slot specifications"
+        ));
+    }
+
+    for (address, &slot_count) in addresses.iter().zip(slots.iter()) {
+        let channel = connect(address, settings, throttles.for_node(), slot_count)
+            .await
+            .context("Failed to connect to node")?;
+
+        let client = VideoEncodingServiceClient::new(channel)
+            .max_decoding_message_size(MAX_MESSAGE_SIZE)
+            .max_encoding_message_size(MAX_MESSAGE_SIZE);
+
+        nodes.push(NodeConnection {
+            client,
+            address: address.clone(),
+            semaphore: Arc::new(Semaphore::new(slot_count)),
+            uploaded_chunks: UploadedChunks::default(),
+            uploaded_bytes: Arc::default(),
+        });
+        info!("Connected to node at {} with {} slots", address, slot_count);
+    }
+
+    if nodes.is_empty() {
+        return Err(anyhow::anyhow!("No nodes available"));
+    }
+
+    Ok(nodes)
+}
+
+/// Keeps nodes that have the selected encoder, and hardware codec that parameters
+/// select, so chunks aren't sent to nodes that would reject them.
+/// Nodes that don't report their encoders are assumed to have only ffmpeg.
+#[instrument(skip(nodes, settings))]
+pub async fn select_capable_nodes(
+    nodes: Vec<NodeConnection>,
+    settings: &Settings,
+) -> Result<Vec<NodeConnection>> {
+    let encoder = settings.client.encoder.name();
+
+    let mut capable = Vec::new();
+    for node in nodes {
+        let status = match node.client.clone().get_status(GetStatusRequest {}).await {
+            Ok(status) => status.into_inner(),
+            Err(e) => {
+                warn!("Failed to get encoders of node {}: {}", node.address, e);
+                GetStatusResponse::default()
+            }
+        };
+
+        if can_encode(&status, settings) {
+            let version = status.encoder_versions.get(encoder).cloned();
+            capable.push((node, version));
+        } else {
+            warn!(
+                "Node {} can't encode with {}, no chunks are sent to it",
+                node.address,
+                required_encoder(settings)
+            );
+        }
+    }
+
+    if capable.is_empty() {
+        return Err(anyhow::anyhow!(
+            "No node can encode with {}",
+            required_encoder(settings)
+        ));
+    }
+
+    select_encoder_version(capable, settings)
+}
+
+/// Whether node of `status` has the encoder, and hardware codec that parameters select
+fn can_encode(status: &GetStatusResponse, settings: &Settings) -> bool {
+    let encoder = settings.client.encoder.name();
+    let codec = hardware_codec(&settings.client.encoder_params);
+    let has_encoder = if status.encoders.is_empty() {
+        encoder == EncoderKind::Ffmpeg.name()
+    } else {
+        status.encoders.iter().any(|name| name == encoder)
+    };
+    let has_codec =
+        codec.is_none_or(|codec| status.hardware_codecs.iter().any(|name| name == codec));
+    has_encoder && has_codec
+}
+
+/// Encoder, and hardware codec that parameters select, like `ffmpeg hevc_nvenc`
+fn required_encoder(settings: &Settings) -> String {
+    let encoder = settings.client.encoder.name();
+    match hardware_codec(&settings.client.encoder_params) {
+        Some(codec) => format!("{} {}", encoder, codec),
+        None => encoder.to_string(),
+    }
+}
+
+/// Checks that every node is reachable and can encode with the encoder of settings
+pub async fn check_nodes(settings: &Settings) -> Result<()> {
+    let addresses = &settings.client.node_addresses;
+    let statuses = futures::future::join_all(
+        addresses
+            .iter()
+            .map(|address| query_node_status(address, settings)),
+    )
+    .await;
+
+    let mut failed = 0;
+    for (address, status) in addresses.iter().zip(&statuses) {
+        match status {
+            Ok(status) if can_encode(status, settings) => println!("{:<32} ok", address),
+            Ok(_) => {
+                failed += 1;
+                println!(
+                    "{:<32} can't encode with {}",
+                    address,
+                    required_encoder(settings)
+                );
+            }
+            Err(e) => {
+                failed += 1;
+                println!("{:<32} unreachable: {:#}", address, e);
+            }
+        }
+    }
+
+    if failed > 0 {
+        anyhow::bail!("{} of {} nodes failed the check", failed, addresses.len());
+    }
+    Ok(())
+}
+
+/// Encodes the first `duration` seconds of `input` on every node at once, and prints
+/// how fast every node encoded them
+pub async fn benchmark_nodes(input: &Path, duration: f64, settings: &Settings) -> Result<()> {
+    if !duration.is_finite() || duration <= 0.0 {
+        anyhow::bail!("Duration of the sample has to be positive");
+    }
+    verify_ffmpeg()?;
+    let encoder = settings.client.encoder.encoder();
+    encoder.validate(&settings.client.encoder_params)?;
+
+    let dir = settings.processing.temp_dir.join("benchmark");
+    std::fs::create_dir_all(&dir)?;
+    let sample = dir.join("sample.mkv");
+    trim_input(
+        input,
+        None,
+        Some(Position::Time(duration)),
+        &sample,
+        settings.processing.lossless_intermediate,
+    )?;
+    let frames = probe_media(&sample)?.frames;
+    info!("Benchmarking nodes with {} frames of {:?}", frames, input);
+
+    let job_id = Uuid::new_v4().to_string();
+    let cipher = match &settings.encryption.key_file {
+        Some(key_file) => Some(Arc::new(
+            MasterKey::from_file(key_file)?.job_cipher(&job_id),
+        )),
+        None => None,
+    };
+    let options = RequestOptions {
+        job_id,
+        cipher,
+        encoder: settings.client.encoder,
+        passes: settings.client.passes,
+        film_grain_table: String::new(),
+        video_filter: settings.client.video_filters.join(","),
+        target_quality: None,
+        quality_metrics: Vec::new(),
+        vmaf: None,
+        quality_subsample: settings.client.quality.subsample,
+        verify: VerifySettings::default(),
+        reproducible: false,
+    };
+    let chunk = Chunk::new(sample, 0, settings.client.encoder_params.clone());
+
+    let addresses = &settings.client.node_addresses;
+    let results =
+        futures::future::join_all(addresses.iter().enumerate().map(|(number, address)| {
+            let encode_dir = dir.join(format!("node_{}", number));
+            benchmark_node(
+                address,
+                chunk.clone(),
+                options.clone(),
+                encode_dir,
+                settings,
+            )
+        }))
+        .await;
+
+    println!("{:<32} {:>9} {:>8}", "NODE", "TIME", "FPS");
+    for (address, result) in addresses.iter().zip(&results) {
+        match result {
+            Ok(time) => println!(
+                "{:<32} {:>8.1}s {:>8.2}",
+                address,
+                time,
+                frames as f64 / time.max(f64::EPSILON)
+            ),
+            Err(e) => println!("{:<32} failed: {:#}", address, e),
+        }
+    }
+
+    std::fs::remove_dir_all(&dir)?;
+    Ok(())
+}
+
+/// Seconds node at `address` spends encoding `chunk`
+async fn benchmark_node(
+    address: &str,
+    chunk: Chunk,
+    options: RequestOptions,
+    encode_dir: PathBuf,
+    settings: &Settings,
+) -> Result<f64> {
+    std::fs::create_dir_all(&encode_dir)?;
+    let channel = connect(address, settings, Throttle::default(), 1)
+        .await
+        .context("Failed to connect to node")?;
+    let client = VideoEncodingServiceClient::new(channel)
+        .max_decoding_message_size(MAX_MESSAGE_SIZE)
+        .max_encoding_message_size(MAX_MESSAGE_SIZE);
+    let (_, result) = send_chunk(
+        chunk,
+        options,
+        encode_dir,
+        client,
+        UploadedChunks::default(),
+        &AtomicU64::default(),
+    )
+    .await?;
+    Ok(result.encode_time)
+}
+
+/// Applies version policy to nodes by versions of the encoder they report. Required
+/// version, or the one most nodes have with strict policy, is the only one chunks
+/// are sent to. Nodes that don't report versions never have the required one.
+fn select_encoder_version(
+    nodes: Vec<(NodeConnection, Option<String>)>,
+    settings: &Settings,
+) -> Result<Vec<NodeConnection>> {
+    let encoder = settings.client.encoder.name();
+    // Versions in order of nodes, so the first of equally common ones wins
+    let mut counts: Vec<(&str, usize)> = Vec::new();
+    for version in nodes.iter().filter_map(|(_, version)| version.as_deref()) {
+        match counts.iter_mut().find(|(known, _)| *known == version) {
+            Some((_, count)) => *count += 1,
+            None => counts.push((version, 1)),
+        }
+    }
+    let unknown = nodes.iter().any(|(_, version)| version.is_none());
+
+    let required = match (
+        &settings.client.encoder_version,
+        settings.client.version_policy,
+    ) {
+        (Some(version), _) => Some(version.clone()),
+        (None, VersionPolicy::Strict) => counts
+            .iter()
+            .rev()
+            .max_by_key(|(_, count)| *count)
+            .map(|(version, _)| version.to_string()),
+        (None, VersionPolicy::Warn) => None,
+    };
+    let versions = nodes
+        .iter()
+        .map(|(node, version)| {
+            format!(
+                "{} has {}",
+                node.address,
+                version.as_deref().unwrap_or("unknown version")
+            )
+        })
+        .collect::<Vec<_>>()
+        .join(", ");
+
+    let Some(required) = required else {
+        if counts.len() > 1 || (unknown && !counts.is_empty()) {
+            warn!(
+                "Nodes have different versions of {}, chunks can look different: {}",
+                encoder, versions
+            );
+        } else if let [(version, _)] = counts[..] {
+            info!("All nodes have {} {}", encoder, version);
+        }
+        return Ok(nodes.into_iter().map(|(node, _)| node).collect());
+    };
+
+    let mut selected = Vec::new();
+    for (node, version) in nodes {
+        if version.as_deref() == Some(required.as_str()) {
+            selected.push(node);
+        } else {
+            warn!(
+                "Node {} has {} {}, not {}, no chunks are sent to it",
+                node.address,
+                encoder,
+                version.as_deref().unwrap_or("of unknown version"),
+                required
+            );
+        }
+    }
+
+    if selected.is_empty() {
+        anyhow::bail!("No node has {} {}: {}", encoder, required, versions);
+    }
+    info!(
+        "Chunks are sent to {} nodes with {} {}",
+        selected.len(),
+        encoder,
+        required
+    );
+    Ok(selected)
+}
+
+/// Queries status of all configured nodes and prints it as a table
+#[instrument(skip(settings))]
+pub async fn print_node_status(settings: &Settings) -> Result<()> {
+    let statuses = futures::future::join_all(
+        settings
+            .client
+            .node_addresses
+            .iter()
+            .map(|address| query_node_status(address, settings)),
+    )
+    .await;
+
+    println!(
+        "{:<32} {:>6} {:>6} {:>9} {:>5} {:>5} {:>5} {:>10} {:>8}  ENCODERS",
+        "NODE", "ACTIVE", "QUEUED", "SLOTS", "CPU", "MEM", "DISK", "DISK FREE", "FAILURES"
+    );
+    for (address, status) in settings.client.node_addresses.iter().zip(&statuses) {
+        match status {
+            Ok(status) => {
+                let slots = match status.total_slots {
+                    0 => "unlimited".to_string(),
+                    total => format!("{}/{}", status.active_encodes, total),
+                };
+                // Nodes that don't report usage have no memory
+                let cpu = match status.memory_total_bytes {
+                    0 => "-".to_string(),
+                    _ => format!("{:.0}%", status.cpu_usage),
+                };
+                println!(
+                    "{:<32} {:>6} {:>6} {:>9} {:>5} {:>5} {:>5} {:>10} {:>8}  {}",
+                    address,
+                    status.active_encodes,
+                    status.queued_encodes,
+                    slots,
+                    cpu,
+                    format_usage(status.memory_used_bytes, status.memory_total_bytes),
+                    format_usage(
+                        status
+                            .disk_total_bytes
+                            .saturating_sub(status.disk_free_bytes),
+                        status.disk_total_bytes
+                    ),
+                    format_bytes(status.disk_free_bytes),
+                    status.recent_failures.len(),
+                    format_encoders(status)
+                );
+            }
+            Err(e) => println!("{:<32} unreachable: {:#}", address, e),
+        }
+    }
+
+    for (address, status) in settings.client.node_addresses.iter().zip(&statuses) {
+        let Ok(status) = status else { continue };
+        if status.encoder_versions.is_empty() {
+            continue;
+        }
+
+        println!("\nEncoder versions on {}:", address);
+        let mut versions: Vec<_> = status.encoder_versions.iter().collect();
+        versions.sort();
+        for (name, version) in versions {
+            println!("  {}: {}", name, version);
+        }
+    }
+
+    for (address, status) in settings.client.node_addresses.iter().zip(&statuses) {
+        let Ok(status) = status else { continue };
+        if status.recent_failures.is_empty() {
+            continue;
+        }
+
+        println!("\nRecent failures on {}:", address);
+        for failure in &status.recent_failures {
+            let age = (UNIX_EPOCH + Duration::from_secs(failure.timestamp))
+                .elapsed()
+                .unwrap_or_default();
+            println!(
+                "  {} ago, job {} chunk {}: {}",
+                format_duration(age),
+                failure.job_id,
+                failure.chunk_index,
+                failure.error_message
+            );
+        }
+    }
+
+    Ok(())
+}
+
+pub async fn query_node_status(address: &str, settings: &Settings) -> Result<GetStatusResponse> {
+    let channel = connect(address, settings, Throttle::default(), 1)
+        .await
+        .context("Failed to connect to node")?;
+
+    let status = VideoEncodingServiceClient::new(channel)
+        .get_status(GetStatusRequest {})
+        .await
+        .context("Failed to get node status")?
+        .into_inner();
+
+    Ok(status)
+}
+
+/// Formats encoders of the node, with hardware codecs that work,
+/// e.g. `ffmpeg, nvenc (h264_nvenc, hevc_nvenc)`
+pub fn format_encoders(status: &GetStatusResponse) -> String {
+    if status.encoders.is_empty() {
+        return "-".to_string();
+    }
+
+    status
+        .encoders
+        .iter()
+        .map(|name| {
+            let codecs: Vec<&str> = status
+                .hardware_codecs
+                .iter()
+                .filter(|codec| codec.ends_with(&format!("_{}", name)))
+                .map(String::as_str)
+                .collect();
+            if codecs.is_empty() {
+                name.clone()
+            } else {
+                format!("{} ({})", name, codecs.join(", "))
+            }
+        })
+        .collect::<Vec<_>>()
+        .join(", ")
+}
+
+/// Formats byte count with binary unit, e.g. `12.3 GiB`
+/// Percent of `total` that is used, `-` when total isn't known
+fn format_usage(used: u64, total: u64) -> String {
+    match total {
+        0 => "-".to_string(),
+        total => format!("{:.0}%", used as f64 * 100.0 / total as f64),
+    }
+}
+
+pub fn format_bytes(bytes: u64) -> String {
+    const UNITS: [&str; 5] = ["B", "KiB", "MiB", "GiB", "TiB"];
+
+    let mut value = bytes as f64;
+    let mut unit = 0;
+    while value >= 1024.0 && unit < UNITS.len() - 1 {
+        value /= 1024.0;
+        unit += 1;
+    }
+
+    format!("{:.1} {}", value, UNITS[unit])
+}
@@ -0,0 +1,150 @@
+/// This module sends a summary of an encode when it finishes or fails: posted as JSON
+/// to a webhook, as a message to a Discord webhook, or shown as a desktop notification.
+/// Notifications that can't be sent are logged, and never fail the encode.
+use std::path::PathBuf;
+use std::time::Duration;
+
+use serde::Serialize;
+use tokio::process::Command;
+use tracing::{debug, instrument, warn};
+
+use crate::error::VideoEncodeError;
+use crate::progress::format_duration;
+use crate::settings::{NotifyOn, NotifySettings};
+
+/// Time a webhook has to answer
+const WEBHOOK_TIMEOUT: Duration = Duration::from_secs(10);
+/// Discord rejects longer messages
+const DISCORD_MESSAGE_LENGTH: usize = 2000;
+
+/// Summary of an encode, posted to webhooks as JSON
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct JobSummary {
+    pub inputs: Vec<PathBuf>,
+    /// Outputs that were written
+    pub outputs: Vec<PathBuf>,
+    /// Number of outputs that failed
+    pub failed_outputs: usize,
+    /// Number of chunks that were encoded
+    pub chunks: usize,
+    /// Seconds the encode took
+    pub elapsed: f64,
+    /// Bytes of outputs that were written
+    pub output_size: u64,
+    /// Why the encode failed
+    pub error: Option<String>,
+}
+
+impl JobSummary {
+    pub fn success(&self) -> bool {
+        self.error.is_none()
+    }
+
+    fn title(&self) -> &'static str {
+        if self.success() {
+            "Encode finished"
+        } else {
+            "Encode failed"
+        }
+    }
+
+    /// Summary as lines of text
+    fn text(&self) -> String {
+        let inputs: Vec<_> = self
+            .inputs
+            .iter()
+            .map(|input| input.display().to_string())
+            .collect();
+        let mut text = format!(
+            "{}\n{} outputs, {} failed, {} chunks, {:.1} MiB in {}",
+            inputs.join(", "),
+            self.outputs.len(),
+            self.failed_outputs,
+            self.chunks,
+            self.output_size as f64 / (1024.0 * 1024.0),
+            format_duration(Duration::from_secs_f64(self.elapsed))
+        );
+        if let Some(error) = &self.error {
+            text.push('\n');
+            text.push_str(error);
+        }
+        text
+    }
+}
+
+/// Sends `summary` everywhere `settings` say
+#[instrument(skip(summary))]
+pub async fn notify(settings: &NotifySettings, summary: &JobSummary) {
+    if settings.on == NotifyOn::Failure && summary.success() {
+        return;
+    }
+
+    if let Some(url) = &settings.webhook {
+        if let Err(e) = post(url, &serde_json::to_vec(summary).unwrap_or_default()).await {
+            warn!("Failed to send notification to webhook: {}", e);
+        }
+    }
+    if let Some(url) = &settings.discord {
+        let content: String = format!("**{}**\n{}", summary.title(), summary.text())
+            .chars()
+            .take(DISCORD_MESSAGE_LENGTH)
+            .collect();
+        let message = serde_json::json!({ "content": content });
+        if let Err(e) = post(url, &serde_json::to_vec(&message).unwrap_or_default()).await {
+            warn!("Failed to send notification to Discord: {}", e);
+        }
+    }
+    if settings.desktop {
+        if let Err(e) = notify_desktop(summary.title(), &summary.text()).await {
+            warn!("Failed to show desktop notification: {}", e);
+        }
+    }
+}
+
+/// Posts JSON `body` to `url`
+async fn post(url: &str, body: &[u8]) -> Result<(), VideoEncodeError> {
+    let notify_error = |e: reqwest::Error| VideoEncodeError::Encoding(e.to_string());
+    let response = reqwest::Client::builder()
+        .timeout(WEBHOOK_TIMEOUT)
+        .build()
+        .map_err(notify_error)?
+        .post(url)
+        .header(reqwest::header::CONTENT_TYPE, "application/json")
+        .body(body.to_vec())
+        .send()
+        .await
+        .map_err(notify_error)?;
+    if !response.status().is_success() {
+        return Err(VideoEncodeError::Encoding(format!(
+            "Webhook answered {}",
+            response.status()
+        )));
+    }
+    debug!("Notification was sent");
+    Ok(())
+}
+
+/// Shows notification with notify-send, or osascript on macOS
+async fn notify_desktop(title: &str, body: &str) -> Result<(), VideoEncodeError> {
+    let mut command = if cfg!(target_os = "macos") {
+        let quote = |text: &str| text.replace('\\', "\\\\").replace('"', "\\\"");
+        let mut command = Command::new("osascript");
+        command.arg("-e").arg(format!(
+            "display notification \"{}\" with title \"{}\"",
+            quote(body),
+            quote(title)
+        ));
+        command
+    } else {
+        let mut command = Command::new("notify-send");
+        command.args([title, body]);
+        command
+    };
+    let output = command.output().await?;
+    if !output.status.success() {
+        return Err(VideoEncodeError::Encoding(
+            String::from_utf8_lossy(&output.stderr).trim().to_string(),
+        ));
+    }
+    Ok(())
+}
@@ -0,0 +1,1208 @@
+/// This module prepares inputs of an encode before anything is sent to nodes: settings
+/// and outputs are checked, inputs are trimmed, deinterlaced, split into chunks and
+/// given their film grain, content tuning, CRF, zones and bitrates. Dry run prints
+/// the plan of the encode from the same checks.
+use anyhow::{Context, Result};
+use futures::StreamExt;
+use std::collections::{HashMap, HashSet};
+use std::path::{Path, PathBuf};
+use tracing::{debug, info, instrument, warn};
+
+use crate::bitrate::{allocate, measure_complexity};
+use crate::chunk::{
+    convert_files_to_chunks, index_chunks, index_script_chunks, index_sequence_chunks, split_video,
+    Chunk,
+};
+use crate::config::TempConfig;
+use crate::crf::{sample_chunks, select_crf};
+use crate::download::is_url;
+use crate::encode::EncodeOptions;
+use crate::encoder::pixel_format::{
+    resolve_pixel_format, take_pixel_format, PixelFormat, PIX_FMT_OPTION,
+};
+use crate::encoder::{add_filter, crf_value, with_crf, Encoder, EncoderKind, Vbv};
+use crate::ffmpeg::container::{check_input_streams, is_stdout, output_container, Container};
+use crate::ffmpeg::content::{measure_content, ContentStats};
+use crate::ffmpeg::grain::write_photon_noise_table;
+use crate::ffmpeg::interlace::{detect_scan_type, filter_input, ScanType};
+use crate::ffmpeg::metadata::{read_metadata, Metadata};
+use crate::ffmpeg::mp4box::{is_mp4, verify_mp4box};
+use crate::ffmpeg::probe::{probe_frame_times, probe_media, probe_open_gop, probe_pixel_format};
+use crate::ffmpeg::quality::Measurement;
+use crate::ffmpeg::segment::{extract_chapters, extract_non_video_streams};
+use crate::ffmpeg::sequence::{is_sequence, parse_frame_rate};
+use crate::ffmpeg::timestamps::{verify_mkvmerge, write_timecodes};
+use crate::ffmpeg::trim::trim_input;
+use crate::ffmpeg::webm::check_webm_codecs;
+use crate::nodes::{format_bytes, format_encoders, query_node_status};
+use crate::proto::{TargetQuality, Vmaf};
+use crate::settings::{
+    BitrateSettings, Chapters, ConcatMethod, ContentDetection, ContentSettings, ContentType,
+    CrfSearch, CrfSettings, Deinterlace, ExistingTemp, OpenGop, OverwritePolicy,
+    ProcessingSettings, Rendition, Settings, SplitMethod, VmafModel, VmafSettings,
+};
+use crate::vapoursynth::is_script;
+use crate::zones::override_params;
+
+/// Part of the input checked for open GOPs, in seconds from the start
+const OPEN_GOP_PROBE_SECONDS: u32 = 120;
+
+/// Number of chunks content type of an input is detected from
+const CONTENT_SAMPLES: usize = 8;
+
+/// Lossless intermediates are commonly this many times larger than a compressed source
+const LOSSLESS_INTERMEDIATE_RATIO: u64 = 8;
+
+/// Part of the size of the input non-video streams are estimated to take
+const NON_VIDEO_SHARE: f64 = 0.1;
+
+/// Input that is encoded into its own output, sharing nodes with other inputs
+pub struct Job {
+    pub output_file: PathBuf,
+    pub config: TempConfig,
+    /// Chunks of the input, until they are queued for encoding
+    pub chunks: Vec<Chunk>,
+    pub non_video_streams: Option<PathBuf>,
+    /// FFMETADATA file with chapters of the input, if they are kept
+    pub chapters: Option<PathBuf>,
+    /// Timecode file with source timestamps, if they are preserved
+    pub timecodes: Option<PathBuf>,
+    /// Photon noise table chunks are encoded with
+    pub film_grain_table: Option<String>,
+    /// Pixel format of the source, when it's encoded losslessly
+    pub lossless_format: Option<PixelFormat>,
+    /// Input sync of the output is checked against, none for scripts and image sequences
+    pub source: Option<PathBuf>,
+    /// Tags of the input and custom tags written into the output
+    pub metadata: Metadata,
+}
+
+/// File that `output_file` is written to, as `policy` says when it exists
+pub fn resolve_output(output_file: PathBuf, policy: OverwritePolicy) -> Result<PathBuf> {
+    if is_stdout(&output_file) || !output_file.exists() {
+        return Ok(output_file);
+    }
+    match policy {
+        OverwritePolicy::Overwrite => {
+            warn!("Output {:?} exists and will be replaced", output_file);
+            Ok(output_file)
+        }
+        OverwritePolicy::Fail => anyhow::bail!(
+            "Output {:?} exists, use --overwrite to replace it or --unique-name to write \
+             next to it",
+            output_file
+        ),
+        OverwritePolicy::UniqueName => {
+            let stem = output_file
+                .file_stem()
+                .unwrap_or_default()
+                .to_string_lossy();
+            let extension = output_file.extension().map(|e| e.to_string_lossy());
+            let unique = (1..)
+                .map(|number| {
+                    let name = match &extension {
+                        Some(extension) => format!("{}_{}.{}", stem, number, extension),
+                        None => format!("{}_{}", stem, number),
+                    };
+                    output_file.with_file_name(name)
+                })
+                .find(|path| !path.exists())
+                .unwrap_or_default();
+            info!("Output {:?} exists, writing {:?}", output_file, unique);
+            Ok(unique)
+        }
+    }
+}
+
+/// Checks that the temporary directory has space for files of the encode of `inputs`:
+/// segments, encoded chunks of every rendition and non-video streams. Encoded chunks
+/// are estimated at the size of the source, which they rarely exceed. Inputs of unknown
+/// size, like URLs and scripts, aren't counted
+pub fn check_temp_space(inputs: &[PathBuf], settings: &Settings) -> Result<()> {
+    let source: u64 = inputs
+        .iter()
+        .filter(|input| !is_url(input) && !is_script(input))
+        .filter_map(|input| std::fs::metadata(input).ok())
+        .map(|metadata| metadata.len())
+        .sum();
+    let processing = &settings.processing;
+    let segments = if processing.extract_on_demand {
+        0
+    } else if processing.lossless_intermediate {
+        source * LOSSLESS_INTERMEDIATE_RATIO
+    } else {
+        source
+    };
+    let encoded = source * settings.client.renditions.len().max(1) as u64;
+    let non_video = (source as f64 * NON_VIDEO_SHARE) as u64;
+    let required = segments + encoded + non_video;
+
+    let free = fs2::available_space(&processing.temp_dir).with_context(|| {
+        format!(
+            "Failed to get free space of temporary directory {:?}",
+            processing.temp_dir
+        )
+    })?;
+    debug!(
+        "Encode needs about {} of {} free in temporary directory",
+        format_bytes(required),
+        format_bytes(free)
+    );
+    if free < required {
+        anyhow::bail!(
+            "Temporary directory {:?} has {} free, but the encode needs about {}: {} of \
+             segments, {} of encoded chunks and {} of other streams. Free up space, use \
+             another temp_dir, or set check_free_space = false",
+            processing.temp_dir,
+            format_bytes(free),
+            format_bytes(required),
+            format_bytes(segments),
+            format_bytes(encoded),
+            format_bytes(non_video)
+        );
+    }
+    Ok(())
+}
+
+/// Handles files left in `temp_dir` by an earlier encode as `existing` says.
+/// Returns paths of files and directories that are reused
+pub fn prepare_temp_dir(temp_dir: &Path, existing: ExistingTemp) -> Result<HashSet<PathBuf>> {
+    let mut kept = HashSet::new();
+    let left = match std::fs::read_dir(temp_dir) {
+        Ok(mut entries) => entries.next().is_some(),
+        Err(_) => false,
+    };
+    if !left {
+        return Ok(kept);
+    }
+    match existing {
+        ExistingTemp::Fail => anyhow::bail!(
+            "Temporary directory {:?} has files of an earlier encode, which would be \
+             removed, continue that encode with `client resume`, or use --existing-temp \
+             clean to remove them first or reuse to keep them",
+            temp_dir
+        ),
+        ExistingTemp::Clean => {
+            info!("Removing files of an earlier encode from {:?}", temp_dir);
+            std::fs::remove_dir_all(temp_dir)?;
+            std::fs::create_dir_all(temp_dir)?;
+        }
+        ExistingTemp::Reuse => {
+            info!("Reusing files of an earlier encode in {:?}", temp_dir);
+            list_files(temp_dir, &mut kept)?;
+        }
+    }
+    Ok(kept)
+}
+
+/// Adds paths of files and directories in `dir` to `files`, recursively
+fn list_files(dir: &Path, files: &mut HashSet<PathBuf>) -> Result<()> {
+    for entry in std::fs::read_dir(dir)? {
+        let path = entry?.path();
+        if path.is_dir() {
+            list_files(&path, files)?;
+        }
+        files.insert(path);
+    }
+    Ok(())
+}
+
+/// Removes files and directories in `dir` that aren't `kept`
+pub fn remove_created(dir: &Path, kept: &HashSet<PathBuf>) -> Result<()> {
+    for entry in std::fs::read_dir(dir)? {
+        let path = entry?.path();
+        if kept.contains(&path) {
+            if path.is_dir() {
+                remove_created(&path, kept)?;
+            }
+        } else if path.is_dir() {
+            std::fs::remove_dir_all(&path)?;
+        } else {
+            std::fs::remove_file(&path)?;
+        }
+    }
+    Ok(())
+}
+
+/// Checks that nothing needs output written to stdout as a file
+pub fn check_stdout(settings: &Settings, batch: bool) -> Result<()> {
+    let client = &settings.client;
+    let needs_file = [
+        (batch, "Multiple inputs"),
+        (!client.renditions.is_empty(), "Renditions"),
+        (client.package.format.is_some(), "Packaging"),
+        (client.quality.report.is_some(), "Reports"),
+        (client.quality.scenes, "Scene reports"),
+        (client.reproducible.enabled, "Reproducible encoding"),
+        (client.output.preview, "Previews"),
+    ];
+    if let Some((_, what)) = needs_file.iter().find(|(needed, _)| *needed) {
+        anyhow::bail!("{} need output file, and can't be written to stdout", what);
+    }
+    Ok(())
+}
+
+/// Checks that every rendition has its own name, and that encoder can encode it
+pub fn check_renditions(
+    renditions: &[Rendition],
+    encoder: &dyn Encoder,
+    settings: &Settings,
+) -> Result<()> {
+    if renditions.is_empty() {
+        return Ok(());
+    }
+    if settings.client.bitrate.target.is_some() {
+        anyhow::bail!(
+            "Target bitrate can't be combined with renditions, set rate of every rendition in its parameters"
+        );
+    }
+
+    let mut names = HashSet::new();
+    for rendition in renditions {
+        if rendition.name.is_empty() || !names.insert(&rendition.name) {
+            anyhow::bail!(
+                "Every rendition needs its own name, {:?} is not one",
+                rendition.name
+            );
+        }
+        let params = rendition_params(&settings.client.encoder_params, rendition);
+        encoder
+            .validate(&params)
+            .with_context(|| format!("Invalid parameters of rendition {}", rendition.name))?;
+        if rendition.height.is_some() && !settings.client.quality.metrics.is_empty() {
+            anyhow::bail!(
+                "Quality of rendition {} can't be measured, it's scaled unlike the source",
+                rendition.name
+            );
+        }
+        if rendition.height.is_some() && encoder.with_filter(&params, "scale").is_none() {
+            anyhow::bail!(
+                "Encoder {} can't scale rendition {}",
+                encoder.name(),
+                rendition.name
+            );
+        }
+    }
+
+    info!(
+        "Encoding renditions {}",
+        renditions
+            .iter()
+            .map(|rendition| rendition.name.as_str())
+            .collect::<Vec<_>>()
+            .join(", ")
+    );
+    Ok(())
+}
+
+/// Encoder parameters of rendition, scaled after other filters
+fn rendition_params(params: &[String], rendition: &Rendition) -> Vec<String> {
+    let params = override_params(params, &rendition.encoder_params);
+    match rendition.height {
+        Some(height) => add_filter(&params, &format!("scale=-2:{}", height), false),
+        None => params,
+    }
+}
+
+/// Splits job into a job for every rendition, which share its chunks and temporary
+/// files. The first rendition keeps indices of chunks, others get new ones after
+/// `next_index`. Chunks keep their sources, so nodes reuse uploads of other renditions.
+pub fn rendition_jobs(job: Job, renditions: &[Rendition], next_index: &mut usize) -> Vec<Job> {
+    let stem = job
+        .output_file
+        .file_stem()
+        .unwrap_or_default()
+        .to_string_lossy()
+        .to_string();
+    let extension = job
+        .output_file
+        .extension()
+        .map(|extension| extension.to_string_lossy().to_string());
+
+    renditions
+        .iter()
+        .enumerate()
+        .map(|(number, rendition)| {
+            let chunks = job
+                .chunks
+                .iter()
+                .map(|chunk| {
+                    let mut chunk = chunk.clone();
+                    if number > 0 {
+                        chunk.index = *next_index;
+                        *next_index += 1;
+                    }
+                    chunk.encoder_parameters =
+                        rendition_params(&chunk.encoder_parameters, rendition);
+                    // Chunks extracted on demand are written by every rendition
+                    if chunk.range.is_some() {
+                        let source_stem = chunk
+                            .source_path
+                            .file_stem()
+                            .unwrap_or_default()
+                            .to_string_lossy()
+                            .to_string();
+                        chunk
+                            .source_path
+                            .set_file_name(format!("{}_{}.mp4", source_stem, rendition.name));
+                    }
+                    chunk
+                })
+                .collect();
+
+            let output_file = job.output_file.with_file_name(match &extension {
+                Some(extension) => format!("{}_{}.{}", stem, rendition.name, extension),
+                None => format!("{}_{}", stem, rendition.name),
+            });
+
+            Job {
+                output_file,
+                config: job.config.clone(),
+                chunks,
+                non_video_streams: job.non_video_streams.clone(),
+                chapters: job.chapters.clone(),
+                timecodes: job.timecodes.clone(),
+                film_grain_table: job.film_grain_table.clone(),
+                lossless_format: job.lossless_format,
+                source: job.source.clone(),
+                metadata: job.metadata.clone(),
+            }
+        })
+        .collect()
+}
+
+/// Checks that encoder can encode to target bitrate within VBV constraints,
+/// and returns the constraints
+pub fn check_bitrate(encoder: &dyn Encoder, settings: &BitrateSettings) -> Result<Option<Vbv>> {
+    if settings.bufsize.is_some() && settings.maxrate.is_none() {
+        anyhow::bail!("VBV buffer size requires maximum rate");
+    }
+    let vbv = settings.vbv();
+    let Some(target) = settings.target else {
+        if vbv.is_some() {
+            anyhow::bail!("VBV constraints require target bitrate");
+        }
+        return Ok(None);
+    };
+
+    if encoder.rate_params(target, vbv).is_none() {
+        anyhow::bail!(
+            "{} can't encode to target bitrate{}",
+            encoder.name(),
+            if vbv.is_some() { " within VBV" } else { "" }
+        );
+    }
+    if settings.complexity_weight < 0.0 || settings.max_deviation < 1.0 {
+        anyhow::bail!("Complexity weight can't be negative, and maximum deviation is at least 1");
+    }
+
+    Ok(vbv)
+}
+
+/// Lossless parameters of the encoder, with settings that would make output lossy rejected
+pub fn lossless_params(encoder: &dyn Encoder, settings: &Settings) -> Result<Vec<String>> {
+    let client = &settings.client;
+    if client.bitrate.target.is_some() || client.crf.target.is_some() {
+        anyhow::bail!("Lossless encoding can't be combined with target bitrate or quality");
+    }
+    if client.grain.enabled() {
+        anyhow::bail!("Lossless encoding can't be combined with film grain synthesis");
+    }
+    if !client.renditions.is_empty() {
+        anyhow::bail!("Lossless encoding can't be combined with renditions");
+    }
+
+    let params = encoder
+        .lossless_params(&client.encoder_params)
+        .with_context(|| {
+            format!(
+                "{} with these parameters can't encode losslessly",
+                encoder.name()
+            )
+        })?;
+    encoder
+        .validate(&params)
+        .context("Invalid lossless parameters")?;
+    info!("Encoding losslessly with {}", params.join(" "));
+    Ok(params)
+}
+
+/// Pixel format of the source, which lossless output has to keep. Requested format
+/// and bit depth can't differ from it. `None` for scripts, whose format isn't known.
+pub fn lossless_format(
+    source: Option<&str>,
+    pix_fmt: Option<&str>,
+    bit_depth: Option<u32>,
+    script: bool,
+) -> Result<Option<PixelFormat>> {
+    let Some(source) = source else {
+        if script {
+            warn!("Pixel format of script is not known, lossless output may convert it");
+            return Ok(None);
+        }
+        anyhow::bail!("Pixel format of the source is not known, so it can't be kept losslessly");
+    };
+    let format = PixelFormat::parse(source).with_context(|| {
+        format!(
+            "Source is in {}, which can't be encoded losslessly, only YUV formats can",
+            source
+        )
+    })?;
+
+    if let Some(requested) =
+        pix_fmt.filter(|&requested| PixelFormat::parse(requested) != Some(format))
+    {
+        anyhow::bail!(
+            "Lossless output keeps pixel format {} of the source, not {}",
+            source,
+            requested
+        );
+    }
+    if let Some(depth) = bit_depth.filter(|&depth| depth != format.bit_depth) {
+        anyhow::bail!(
+            "Lossless output keeps bit depth {} of the source, not {}",
+            format.bit_depth,
+            depth
+        );
+    }
+    Ok(Some(format))
+}
+
+/// Checks parameters tuned for content types, when they're applied
+pub fn check_content(encoder: &dyn Encoder, settings: &Settings) -> Result<()> {
+    let content = &settings.client.content;
+    if !content.enabled() {
+        return Ok(());
+    }
+    if content.animation_params.is_empty() && content.live_action_params.is_empty() {
+        anyhow::bail!("Content type is detected, but no parameters are tuned for it");
+    }
+
+    for content_type in [ContentType::Animation, ContentType::LiveAction] {
+        let params = override_params(
+            &settings.client.encoder_params,
+            content.params(content_type),
+        );
+        encoder
+            .validate(&params)
+            .with_context(|| format!("Invalid parameters of {:?}", content_type))?;
+    }
+    Ok(())
+}
+
+/// Applies encoder parameters tuned for content to chunks of the job. Content type is
+/// the one that's set, or detected for the job from sampled chunks, or for every chunk.
+/// Chunks that couldn't be measured get the type most of the job has.
+#[instrument(skip_all, fields(output = ?job.output_file))]
+pub async fn apply_content_params(job: &mut Job, settings: &ContentSettings) {
+    let count = job.chunks.len();
+    let types: Vec<Option<ContentType>> = match (settings.content_type, settings.detect) {
+        (Some(content_type), _) => vec![Some(content_type); count],
+        (None, ContentDetection::Off) => return,
+        (None, detect) => {
+            let measured: Vec<usize> = match detect {
+                ContentDetection::Job => {
+                    let samples = CONTENT_SAMPLES.min(count);
+                    (0..samples)
+                        .map(|part| (2 * part + 1) * count / (2 * samples))
+                        .collect()
+                }
+                _ => (0..count).collect(),
+            };
+            info!("Detecting content type of {} chunks", measured.len());
+
+            let jobs = std::thread::available_parallelism().map_or(1, |jobs| jobs.get());
+            let chunks = &job.chunks;
+            let detected: Vec<(usize, Option<ContentType>)> = futures::stream::iter(measured)
+                .map(|index| async move {
+                    let stats = measure_content(&chunks[index]).await.unwrap_or_else(|e| {
+                        warn!("{}, it gets type of the input", e);
+                        None
+                    });
+                    (index, stats.map(ContentStats::content_type))
+                })
+                .buffered(jobs)
+                .collect()
+                .await;
+
+            // Type that most of the measured duration has
+            let mut durations: HashMap<ContentType, f64> = HashMap::new();
+            for (index, content_type) in &detected {
+                if let Some(content_type) = content_type {
+                    *durations.entry(*content_type).or_default() +=
+                        chunks[*index].duration().unwrap_or(1.0);
+                }
+            }
+            let majority = durations
+                .into_iter()
+                .max_by(|a, b| a.1.total_cmp(&b.1))
+                .map(|(content_type, _)| content_type);
+            if majority.is_none() {
+                warn!("Content type couldn't be detected, parameters aren't tuned");
+            }
+
+            let mut types = vec![majority; count];
+            if detect == ContentDetection::Chunk {
+                for (index, content_type) in detected {
+                    types[index] = content_type.or(majority);
+                }
+            }
+            types
+        }
+    };
+
+    let mut animation = 0;
+    for (chunk, content_type) in job.chunks.iter_mut().zip(types) {
+        let Some(content_type) = content_type else {
+            continue;
+        };
+        if content_type == ContentType::Animation {
+            animation += 1;
+        }
+        chunk.encoder_parameters =
+            override_params(&chunk.encoder_parameters, settings.params(content_type));
+    }
+    info!(
+        "Tuned {} chunks for animation and {} for live action",
+        animation,
+        count - animation
+    );
+}
+
+/// Checks that encoder can encode at constant rate factor, when it's selected
+pub fn check_crf(encoder: &dyn Encoder, settings: &Settings) -> Result<()> {
+    let crf = &settings.client.crf;
+    if crf.target.is_none() {
+        return Ok(());
+    }
+    if settings.client.bitrate.target.is_some() {
+        anyhow::bail!("Target quality can't be combined with target bitrate");
+    }
+    if !settings.client.renditions.is_empty() {
+        anyhow::bail!(
+            "Target quality can't be combined with renditions, set CRF of every rendition in its parameters"
+        );
+    }
+    if encoder
+        .crf_option(&settings.client.encoder_params)
+        .is_none()
+    {
+        anyhow::bail!(
+            "{} with these parameters can't encode at constant rate factor",
+            encoder.name()
+        );
+    }
+    match crf.search {
+        CrfSearch::Title if crf.samples == 0 => {
+            anyhow::bail!("At least one chunk has to be sampled to select CRF")
+        }
+        CrfSearch::Chunk if crf.probes < 2 => {
+            anyhow::bail!("Every chunk has to be encoded at two CRFs at least to select CRF")
+        }
+        CrfSearch::Chunk if crf.max_bitrate.is_some() => {
+            anyhow::bail!("Maximum bitrate can only be kept when CRF is selected per title")
+        }
+        _ => Ok(()),
+    }
+}
+
+/// Checks that chunks below the quality floor can be encoded with stronger settings
+pub fn check_floor(encoder: &dyn Encoder, settings: &Settings) -> Result<()> {
+    let Some(floor) = &settings.client.quality.floor else {
+        return Ok(());
+    };
+    if settings.client.crf.target.is_some() && settings.client.crf.search == CrfSearch::Chunk {
+        anyhow::bail!("Quality floor can't lower CRF that nodes select for every chunk");
+    }
+    let lowers_crf =
+        floor.crf_step > 0 && crf_value(encoder, &settings.client.encoder_params).is_some();
+    if !lowers_crf && floor.params.is_empty() {
+        anyhow::bail!(
+            "Quality floor needs CRF in encoder parameters or parameters of retries to encode chunks with"
+        );
+    }
+    encoder.validate(&override_params(
+        &settings.client.encoder_params,
+        &floor.params,
+    ))?;
+    Ok(())
+}
+
+/// Target quality nodes select CRF of every chunk for, when CRF is searched per chunk
+pub fn target_quality(settings: &CrfSettings) -> Option<TargetQuality> {
+    let score = settings
+        .target
+        .filter(|_| settings.search == CrfSearch::Chunk)?;
+    Some(TargetQuality {
+        score,
+        metric: settings.metric.name().to_string(),
+        probes: settings.probes as u32,
+        min_crf: settings.min.unwrap_or(0),
+        max_crf: settings.max.unwrap_or(0),
+        probe_params: settings.probe_params.clone(),
+    })
+}
+
+/// VMAF model nodes compute VMAF with, with custom model read from its file.
+/// `None` when it's the default model of libvmaf.
+pub fn vmaf_model(settings: &VmafSettings) -> Result<Option<Vmaf>> {
+    let custom_model = match &settings.model_path {
+        Some(path) => {
+            let model = std::fs::read(path)
+                .with_context(|| format!("Failed to read VMAF model {:?}", path))?;
+            serde_json::from_slice::<serde_json::Value>(&model)
+                .with_context(|| format!("VMAF model {:?} isn't JSON", path))?;
+            model
+        }
+        None if settings.model == VmafModel::default() => return Ok(None),
+        None => Vec::new(),
+    };
+    Ok(Some(Vmaf {
+        model: settings.model.name().to_string(),
+        custom_model,
+    }))
+}
+
+/// Selects CRF of the job by probe encodes of sampled chunks on this machine,
+/// and sets it in parameters of every chunk
+#[instrument(skip_all, fields(output = ?job.output_file))]
+pub async fn select_job_crf(
+    job: &mut Job,
+    settings: &CrfSettings,
+    measurement: &Measurement,
+    encoder: &dyn Encoder,
+    video_filter: &str,
+) -> Result<()> {
+    let dir = job.config.temp_dir.join("crf");
+    std::fs::create_dir_all(&dir).context("Failed to create directory of probe encodes")?;
+    let result = async {
+        let samples = sample_chunks(&job.chunks, settings.samples, &dir).await?;
+        info!(
+            "Probing CRF on {} of {} chunks",
+            samples.len(),
+            job.chunks.len()
+        );
+        select_crf(&samples, encoder, settings, measurement, video_filter, &dir).await
+    }
+    .await;
+    let _ = std::fs::remove_dir_all(&dir);
+    let crf = result?;
+
+    for chunk in &mut job.chunks {
+        chunk.encoder_parameters = with_crf(encoder, &chunk.encoder_parameters, crf)
+            .expect("encoder was checked to support CRF");
+    }
+    Ok(())
+}
+
+/// Measures complexity of chunks of the job, and appends bitrate allocated to every
+/// chunk to its parameters. Chunks that couldn't be analyzed get the average complexity.
+#[instrument(skip_all, fields(output = ?job.output_file))]
+pub async fn allocate_bitrates(
+    job: &mut Job,
+    target: u64,
+    vbv: Option<Vbv>,
+    settings: &BitrateSettings,
+    encoder: &dyn Encoder,
+) {
+    info!("Analyzing complexity of {} chunks", job.chunks.len());
+    let jobs = std::thread::available_parallelism().map_or(1, |jobs| jobs.get());
+    let complexities: Vec<Option<f64>> = futures::stream::iter(&job.chunks)
+        .map(|chunk| async move {
+            measure_complexity(chunk).await.unwrap_or_else(|e| {
+                warn!("{}, it gets the average bitrate", e);
+                None
+            })
+        })
+        .buffered(jobs)
+        .collect()
+        .await;
+
+    let chunks: Vec<(usize, f64, Option<f64>)> = job
+        .chunks
+        .iter()
+        .zip(complexities)
+        .map(|(chunk, complexity)| (chunk.index, chunk.duration().unwrap_or(0.0), complexity))
+        .collect();
+    let bitrates = allocate(&chunks, target, vbv, settings);
+
+    for chunk in &mut job.chunks {
+        let bitrate = bitrates[&chunk.index];
+        let params = encoder
+            .rate_params(bitrate, vbv)
+            .expect("encoder was checked to support bitrate");
+        chunk.encoder_parameters.extend(params);
+    }
+
+    let (lowest, highest) = bitrates
+        .values()
+        .fold((u64::MAX, 0), |(low, high), &bitrate| {
+            (low.min(bitrate), high.max(bitrate))
+        });
+    info!(
+        "Allocated {}-{} kbit/s to chunks, {} kbit/s on average",
+        lowest, highest, target
+    );
+}
+
+/// Prints what would be encoded, into how many chunks, on which nodes and with which
+/// command, after checking that inputs can be encoded into their outputs
+pub async fn print_plan(
+    input_files: &[PathBuf],
+    output: &Path,
+    batch: bool,
+    settings: &Settings,
+    encoder: &dyn Encoder,
+    params: &[String],
+) -> Result<()> {
+    let processing = &settings.processing;
+    println!("Encoder: {}", encoder.name());
+    let command = encoder.command(Path::new("chunk.mkv"), Path::new("encoded.mkv"), params);
+    println!("Command of every chunk: {:?}", command.as_std());
+
+    let mut total_chunks = 0;
+    let output_files = output_paths(input_files, output, batch)?;
+    for (input_file, output_file) in input_files.iter().zip(output_files) {
+        let output_file = resolve_output(output_file, settings.client.output.overwrite)?;
+        println!();
+        println!("{} -> {}", input_file.display(), output_file.display());
+        if is_url(input_file) {
+            println!("  Remote input is probed once it's downloaded");
+            continue;
+        }
+
+        let frame_input = is_script(input_file) || is_sequence(input_file);
+        check_output(input_file, &output_file, frame_input, params, settings)
+            .with_context(|| format!("Can't encode {:?}", input_file))?;
+        if frame_input {
+            println!("  Frames of scripts and image sequences are counted when they're split");
+            continue;
+        }
+
+        let media = probe_media(input_file)?;
+        println!(
+            "  {}x{}, {} frames, {:.1} s",
+            media.width, media.height, media.frames, media.duration
+        );
+        // Scenes and keyframes only make more chunks than splitting by time
+        let chunks = match processing.segment_frames {
+            Some(frames) => media.frames.div_ceil(frames.max(1) as u64) as usize,
+            None => (media.duration / processing.segment_duration).ceil() as usize,
+        };
+        let qualifier = match processing.split_method {
+            SplitMethod::Time => "",
+            SplitMethod::Scene | SplitMethod::Keyframes => "at least ",
+        };
+        println!("  {}{} chunks", qualifier, chunks.max(1));
+        total_chunks += chunks.max(1);
+    }
+
+    println!();
+    let statuses = futures::future::join_all(
+        settings
+            .client
+            .node_addresses
+            .iter()
+            .map(|address| query_node_status(address, settings)),
+    )
+    .await;
+    let mut reachable = 0;
+    for (address, status) in settings.client.node_addresses.iter().zip(&statuses) {
+        match status {
+            Ok(status) => {
+                reachable += 1;
+                println!("Node {}: {}", address, format_encoders(status));
+            }
+            Err(e) => println!("Node {}: unreachable: {:#}", address, e),
+        }
+    }
+    if reachable == 0 {
+        anyhow::bail!("None of the nodes is reachable");
+    }
+
+    println!();
+    println!(
+        "{} chunks of {} inputs on {} nodes, nothing was encoded",
+        total_chunks,
+        input_files.len(),
+        reachable
+    );
+    Ok(())
+}
+
+/// Expands directories into files they contain. Returns inputs, and whether
+/// it's a batch that is encoded into a directory of outputs.
+pub fn collect_inputs(paths: &[PathBuf]) -> Result<(Vec<PathBuf>, bool)> {
+    let mut inputs = Vec::new();
+    let mut batch = paths.len() > 1;
+
+    for path in paths {
+        if path.is_dir() {
+            batch = true;
+            let mut files: Vec<PathBuf> = std::fs::read_dir(path)
+                .with_context(|| format!("Failed to read input directory {:?}", path))?
+                .filter_map(|entry| Some(entry.ok()?.path()))
+                .filter(|path| path.is_file())
+                .collect();
+            files.sort();
+            inputs.extend(files);
+        } else {
+            inputs.push(path.clone());
+        }
+    }
+
+    if inputs.is_empty() {
+        return Err(anyhow::anyhow!("No input files found"));
+    }
+
+    Ok((inputs, batch))
+}
+
+/// Outputs of `input_files`, named after every input in the `output` directory with
+/// multiple inputs. Fails when two inputs would be written to the same output.
+pub fn output_paths(input_files: &[PathBuf], output: &Path, batch: bool) -> Result<Vec<PathBuf>> {
+    if !batch {
+        return Ok(vec![output.to_path_buf()]);
+    }
+
+    let mut inputs: HashMap<PathBuf, &PathBuf> = HashMap::new();
+    input_files
+        .iter()
+        .map(|input_file| {
+            // Stem is kept whole, `Show.S01E01` isn't cut to `Show`
+            let mut name = input_file.file_stem().unwrap_or_default().to_os_string();
+            name.push(".mkv");
+            let output_file = output.join(name);
+            if let Some(other) = inputs.insert(output_file.clone(), input_file) {
+                anyhow::bail!(
+                    "Inputs {:?} and {:?} would both be written to {:?}, rename one of them",
+                    other,
+                    input_file,
+                    output_file
+                );
+            }
+            Ok(output_file)
+        })
+        .collect()
+}
+
+/// Checks that `output_file` can be written from `input_file` with these settings,
+/// before anything is split or encoded
+fn check_output(
+    input_file: &Path,
+    output_file: &Path,
+    frame_input: bool,
+    encoder_params: &[String],
+    settings: &Settings,
+) -> Result<()> {
+    if settings.client.concat == ConcatMethod::Mp4box {
+        verify_mp4box()?;
+        if !is_mp4(output_file) {
+            anyhow::bail!(
+                "MP4Box only joins chunks of MP4 outputs, {:?} isn't one",
+                output_file
+            );
+        }
+    }
+
+    let output = &settings.client.output;
+    if output.fragmented && !is_mp4(output_file) {
+        anyhow::bail!(
+            "Only MP4 outputs can be fragmented, {:?} isn't one",
+            output_file
+        );
+    }
+    if output
+        .fragment_duration
+        .is_some_and(|duration| duration <= 0.0)
+    {
+        anyhow::bail!("Fragment duration has to be positive");
+    }
+    if settings.client.package.segment_duration <= 0.0 {
+        anyhow::bail!("Duration of packaged segments has to be positive");
+    }
+    // Output is checked before anything is encoded, rather than when it's muxed
+    let container = output_container(output_file, &settings.client.output)?;
+    if container == Container::WebM {
+        check_webm_codecs(
+            settings.client.encoder,
+            encoder_params,
+            &settings.client.audio,
+        )?;
+    }
+    if !frame_input {
+        check_input_streams(
+            input_file,
+            container,
+            &settings.client.tracks,
+            &settings.client.audio,
+        )?;
+    }
+    Ok(())
+}
+
+/// Prepares input for encoding and splits it into chunks, in its own temp directory
+#[instrument(skip(settings, options))]
+pub fn prepare_job(
+    input_file: &Path,
+    output_file: PathBuf,
+    temp_dir: PathBuf,
+    settings: &Settings,
+    options: &EncodeOptions,
+) -> Result<Job> {
+    let mut processing = settings.processing.clone();
+    let mut encoder_params = settings.client.encoder_params.clone();
+
+    // Scripts and image sequences are read frame by frame, as video without other streams
+    let frame_input = is_script(input_file) || is_sequence(input_file);
+
+    // Frame inputs have constant frame rate, so there are no timestamps to preserve
+    let preserve_timestamps = processing.preserve_timestamps && !frame_input;
+    if preserve_timestamps {
+        verify_mkvmerge()?;
+        // Every encoded frame gets timestamp of a source frame, so nodes must not
+        // drop or duplicate frames. Other encoders get frames passed through already
+        if settings.client.encoder == EncoderKind::Ffmpeg {
+            encoder_params.extend(["-fps_mode".to_string(), "passthrough".to_string()]);
+        }
+    }
+
+    check_output(
+        input_file,
+        &output_file,
+        frame_input,
+        &encoder_params,
+        settings,
+    )?;
+
+    if !frame_input && !processing.lossless_intermediate {
+        check_open_gop(input_file, &mut processing);
+    }
+
+    let config = TempConfig::new(
+        Some(temp_dir),
+        &input_file.to_path_buf(),
+        &output_file.to_string_lossy(),
+    );
+
+    // Only the requested part is split and encoded, as if it was the whole input
+    let input_file = if options.start.is_some() || options.end.is_some() {
+        if frame_input {
+            return Err(anyhow::anyhow!(
+                "--start and --end are not supported for scripts and image sequences"
+            ));
+        }
+        let trimmed = config.temp_dir.join("trimmed.mkv");
+        trim_input(
+            input_file,
+            options.start,
+            options.end,
+            &trimmed,
+            processing.lossless_intermediate,
+        )?;
+        trimmed
+    } else {
+        input_file.to_path_buf()
+    };
+    let source = (!frame_input).then(|| input_file.clone());
+
+    // Scripts are expected to deinterlace the clip themselves, images are progressive
+    let field_filter = if frame_input {
+        None
+    } else {
+        field_filter(&input_file, &processing)
+    };
+    let input_file = match field_filter {
+        Some(filter) => {
+            let filtered = config.temp_dir.join("deinterlaced.mkv");
+            filter_input(&input_file, filter, &filtered)?;
+            filtered
+        }
+        None => input_file,
+    };
+
+    // Pixel format of parameters is replaced by the resolved one
+    let source_format = if is_script(&input_file) {
+        None
+    } else {
+        probe_pixel_format(&input_file)?
+    };
+    let (params_format, mut encoder_params) = take_pixel_format(&encoder_params);
+    let requested_format = settings
+        .client
+        .pix_fmt
+        .as_deref()
+        .or(params_format.as_deref());
+    let lossless_format = if settings.client.lossless {
+        lossless_format(
+            source_format.as_deref(),
+            requested_format,
+            settings.client.bit_depth,
+            is_script(&input_file),
+        )?
+    } else {
+        None
+    };
+    // Lossless output is encoded in the source format, which isn't promoted to 10 bits
+    let lossless_name = lossless_format.map(PixelFormat::name);
+    let pixel_format = resolve_pixel_format(
+        source_format.as_deref(),
+        lossless_name.as_deref().or(requested_format),
+        settings.client.bit_depth,
+        settings.client.encoder.encoder().as_ref(),
+        &encoder_params,
+    )?;
+    if let Some(format) = pixel_format {
+        debug!("Encoding {:?} in {}", input_file, format);
+        encoder_params.extend([PIX_FMT_OPTION.to_string(), format.name()]);
+    }
+
+    let chunks = if is_script(&input_file) {
+        index_script_chunks(
+            &input_file,
+            &processing,
+            &config.segment_dir(),
+            encoder_params,
+        )?
+    } else if is_sequence(&input_file) {
+        let frame_rate = processing
+            .frame_rate
+            .as_deref()
+            .context("Frame rate of image sequence is required, set it with --frame-rate")?;
+        let fps = parse_frame_rate(frame_rate)
+            .with_context(|| format!("Invalid frame rate: {}", frame_rate))?;
+        index_sequence_chunks(
+            &input_file,
+            fps,
+            &processing,
+            &config.segment_dir(),
+            encoder_params,
+        )?
+    } else if processing.extract_on_demand {
+        index_chunks(
+            &input_file,
+            &processing,
+            &config.segment_dir(),
+            encoder_params,
+        )?
+    } else {
+        let segments = split_video(
+            &input_file,
+            &processing,
+            &config.segment_dir(),
+            &encoder_params,
+            &config.encode_dir(),
+        )?;
+        convert_files_to_chunks(segments, encoder_params)?
+    };
+
+    let non_video_streams = if frame_input {
+        None
+    } else {
+        extract_non_video_streams(&input_file, &config.temp_dir, &settings.client.tracks)?
+    };
+
+    let mut metadata = match &source {
+        Some(source) if settings.client.metadata.keep => read_metadata(source)?,
+        _ => Metadata::default(),
+    };
+    metadata
+        .global
+        .extend(settings.client.metadata.tags.clone());
+
+    let chapters = config.temp_dir.join("chapters.txt");
+    let chapters = if frame_input || settings.client.tracks.chapters == Chapters::Drop {
+        None
+    } else if extract_chapters(&input_file, &chapters)? {
+        Some(chapters)
+    } else {
+        None
+    };
+
+    let timecodes = if preserve_timestamps {
+        let path = config.temp_dir.join("timecodes.txt");
+        write_timecodes(&probe_frame_times(&input_file)?, &path)?;
+        Some(path)
+    } else {
+        None
+    };
+
+    let film_grain_table = if settings.client.grain.enabled() {
+        if frame_input {
+            return Err(anyhow::anyhow!(
+                "Film grain synthesis is not supported for scripts and image sequences"
+            ));
+        }
+        Some(write_photon_noise_table(
+            &input_file,
+            settings.client.grain.photon_noise,
+            settings.client.grain.chroma,
+            settings.client.grain.denoise,
+            &config.temp_dir.join("grain.tbl"),
+        )?)
+    } else {
+        None
+    };
+
+    info!(
+        "Created {} chunks from segments of {:?}",
+        chunks.len(),
+        input_file
+    );
+
+    Ok(Job {
+        output_file,
+        config,
+        chunks,
+        non_video_streams,
+        chapters,
+        timecodes,
+        film_grain_table,
+        lossless_format,
+        source,
+        metadata,
+    })
+}
+
+/// Switches to lossless intermediate, or warns, when input has open GOPs
+fn check_open_gop(input_file: &Path, processing: &mut ProcessingSettings) {
+    match probe_open_gop(input_file, OPEN_GOP_PROBE_SECONDS) {
+        Ok(false) => {}
+        Ok(true) => match processing.open_gop {
+            OpenGop::Warn => warn!(
+                "Input has open GOPs, chunks split by stream copy will start with broken frames. \
+                 Use --lossless-intermediate or --open-gop lossless to split it correctly"
+            ),
+            OpenGop::Lossless => {
+                warn!("Input has open GOPs, splitting it through lossless intermediate");
+                processing.lossless_intermediate = true;
+            }
+        },
+        Err(e) => warn!("Failed to check input for open GOPs: {}", e),
+    }
+}
+
+/// Returns filter that removes interlacing from the input, if it needs one
+fn field_filter<'a>(input_file: &Path, processing: &'a ProcessingSettings) -> Option<&'a str> {
+    let scan_type = match processing.deinterlace {
+        Deinterlace::Off => return None,
+        Deinterlace::Deinterlace => ScanType::Interlaced,
+        Deinterlace::Ivtc => ScanType::Telecined,
+        Deinterlace::Auto => match detect_scan_type(input_file) {
+            Ok(scan_type) => scan_type,
+            Err(e) => {
+                warn!(
+                    "Failed to detect interlacing, input is split as it is: {}",
+                    e
+                );
+                ScanType::Progressive
+            }
+        },
+    };
+
+    match scan_type {
+        ScanType::Progressive => None,
+        ScanType::Interlaced => Some(&processing.deinterlace_filter),
+        ScanType::Telecined => Some(&processing.ivtc_filter),
+    }
+}
@@ -0,0 +1,170 @@
+/// This module lowers scheduling priority of the node, so encodes don't make
+/// a machine that is used at the same time unresponsive. Encoder processes and
+/// threads started by the node inherit its priority.
+use serde::{Deserialize, Serialize};
+use tracing::info;
+
+use crate::error::VideoEncodeError;
+
+/// I/O scheduling class of the node, on Linux
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Deserialize, Serialize, clap::ValueEnum)]
+#[serde(rename_all = "kebab-case")]
+pub enum IoClass {
+    /// Level of I/O priority follows niceness
+    #[default]
+    BestEffort,
+    /// I/O is only done when no other process needs the disk
+    Idle,
+}
+
+/// Sets niceness from -20 to 19, where higher is lower priority, and I/O class
+/// of the node. On Windows niceness maps to priority class, and I/O class is ignored.
+pub fn set_priority(nice: Option<i32>, io_class: Option<IoClass>) -> Result<(), VideoEncodeError> {
+    if let Some(nice) = nice {
+        if !(-20..=19).contains(&nice) {
+            return Err(VideoEncodeError::Encoding(format!(
+                "Niceness is from -20 to 19, not {}",
+                nice
+            )));
+        }
+        platform::set_nice(nice)?;
+        info!("Running encodes at niceness {}", nice);
+    }
+
+    if let Some(io_class) = io_class {
+        platform::set_io_class(io_class, nice.unwrap_or(0))?;
+        info!("Running encodes in {:?} I/O class", io_class);
+    }
+
+    Ok(())
+}
+
+#[cfg(target_os = "linux")]
+mod platform {
+    use std::io;
+
+    use super::IoClass;
+    use crate::error::VideoEncodeError;
+
+    const IOPRIO_WHO_PROCESS: libc::c_int = 1;
+    const IOPRIO_CLASS_SHIFT: libc::c_int = 13;
+    const IOPRIO_CLASS_BE: libc::c_int = 2;
+    const IOPRIO_CLASS_IDLE: libc::c_int = 3;
+
+    /// Priorities of Linux are per thread, so they are set for every thread
+    /// of the node, and new threads inherit them from threads that start them
+    fn threads() -> Result<Vec<libc::pid_t>, VideoEncodeError> {
+        Ok(std::fs::read_dir("/proc/self/task")?
+            .filter_map(|entry| entry.ok()?.file_name().to_str()?.parse().ok())
+            .collect())
+    }
+
+    pub fn set_nice(nice: i32) -> Result<(), VideoEncodeError> {
+        for thread in threads()? {
+            // SAFETY: setpriority only reads its arguments
+            if unsafe { libc::setpriority(libc::PRIO_PROCESS, thread as libc::id_t, nice) } != 0 {
+                return Err(io::Error::last_os_error().into());
+            }
+        }
+        Ok(())
+    }
+
+    pub fn set_io_class(io_class: IoClass, nice: i32) -> Result<(), VideoEncodeError> {
+        let priority = match io_class {
+            // Same level as kernel derives from niceness
+            IoClass::BestEffort => {
+                (IOPRIO_CLASS_BE << IOPRIO_CLASS_SHIFT) | ((nice + 20) / 5).clamp(0, 7)
+            }
+            IoClass::Idle => IOPRIO_CLASS_IDLE << IOPRIO_CLASS_SHIFT,
+        };
+
+        for thread in threads()? {
+            // SAFETY: ioprio_set only reads its arguments
+            let result = unsafe {
+                libc::syscall(libc::SYS_ioprio_set, IOPRIO_WHO_PROCESS, thread, priority)
+            };
+            if result != 0 {
+                return Err(io::Error::last_os_error().into());
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(all(unix, not(target_os = "linux")))]
+mod platform {
+    use std::io;
+
+    use tracing::warn;
+
+    use super::IoClass;
+    use crate::error::VideoEncodeError;
+
+    pub fn set_nice(nice: i32) -> Result<(), VideoEncodeError> {
+        // SAFETY: setpriority only reads its arguments
+        if unsafe { libc::setpriority(libc::PRIO_PROCESS, 0, nice) } != 0 {
+            return Err(io::Error::last_os_error().into());
+        }
+        Ok(())
+    }
+
+    pub fn set_io_class(io_class: IoClass, _nice: i32) -> Result<(), VideoEncodeError> {
+        warn!("I/O class {:?} is only supported on Linux", io_class);
+        Ok(())
+    }
+}
+
+#[cfg(windows)]
+mod platform {
+    use std::io;
+
+    use tracing::warn;
+    use windows_sys::Win32::System::Threading::{
+        GetCurrentProcess, SetPriorityClass, ABOVE_NORMAL_PRIORITY_CLASS,
+        BELOW_NORMAL_PRIORITY_CLASS, HIGH_PRIORITY_CLASS, IDLE_PRIORITY_CLASS,
+        NORMAL_PRIORITY_CLASS,
+    };
+
+    use super::IoClass;
+    use crate::error::VideoEncodeError;
+
+    /// Processes inherit idle and below normal classes of processes that start them
+    pub fn set_nice(nice: i32) -> Result<(), VideoEncodeError> {
+        let class = match nice {
+            15.. => IDLE_PRIORITY_CLASS,
+            5..=14 => BELOW_NORMAL_PRIORITY_CLASS,
+            -4..=4 => NORMAL_PRIORITY_CLASS,
+            -14..=-5 => ABOVE_NORMAL_PRIORITY_CLASS,
+            _ => HIGH_PRIORITY_CLASS,
+        };
+
+        // SAFETY: handle of the current process doesn't need to be closed
+        if unsafe { SetPriorityClass(GetCurrentProcess(), class) } == 0 {
+            return Err(io::Error::last_os_error().into());
+        }
+        Ok(())
+    }
+
+    pub fn set_io_class(io_class: IoClass, _nice: i32) -> Result<(), VideoEncodeError> {
+        warn!("I/O class {:?} is only supported on Linux", io_class);
+        Ok(())
+    }
+}
+
+#[cfg(not(any(unix, windows)))]
+mod platform {
+    use tracing::warn;
+
+    use super::IoClass;
+    use crate::error::VideoEncodeError;
+
+    pub fn set_nice(nice: i32) -> Result<(), VideoEncodeError> {
+        warn!("Niceness {} is not supported on this platform", nice);
+        Ok(())
+    }
+
+    pub fn set_io_class(io_class: IoClass, _nice: i32) -> Result<(), VideoEncodeError> {
+        warn!("I/O class {:?} is not supported on this platform", io_class);
+        Ok(())
+    }
+}
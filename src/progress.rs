@@ -0,0 +1,162 @@
+/// This module tracks progress of the whole encoding job,
+/// combining finished chunks with progress reported by nodes
+/// for chunks that are still being encoded
+use std::{
+    collections::HashMap,
+    time::{Duration, Instant},
+};
+
+use crate::ffmpeg::progress::Progress;
+
+/// Progress of all chunks of a job
+#[derive(Debug)]
+pub struct JobProgress {
+    started: Instant,
+    /// Expected duration of each chunk in seconds
+    durations: HashMap<usize, f64>,
+    /// Last reported progress of chunks that are being encoded
+    in_flight: HashMap<usize, Progress>,
+    completed: HashMap<usize, f64>,
+}
+
+impl JobProgress {
+    /// Creates tracker for chunks with given durations in seconds
+    pub fn new(durations: HashMap<usize, f64>) -> Self {
+        JobProgress {
+            started: Instant::now(),
+            durations,
+            in_flight: HashMap::new(),
+            completed: HashMap::new(),
+        }
+    }
+
+    /// Records progress report for chunk that is being encoded
+    pub fn update(&mut self, chunk_index: usize, progress: Progress) {
+        if !self.completed.contains_key(&chunk_index) {
+            self.in_flight.insert(chunk_index, progress);
+        }
+    }
+
+    /// Marks chunk as encoded
+    pub fn complete(&mut self, chunk_index: usize) {
+        self.in_flight.remove(&chunk_index);
+        let duration = self.durations.get(&chunk_index).copied().unwrap_or(0.0);
+        self.completed.insert(chunk_index, duration);
+    }
+
+    /// Discards progress of chunk that failed and will be encoded again
+    pub fn reset(&mut self, chunk_index: usize) {
+        self.in_flight.remove(&chunk_index);
+    }
+
+    /// Replaces chunk that was split with chunks of given durations
+    pub fn replace(&mut self, chunk_index: usize, parts: impl IntoIterator<Item = (usize, f64)>) {
+        self.in_flight.remove(&chunk_index);
+        self.durations.remove(&chunk_index);
+        self.durations.extend(parts);
+    }
+
+    /// Seconds of source that are already encoded
+    pub fn encoded_duration(&self) -> f64 {
+        let in_flight: f64 = self
+            .in_flight
+            .iter()
+            .map(|(index, progress)| {
+                let duration = self.durations.get(index).copied().unwrap_or(f64::MAX);
+                progress.out_time.min(duration)
+            })
+            .sum();
+
+        self.completed.values().sum::<f64>() + in_flight
+    }
+
+    /// Seconds of source in the whole job
+    pub fn total_duration(&self) -> f64 {
+        self.durations.values().sum()
+    }
+
+    /// Fraction of the job that is done, from 0 to 1
+    pub fn fraction(&self) -> f64 {
+        let total = self.total_duration();
+        if total <= 0.0 {
+            return 0.0;
+        }
+        (self.encoded_duration() / total).min(1.0)
+    }
+
+    /// Last reported progress of chunks that are being encoded
+    pub fn in_flight(&self) -> impl Iterator<Item = (usize, &Progress)> + '_ {
+        self.in_flight
+            .iter()
+            .map(|(index, progress)| (*index, progress))
+    }
+
+    /// Part of chunk that is encoded, from 0 to 1
+    pub fn chunk_fraction(&self, chunk_index: usize) -> f64 {
+        if self.completed.contains_key(&chunk_index) {
+            return 1.0;
+        }
+        match (
+            self.in_flight.get(&chunk_index),
+            self.durations.get(&chunk_index),
+        ) {
+            (Some(progress), Some(&duration)) if duration > 0.0 => {
+                (progress.out_time / duration).clamp(0.0, 1.0)
+            }
+            _ => 0.0,
+        }
+    }
+
+    /// Estimated time until chunk is encoded, at the speed it's being encoded at
+    pub fn chunk_eta(&self, chunk_index: usize) -> Option<Duration> {
+        let progress = self.in_flight.get(&chunk_index)?;
+        let fraction = self.chunk_fraction(chunk_index);
+        if fraction <= 0.0 || progress.fps <= 0.0 {
+            return None;
+        }
+        // Frames of the chunk are only known from how far the encode got
+        let remaining = progress.frame as f64 * (1.0 - fraction) / fraction;
+        Some(Duration::from_secs_f64(remaining / progress.fps))
+    }
+
+    /// Time since the job started
+    pub fn elapsed(&self) -> Duration {
+        self.started.elapsed()
+    }
+
+    /// Sum of current encoding speed of all chunks in flight
+    pub fn fps(&self) -> f64 {
+        self.in_flight.values().map(|p| p.fps).sum()
+    }
+
+    /// Indices of all chunks, with chunks split from another one instead of it
+    pub fn chunk_indices(&self) -> impl Iterator<Item = usize> + '_ {
+        self.durations.keys().copied()
+    }
+
+    pub fn completed_chunks(&self) -> usize {
+        self.completed.len()
+    }
+
+    pub fn total_chunks(&self) -> usize {
+        self.durations.len()
+    }
+
+    /// Estimated time until the job is done, based on average throughput so far
+    pub fn eta(&self) -> Option<Duration> {
+        let encoded = self.encoded_duration();
+        let elapsed = self.started.elapsed().as_secs_f64();
+        if encoded <= 0.0 || elapsed <= 0.0 {
+            return None;
+        }
+
+        let remaining = (self.total_duration() - encoded).max(0.0);
+        Some(Duration::from_secs_f64(remaining * elapsed / encoded))
+    }
+}
+
+/// Formats duration as `HH:MM:SS`
+pub fn format_duration(duration: Duration) -> String {
+    let secs = duration.as_secs();
+    format!("{:02}:{:02}:{:02}", secs / 3600, secs / 60 % 60, secs % 60)
+}
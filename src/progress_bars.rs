@@ -0,0 +1,119 @@
+/// This module shows progress of an encode as progress bars on stderr, for terminals
+/// the dashboard is too much for: one bar of frames of the whole job, and one bar of
+/// chunks of every node. Log lines are printed above the bars through [`BarsWriter`].
+use std::collections::HashMap;
+use std::io::{self, Write};
+use std::time::Duration;
+
+use indicatif::{MultiProgress, ProgressBar, ProgressStyle};
+use tracing_subscriber::fmt::MakeWriter;
+
+use crate::dashboard::DashboardView;
+use crate::progress::format_duration;
+
+/// Progress bars of the job and its nodes
+pub struct ProgressBars {
+    overall: ProgressBar,
+    /// Bars of nodes, by address of the node
+    nodes: HashMap<String, ProgressBar>,
+}
+
+impl ProgressBars {
+    /// Adds bars of a job of `total_frames` frames, encoded on nodes of `addresses`,
+    /// to `multi`
+    pub fn new(multi: &MultiProgress, total_frames: u64, addresses: &[String]) -> Self {
+        let overall = multi.add(ProgressBar::new(total_frames));
+        overall.set_style(
+            ProgressStyle::with_template(
+                "{bar:40.green/white} {pos}/{len} frames ({percent}%) {msg}",
+            )
+            .expect("Progress bar template is valid"),
+        );
+
+        let width = addresses.iter().map(String::len).max().unwrap_or_default();
+        let node_style = ProgressStyle::with_template(&format!(
+            "{{prefix:{}}} {{bar:30.cyan/white}} {{pos}}/{{len}} chunks {{msg}}",
+            width
+        ))
+        .expect("Progress bar template is valid");
+        let nodes = addresses
+            .iter()
+            .map(|address| {
+                let bar = multi.add(ProgressBar::new(0));
+                bar.set_style(node_style.clone());
+                bar.set_prefix(address.clone());
+                (address.clone(), bar)
+            })
+            .collect();
+
+        ProgressBars { overall, nodes }
+    }
+
+    /// Moves bars to the state of `view`
+    pub fn update(&self, view: &DashboardView) {
+        let total_frames = self.overall.length().unwrap_or_default();
+        self.overall
+            .set_position((view.fraction.clamp(0.0, 1.0) * total_frames as f64) as u64);
+        self.overall
+            .set_message(format!("{:.1} fps, ETA {}", view.fps, format_eta(view.eta)));
+
+        for node in &view.nodes {
+            let Some(bar) = self.nodes.get(&node.address) else {
+                continue;
+            };
+            let chunks = view
+                .chunks
+                .iter()
+                .filter(|chunk| chunk.node == node.address);
+            let (fps, eta) = chunks.fold((0.0, None), |(fps, eta), chunk| {
+                (fps + chunk.fps, eta.max(chunk.eta))
+            });
+
+            // Nodes take chunks as they free up, so a node has no share of the job up
+            // front, and its bar counts chunks it took so far
+            bar.set_length((node.completed + node.active) as u64);
+            bar.set_position(node.completed as u64);
+            bar.set_message(format!(
+                "{}/{} slots, {:.1} fps, chunks done in {}",
+                node.active,
+                node.slots,
+                fps,
+                format_eta(eta)
+            ));
+        }
+    }
+}
+
+fn format_eta(eta: Option<Duration>) -> String {
+    eta.map(format_duration)
+        .unwrap_or_else(|| "unknown".to_string())
+}
+
+/// Writes log lines to stderr above progress bars, which are drawn again below them
+#[derive(Clone)]
+pub struct BarsWriter(MultiProgress);
+
+impl BarsWriter {
+    pub fn new(multi: MultiProgress) -> Self {
+        BarsWriter(multi)
+    }
+}
+
+impl Write for BarsWriter {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.0.suspend(|| io::stderr().write_all(buf))?;
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        io::stderr().flush()
+    }
+}
+
+impl<'writer> MakeWriter<'writer> for BarsWriter {
+    type Writer = BarsWriter;
+
+    fn make_writer(&'writer self) -> Self::Writer {
+        self.clone()
+    }
+}
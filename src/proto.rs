@@ -0,0 +1,3 @@
+// This module has messages and services of the protocol between client and nodes,
+// generated from `proto/video_encoding.proto`
+tonic::include_proto!("video_encoding");
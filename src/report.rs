@@ -0,0 +1,613 @@
+/// This module builds reports of encoded outputs, with a row for every chunk: its
+/// quality scores, bitrate, encode time and the node that encoded it, so results
+/// of different settings can be graphed and compared. Distribution of chunk bitrates
+/// is summarized, with chunks far over the median flagged as outliers. Scores of frames
+/// can be broken down by scene, so problems map to content instead of chunk indices.
+/// Reports are written as JSON or CSV.
+use anyhow::Result;
+use serde::Serialize;
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+use tracing::{info, instrument, warn};
+
+use crate::bitrate::check_vbv;
+use crate::chunk::Chunk;
+use crate::encoder::pixel_format::PixelFormat;
+use crate::encoder::Vbv;
+use crate::error::VideoEncodeError;
+use crate::ffmpeg::container::is_stdout;
+use crate::ffmpeg::probe::{probe_media, probe_pixel_format};
+use crate::progress::format_duration;
+use crate::settings::{QualityMetric, ReportFormat};
+use crate::timing::{timing_path, TimingReport};
+
+/// Result of encoding a chunk on a node
+#[derive(Debug, Clone, Default)]
+pub struct ChunkResult {
+    /// Address of the node that encoded the chunk
+    pub node: String,
+    /// Seconds the node spent encoding the chunk
+    pub encode_time: f64,
+    /// CRF the node selected for the chunk, when CRF is selected per chunk
+    pub crf: Option<u32>,
+    /// Scores of quality metrics that were measured
+    pub scores: Vec<(QualityMetric, f64)>,
+    /// Scores of measured frames in order, by metric
+    pub frames: Vec<(QualityMetric, Vec<f64>)>,
+    /// Hash of video packets, when encodes are reproducible
+    pub hash: Option<String>,
+}
+
+/// Row of a chunk in the report
+#[derive(Debug, Clone, Serialize)]
+pub struct ChunkReport {
+    pub index: usize,
+    /// Seconds of the input the chunk covers
+    pub duration: f64,
+    /// Size of the encoded chunk in bytes
+    pub size: u64,
+    /// kbit/s
+    pub bitrate: f64,
+    pub encode_time: f64,
+    pub node: String,
+    pub crf: Option<u32>,
+    /// Scores by names of metrics
+    pub scores: BTreeMap<&'static str, f64>,
+    pub hash: Option<String>,
+    /// Scores of frames by names of metrics, too many to be written
+    #[serde(skip)]
+    pub frames: BTreeMap<&'static str, Vec<f64>>,
+}
+
+impl ChunkReport {
+    /// Row of chunk that covers `duration` seconds and is encoded into `size` bytes
+    pub fn new(index: usize, duration: f64, size: u64, result: &ChunkResult) -> Self {
+        ChunkReport {
+            index,
+            duration,
+            size,
+            bitrate: bitrate(size, duration),
+            encode_time: result.encode_time,
+            node: result.node.clone(),
+            crf: result.crf,
+            scores: result
+                .scores
+                .iter()
+                .map(|(metric, score)| (metric.name(), *score))
+                .collect(),
+            hash: result.hash.clone(),
+            frames: result
+                .frames
+                .iter()
+                .map(|(metric, frames)| (metric.name(), frames.clone()))
+                .collect(),
+        }
+    }
+}
+
+/// Scores of a metric over chunks of the output
+#[derive(Debug, Clone, Copy, Serialize)]
+pub struct ScoreSummary {
+    /// Average of chunks weighted by their duration
+    pub average: f64,
+    pub worst: f64,
+    /// Index of the chunk with the worst score
+    pub worst_chunk: usize,
+    /// Number of chunks the metric was measured on
+    pub measured: usize,
+    /// Score the worst 1% of frames fall to, when scores of frames are known
+    pub worst_percent: Option<f64>,
+}
+
+/// Scores of frames of a scene of the output
+#[derive(Debug, Clone, Serialize)]
+pub struct SceneReport {
+    /// Seconds into the output the scene starts and ends at
+    pub start: f64,
+    pub end: f64,
+    /// Scores by names of metrics
+    pub scores: BTreeMap<&'static str, SceneScore>,
+}
+
+/// Scores of a metric over frames of a scene
+#[derive(Debug, Clone, Copy, Serialize)]
+pub struct SceneScore {
+    pub average: f64,
+    /// Score the worst 1% of frames fall to
+    pub worst_percent: f64,
+    /// Number of frames that were measured
+    pub frames: usize,
+}
+
+/// Distribution of bitrates of chunks of known duration, in kbit/s
+#[derive(Debug, Clone, Serialize)]
+pub struct BitrateSummary {
+    pub min: f64,
+    pub median: f64,
+    /// Bitrate 90% of chunks stay within
+    pub p90: f64,
+    pub max: f64,
+    pub std_dev: f64,
+    /// Chunks over the median by the outlier factor, in order
+    pub outliers: Vec<Outlier>,
+}
+
+/// Chunk with bitrate far over the median
+#[derive(Debug, Clone, Copy, Serialize)]
+pub struct Outlier {
+    pub index: usize,
+    /// Seconds into the output the chunk starts at
+    pub start: f64,
+    pub bitrate: f64,
+    /// Times the median
+    pub ratio: f64,
+}
+
+/// Report of an output, with its chunks in order
+#[derive(Debug, Clone, Serialize)]
+pub struct Report {
+    pub output: PathBuf,
+    pub duration: f64,
+    pub size: u64,
+    pub bitrate: f64,
+    /// Summaries by names of metrics
+    pub scores: BTreeMap<&'static str, ScoreSummary>,
+    /// Not summarized when no chunk has known duration
+    pub bitrates: Option<BitrateSummary>,
+    /// Scenes in order, empty when scores aren't broken down by scene
+    pub scenes: Vec<SceneReport>,
+    pub chunks: Vec<ChunkReport>,
+}
+
+impl Report {
+    /// Report of `output` with rows of its chunks, scores are summarized for `metrics`.
+    /// Chunks over the median bitrate by `outlier_factor` are flagged.
+    pub fn new(
+        output: &Path,
+        chunks: Vec<ChunkReport>,
+        metrics: &[QualityMetric],
+        outlier_factor: f64,
+    ) -> Self {
+        let duration = chunks.iter().map(|chunk| chunk.duration).sum();
+        let size = chunks.iter().map(|chunk| chunk.size).sum();
+        let scores = metrics
+            .iter()
+            .filter_map(|&metric| Some((metric.name(), summarize(&chunks, metric)?)))
+            .collect();
+        Report {
+            output: output.to_path_buf(),
+            duration,
+            size,
+            bitrate: bitrate(size, duration),
+            scores,
+            bitrates: summarize_bitrates(&chunks, outlier_factor),
+            scenes: Vec::new(),
+            chunks,
+        }
+    }
+
+    /// Breaks scores of frames of `metrics` down into scenes that start at `scene_changes`,
+    /// in seconds. Frames are assumed to be spread evenly over their chunks.
+    pub fn break_down(&mut self, scene_changes: &[f64], metrics: &[QualityMetric]) {
+        // Frames of every scene, by position of metric
+        let mut scene_frames = vec![vec![Vec::new(); metrics.len()]; scene_changes.len() + 1];
+        let mut start = 0.0;
+        for chunk in &self.chunks {
+            for (position, metric) in metrics.iter().enumerate() {
+                let Some(frames) = chunk.frames.get(metric.name()) else {
+                    continue;
+                };
+                for (number, &score) in frames.iter().enumerate() {
+                    let time = start + (number as f64 + 0.5) * chunk.duration / frames.len() as f64;
+                    let scene = scene_changes.partition_point(|&change| change <= time);
+                    scene_frames[scene][position].push(score);
+                }
+            }
+            start += chunk.duration;
+        }
+
+        let bounds = std::iter::once(0.0)
+            .chain(scene_changes.iter().copied())
+            .chain(std::iter::once(self.duration));
+        let ends = bounds.clone().skip(1);
+        self.scenes = bounds
+            .zip(ends)
+            .zip(scene_frames)
+            .filter(|(_, frames)| frames.iter().any(|frames| !frames.is_empty()))
+            .map(|((start, end), frames)| SceneReport {
+                start,
+                end,
+                scores: metrics
+                    .iter()
+                    .zip(frames)
+                    .filter(|(_, frames)| !frames.is_empty())
+                    .map(|(&metric, mut frames)| {
+                        let score = SceneScore {
+                            average: frames.iter().sum::<f64>() / frames.len() as f64,
+                            worst_percent: worst_percent(&mut frames, metric),
+                            frames: frames.len(),
+                        };
+                        (metric.name(), score)
+                    })
+                    .collect(),
+            })
+            .collect();
+    }
+
+    /// Writes report into `path`
+    #[instrument(skip(self))]
+    pub fn write(&self, path: &Path, format: ReportFormat) -> Result<(), VideoEncodeError> {
+        let content = match format {
+            ReportFormat::Json => serde_json::to_string_pretty(self)?,
+            ReportFormat::Csv => self.to_csv(),
+        };
+        std::fs::write(path, content)?;
+        info!("Wrote report of {:?} into {:?}", self.output, path);
+        Ok(())
+    }
+
+    /// Row of every chunk, with a column of every metric any chunk was measured with
+    fn to_csv(&self) -> String {
+        let metrics: Vec<&str> = self
+            .chunks
+            .iter()
+            .flat_map(|chunk| chunk.scores.keys().copied())
+            .collect::<BTreeSet<_>>()
+            .into_iter()
+            .collect();
+
+        let mut csv = String::from("index,duration,size,bitrate,encode_time,node,crf,hash");
+        for metric in &metrics {
+            csv.push(',');
+            csv.push_str(metric);
+        }
+        csv.push('\n');
+
+        for chunk in &self.chunks {
+            let mut fields = vec![
+                chunk.index.to_string(),
+                format!("{:.3}", chunk.duration),
+                chunk.size.to_string(),
+                format!("{:.1}", chunk.bitrate),
+                format!("{:.3}", chunk.encode_time),
+                csv_field(&chunk.node),
+                chunk.crf.map(|crf| crf.to_string()).unwrap_or_default(),
+                chunk.hash.clone().unwrap_or_default(),
+            ];
+            fields.extend(metrics.iter().map(|metric| {
+                chunk
+                    .scores
+                    .get(metric)
+                    .map(|score| format!("{:.4}", score))
+                    .unwrap_or_default()
+            }));
+            csv.push_str(&fields.join(","));
+            csv.push('\n');
+        }
+        csv
+    }
+}
+
+/// Path of the report of `output`, like `movie.report.json` for `movie.mkv`
+pub fn report_path(output: &Path, format: ReportFormat) -> PathBuf {
+    let extension = match format {
+        ReportFormat::Json => "report.json",
+        ReportFormat::Csv => "report.csv",
+    };
+    output.with_extension(extension)
+}
+
+/// Summary of scores of `metric`, `None` when no chunk was measured with it
+fn summarize(chunks: &[ChunkReport], metric: QualityMetric) -> Option<ScoreSummary> {
+    let measured: Vec<(usize, f64, f64)> = chunks
+        .iter()
+        .filter_map(|chunk| {
+            let score = chunk.scores.get(metric.name())?;
+            Some((chunk.index, chunk.duration, *score))
+        })
+        .collect();
+    let &(worst_chunk, _, worst) = measured.iter().min_by(|a, b| {
+        if metric.higher_is_better() {
+            a.2.total_cmp(&b.2)
+        } else {
+            b.2.total_cmp(&a.2)
+        }
+    })?;
+
+    // Chunks count equally when none has known duration
+    let duration: f64 = measured.iter().map(|(_, duration, _)| duration).sum();
+    let average = if duration > 0.0 {
+        measured
+            .iter()
+            .map(|(_, duration, score)| score * duration)
+            .sum::<f64>()
+            / duration
+    } else {
+        measured.iter().map(|(_, _, score)| score).sum::<f64>() / measured.len() as f64
+    };
+
+    let mut frames: Vec<f64> = chunks
+        .iter()
+        .filter_map(|chunk| chunk.frames.get(metric.name()))
+        .flatten()
+        .copied()
+        .collect();
+    Some(ScoreSummary {
+        average,
+        worst,
+        worst_chunk,
+        measured: measured.len(),
+        worst_percent: (!frames.is_empty()).then(|| worst_percent(&mut frames, metric)),
+    })
+}
+
+/// Score the worst 1% of `frames` fall to, which aren't empty
+fn worst_percent(frames: &mut [f64], metric: QualityMetric) -> f64 {
+    frames.sort_by(f64::total_cmp);
+    let worst = frames.len() / 100;
+    if metric.higher_is_better() {
+        frames[worst]
+    } else {
+        frames[frames.len() - 1 - worst]
+    }
+}
+
+/// Distribution of bitrates of `chunks`, which are in order of the output
+fn summarize_bitrates(chunks: &[ChunkReport], outlier_factor: f64) -> Option<BitrateSummary> {
+    let mut bitrates: Vec<f64> = chunks
+        .iter()
+        .filter(|chunk| chunk.duration > 0.0)
+        .map(|chunk| chunk.bitrate)
+        .collect();
+    if bitrates.is_empty() {
+        return None;
+    }
+    bitrates.sort_by(f64::total_cmp);
+
+    let percentile = |share: f64| bitrates[((bitrates.len() - 1) as f64 * share).round() as usize];
+    let median = percentile(0.5);
+    let mean = bitrates.iter().sum::<f64>() / bitrates.len() as f64;
+    let variance = bitrates
+        .iter()
+        .map(|bitrate| (bitrate - mean).powi(2))
+        .sum::<f64>()
+        / bitrates.len() as f64;
+
+    let mut outliers = Vec::new();
+    let mut start = 0.0;
+    for chunk in chunks {
+        if chunk.duration > 0.0 && median > 0.0 && chunk.bitrate > median * outlier_factor {
+            outliers.push(Outlier {
+                index: chunk.index,
+                start,
+                bitrate: chunk.bitrate,
+                ratio: chunk.bitrate / median,
+            });
+        }
+        start += chunk.duration;
+    }
+
+    Some(BitrateSummary {
+        min: bitrates[0],
+        median,
+        p90: percentile(0.9),
+        max: bitrates[bitrates.len() - 1],
+        std_dev: variance.sqrt(),
+        outliers,
+    })
+}
+
+/// kbit/s of `size` bytes over `duration` seconds, 0 when duration is not known
+fn bitrate(size: u64, duration: f64) -> f64 {
+    if duration > 0.0 {
+        size as f64 * 8.0 / 1000.0 / duration
+    } else {
+        0.0
+    }
+}
+
+/// Field quoted when it contains separators or quotes
+fn csv_field(value: &str) -> String {
+    if value.contains([',', '"', '\n']) {
+        format!("\"{}\"", value.replace('"', "\"\""))
+    } else {
+        value.to_string()
+    }
+}
+
+/// Lossless encoders compress video a few times, output compressed more is lossy
+const MAX_LOSSLESS_COMPRESSION: f64 = 15.0;
+
+/// Warns when output of lossless encode isn't in pixel format of the source, or is
+/// compressed more than lossless encoders can, which means parameters made it lossy
+pub fn report_lossless(output_file: &Path, format: PixelFormat) {
+    let result = probe_pixel_format(output_file)
+        .and_then(|output_format| probe_media(output_file).map(|media| (output_format, media)));
+    let (output_format, media) = match result {
+        Ok(result) => result,
+        Err(e) => {
+            warn!("Failed to check lossless output {:?}: {}", output_file, e);
+            return;
+        }
+    };
+
+    if output_format.as_deref().and_then(PixelFormat::parse) != Some(format) {
+        warn!(
+            "{:?} is in {}, not {} of the source, so it's not lossless",
+            output_file,
+            output_format.as_deref().unwrap_or("unknown format"),
+            format
+        );
+    }
+
+    let raw_bits = media.frames as f64
+        * media.width as f64
+        * media.height as f64
+        * format.bits_per_pixel() as f64;
+    let bits = media.size.unwrap_or_default() as f64 * 8.0;
+    if bits == 0.0 {
+        return;
+    }
+    if raw_bits / bits > MAX_LOSSLESS_COMPRESSION {
+        warn!(
+            "{:?} is {:.0} times smaller than uncompressed video, which lossless encoders \
+             don't reach, check that encoder parameters don't make it lossy",
+            output_file,
+            raw_bits / bits
+        );
+    } else {
+        info!(
+            "{:?} is compressed {:.1} times, as lossless output",
+            output_file,
+            raw_bits / bits
+        );
+    }
+}
+
+/// Checks that output plays within VBV constraints, chunks are encoded separately
+/// so buffer state at their boundaries is not known to the encoder
+pub fn report_vbv(output_file: &Path, vbv: Vbv) {
+    match check_vbv(output_file, vbv) {
+        Ok(underflows) if underflows.is_empty() => {
+            info!("{:?} is within VBV constraints", output_file)
+        }
+        Ok(underflows) => warn!(
+            "{:?} underflows VBV buffer {} times, first at {:.3}s",
+            output_file,
+            underflows.len(),
+            underflows[0]
+        ),
+        Err(e) => warn!("Failed to check VBV of {:?}: {}", output_file, e),
+    }
+}
+
+/// Report of the output with rows of its chunks. Chunks of unknown duration are
+/// assumed to be of `default_duration`.
+pub fn output_report(
+    output_file: &Path,
+    chunks: &[&Chunk],
+    results: &HashMap<usize, ChunkResult>,
+    metrics: &[QualityMetric],
+    default_duration: f64,
+    outlier_factor: f64,
+) -> Report {
+    let rows = chunks
+        .iter()
+        .map(|chunk| {
+            let size = chunk
+                .encoded_path
+                .as_ref()
+                .and_then(|path| std::fs::metadata(path).ok())
+                .map_or(0, |metadata| metadata.len());
+            ChunkReport::new(
+                chunk.index,
+                chunk.duration().unwrap_or(default_duration),
+                size,
+                results.get(&chunk.index).unwrap_or(&ChunkResult::default()),
+            )
+        })
+        .collect();
+    Report::new(output_file, rows, metrics, outlier_factor)
+}
+
+/// Logs quality of the output from scores nodes measured for its chunks: their average
+/// weighted by duration, and the worst chunk
+pub fn report_quality(report: &Report, metric: QualityMetric) {
+    let Some(summary) = report.scores.get(metric.name()) else {
+        warn!("{:?} of {:?} wasn't measured", metric, report.output);
+        return;
+    };
+    info!(
+        "{:?} of {:?} is {:.3} on average, {:.3} at worst in chunk {}",
+        metric, report.output, summary.average, summary.worst, summary.worst_chunk
+    );
+    if let Some(worst_percent) = summary.worst_percent {
+        info!(
+            "Worst 1% of frames of {:?} have {:?} of {:.3}",
+            report.output, metric, worst_percent
+        );
+    }
+    if summary.measured < report.chunks.len() {
+        warn!(
+            "{:?} of {:?} is measured on {} of {} chunks",
+            metric,
+            report.output,
+            summary.measured,
+            report.chunks.len()
+        );
+    }
+}
+
+/// Prints table of times of chunks and nodes, and writes it next to `output`. Output
+/// written to stdout only gets the table, on stderr
+pub fn report_timing(report: TimingReport, output: &Path, batch: bool) {
+    if is_stdout(output) {
+        eprintln!("{}", report.table());
+        return;
+    }
+    println!("{}", report.table());
+    if let Err(e) = report.write(&timing_path(output, batch)) {
+        warn!("Failed to write timing of chunks: {}", e);
+    }
+}
+
+/// Number of the worst scenes of the output that are logged
+const LOGGED_SCENES: usize = 3;
+
+/// Logs scenes of the output with the worst 1% of frames by `metric`
+pub fn report_scenes(report: &Report, metric: QualityMetric) {
+    let mut scenes: Vec<(f64, f64)> = report
+        .scenes
+        .iter()
+        .filter_map(|scene| Some((scene.start, scene.scores.get(metric.name())?.worst_percent)))
+        .collect();
+    if scenes.is_empty() {
+        return;
+    }
+    scenes.sort_by(|a, b| {
+        if metric.higher_is_better() {
+            a.1.total_cmp(&b.1)
+        } else {
+            b.1.total_cmp(&a.1)
+        }
+    });
+    let worst: Vec<String> = scenes
+        .iter()
+        .take(LOGGED_SCENES)
+        .map(|(start, score)| {
+            format!(
+                "{} ({:.3})",
+                format_duration(Duration::from_secs_f64(*start)),
+                score
+            )
+        })
+        .collect();
+    info!(
+        "Scenes of {:?} with the worst 1% of frames by {:?}: {}",
+        report.output,
+        metric,
+        worst.join(", ")
+    );
+}
+
+/// Logs distribution of chunk bitrates of the output, and every chunk flagged as outlier
+pub fn report_bitrates(report: &Report) {
+    let Some(bitrates) = &report.bitrates else {
+        return;
+    };
+    info!(
+        "Chunks of {:?} have {:.0} kbit/s median, {:.0} at 90th percentile, {:.0}-{:.0} kbit/s",
+        report.output, bitrates.median, bitrates.p90, bitrates.min, bitrates.max
+    );
+    for outlier in &bitrates.outliers {
+        warn!(
+            "Chunk {} of {:?} at {} has {:.0} kbit/s, {:.1} times the median",
+            outlier.index,
+            report.output,
+            format_duration(Duration::from_secs_f64(outlier.start)),
+            outlier.bitrate,
+            outlier.ratio
+        );
+    }
+}
@@ -0,0 +1,68 @@
+/// This module keeps hashes of encoded chunks of an output in a manifest next to it,
+/// so a later encode of the same input with the same settings, on any nodes, can be
+/// verified to reproduce every chunk bit-exactly
+use std::{
+    collections::BTreeMap,
+    path::{Path, PathBuf},
+};
+
+use tracing::{info, instrument, warn};
+
+use crate::error::VideoEncodeError;
+
+/// Hashes of video packets of encoded chunks, by chunk index
+pub type Hashes = BTreeMap<usize, String>;
+
+/// Result of comparing hashes of an encode with recorded ones
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Comparison {
+    /// Chunks whose hash matches
+    pub matched: usize,
+    /// Indices of chunks whose hash differs
+    pub mismatched: Vec<usize>,
+    /// Chunks without a recorded hash, like chunks that were split after failures
+    pub unrecorded: usize,
+}
+
+impl Comparison {
+    /// Whether every chunk that was recorded is reproduced
+    pub fn reproduced(&self) -> bool {
+        self.mismatched.is_empty()
+    }
+}
+
+/// Path of the manifest of `output`, like `movie.hashes.json` for `movie.mkv`
+pub fn manifest_path(output: &Path) -> PathBuf {
+    output.with_extension("hashes.json")
+}
+
+#[instrument(skip(hashes))]
+pub fn write_manifest(path: &Path, hashes: &Hashes) -> Result<(), VideoEncodeError> {
+    std::fs::write(path, serde_json::to_string_pretty(hashes)?)?;
+    info!("Recorded hashes of {} chunks in {:?}", hashes.len(), path);
+    Ok(())
+}
+
+#[instrument]
+pub fn read_manifest(path: &Path) -> Result<Hashes, VideoEncodeError> {
+    Ok(serde_json::from_slice(&std::fs::read(path)?)?)
+}
+
+/// Compares `hashes` of an encode with `recorded` ones
+pub fn compare(recorded: &Hashes, hashes: &Hashes) -> Comparison {
+    let mut comparison = Comparison::default();
+    for (index, hash) in hashes {
+        match recorded.get(index) {
+            Some(recorded) if recorded == hash => comparison.matched += 1,
+            Some(recorded) => {
+                warn!(
+                    "Chunk {} has hash {}, recorded hash is {}",
+                    index, hash, recorded
+                );
+                comparison.mismatched.push(*index);
+            }
+            None => comparison.unrecorded += 1,
+        }
+    }
+    comparison
+}
@@ -1,45 +1,1625 @@
-use config::{Config, ConfigError, File};
-use serde::Deserialize;
+use config::{Config, ConfigError, Environment, File};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
 use std::path::{Path, PathBuf};
 use tracing::debug;
 
-#[derive(Debug, Deserialize)]
+use crate::encoder::profile::{profile_params, Profile};
+use crate::encoder::{EncoderKind, Vbv};
+use crate::priority::IoClass;
+use crate::zones::override_params;
+
+#[derive(Debug, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
 pub struct ClientSettings {
     pub node_addresses: Vec<String>,
+    #[serde(default)]
+    pub encoder: EncoderKind,
+    /// What is done when nodes report different versions of the encoder
+    #[serde(default)]
+    pub version_policy: VersionPolicy,
+    /// Version of the encoder that nodes have to report, like `SVT-AV1 v2.1.0 (release)`.
+    /// Chunks are only sent to nodes with this version, whatever the policy is
+    pub encoder_version: Option<String>,
+    /// Parameters of the encoder, ffmpeg options or options of standalone encoder
+    #[serde(default)]
+    pub encoder_params: Vec<String>,
+    /// Built-in profile that replaces encoder_params
+    pub profile: Option<Profile>,
+    #[serde(default)]
+    pub bandwidth: BandwidthSettings,
+    /// Number of failed attempts after which chunk is split into smaller ones, 0 disables it
+    #[serde(default = "default_resplit_after")]
+    pub resplit_after: usize,
+    /// Number of passes every chunk is encoded in, 1 or 2
+    #[serde(default = "default_passes")]
+    pub passes: u32,
+    #[serde(default)]
+    pub bitrate: BitrateSettings,
+    #[serde(default)]
+    pub crf: CrfSettings,
+    #[serde(default)]
+    pub content: ContentSettings,
+    #[serde(default)]
+    pub quality: QualitySettings,
+    #[serde(default)]
+    pub vmaf: VmafSettings,
+    #[serde(default)]
+    pub verify: VerifySettings,
+    #[serde(default)]
+    pub sync: SyncSettings,
+    #[serde(default)]
+    pub reproducible: ReproducibleSettings,
+    #[serde(default)]
+    pub grain: GrainSettings,
+    #[serde(default)]
+    pub audio: AudioSettings,
+    #[serde(default)]
+    pub tracks: TrackSettings,
+    #[serde(default)]
+    pub metadata: MetadataSettings,
+    /// Pixel format chunks are encoded in, like `yuv420p10le`
+    pub pix_fmt: Option<String>,
+    /// ffmpeg filters applied to every chunk on nodes before it's encoded,
+    /// like `crop=1920:800` or `scale=-2:1080`, in this order
+    #[serde(default)]
+    pub video_filters: Vec<String>,
+    /// Bit depth chunks are encoded in, with chroma subsampling of the source.
+    /// 8-bit sources are encoded in 10 bits with AV1 encoders when neither is set
+    pub bit_depth: Option<u32>,
+    /// Encode losslessly in pixel format of the source, with lossless parameters
+    /// of the encoder replacing rate options of encoder parameters
+    #[serde(default)]
+    pub lossless: bool,
+    /// Outputs encoded from the same chunks, one for every rendition. Output
+    /// is encoded as it is when none are set
+    #[serde(default)]
+    pub renditions: Vec<Rendition>,
+    #[serde(default)]
+    pub concat: ConcatMethod,
+    #[serde(default)]
+    pub output: OutputSettings,
+    #[serde(default)]
+    pub package: PackageSettings,
+    #[serde(default)]
+    pub notify: NotifySettings,
+    /// Address metrics of encodes are served on over HTTP, like `0.0.0.0:9100`
+    pub metrics_address: Option<String>,
+    /// Address the web UI of encodes is served on, like `0.0.0.0:8080`
+    pub web_address: Option<String>,
+}
+
+/// Container the output is muxed into
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct OutputSettings {
+    /// Write MP4 outputs fragmented and compatible with CMAF, for streaming packagers
+    #[serde(default)]
+    pub fragmented: bool,
+    /// Minimum duration of fragments in seconds, fragments start at every keyframe
+    /// when not set
+    pub fragment_duration: Option<f64>,
+    /// Container of output written to stdout, when output file is `-`
+    #[serde(default)]
+    pub stdout_format: StdoutFormat,
+    /// Streams of the input muxed after video, in this order, like `["a:1", "a:0", "s"]`.
+    /// Types are `v`, `a`, `s` and `t`, numbers count kept tracks of the type. All
+    /// streams but video are muxed in order of the input when it's empty
+    #[serde(default)]
+    pub map: Vec<String>,
+    /// Join chunks encoded from the start of every output into its preview while the
+    /// rest is encoded, like `movie.preview.mkv`. Preview is removed once output is done
+    #[serde(default)]
+    pub preview: bool,
+    /// Seconds between updates of previews
+    #[serde(default = "default_preview_interval")]
+    pub preview_interval: u64,
+    /// What is done when the output file exists
+    #[serde(default)]
+    pub overwrite: OverwritePolicy,
+}
+
+impl Default for OutputSettings {
+    fn default() -> Self {
+        OutputSettings {
+            fragmented: false,
+            fragment_duration: None,
+            stdout_format: StdoutFormat::default(),
+            map: Vec::new(),
+            preview: false,
+            preview_interval: default_preview_interval(),
+            overwrite: OverwritePolicy::default(),
+        }
+    }
+}
+
+fn default_preview_interval() -> u64 {
+    60
+}
+
+/// What is done when the output file exists, checked before anything is encoded
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Deserialize, Serialize, clap::ValueEnum)]
+#[serde(rename_all = "kebab-case")]
+pub enum OverwritePolicy {
+    /// Existing file is replaced
+    Overwrite,
+    /// Encode fails
+    #[default]
+    Fail,
+    /// Output is written under the first free name, like `movie_1.mkv`
+    UniqueName,
+}
+
+/// Containers that can be written to stdout, which can't be seeked back
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Deserialize, Serialize, clap::ValueEnum)]
+#[serde(rename_all = "kebab-case")]
+pub enum StdoutFormat {
+    #[default]
+    Matroska,
+    Nut,
+}
+
+/// Packaging of outputs for streaming, into segments and manifests in a directory
+/// next to every output, like `movie_package`
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct PackageSettings {
+    /// Manifests that are written, outputs aren't packaged when not set
+    pub format: Option<PackageFormat>,
+    /// Target duration of segments in seconds, segments start at keyframes
+    #[serde(default = "default_package_segment_duration")]
+    pub segment_duration: f64,
+    /// Template of names of initialization segments, with identifiers of DASH templates
+    #[serde(default = "default_init_segment")]
+    pub init_segment: String,
+    /// Template of names of media segments, with identifiers of DASH templates
+    #[serde(default = "default_media_segment")]
+    pub media_segment: String,
+}
+
+impl Default for PackageSettings {
+    fn default() -> Self {
+        PackageSettings {
+            format: None,
+            segment_duration: default_package_segment_duration(),
+            init_segment: default_init_segment(),
+            media_segment: default_media_segment(),
+        }
+    }
+}
+
+/// Notifications sent when an encode finishes or fails, so it doesn't have to be watched
+#[derive(Debug, Clone, Default, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct NotifySettings {
+    /// URL summary of the encode is posted to as JSON
+    pub webhook: Option<String>,
+    /// URL of a Discord webhook summary is posted to as a message
+    pub discord: Option<String>,
+    /// Whether summary is shown as a desktop notification
+    #[serde(default)]
+    pub desktop: bool,
+    #[serde(default)]
+    pub on: NotifyOn,
+}
+
+impl NotifySettings {
+    pub fn enabled(&self) -> bool {
+        self.webhook.is_some() || self.discord.is_some() || self.desktop
+    }
+}
+
+/// Encodes that are notified about
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Deserialize, Serialize, clap::ValueEnum)]
+#[serde(rename_all = "kebab-case")]
+pub enum NotifyOn {
+    /// Finished and failed encodes
+    #[default]
+    All,
+    /// Only failed encodes
+    Failure,
+}
+
+fn default_package_segment_duration() -> f64 {
+    4.0
+}
+
+fn default_init_segment() -> String {
+    "init_$RepresentationID$.$ext$".to_string()
+}
+
+fn default_media_segment() -> String {
+    "segment_$RepresentationID$_$Number%05d$.$ext$".to_string()
+}
+
+/// Manifests outputs are packaged with
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize, Serialize, clap::ValueEnum)]
+#[serde(rename_all = "kebab-case")]
+pub enum PackageFormat {
+    /// DASH manifest
+    Dash,
+    /// HLS playlists
+    Hls,
+    /// DASH manifest and HLS playlists of the same segments
+    Both,
+}
+
+/// How encoded chunks are joined into the output
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Deserialize, Serialize, clap::ValueEnum)]
+#[serde(rename_all = "kebab-case")]
+pub enum ConcatMethod {
+    /// Containers of chunks are joined by the concat demuxer of ffmpeg
+    #[default]
+    Ffmpeg,
+    /// Bitstreams of AV1 or VP9 chunks are joined in IVF, and muxed once
+    Ivf,
+    /// Chunks of MP4 outputs are joined by MP4Box of GPAC, and muxed once
+    Mp4box,
+}
+
+/// What is done when nodes have different versions of the encoder,
+/// which encode chunks of one output visibly differently
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Deserialize, Serialize, clap::ValueEnum)]
+#[serde(rename_all = "kebab-case")]
+pub enum VersionPolicy {
+    /// Chunks are sent to all nodes, with a warning
+    #[default]
+    Warn,
+    /// Chunks are only sent to nodes with the version most of them have
+    Strict,
+}
+
+/// Output of an encoding ladder, like 1080p at higher CRF than 2160p
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct Rendition {
+    /// Added to name of the output file, like `movie_1080p.mkv`
+    pub name: String,
+    /// Height frames are scaled to, keeping aspect ratio. Not scaled when not set
+    pub height: Option<u32>,
+    /// Parameters that replace the same options of encoder parameters, like `["-crf", "28"]`
+    #[serde(default)]
     pub encoder_params: Vec<String>,
 }
 
-#[derive(Debug, Deserialize)]
+fn default_resplit_after() -> usize {
+    2
+}
+
+fn default_passes() -> u32 {
+    1
+}
+
+/// Distribution of the bit budget across chunks, when output is encoded to average bitrate
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct BitrateSettings {
+    /// Average bitrate of every output in kbit/s. Chunks are encoded as parameters
+    /// say when not set
+    pub target: Option<u64>,
+    /// Maximum rate of VBV buffer in kbit/s
+    pub maxrate: Option<u64>,
+    /// Size of VBV buffer in kbit, twice the maximum rate when not set
+    pub bufsize: Option<u64>,
+    /// How much complexity of a chunk shifts bits to it. 0 gives every chunk
+    /// the target bitrate, 1 gives chunks bits in proportion to their complexity
+    #[serde(default = "default_complexity_weight")]
+    pub complexity_weight: f64,
+    /// Largest ratio between bitrate of a chunk and the target, in both directions
+    #[serde(default = "default_max_deviation")]
+    pub max_deviation: f64,
+}
+
+impl BitrateSettings {
+    /// VBV constraints, when maximum rate is set
+    pub fn vbv(&self) -> Option<Vbv> {
+        self.maxrate.map(|maxrate| Vbv {
+            maxrate,
+            bufsize: self.bufsize.unwrap_or(maxrate * 2),
+        })
+    }
+}
+
+impl Default for BitrateSettings {
+    fn default() -> Self {
+        BitrateSettings {
+            target: None,
+            maxrate: None,
+            bufsize: None,
+            complexity_weight: default_complexity_weight(),
+            max_deviation: default_max_deviation(),
+        }
+    }
+}
+
+fn default_complexity_weight() -> f64 {
+    0.6
+}
+
+fn default_max_deviation() -> f64 {
+    2.0
+}
+
+/// Encoder parameters tuned for animation or live action, applied to chunks
+/// of the type that's set, or detected from their frames
+#[derive(Debug, Clone, Default, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct ContentSettings {
+    /// Whether content type is detected once for every input, or for every chunk
+    #[serde(default)]
+    pub detect: ContentDetection,
+    /// Type of every input, which isn't detected then
+    pub content_type: Option<ContentType>,
+    /// Parameters that replace the same options of encoder parameters in chunks
+    /// of animation, like `["--tune", "0"]`
+    #[serde(default)]
+    pub animation_params: Vec<String>,
+    /// Parameters that replace the same options of encoder parameters in chunks
+    /// of live action
+    #[serde(default)]
+    pub live_action_params: Vec<String>,
+}
+
+impl ContentSettings {
+    /// Whether tuned parameters are applied
+    pub fn enabled(&self) -> bool {
+        self.content_type.is_some() || self.detect != ContentDetection::Off
+    }
+
+    /// Parameters tuned for content of the type
+    pub fn params(&self, content_type: ContentType) -> &[String] {
+        match content_type {
+            ContentType::Animation => &self.animation_params,
+            ContentType::LiveAction => &self.live_action_params,
+        }
+    }
+}
+
+/// Part of the input that content type is detected for
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Deserialize, Serialize, clap::ValueEnum)]
+#[serde(rename_all = "kebab-case")]
+pub enum ContentDetection {
+    /// Content type isn't detected
+    #[default]
+    Off,
+    /// Every input gets the type most of its sampled chunks have
+    Job,
+    /// Every chunk gets its own type
+    Chunk,
+}
+
+/// Type of content that parameters are tuned for
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Deserialize, Serialize, clap::ValueEnum)]
+#[serde(rename_all = "kebab-case")]
+pub enum ContentType {
+    /// Flat areas with sharp edges, like cartoons and anime
+    Animation,
+    /// Camera footage, with texture and grain
+    LiveAction,
+}
+
+/// Selection of constant rate factor for every output by probe encodes of sampled chunks,
+/// or for every chunk by probe encodes of the chunk on its node
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct CrfSettings {
+    /// Score of `metric` that sampled chunks have to reach on average, or every chunk
+    /// when CRF is searched per chunk, like 93 VMAF. CRF of parameters is used when not set
+    pub target: Option<f64>,
+    #[serde(default)]
+    pub metric: QualityMetric,
+    #[serde(default)]
+    pub search: CrfSearch,
+    /// Number of CRFs every chunk is encoded at, when CRF is searched per chunk
+    #[serde(default = "default_crf_probes")]
+    pub probes: usize,
+    /// Bitrate of sampled chunks in kbit/s that CRF is raised to stay within,
+    /// even when quality falls below the target
+    pub max_bitrate: Option<u64>,
+    /// Number of chunks that are encoded at every probed CRF
+    #[serde(default = "default_crf_samples")]
+    pub samples: usize,
+    /// Lowest and highest CRF that can be selected, within range of the encoder
+    pub min: Option<u32>,
+    pub max: Option<u32>,
+    /// Parameters that replace the same options of encoder parameters in probe encodes,
+    /// like a faster preset, which speeds up the search but makes it less exact
+    #[serde(default)]
+    pub probe_params: Vec<String>,
+}
+
+impl Default for CrfSettings {
+    fn default() -> Self {
+        CrfSettings {
+            target: None,
+            metric: QualityMetric::default(),
+            search: CrfSearch::default(),
+            probes: default_crf_probes(),
+            max_bitrate: None,
+            samples: default_crf_samples(),
+            min: None,
+            max: None,
+            probe_params: Vec::new(),
+        }
+    }
+}
+
+fn default_crf_samples() -> usize {
+    4
+}
+
+fn default_crf_probes() -> usize {
+    4
+}
+
+/// Part of the input that CRF is selected for
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Deserialize, Serialize, clap::ValueEnum)]
+#[serde(rename_all = "kebab-case")]
+pub enum CrfSearch {
+    /// Every output gets CRF selected on this machine from sampled chunks
+    #[default]
+    Title,
+    /// Every chunk gets its own CRF, selected by the node that encodes it
+    Chunk,
+}
+
+/// Metric that quality of probe encodes is measured with, against the source
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Deserialize, Serialize, clap::ValueEnum)]
+#[serde(rename_all = "kebab-case")]
+pub enum QualityMetric {
+    /// VMAF score from 0 to 100, requires ffmpeg built with libvmaf
+    #[default]
+    Vmaf,
+    /// SSIM from 0 to 1
+    Ssim,
+    /// PSNR in dB
+    Psnr,
+    /// Largest butteraugli distance of sampled frames, lower is better, requires
+    /// butteraugli_main of libjxl
+    ButteraugliMax,
+    /// Average 3-norm of butteraugli distances of sampled frames, lower is better,
+    /// requires butteraugli_main of libjxl
+    ButteraugliPnorm,
+}
+
+impl QualityMetric {
+    /// Name of the metric, as it's set in configuration
+    pub fn name(self) -> &'static str {
+        match self {
+            QualityMetric::Vmaf => "vmaf",
+            QualityMetric::Ssim => "ssim",
+            QualityMetric::Psnr => "psnr",
+            QualityMetric::ButteraugliMax => "butteraugli-max",
+            QualityMetric::ButteraugliPnorm => "butteraugli-pnorm",
+        }
+    }
+
+    /// Whether higher scores are better, butteraugli measures distance instead
+    pub fn higher_is_better(self) -> bool {
+        !matches!(
+            self,
+            QualityMetric::ButteraugliMax | QualityMetric::ButteraugliPnorm
+        )
+    }
+
+    /// Whether `score` is as good as `target` or better
+    pub fn meets(self, score: f64, target: f64) -> bool {
+        if self.higher_is_better() {
+            score >= target
+        } else {
+            score <= target
+        }
+    }
+}
+
+/// Quality of encoded chunks, measured by nodes against their sources
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct QualitySettings {
+    /// Metrics every chunk is measured with, scores are reported for every output
+    #[serde(default)]
+    pub metrics: Vec<QualityMetric>,
+    /// Format of report written next to every output, with scores, bitrate, encode
+    /// time and node of every chunk. Not written when not set
+    pub report: Option<ReportFormat>,
+    /// Score every chunk has to reach, chunks below it are encoded again
+    pub floor: Option<QualityFloor>,
+    /// Chunks with bitrate this many times the median of the output are flagged
+    /// as outliers in its summary
+    #[serde(default = "default_bitrate_outlier")]
+    pub bitrate_outlier: f64,
+    /// Only every this many frames are measured, by nodes and in probe encodes of
+    /// target quality, which speeds up measurement but makes it less exact
+    #[serde(default = "default_subsample")]
+    pub subsample: u32,
+    /// Break scores of frames of every output down by scene, scenes are detected
+    /// in the output
+    #[serde(default)]
+    pub scenes: bool,
+}
+
+impl Default for QualitySettings {
+    fn default() -> Self {
+        QualitySettings {
+            metrics: Vec::new(),
+            report: None,
+            floor: None,
+            bitrate_outlier: default_bitrate_outlier(),
+            subsample: default_subsample(),
+            scenes: false,
+        }
+    }
+}
+
+fn default_bitrate_outlier() -> f64 {
+    5.0
+}
+
+fn default_subsample() -> u32 {
+    1
+}
+
+/// Model VMAF is computed with, by nodes and when CRF is selected on this machine
+#[derive(Debug, Clone, Default, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct VmafSettings {
+    #[serde(default)]
+    pub model: VmafModel,
+    /// JSON model file of libvmaf that replaces the built-in model, it's sent to nodes
+    pub model_path: Option<PathBuf>,
+}
+
+/// Model built into libvmaf
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Deserialize, Serialize, clap::ValueEnum)]
+#[serde(rename_all = "kebab-case")]
+pub enum VmafModel {
+    /// vmaf_v0.6.1, for HD video watched on a TV
+    #[default]
+    Hd,
+    /// vmaf_4k_v0.6.1, for 4K video watched on a TV
+    #[serde(rename = "4k")]
+    #[value(name = "4k")]
+    Uhd,
+    /// vmaf_v0.6.1 transformed for phone screens, which hide more artifacts
+    Phone,
+    /// vmaf_v0.6.1neg, which doesn't reward sharpening and other enhancement
+    Neg,
+}
+
+impl VmafModel {
+    /// Name of the model, as it's set in configuration
+    pub fn name(self) -> &'static str {
+        match self {
+            VmafModel::Hd => "hd",
+            VmafModel::Uhd => "4k",
+            VmafModel::Phone => "phone",
+            VmafModel::Neg => "neg",
+        }
+    }
+}
+
+/// Score of a metric that chunks are encoded again to reach, with CRF lowered
+/// and `params` applied on every retry, until the chunk reaches it or runs out of retries
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct QualityFloor {
+    pub score: f64,
+    #[serde(default)]
+    pub metric: QualityMetric,
+    /// Number of times a chunk is encoded again at most, the last encode is kept
+    #[serde(default = "default_floor_retries")]
+    pub retries: usize,
+    /// Steps CRF of parameters is lowered by on every retry
+    #[serde(default = "default_floor_crf_step")]
+    pub crf_step: u32,
+    /// Parameters that replace the same options of encoder parameters on retries,
+    /// like a slower preset
+    #[serde(default)]
+    pub params: Vec<String>,
+}
+
+impl QualityFloor {
+    /// Floor of `score` with defaults of other settings
+    pub fn new(score: f64) -> Self {
+        QualityFloor {
+            score,
+            metric: QualityMetric::default(),
+            retries: default_floor_retries(),
+            crf_step: default_floor_crf_step(),
+            params: Vec::new(),
+        }
+    }
+}
+
+fn default_floor_retries() -> usize {
+    2
+}
+
+fn default_floor_crf_step() -> u32 {
+    4
+}
+
+/// Checks of encoded chunks returned by nodes, chunk that fails them is encoded again
+#[derive(Debug, Clone, Default, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct VerifySettings {
+    /// Decode every chunk, and reject it when ffmpeg reports any error
+    #[serde(default)]
+    pub decode: bool,
+    /// Reject chunk with other number of frames than its source, when it's known.
+    /// Filters that change number of frames can't be applied
+    #[serde(default)]
+    pub frame_count: bool,
+}
+
+/// Check of audio and video sync of every output against its source, after chunks
+/// are joined
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct SyncSettings {
+    #[serde(default)]
+    pub check: SyncCheck,
+    /// Drift in seconds above which the check warns or fails
+    #[serde(default = "default_max_drift")]
+    pub max_drift: f64,
+}
+
+impl Default for SyncSettings {
+    fn default() -> Self {
+        SyncSettings {
+            check: SyncCheck::default(),
+            max_drift: default_max_drift(),
+        }
+    }
+}
+
+fn default_max_drift() -> f64 {
+    0.1
+}
+
+/// What is done when audio and video of an output drift apart
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Deserialize, Serialize, clap::ValueEnum)]
+#[serde(rename_all = "kebab-case")]
+pub enum SyncCheck {
+    /// Sync isn't checked
+    Off,
+    /// Drift is logged as a warning
+    #[default]
+    Warn,
+    /// Output fails, and its temporary files are kept
+    Fail,
+}
+
+/// Reproducible encoding, which pins deterministic encoder parameters and records
+/// hashes of encoded chunks next to every output, like `movie.hashes.json`
+#[derive(Debug, Clone, Default, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct ReproducibleSettings {
+    #[serde(default)]
+    pub enabled: bool,
+    /// Compare hashes with the ones recorded by a previous encode, instead of recording
+    /// them, output fails when any chunk differs
+    #[serde(default)]
+    pub verify: bool,
+}
+
+/// Format of reports of outputs
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize, Serialize, clap::ValueEnum)]
+#[serde(rename_all = "kebab-case")]
+pub enum ReportFormat {
+    Json,
+    Csv,
+}
+
+/// Audio and subtitle tracks of the input kept in the output. Tracks of a type are
+/// kept when they match a language or an index, and all of them when neither is set.
+/// Language `none` keeps no tracks of the type.
+#[derive(Debug, Clone, Default, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct TrackSettings {
+    /// Languages of audio tracks, like `["jpn", "eng"]`
+    #[serde(default)]
+    pub audio_languages: Vec<String>,
+    /// Indices of audio tracks among audio tracks of the input, from 0
+    #[serde(default)]
+    pub audio_tracks: Vec<usize>,
+    /// Languages of subtitle tracks
+    #[serde(default)]
+    pub subtitle_languages: Vec<String>,
+    /// Indices of subtitle tracks among subtitle tracks of the input, from 0
+    #[serde(default)]
+    pub subtitle_tracks: Vec<usize>,
+    /// Whether chapters of the input are kept
+    #[serde(default)]
+    pub chapters: Chapters,
+    /// What is done with subtitle tracks of ffmpeg codecs, like `hdmv_pgs_subtitle`.
+    /// Tracks of other codecs are copied, except `mov_text`, which is converted to SRT
+    #[serde(default)]
+    pub subtitle_formats: HashMap<String, SubtitlePolicy>,
+}
+
+impl TrackSettings {
+    /// Whether audio track with `index` among audio tracks is kept
+    pub fn keeps_audio(&self, index: usize, language: Option<&str>) -> bool {
+        keeps_track(&self.audio_languages, &self.audio_tracks, index, language)
+    }
+
+    /// Whether subtitle track with `index` among subtitle tracks is kept
+    pub fn keeps_subtitle(&self, index: usize, language: Option<&str>) -> bool {
+        keeps_track(
+            &self.subtitle_languages,
+            &self.subtitle_tracks,
+            index,
+            language,
+        )
+    }
+
+    /// What is done with subtitle tracks of `codec`
+    pub fn subtitle_policy(&self, codec: &str) -> SubtitlePolicy {
+        match self.subtitle_formats.get(codec) {
+            Some(&policy) => policy,
+            // Matroska can't store subtitles of MP4
+            None if codec == "mov_text" => SubtitlePolicy::Srt,
+            None => SubtitlePolicy::Copy,
+        }
+    }
+}
+
+/// Tags of the output, written over tags of the input it keeps
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct MetadataSettings {
+    /// Keep global tags and tags of the video track of the input
+    #[serde(default = "default_keep_metadata")]
+    pub keep: bool,
+    /// Global tags written into every output, like `{ comment = "Encoded for archive" }`
+    #[serde(default)]
+    pub tags: HashMap<String, String>,
+    /// Write encoder and its parameters into `ENCODER_SETTINGS` tag
+    #[serde(default)]
+    pub encoder_settings: bool,
+    /// Write identifier of the encode into `JOB_ID` tag
+    #[serde(default)]
+    pub job_id: bool,
+}
+
+impl Default for MetadataSettings {
+    fn default() -> Self {
+        MetadataSettings {
+            keep: default_keep_metadata(),
+            tags: HashMap::new(),
+            encoder_settings: false,
+            job_id: false,
+        }
+    }
+}
+
+fn default_keep_metadata() -> bool {
+    true
+}
+
+/// What is done with chapters of the input
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Deserialize, Serialize, clap::ValueEnum)]
+#[serde(rename_all = "kebab-case")]
+pub enum Chapters {
+    /// Chapters are written to the output
+    #[default]
+    Keep,
+    /// Output has no chapters
+    Drop,
+}
+
+/// What is done with subtitle tracks of a format
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum SubtitlePolicy {
+    /// Track is copied as it is
+    Copy,
+    /// Track is left out
+    Drop,
+    /// Text track is converted to SubRip
+    Srt,
+    /// Text track is converted to Advanced SubStation Alpha
+    Ass,
+}
+
+impl SubtitlePolicy {
+    /// ffmpeg codec tracks are converted to, none when they're copied or dropped
+    pub fn codec(self) -> Option<&'static str> {
+        match self {
+            SubtitlePolicy::Copy | SubtitlePolicy::Drop => None,
+            SubtitlePolicy::Srt => Some("srt"),
+            SubtitlePolicy::Ass => Some("ass"),
+        }
+    }
+}
+
+fn keeps_track(
+    languages: &[String],
+    indices: &[usize],
+    index: usize,
+    language: Option<&str>,
+) -> bool {
+    if languages.is_empty() && indices.is_empty() {
+        return true;
+    }
+    // Tracks without language tag are undetermined, like in Matroska
+    let language = language.unwrap_or("und");
+    indices.contains(&index)
+        || languages
+            .iter()
+            .any(|wanted| wanted.eq_ignore_ascii_case(language))
+}
+
+/// Transcoding of audio tracks, which are copied as they are by default.
+/// Audio is transcoded while video is encoded on nodes.
+#[derive(Debug, Clone, Default, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct AudioSettings {
+    /// ffmpeg codec every audio track is transcoded to, like `libopus`
+    pub codec: Option<String>,
+    /// Bitrate of every audio track in kbit/s, default of the codec when not set
+    pub bitrate: Option<u32>,
+    /// Settings of single tracks, which replace the ones above
+    #[serde(default)]
+    pub tracks: Vec<AudioTrackSettings>,
+}
+
+impl AudioSettings {
+    /// Whether any track is transcoded
+    pub fn enabled(&self) -> bool {
+        self.codec.is_some() || self.tracks.iter().any(|track| track.codec.is_some())
+    }
+
+    /// Codec and bitrate of audio track with `index`, no codec when it's copied
+    pub fn track(&self, index: usize) -> (Option<&str>, Option<u32>) {
+        let track = self.tracks.iter().find(|track| track.index == index);
+        let codec = track
+            .and_then(|track| track.codec.as_deref())
+            .or(self.codec.as_deref())
+            .filter(|&codec| codec != "copy");
+        let bitrate = track.and_then(|track| track.bitrate).or(self.bitrate);
+        (codec, bitrate)
+    }
+}
+
+/// Transcoding of a single audio track
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct AudioTrackSettings {
+    /// Index of the track among audio tracks, from 0
+    pub index: usize,
+    /// ffmpeg codec of the track, `copy` keeps it as it is
+    pub codec: Option<String>,
+    /// Bitrate of the track in kbit/s
+    pub bitrate: Option<u32>,
+}
+
+/// Film grain synthesis with photon noise tables, for encodes that remove grain
+/// of the source with a denoiser
+#[derive(Debug, Clone, Default, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct GrainSettings {
+    /// Strength of photon noise in ISO, like 800
+    pub photon_noise: Option<u32>,
+    /// Measure grain of the source and use photon noise of matching strength,
+    /// when `photon_noise` is not set
+    #[serde(default)]
+    pub measure: bool,
+    /// Apply noise to chroma as well
+    #[serde(default)]
+    pub chroma: bool,
+    /// Denoise chunks with hqdn3d of this strength, where 1.0 is its default strength,
+    /// and synthesize the grain it removes. Grain is measured when `photon_noise` is not set
+    pub denoise: Option<f64>,
+}
+
+impl GrainSettings {
+    /// Whether chunks are encoded with a film grain table
+    pub fn enabled(&self) -> bool {
+        self.photon_noise.is_some() || self.measure || self.denoise.is_some()
+    }
+}
+
+/// Transfer rate limits in bytes per second, unlimited when not set
+#[derive(Debug, Default, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct BandwidthSettings {
+    /// Upload limit shared by all nodes
+    pub upload_limit: Option<u64>,
+    /// Download limit shared by all nodes
+    pub download_limit: Option<u64>,
+    /// Upload limit for each node
+    pub node_upload_limit: Option<u64>,
+    /// Download limit for each node
+    pub node_download_limit: Option<u64>,
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
 pub struct NodeSettings {
     pub address: String,
+    /// How long received chunks are kept for retries, in seconds. 0 disables cache
+    #[serde(default = "default_chunk_cache_ttl")]
+    pub chunk_cache_ttl: u64,
+    /// Permissions of the socket file when listening on Unix socket, e.g. 0o660
+    pub socket_permissions: Option<u32>,
+    /// Maximum number of concurrent encodes, others wait in queue. Unlimited when not set
+    pub slots: Option<usize>,
+    /// Maximum number of threads of every encode. Defaults to CPUs divided by slots,
+    /// encoders choose themselves when neither is set. 0 disables the limit
+    pub threads: Option<usize>,
+    /// Niceness of the node and its encodes from -20 to 19, higher is lower priority.
+    /// Maps to priority class on Windows
+    pub nice: Option<i32>,
+    /// I/O scheduling class of the node and its encodes, on Linux
+    pub io_class: Option<IoClass>,
+    /// Encoder processes kept started for parameters of recent chunks, so they don't
+    /// wait for the encoder to start. Only standalone encoders use them. 0 disables them
+    #[serde(default)]
+    pub warm_processes: usize,
+    #[serde(default)]
+    pub cgroup: CgroupSettings,
+    /// Space in MiB left free on the disk of the temp directory. Chunks that would take
+    /// it below are rejected, so the client sends them elsewhere. 0 disables the check
+    #[serde(default = "default_min_free_space")]
+    pub min_free_space: u64,
 }
 
-#[derive(Debug, Deserialize)]
+/// Limits of every encode, applied with cgroups v2 on Linux. Encodes aren't limited
+/// when neither is set
+#[derive(Debug, Clone, Default, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct CgroupSettings {
+    /// Memory of every encode in MiB, encode that exceeds it is killed
+    pub memory_max: Option<u64>,
+    /// CPU time of every encode in CPUs, like 4 or 2.5
+    pub cpu_max: Option<f64>,
+}
+
+impl NodeSettings {
+    /// Thread limit of every encode, so concurrent encodes don't oversubscribe CPUs
+    pub fn encode_threads(&self) -> Option<usize> {
+        match (self.threads, self.slots) {
+            (Some(0), _) => None,
+            (Some(threads), _) => Some(threads),
+            (None, Some(slots)) => {
+                let cpus = std::thread::available_parallelism().map_or(1, |cpus| cpus.get());
+                Some((cpus / slots.max(1)).max(1))
+            }
+            (None, None) => None,
+        }
+    }
+}
+
+fn default_chunk_cache_ttl() -> u64 {
+    300
+}
+
+fn default_min_free_space() -> u64 {
+    1024
+}
+
+fn default_check_free_space() -> bool {
+    true
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
 pub struct ProcessingSettings {
+    /// Duration of chunks in seconds. With scene or keyframe splitting it's the
+    /// maximum duration, longer scenes are split into multiple chunks
     pub segment_duration: f64,
+    /// Number of frames in each chunk when splitting by time, used instead of
+    /// `segment_duration`. Gives even chunks for variable frame rate sources
+    pub segment_frames: Option<usize>,
     pub temp_dir: PathBuf,
+    /// What the client does with files left in the temporary directory by an earlier
+    /// encode, which would be overwritten and removed with the ones of this encode
+    #[serde(default)]
+    pub existing_temp: ExistingTemp,
+    /// Frame rate of image sequence input, as fraction like `24000/1001` or number
+    pub frame_rate: Option<String>,
+    #[serde(default)]
+    pub split_method: SplitMethod,
+    /// Score of scdet filter from 0 to 100, above which frame is considered a scene change
+    #[serde(default = "default_scene_threshold")]
+    pub scene_threshold: f64,
+    /// Minimum duration of chunks in seconds when splitting by scenes or keyframes
+    #[serde(default = "default_min_segment_duration")]
+    pub min_segment_duration: f64,
+    /// Re-encode chunks to lossless intermediate, so they are split exactly at
+    /// requested points instead of the nearest keyframes of the source
+    #[serde(default)]
+    pub lossless_intermediate: bool,
+    /// What to do when input has open GOPs, which break chunks split by stream copy
+    #[serde(default)]
+    pub open_gop: OpenGop,
+    /// Extract every chunk from the input when it's dispatched, instead of
+    /// writing all of them at once before encoding
+    #[serde(default)]
+    pub extract_on_demand: bool,
+    /// Check before the input is split that the temporary directory has space for
+    /// segments, encoded chunks and other streams of the encode
+    #[serde(default = "default_check_free_space")]
+    pub check_free_space: bool,
+    /// Read split points from JSON file instead of computing them
+    pub import_splits: Option<PathBuf>,
+    /// Write computed split points to JSON file
+    pub export_splits: Option<PathBuf>,
+    /// File of zones that override encoder parameters for frame ranges.
+    /// Input is split at zone boundaries
+    pub zones: Option<PathBuf>,
+    /// Keep timestamps of variable frame rate sources, by applying source timestamps
+    /// to the encoded video. Requires mkvmerge
+    #[serde(default)]
+    pub preserve_timestamps: bool,
+    /// Number of ffmpeg processes segmenting the input in parallel,
+    /// defaults to number of CPUs up to 4
+    pub segment_jobs: Option<usize>,
+    #[serde(default)]
+    pub deinterlace: Deinterlace,
+    /// Filter applied to interlaced input
+    #[serde(default = "default_deinterlace_filter")]
+    pub deinterlace_filter: String,
+    /// Filter applied to telecined input
+    #[serde(default = "default_ivtc_filter")]
+    pub ivtc_filter: String,
+}
+
+/// How input with open GOPs is split
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Deserialize, Serialize, clap::ValueEnum)]
+#[serde(rename_all = "lowercase")]
+pub enum OpenGop {
+    /// Input is split by stream copy anyway, chunks may start with broken frames
+    Warn,
+    /// Input is split through lossless intermediate
+    #[default]
+    Lossless,
+}
+
+/// How interlaced input is processed before it's split into chunks
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Deserialize, Serialize, clap::ValueEnum)]
+#[serde(rename_all = "lowercase")]
+pub enum Deinterlace {
+    /// Input is split as it is
+    Off,
+    /// Input is analyzed, and filtered with deinterlace or IVTC filter when needed
+    #[default]
+    Auto,
+    /// Deinterlace filter is always applied
+    Deinterlace,
+    /// IVTC filter is always applied
+    Ivtc,
+}
+
+/// What is done with files left in the temporary directory when encode starts
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Deserialize, Serialize, clap::ValueEnum)]
+#[serde(rename_all = "kebab-case")]
+pub enum ExistingTemp {
+    /// Encode fails
+    #[default]
+    Fail,
+    /// Files are removed
+    Clean,
+    /// Files are kept, so interrupted downloads are resumed, and replaced when they're
+    /// written again
+    Reuse,
+}
+
+/// How input video is split into chunks
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Deserialize, Serialize, clap::ValueEnum)]
+#[serde(rename_all = "lowercase")]
+pub enum SplitMethod {
+    /// Chunks of fixed duration
+    Time,
+    /// Chunks that start at scene changes
+    #[default]
+    Scene,
+    /// Chunks that start at source keyframes, as long as they fit into `segment_duration`
+    Keyframes,
+}
+
+fn default_scene_threshold() -> f64 {
+    10.0
+}
+
+fn default_min_segment_duration() -> f64 {
+    2.0
+}
+
+fn default_deinterlace_filter() -> String {
+    "bwdif=mode=send_frame".to_string()
+}
+
+fn default_ivtc_filter() -> String {
+    "fieldmatch,yadif=deint=interlaced,decimate".to_string()
+}
+
+/// Tuning of gRPC connections, used for client channels and node server.
+/// Durations are in seconds, unset values keep tonic defaults.
+#[derive(Debug, Default, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct GrpcSettings {
+    /// Timeout for establishing connection to a node
+    pub connect_timeout: Option<u64>,
+    /// Timeout for a single chunk request, node stops encoding when it expires
+    pub request_timeout: Option<u64>,
+    /// Interval of HTTP/2 keepalive pings
+    pub keepalive_interval: Option<u64>,
+    /// Time to wait for keepalive ping acknowledgement before closing connection
+    pub keepalive_timeout: Option<u64>,
+    /// Send keepalive pings even when there are no requests in flight
+    #[serde(default)]
+    pub keepalive_while_idle: bool,
+    /// Interval of TCP keepalive probes
+    pub tcp_keepalive: Option<u64>,
+    /// Maximum number of concurrent requests per connection
+    pub concurrency_limit: Option<usize>,
+    /// Initial HTTP/2 stream window size in bytes
+    pub initial_stream_window_size: Option<u32>,
+    /// Initial HTTP/2 connection window size in bytes
+    pub initial_connection_window_size: Option<u32>,
+}
+
+/// Settings for QUIC transport, used for `quic://` addresses
+#[derive(Debug, Default, Clone, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct QuicSettings {
+    /// PEM certificate of the node. Node presents it, client trusts it.
+    /// Node generates self-signed certificate in its temp directory when not set
+    pub cert: Option<PathBuf>,
+    /// PEM private key of the node certificate
+    pub key: Option<PathBuf>,
+    /// Name the certificate is issued for, "localhost" when not set
+    pub server_name: Option<String>,
+}
+
+/// Encryption of chunk payloads. Client and nodes have to use the same key,
+/// nodes with key set reject unencrypted chunks
+#[derive(Debug, Default, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct EncryptionSettings {
+    /// File with hex encoded 32 byte key
+    pub key_file: Option<PathBuf>,
 }
 
-#[derive(Debug, Deserialize)]
+/// When the log file is rotated, keeping the current one apart from older ones
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum LogRotation {
+    Hourly,
+    #[default]
+    Daily,
+    /// When the file reaches max_size
+    Size,
+    /// Everything is written into one file
+    Never,
+}
+
+/// Log file of client and node, with its own filter apart from verbosity of the console
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct LogSettings {
+    /// Write logs into files, besides the console
+    #[serde(default = "default_log_enabled")]
+    pub enabled: bool,
+    /// Directory of log files
+    #[serde(default = "default_log_dir")]
+    pub dir: PathBuf,
+    /// Filter of the log file, like `debug` or `info,node=trace`. Same as the one of
+    /// the console when not set
+    pub level: Option<String>,
+    #[serde(default)]
+    pub rotation: LogRotation,
+    /// Size in MiB the log file is rotated at, with size rotation
+    #[serde(default = "default_log_max_size")]
+    pub max_size: u64,
+    /// Number of log files that are kept, older ones are removed. 0 keeps all of them
+    #[serde(default = "default_log_max_files")]
+    pub max_files: usize,
+}
+
+impl Default for LogSettings {
+    fn default() -> Self {
+        LogSettings {
+            enabled: default_log_enabled(),
+            dir: default_log_dir(),
+            level: None,
+            rotation: LogRotation::default(),
+            max_size: default_log_max_size(),
+            max_files: default_log_max_files(),
+        }
+    }
+}
+
+fn default_log_enabled() -> bool {
+    true
+}
+
+fn default_log_dir() -> PathBuf {
+    PathBuf::from("logs")
+}
+
+fn default_log_max_size() -> u64 {
+    100
+}
+
+fn default_log_max_files() -> usize {
+    14
+}
+
+/// Config file with every setting, commented, written by `client init-config`
+pub const DEFAULT_CONFIG: &str = include_str!("../config.toml");
+
+/// Settings from variables like `RAV1AN_NODE__SLOTS`, which override the config file.
+/// Sections and keys are separated by double underscore, lists by comma
+/// Settings that hold secrets, or point at them, left out of settings that are shared
+const SECRET_SETTINGS: &[&[&str]] = &[
+    &["client", "notify", "webhook"],
+    &["client", "notify", "discord"],
+    &["encryption", "key_file"],
+    &["quic", "key"],
+];
+
+fn environment() -> Environment {
+    Environment::with_prefix("RAV1AN")
+        .prefix_separator("_")
+        .separator("__")
+        .ignore_empty(true)
+        .try_parsing(true)
+        .list_separator(",")
+        .with_list_parse_key("client.node_addresses")
+        .with_list_parse_key("client.encoder_params")
+        .with_list_parse_key("client.video_filters")
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
 pub struct Settings {
     pub client: ClientSettings,
     pub node: NodeSettings,
     pub processing: ProcessingSettings,
+    #[serde(default)]
+    pub grpc: GrpcSettings,
+    #[serde(default)]
+    pub quic: QuicSettings,
+    #[serde(default)]
+    pub encryption: EncryptionSettings,
+    /// Named encoder settings, selected with `--preset`
+    #[serde(default)]
+    pub presets: HashMap<String, Preset>,
+    #[serde(default)]
+    pub log: LogSettings,
+}
+
+/// Encoder settings under a name, like `[presets.animation]`.
+/// Settings that are set replace client settings when preset is selected.
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct Preset {
+    pub encoder: Option<EncoderKind>,
+    pub encoder_params: Vec<String>,
+    pub passes: Option<u32>,
+    pub video_filters: Option<Vec<String>>,
+    pub pix_fmt: Option<String>,
+    pub bit_depth: Option<u32>,
+    pub lossless: Option<bool>,
 }
 
 impl Settings {
     pub fn from_file(path: &Path) -> Result<Self, ConfigError> {
-        let config = Config::builder().add_source(File::from(path)).build()?;
+        let config = Config::builder()
+            .add_source(File::from(path))
+            .add_source(environment())
+            .build()?;
 
         config.try_deserialize()
     }
 
+    /// Log settings of the config file at `path`, or of the default config file, which
+    /// are needed before logging starts. Defaults are used when settings can't be read,
+    /// errors of the config file are reported once it's loaded as a whole
+    pub fn log_settings(path: Option<&Path>) -> LogSettings {
+        let file = match path {
+            Some(path) => File::from(path),
+            None => File::with_name("config"),
+        };
+        Config::builder()
+            .add_source(file)
+            .add_source(environment())
+            .build()
+            .and_then(|config| config.get("log"))
+            .unwrap_or_default()
+    }
+
     pub fn new() -> Result<Self, ConfigError> {
         let config = Config::builder()
             .add_source(File::with_name("config"))
+            .add_source(environment())
             .build()?;
 
         debug!("Created config : {:?}", config);
         config.try_deserialize()
     }
+
+    /// Settings as a config file that can be loaded back, with secrets redacted, so it
+    /// can be shared
+    pub fn redacted_toml(&self) -> Result<String, ConfigError> {
+        let mut value =
+            toml::Value::try_from(self).map_err(|e| ConfigError::Message(e.to_string()))?;
+        for path in SECRET_SETTINGS {
+            let (key, tables) = path.split_last().expect("Setting path isn't empty");
+            let table = tables
+                .iter()
+                .try_fold(&mut value, |value, table| value.get_mut(table));
+            if let Some(secret) = table.and_then(|table| table.get_mut(key)) {
+                *secret = toml::Value::String("<redacted>".to_string());
+            }
+        }
+        toml::to_string_pretty(&value).map_err(|e| ConfigError::Message(e.to_string()))
+    }
+
+    /// Replaces client encoder_params with parameters of the profile, with options of
+    /// `overrides` replacing its options. Without a profile `overrides` replace
+    /// encoder_params, and the balanced profile is used when there are no parameters
+    pub fn apply_profile(&mut self, overrides: Option<Vec<String>>) -> Result<(), ConfigError> {
+        let client = &mut self.client;
+        let (profile, overrides) = match (client.profile, overrides) {
+            (Some(profile), overrides) => (profile, overrides.unwrap_or_default()),
+            (None, None) if client.encoder_params.is_empty() => (Profile::Balanced, Vec::new()),
+            (None, Some(overrides)) => {
+                client.encoder_params = overrides;
+                return Ok(());
+            }
+            (None, None) => return Ok(()),
+        };
+        let params = profile_params(client.encoder, profile)
+            .map_err(|e| ConfigError::Message(e.to_string()))?;
+        debug!("Applying profile {:?}: {:?}", profile, params);
+        client.encoder_params = override_params(&params, &overrides);
+        Ok(())
+    }
+
+    /// Checks settings the client uses, after they're overridden by options
+    pub fn validate_client(&self) -> Result<(), ConfigError> {
+        if self.client.node_addresses.is_empty() {
+            return Err(ConfigError::Message(
+                "client.node_addresses is empty, list nodes in the config file or with --nodes"
+                    .to_string(),
+            ));
+        }
+        if let Some(address) = self
+            .client
+            .node_addresses
+            .iter()
+            .find(|address| address.trim().is_empty())
+        {
+            return Err(ConfigError::Message(format!(
+                "client.node_addresses has empty address {:?}",
+                address
+            )));
+        }
+        self.validate_processing()
+    }
+
+    /// Checks settings the node uses, after they're overridden by options
+    pub fn validate_node(&self) -> Result<(), ConfigError> {
+        if self.node.address.trim().is_empty() {
+            return Err(ConfigError::Message(
+                "node.address is empty, set it in the config file or with --node".to_string(),
+            ));
+        }
+        if self.node.slots == Some(0) {
+            return Err(ConfigError::Message(
+                "node.slots is 0, so nothing would be encoded, leave it out for no limit"
+                    .to_string(),
+            ));
+        }
+        self.validate_processing()
+    }
+
+    fn validate_processing(&self) -> Result<(), ConfigError> {
+        let processing = &self.processing;
+        if !processing.segment_duration.is_finite() || processing.segment_duration <= 0.0 {
+            return Err(ConfigError::Message(format!(
+                "processing.segment_duration is {}, it must be positive number of seconds",
+                processing.segment_duration
+            )));
+        }
+        // Chunks that can't be shorter than they can be long leave no room to split
+        if !processing.min_segment_duration.is_finite()
+            || processing.min_segment_duration <= 0.0
+            || processing.min_segment_duration > processing.segment_duration
+        {
+            return Err(ConfigError::Message(format!(
+                "processing.min_segment_duration is {}, it must be positive number of \
+                 seconds up to segment_duration {}",
+                processing.min_segment_duration, processing.segment_duration
+            )));
+        }
+        if !(0.0..=100.0).contains(&processing.scene_threshold) {
+            return Err(ConfigError::Message(format!(
+                "processing.scene_threshold is {}, it must be a score from 0 to 100",
+                processing.scene_threshold
+            )));
+        }
+        if processing.segment_frames == Some(0) {
+            return Err(ConfigError::Message(
+                "processing.segment_frames is 0, it must be positive or left out".to_string(),
+            ));
+        }
+
+        // Temporary directory is created when it's missing, so it's checked by writing
+        let temp_dir = &processing.temp_dir;
+        std::fs::create_dir_all(temp_dir)
+            .and_then(|_| tempfile::tempfile_in(temp_dir))
+            .map_err(|e| {
+                ConfigError::Message(format!(
+                    "processing.temp_dir {:?} isn't writable: {}, choose other directory \
+                     with --temp-dir",
+                    temp_dir, e
+                ))
+            })?;
+        Ok(())
+    }
+
+    /// Replaces client settings with settings of preset `name`
+    pub fn apply_preset(&mut self, name: &str) -> Result<(), ConfigError> {
+        let preset = self.presets.get(name).cloned().ok_or_else(|| {
+            let mut names: Vec<&str> = self.presets.keys().map(String::as_str).collect();
+            names.sort_unstable();
+            ConfigError::Message(format!(
+                "Preset {} is not defined, defined presets: {}",
+                name,
+                if names.is_empty() {
+                    "none".to_string()
+                } else {
+                    names.join(", ")
+                }
+            ))
+        })?;
+        debug!("Applying preset {}: {:?}", name, preset);
+
+        let client = &mut self.client;
+        client.encoder = preset.encoder.unwrap_or(client.encoder);
+        client.encoder_params = preset.encoder_params;
+        client.profile = None;
+        client.passes = preset.passes.unwrap_or(client.passes);
+        if let Some(video_filters) = preset.video_filters {
+            client.video_filters = video_filters;
+        }
+        if preset.pix_fmt.is_some() {
+            client.pix_fmt = preset.pix_fmt;
+        }
+        if preset.bit_depth.is_some() {
+            client.bit_depth = preset.bit_depth;
+        }
+        client.lossless = preset.lossless.unwrap_or(client.lossless);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn load(variables: &[(&str, &str)]) -> Result<Settings, ConfigError> {
+        let variables = variables
+            .iter()
+            .map(|(name, value)| (name.to_string(), value.to_string()))
+            .collect();
+        Config::builder()
+            .add_source(File::from(Path::new("config.toml")))
+            .add_source(environment().source(Some(variables)))
+            .build()
+            .and_then(Config::try_deserialize)
+    }
+
+    fn with_variables(variables: &[(&str, &str)]) -> Settings {
+        load(variables).unwrap()
+    }
+
+    /// Settings of config.toml with temporary directory that can be written
+    fn writable(temp_dir: &tempfile::TempDir) -> Settings {
+        let mut settings = with_variables(&[]);
+        settings.processing.temp_dir = temp_dir.path().to_path_buf();
+        settings
+    }
+
+    #[test]
+    fn variables_override_settings_of_the_file() {
+        let settings = with_variables(&[
+            ("RAV1AN_NODE__ADDRESS", "0.0.0.0:50051"),
+            ("RAV1AN_NODE__SLOTS", "4"),
+            ("RAV1AN_PROCESSING__TEMP_DIR", "/tmp/rav1an"),
+        ]);
+        assert_eq!(settings.node.address, "0.0.0.0:50051");
+        assert_eq!(settings.node.slots, Some(4));
+        assert_eq!(settings.processing.temp_dir, PathBuf::from("/tmp/rav1an"));
+    }
+
+    #[test]
+    fn lists_are_separated_by_commas() {
+        let settings = with_variables(&[(
+            "RAV1AN_CLIENT__NODE_ADDRESSES",
+            "http://a:50051,http://b:50051",
+        )]);
+        assert_eq!(
+            settings.client.node_addresses,
+            ["http://a:50051", "http://b:50051"]
+        );
+    }
+
+    #[test]
+    fn empty_variables_are_ignored() {
+        let file = with_variables(&[]);
+        let settings = with_variables(&[("RAV1AN_NODE__ADDRESS", "")]);
+        assert_eq!(settings.node.address, file.node.address);
+    }
+
+    #[test]
+    fn unknown_keys_are_rejected() {
+        let error = load(&[("RAV1AN_NODE__ADRESS", "0.0.0.0:50051")]).unwrap_err();
+        assert!(error.to_string().contains("adress"), "{}", error);
+    }
+
+    #[test]
+    fn default_config_is_valid() {
+        let temp_dir = tempfile::tempdir().unwrap();
+        let settings = writable(&temp_dir);
+        settings.validate_client().unwrap();
+        settings.validate_node().unwrap();
+    }
+
+    #[test]
+    fn client_needs_node_addresses() {
+        let temp_dir = tempfile::tempdir().unwrap();
+        let mut settings = writable(&temp_dir);
+        settings.client.node_addresses.clear();
+        assert!(settings.validate_client().is_err());
+        settings.client.node_addresses = vec![" ".to_string()];
+        assert!(settings.validate_client().is_err());
+    }
+
+    #[test]
+    fn chunk_durations_leave_room_to_split() {
+        let temp_dir = tempfile::tempdir().unwrap();
+        let mut settings = writable(&temp_dir);
+        settings.processing.min_segment_duration = settings.processing.segment_duration + 1.0;
+        assert!(settings.validate_client().is_err());
+        settings.processing.min_segment_duration = 0.0;
+        assert!(settings.validate_client().is_err());
+        settings.processing.min_segment_duration = settings.processing.segment_duration;
+        settings.processing.scene_threshold = 101.0;
+        assert!(settings.validate_client().is_err());
+    }
+
+    #[test]
+    fn temp_dir_has_to_be_writable() {
+        let temp_dir = tempfile::tempdir().unwrap();
+        let file = temp_dir.path().join("file");
+        std::fs::write(&file, b"").unwrap();
+        let mut settings = writable(&temp_dir);
+        settings.processing.temp_dir = file.join("temp");
+        assert!(settings.validate_client().is_err());
+    }
+
+    #[test]
+    fn redacted_settings_load_back_without_secrets() {
+        let settings = with_variables(&[("RAV1AN_ENCRYPTION__KEY_FILE", "/etc/rav1an/key")]);
+        let toml = settings.redacted_toml().unwrap();
+        assert!(!toml.contains("/etc/rav1an/key"), "{}", toml);
+
+        let loaded: Settings = Config::builder()
+            .add_source(File::from_str(&toml, config::FileFormat::Toml))
+            .build()
+            .and_then(Config::try_deserialize)
+            .unwrap();
+        assert_eq!(
+            loaded.encryption.key_file,
+            Some(PathBuf::from("<redacted>"))
+        );
+        assert_eq!(loaded.client.node_addresses, settings.client.node_addresses);
+    }
 }
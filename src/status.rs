@@ -0,0 +1,163 @@
+/// This module tracks the load of a node: encodes that are running,
+/// encodes that wait for a free slot, failures of recent encodes, and usage
+/// of CPU and memory of the machine
+use std::{
+    collections::VecDeque,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc, Mutex,
+    },
+    time::{Duration, SystemTime},
+};
+
+use sysinfo::System;
+use tokio::sync::{OwnedSemaphorePermit, Semaphore};
+
+/// Number of failures kept for status reports
+const RECENT_FAILURES: usize = 10;
+
+/// Failed encode of a chunk
+#[derive(Debug, Clone)]
+pub struct Failure {
+    pub job_id: String,
+    pub chunk_index: i32,
+    pub error_message: String,
+    pub time: SystemTime,
+}
+
+/// Usage of resources of the machine the node runs on, at the last sample
+#[derive(Debug, Clone, Copy, Default)]
+pub struct ResourceUsage {
+    /// Percent of all CPU cores that was used since the sample before
+    pub cpu: f32,
+    /// Bytes of memory in use
+    pub memory_used: u64,
+    pub memory_total: u64,
+}
+
+/// Load of the node, shared by all requests
+#[derive(Debug)]
+pub struct NodeStatus {
+    /// Limits number of concurrent encodes, unlimited when not set
+    slots: Option<(usize, Arc<Semaphore>)>,
+    active: Arc<AtomicUsize>,
+    queued: Arc<AtomicUsize>,
+    failures: Mutex<VecDeque<Failure>>,
+    usage: Arc<Mutex<ResourceUsage>>,
+}
+
+impl NodeStatus {
+    /// Creates tracker for node with given number of encoding slots
+    pub fn new(slots: Option<usize>) -> Self {
+        NodeStatus {
+            slots: slots.map(|slots| (slots, Arc::new(Semaphore::new(slots)))),
+            active: Arc::default(),
+            queued: Arc::default(),
+            failures: Mutex::new(VecDeque::with_capacity(RECENT_FAILURES)),
+            usage: Arc::default(),
+        }
+    }
+
+    /// Samples usage of CPU and memory every `period` for the lifetime of the node
+    pub fn sample_usage(&self, period: Duration) {
+        let usage = Arc::clone(&self.usage);
+        tokio::spawn(async move {
+            let mut system = System::new();
+            let mut interval = tokio::time::interval(period);
+            loop {
+                interval.tick().await;
+                // CPU usage is measured between refreshes, so the first one reports none
+                system.refresh_cpu_usage();
+                system.refresh_memory();
+                *usage.lock().unwrap() = ResourceUsage {
+                    cpu: system.global_cpu_info().cpu_usage(),
+                    memory_used: system.used_memory(),
+                    memory_total: system.total_memory(),
+                };
+            }
+        });
+    }
+
+    /// Waits for a free slot. Encode counts as active until returned slot is dropped.
+    pub async fn acquire_slot(&self) -> EncodeSlot {
+        let permit = match &self.slots {
+            Some((_, semaphore)) => {
+                let _queued = Counter::increment(&self.queued);
+                let permit = Arc::clone(semaphore)
+                    .acquire_owned()
+                    .await
+                    .expect("slot semaphore is never closed");
+                Some(permit)
+            }
+            None => None,
+        };
+
+        EncodeSlot {
+            _permit: permit,
+            _active: Counter::increment(&self.active),
+        }
+    }
+
+    /// Records failure, dropping the oldest one if there are too many
+    pub fn record_failure(&self, job_id: &str, chunk_index: i32, error_message: &str) {
+        let mut failures = self.failures.lock().unwrap();
+        if failures.len() == RECENT_FAILURES {
+            failures.pop_front();
+        }
+        failures.push_back(Failure {
+            job_id: job_id.to_string(),
+            chunk_index,
+            error_message: error_message.to_string(),
+            time: SystemTime::now(),
+        });
+    }
+
+    /// Number of encodes that are running
+    pub fn active(&self) -> usize {
+        self.active.load(Ordering::Relaxed)
+    }
+
+    /// Number of encodes waiting for a free slot
+    pub fn queued(&self) -> usize {
+        self.queued.load(Ordering::Relaxed)
+    }
+
+    /// Number of slots, `None` when encodes are not limited
+    pub fn slots(&self) -> Option<usize> {
+        self.slots.as_ref().map(|(slots, _)| *slots)
+    }
+
+    /// Usage of resources at the last sample, none until the node samples it
+    pub fn usage(&self) -> ResourceUsage {
+        *self.usage.lock().unwrap()
+    }
+
+    /// Recent failures, oldest first
+    pub fn recent_failures(&self) -> Vec<Failure> {
+        self.failures.lock().unwrap().iter().cloned().collect()
+    }
+}
+
+/// Slot of a running encode, released when dropped
+#[derive(Debug)]
+pub struct EncodeSlot {
+    _permit: Option<OwnedSemaphorePermit>,
+    _active: Counter,
+}
+
+/// Keeps counter incremented while it's alive, so cancelled requests are not counted
+#[derive(Debug)]
+struct Counter(Arc<AtomicUsize>);
+
+impl Counter {
+    fn increment(counter: &Arc<AtomicUsize>) -> Self {
+        counter.fetch_add(1, Ordering::Relaxed);
+        Counter(Arc::clone(counter))
+    }
+}
+
+impl Drop for Counter {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::Relaxed);
+    }
+}
@@ -0,0 +1,122 @@
+/// This module exports spans of the application to an OpenTelemetry collector over
+/// OTLP, like Jaeger or Tempo, when `OTEL_EXPORTER_OTLP_ENDPOINT` is set. Context of
+/// the trace is sent with requests to nodes in gRPC metadata, so sending a chunk,
+/// encoding it on the node and receiving it back are one trace.
+use std::env;
+use std::path::Path;
+
+use opentelemetry::global;
+use opentelemetry::propagation::{Extractor, Injector};
+use opentelemetry::sdk::propagation::TraceContextPropagator;
+use opentelemetry::sdk::trace::{self, Tracer};
+use opentelemetry::sdk::Resource;
+use opentelemetry::KeyValue;
+use opentelemetry_otlp::WithExportConfig;
+use tonic::metadata::{KeyAndValueRef, MetadataKey, MetadataMap, MetadataValue};
+use tracing::Subscriber;
+use tracing_opentelemetry::{OpenTelemetryLayer, OpenTelemetrySpanExt};
+use tracing_subscriber::registry::LookupSpan;
+
+/// Address of the collector, spans are only exported when it's set
+const OTLP_ENDPOINT: &str = "OTEL_EXPORTER_OTLP_ENDPOINT";
+/// Name the application reports to the collector, `rav1an-client` or `rav1an-node`
+/// when not set
+const SERVICE_NAME: &str = "OTEL_SERVICE_NAME";
+
+/// Layer that exports spans to the collector of `OTEL_EXPORTER_OTLP_ENDPOINT`, if it's
+/// set. Has to be called in the Tokio runtime, which exports spans in batches.
+pub fn otlp_layer<S>() -> Option<OpenTelemetryLayer<S, Tracer>>
+where
+    S: Subscriber + for<'span> LookupSpan<'span>,
+{
+    let endpoint = env::var(OTLP_ENDPOINT).ok()?;
+    let service = env::var(SERVICE_NAME).unwrap_or_else(|_| {
+        let binary = env::args()
+            .next()
+            .and_then(|path| {
+                Path::new(&path)
+                    .file_stem()
+                    .map(|stem| stem.to_string_lossy().into_owned())
+            })
+            .unwrap_or_default();
+        format!("rav1an-{}", binary)
+    });
+
+    let tracer = opentelemetry_otlp::new_pipeline()
+        .tracing()
+        .with_exporter(
+            opentelemetry_otlp::new_exporter()
+                .tonic()
+                .with_endpoint(endpoint),
+        )
+        .with_trace_config(
+            trace::config().with_resource(Resource::new([KeyValue::new("service.name", service)])),
+        )
+        .install_batch(opentelemetry::runtime::Tokio);
+    match tracer {
+        Ok(tracer) => {
+            global::set_text_map_propagator(TraceContextPropagator::new());
+            Some(tracing_opentelemetry::layer().with_tracer(tracer))
+        }
+        Err(e) => {
+            // Logging isn't initialized yet
+            eprintln!("Failed to export traces: {}", e);
+            None
+        }
+    }
+}
+
+/// Exports spans that are left, before the application exits
+pub async fn shutdown_tracing() {
+    // Shutdown blocks until batches are exported by the runtime
+    let _ = tokio::task::spawn_blocking(global::shutdown_tracer_provider).await;
+}
+
+/// Adds context of the current span to `metadata` of a request, so spans of the node
+/// that handles it belong to the same trace
+pub fn inject_context(metadata: &mut MetadataMap) {
+    let context = tracing::Span::current().context();
+    global::get_text_map_propagator(|propagator| {
+        propagator.inject_context(&context, &mut MetadataInjector(metadata))
+    });
+}
+
+/// Makes the current span a child of the span of the client that sent a request with
+/// `metadata`
+pub fn accept_context(metadata: &MetadataMap) {
+    let context = global::get_text_map_propagator(|propagator| {
+        propagator.extract(&MetadataExtractor(metadata))
+    });
+    tracing::Span::current().set_parent(context);
+}
+
+struct MetadataInjector<'a>(&'a mut MetadataMap);
+
+impl Injector for MetadataInjector<'_> {
+    fn set(&mut self, key: &str, value: String) {
+        if let (Ok(key), Ok(value)) = (
+            MetadataKey::from_bytes(key.as_bytes()),
+            MetadataValue::try_from(value),
+        ) {
+            self.0.insert(key, value);
+        }
+    }
+}
+
+struct MetadataExtractor<'a>(&'a MetadataMap);
+
+impl Extractor for MetadataExtractor<'_> {
+    fn get(&self, key: &str) -> Option<&str> {
+        self.0.get(key).and_then(|value| value.to_str().ok())
+    }
+
+    fn keys(&self) -> Vec<&str> {
+        self.0
+            .iter()
+            .filter_map(|entry| match entry {
+                KeyAndValueRef::Ascii(key, _) => Some(key.as_str()),
+                KeyAndValueRef::Binary(..) => None,
+            })
+            .collect()
+    }
+}
@@ -0,0 +1,254 @@
+/// This module provides bandwidth throttling for chunk transfers.
+/// Limits are enforced on the raw connection, so they apply to everything
+/// that goes over the wire, not only to chunk payloads.
+use std::{
+    future::Future,
+    io,
+    pin::Pin,
+    sync::{Arc, Mutex},
+    task::{Context, Poll},
+    time::{Duration, Instant},
+};
+
+use tokio::{
+    io::{AsyncRead, AsyncWrite, ReadBuf},
+    time::{sleep, Sleep},
+};
+
+/// Smallest amount of bytes we wait for before letting IO through,
+/// so slow limits don't degrade into byte-by-byte writes
+const MIN_GRANT: f64 = 16.0 * 1024.0;
+
+/// Token bucket that can be shared between multiple connections
+#[derive(Debug)]
+pub struct RateLimiter {
+    /// Allowed bytes per second
+    rate: f64,
+    /// Maximum amount of bytes that can be sent in a single burst
+    capacity: f64,
+    bucket: Mutex<Bucket>,
+}
+
+#[derive(Debug)]
+struct Bucket {
+    tokens: f64,
+    updated: Instant,
+}
+
+impl RateLimiter {
+    /// Creates limiter that allows `bytes_per_second` on average,
+    /// with bursts of up to one second worth of traffic
+    pub fn new(bytes_per_second: u64) -> Self {
+        let rate = bytes_per_second.max(1) as f64;
+
+        RateLimiter {
+            rate,
+            capacity: rate,
+            bucket: Mutex::new(Bucket {
+                tokens: rate,
+                updated: Instant::now(),
+            }),
+        }
+    }
+
+    /// Returns how many of `want` bytes can go through right now,
+    /// or how long to wait before asking again
+    fn grant(&self, want: usize) -> Result<usize, Duration> {
+        let mut bucket = self.bucket.lock().unwrap();
+
+        let now = Instant::now();
+        let elapsed = now.duration_since(bucket.updated).as_secs_f64();
+        bucket.tokens = (bucket.tokens + elapsed * self.rate).min(self.capacity);
+        bucket.updated = now;
+
+        let threshold = (want as f64).min(MIN_GRANT).min(self.capacity);
+        if bucket.tokens >= threshold {
+            Ok((bucket.tokens as usize).clamp(1, want))
+        } else {
+            Err(Duration::from_secs_f64(
+                (threshold - bucket.tokens) / self.rate,
+            ))
+        }
+    }
+
+    /// Takes `amount` bytes out of the bucket
+    fn consume(&self, amount: usize) {
+        let mut bucket = self.bucket.lock().unwrap();
+        bucket.tokens -= amount as f64;
+    }
+}
+
+/// Asks every limiter for `want` bytes, and returns the smallest grant,
+/// or the longest wait if any of the limiters is exhausted
+fn grant_all(limiters: &[Arc<RateLimiter>], want: usize) -> Result<usize, Duration> {
+    let mut allowed = want;
+    let mut wait = None;
+
+    for limiter in limiters {
+        match limiter.grant(want) {
+            Ok(granted) => allowed = allowed.min(granted),
+            Err(delay) => wait = Some(wait.map_or(delay, |w: Duration| w.max(delay))),
+        }
+    }
+
+    match wait {
+        Some(delay) => Err(delay),
+        None => Ok(allowed),
+    }
+}
+
+/// Stream wrapper that limits read and write rate of the inner stream
+pub struct ThrottledStream<S> {
+    inner: S,
+    read_limiters: Vec<Arc<RateLimiter>>,
+    write_limiters: Vec<Arc<RateLimiter>>,
+    read_delay: Option<Pin<Box<Sleep>>>,
+    write_delay: Option<Pin<Box<Sleep>>>,
+}
+
+impl<S> ThrottledStream<S> {
+    pub fn new(
+        inner: S,
+        read_limiters: Vec<Arc<RateLimiter>>,
+        write_limiters: Vec<Arc<RateLimiter>>,
+    ) -> Self {
+        ThrottledStream {
+            inner,
+            read_limiters,
+            write_limiters,
+            read_delay: None,
+            write_delay: None,
+        }
+    }
+}
+
+/// Polls pending delay, and if there is none, asks limiters for permission.
+/// Returns amount of bytes that can be transferred now.
+fn poll_grant(
+    delay: &mut Option<Pin<Box<Sleep>>>,
+    limiters: &[Arc<RateLimiter>],
+    want: usize,
+    cx: &mut Context<'_>,
+) -> Poll<usize> {
+    loop {
+        if let Some(sleep) = delay.as_mut() {
+            if sleep.as_mut().poll(cx).is_pending() {
+                return Poll::Pending;
+            }
+            *delay = None;
+        }
+
+        match grant_all(limiters, want) {
+            Ok(allowed) => return Poll::Ready(allowed),
+            Err(wait) => *delay = Some(Box::pin(sleep(wait))),
+        }
+    }
+}
+
+impl<S: AsyncRead + Unpin> AsyncRead for ThrottledStream<S> {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+
+        if this.read_limiters.is_empty() || buf.remaining() == 0 {
+            return Pin::new(&mut this.inner).poll_read(cx, buf);
+        }
+
+        let allowed = match poll_grant(
+            &mut this.read_delay,
+            &this.read_limiters,
+            buf.remaining(),
+            cx,
+        ) {
+            Poll::Ready(allowed) => allowed,
+            Poll::Pending => return Poll::Pending,
+        };
+
+        let mut limited = buf.take(allowed);
+        let result = Pin::new(&mut this.inner).poll_read(cx, &mut limited);
+        let read = limited.filled().len();
+
+        // SAFETY: `limited` was filled by the inner reader,
+        // so these bytes of `buf` are initialized
+        unsafe { buf.assume_init(read) };
+        buf.advance(read);
+
+        if let Poll::Ready(Ok(())) = result {
+            this.read_limiters.iter().for_each(|l| l.consume(read));
+        }
+
+        result
+    }
+}
+
+impl<S: AsyncWrite + Unpin> AsyncWrite for ThrottledStream<S> {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        let this = self.get_mut();
+
+        if this.write_limiters.is_empty() || buf.is_empty() {
+            return Pin::new(&mut this.inner).poll_write(cx, buf);
+        }
+
+        let allowed = match poll_grant(&mut this.write_delay, &this.write_limiters, buf.len(), cx) {
+            Poll::Ready(allowed) => allowed,
+            Poll::Pending => return Poll::Pending,
+        };
+
+        let result = Pin::new(&mut this.inner).poll_write(cx, &buf[..allowed]);
+
+        if let Poll::Ready(Ok(written)) = result {
+            this.write_limiters.iter().for_each(|l| l.consume(written));
+        }
+
+        result
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.get_mut().inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.get_mut().inner).poll_shutdown(cx)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn grants_bursts_of_up_to_one_second() {
+        let limiter = RateLimiter::new(100_000);
+        assert_eq!(limiter.grant(1_000_000), Ok(100_000));
+        assert_eq!(limiter.grant(500), Ok(500));
+    }
+
+    #[test]
+    fn empty_bucket_waits_for_refill() {
+        let limiter = RateLimiter::new(100_000);
+        limiter.consume(100_000);
+
+        // Grant of MIN_GRANT bytes refills in about 0.16 seconds
+        let wait = limiter.grant(1_000_000).unwrap_err();
+        assert!(wait > Duration::from_millis(100));
+        assert!(wait <= Duration::from_millis(170));
+    }
+
+    #[test]
+    fn slowest_limiter_decides() {
+        let fast = Arc::new(RateLimiter::new(100_000));
+        let slow = Arc::new(RateLimiter::new(10_000));
+        let limiters = [fast, slow.clone()];
+        assert_eq!(grant_all(&limiters, 1_000_000), Ok(10_000));
+
+        slow.consume(10_000);
+        assert!(grant_all(&limiters, 1_000_000).is_err());
+    }
+}
@@ -0,0 +1,170 @@
+/// This module summarizes where time of an encode went, with a row for every chunk:
+/// how long it waited in the queue, how long it took to send and receive, how long
+/// the node encoded it and how fast, and totals of every node. Slow nodes and slow
+/// links stand out in the table printed at the end of the job, which is also written
+/// as JSON.
+use std::collections::BTreeMap;
+use std::fmt::Write;
+use std::path::{Path, PathBuf};
+
+use serde::Serialize;
+use tracing::{info, instrument};
+
+use crate::error::VideoEncodeError;
+
+/// Times of a chunk that was encoded
+#[derive(Debug, Clone, Serialize)]
+pub struct ChunkTiming {
+    pub index: usize,
+    pub node: String,
+    /// Seconds the chunk waited for a slot since it was queued, or queued again
+    pub queue_wait: f64,
+    /// Seconds of the request that the node wasn't encoding: sending the chunk,
+    /// receiving it back, and waiting on the node
+    pub transfer_time: f64,
+    /// Seconds the node spent encoding the chunk
+    pub encode_time: f64,
+    /// Size of the encoded chunk in bytes
+    pub size: u64,
+    /// Frames of the chunk, when they are known
+    pub frames: Option<u64>,
+}
+
+impl ChunkTiming {
+    /// Frames encoded per second
+    pub fn fps(&self) -> Option<f64> {
+        let frames = self.frames?;
+        (self.encode_time > 0.0).then(|| frames as f64 / self.encode_time)
+    }
+}
+
+/// Totals of chunks a node encoded
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct NodeTiming {
+    pub node: String,
+    pub chunks: usize,
+    /// Average seconds chunks of the node waited in the queue
+    pub queue_wait: f64,
+    pub transfer_time: f64,
+    pub encode_time: f64,
+    pub size: u64,
+    /// Frames per second over chunks with known frames
+    pub fps: Option<f64>,
+}
+
+/// Times of all chunks of a job, by chunk and by node
+#[derive(Debug, Clone, Serialize)]
+pub struct TimingReport {
+    /// Chunks in order
+    pub chunks: Vec<ChunkTiming>,
+    /// Nodes in order of their address
+    pub nodes: Vec<NodeTiming>,
+}
+
+impl TimingReport {
+    pub fn new(mut chunks: Vec<ChunkTiming>) -> Self {
+        chunks.sort_by_key(|chunk| chunk.index);
+
+        let mut nodes: BTreeMap<&str, (NodeTiming, u64, f64)> = BTreeMap::new();
+        for chunk in &chunks {
+            let (node, frames, frames_time) = nodes.entry(&chunk.node).or_default();
+            node.chunks += 1;
+            node.queue_wait += chunk.queue_wait;
+            node.transfer_time += chunk.transfer_time;
+            node.encode_time += chunk.encode_time;
+            node.size += chunk.size;
+            if let Some(chunk_frames) = chunk.frames {
+                *frames += chunk_frames;
+                *frames_time += chunk.encode_time;
+            }
+        }
+        let nodes = nodes
+            .into_iter()
+            .map(|(address, (node, frames, frames_time))| NodeTiming {
+                node: address.to_string(),
+                queue_wait: node.queue_wait / node.chunks as f64,
+                fps: (frames_time > 0.0).then(|| frames as f64 / frames_time),
+                ..node
+            })
+            .collect();
+
+        TimingReport { chunks, nodes }
+    }
+
+    /// Table of chunks followed by table of nodes
+    pub fn table(&self) -> String {
+        let width = self
+            .chunks
+            .iter()
+            .map(|chunk| chunk.node.len())
+            .max()
+            .unwrap_or_default()
+            .max("NODE".len());
+        let fps = |fps: Option<f64>| {
+            fps.map(|fps| format!("{:.1}", fps))
+                .unwrap_or_else(|| "-".to_string())
+        };
+
+        let mut table = String::new();
+        let _ = writeln!(
+            table,
+            "{:>6}  {:<width$}  {:>8}  {:>8}  {:>8}  {:>10}  {:>7}",
+            "CHUNK", "NODE", "QUEUED", "TRANSFER", "ENCODE", "SIZE", "FPS"
+        );
+        for chunk in &self.chunks {
+            let _ = writeln!(
+                table,
+                "{:>6}  {:<width$}  {:>7.1}s  {:>7.1}s  {:>7.1}s  {:>10}  {:>7}",
+                chunk.index,
+                chunk.node,
+                chunk.queue_wait,
+                chunk.transfer_time,
+                chunk.encode_time,
+                format_size(chunk.size),
+                fps(chunk.fps())
+            );
+        }
+
+        let _ = writeln!(
+            table,
+            "\n{:<width$}  {:>6}  {:>10}  {:>8}  {:>8}  {:>10}  {:>7}",
+            "NODE", "CHUNKS", "AVG QUEUED", "TRANSFER", "ENCODE", "SIZE", "FPS"
+        );
+        for node in &self.nodes {
+            let _ = writeln!(
+                table,
+                "{:<width$}  {:>6}  {:>9.1}s  {:>7.0}s  {:>7.0}s  {:>10}  {:>7}",
+                node.node,
+                node.chunks,
+                node.queue_wait,
+                node.transfer_time,
+                node.encode_time,
+                format_size(node.size),
+                fps(node.fps)
+            );
+        }
+        table
+    }
+
+    #[instrument(skip(self))]
+    pub fn write(&self, path: &Path) -> Result<(), VideoEncodeError> {
+        let json = serde_json::to_string_pretty(self)
+            .map_err(|e| VideoEncodeError::Encoding(e.to_string()))?;
+        std::fs::write(path, json)?;
+        info!("Timing of chunks is written to {:?}", path);
+        Ok(())
+    }
+}
+
+/// Path of the timing report of `output`, which is a directory with multiple inputs
+pub fn timing_path(output: &Path, batch: bool) -> PathBuf {
+    if batch {
+        output.join("timing.json")
+    } else {
+        output.with_extension("timing.json")
+    }
+}
+
+fn format_size(size: u64) -> String {
+    format!("{:.1} MiB", size as f64 / (1024.0 * 1024.0))
+}
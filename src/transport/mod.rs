@@ -0,0 +1,406 @@
+/// This module is responsible for establishing connections to nodes.
+/// Transport is selected by the scheme of node address:
+/// `http://host:port` for TCP, `quic://host:port` for QUIC,
+/// `unix:///path/to/socket` for Unix domain sockets.
+pub mod quic;
+
+use std::{
+    net::SocketAddr,
+    path::PathBuf,
+    str::FromStr,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    },
+    task::{Context, Poll},
+    time::Duration,
+};
+
+use socket2::{SockRef, TcpKeepalive};
+use tokio::net::TcpStream;
+use tonic::{
+    body::BoxBody,
+    codegen::http,
+    transport::{Channel, Endpoint, Server, Uri},
+};
+use tower::{service_fn, Service};
+use tracing::{debug, instrument};
+
+use crate::{
+    error::VideoEncodeError,
+    settings::{BandwidthSettings, GrpcSettings, Settings},
+    throttle::{RateLimiter, ThrottledStream},
+};
+
+const QUIC_SCHEME: &str = "quic://";
+const UNIX_SCHEME: &str = "unix://";
+
+/// Rate limiters that apply to a single node connection
+#[derive(Clone, Debug, Default)]
+pub struct Throttle {
+    /// Limiters for data sent to the node
+    pub upload: Vec<Arc<RateLimiter>>,
+    /// Limiters for data received from the node
+    pub download: Vec<Arc<RateLimiter>>,
+}
+
+impl Throttle {
+    pub fn is_empty(&self) -> bool {
+        self.upload.is_empty() && self.download.is_empty()
+    }
+}
+
+/// Creates rate limiters shared by all node connections
+#[derive(Debug)]
+pub struct ThrottleFactory {
+    global_upload: Option<Arc<RateLimiter>>,
+    global_download: Option<Arc<RateLimiter>>,
+    node_upload: Option<u64>,
+    node_download: Option<u64>,
+}
+
+impl ThrottleFactory {
+    pub fn new(settings: &BandwidthSettings) -> Self {
+        ThrottleFactory {
+            global_upload: settings.upload_limit.map(|r| Arc::new(RateLimiter::new(r))),
+            global_download: settings
+                .download_limit
+                .map(|r| Arc::new(RateLimiter::new(r))),
+            node_upload: settings.node_upload_limit,
+            node_download: settings.node_download_limit,
+        }
+    }
+
+    /// Builds throttle for a new node, which shares global limiters
+    /// with every other node and has its own per-node limiters
+    pub fn for_node(&self) -> Throttle {
+        let mut throttle = Throttle::default();
+
+        throttle.upload.extend(self.global_upload.clone());
+        throttle
+            .upload
+            .extend(self.node_upload.map(|r| Arc::new(RateLimiter::new(r))));
+
+        throttle.download.extend(self.global_download.clone());
+        throttle
+            .download
+            .extend(self.node_download.map(|r| Arc::new(RateLimiter::new(r))));
+
+        throttle
+    }
+}
+
+/// Creates endpoint for node at `address` with tuning from settings applied
+pub fn endpoint(address: &str, grpc: &GrpcSettings) -> Result<Endpoint, VideoEncodeError> {
+    let mut endpoint = Endpoint::from_shared(address.to_string())?
+        .tcp_keepalive(grpc.tcp_keepalive.map(Duration::from_secs))
+        .initial_stream_window_size(grpc.initial_stream_window_size)
+        .initial_connection_window_size(grpc.initial_connection_window_size)
+        .keep_alive_while_idle(grpc.keepalive_while_idle);
+
+    if let Some(timeout) = grpc.connect_timeout {
+        endpoint = endpoint.connect_timeout(Duration::from_secs(timeout));
+    }
+    if let Some(timeout) = grpc.request_timeout {
+        endpoint = endpoint.timeout(Duration::from_secs(timeout));
+    }
+    if let Some(interval) = grpc.keepalive_interval {
+        endpoint = endpoint.http2_keep_alive_interval(Duration::from_secs(interval));
+    }
+    if let Some(timeout) = grpc.keepalive_timeout {
+        endpoint = endpoint.keep_alive_timeout(Duration::from_secs(timeout));
+    }
+    if let Some(limit) = grpc.concurrency_limit {
+        endpoint = endpoint.concurrency_limit(limit);
+    }
+
+    Ok(endpoint)
+}
+
+/// Creates server builder with tuning from settings applied
+pub fn server(grpc: &GrpcSettings) -> Server {
+    let mut server = Server::builder()
+        .tcp_keepalive(grpc.tcp_keepalive.map(Duration::from_secs))
+        .initial_stream_window_size(grpc.initial_stream_window_size)
+        .initial_connection_window_size(grpc.initial_connection_window_size)
+        .http2_keepalive_interval(grpc.keepalive_interval.map(Duration::from_secs))
+        .http2_keepalive_timeout(grpc.keepalive_timeout.map(Duration::from_secs));
+
+    if let Some(limit) = grpc.concurrency_limit {
+        server = server.concurrency_limit_per_connection(limit);
+    }
+
+    server
+}
+
+/// Channel to a node, that spreads requests over one or more connections.
+/// Every clone uses the next connection, so requests made through
+/// different clones don't share a connection when there is more than one.
+#[derive(Debug)]
+pub struct NodeChannel {
+    channels: Arc<[Channel]>,
+    next: Arc<AtomicUsize>,
+    current: Channel,
+}
+
+impl NodeChannel {
+    fn new(channels: Vec<Channel>) -> Self {
+        let current = channels[0].clone();
+
+        NodeChannel {
+            channels: channels.into(),
+            next: Arc::new(AtomicUsize::new(1)),
+            current,
+        }
+    }
+}
+
+impl Clone for NodeChannel {
+    fn clone(&self) -> Self {
+        let index = self.next.fetch_add(1, Ordering::Relaxed) % self.channels.len();
+
+        NodeChannel {
+            channels: Arc::clone(&self.channels),
+            next: Arc::clone(&self.next),
+            current: self.channels[index].clone(),
+        }
+    }
+}
+
+impl Service<http::Request<BoxBody>> for NodeChannel {
+    type Response = <Channel as Service<http::Request<BoxBody>>>::Response;
+    type Error = <Channel as Service<http::Request<BoxBody>>>::Error;
+    type Future = <Channel as Service<http::Request<BoxBody>>>::Future;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        Service::poll_ready(&mut self.current, cx)
+    }
+
+    fn call(&mut self, request: http::Request<BoxBody>) -> Self::Future {
+        Service::call(&mut self.current, request)
+    }
+}
+
+/// Address node server listens on
+#[derive(Debug, Clone)]
+pub enum ListenAddress {
+    Tcp(SocketAddr),
+    Quic(SocketAddr),
+    Unix(PathBuf),
+}
+
+impl FromStr for ListenAddress {
+    type Err = VideoEncodeError;
+
+    fn from_str(address: &str) -> Result<Self, Self::Err> {
+        let invalid =
+            |e| VideoEncodeError::NodeConnection(format!("Invalid address {}: {}", address, e));
+
+        if let Some(path) = address.strip_prefix(UNIX_SCHEME) {
+            return Ok(ListenAddress::Unix(PathBuf::from(path)));
+        }
+
+        match address.strip_prefix(QUIC_SCHEME) {
+            Some(addr) => Ok(ListenAddress::Quic(addr.parse().map_err(invalid)?)),
+            None => Ok(ListenAddress::Tcp(address.parse().map_err(invalid)?)),
+        }
+    }
+}
+
+/// Connects to node at `address`, applying bandwidth limits if there are any.
+/// QUIC nodes get `streams` connections, each over its own QUIC stream.
+#[instrument(skip(settings, throttle))]
+pub async fn connect(
+    address: &str,
+    settings: &Settings,
+    throttle: Throttle,
+    streams: usize,
+) -> Result<NodeChannel, VideoEncodeError> {
+    if let Some(path) = address.strip_prefix(UNIX_SCHEME) {
+        return Ok(NodeChannel::new(vec![
+            connect_unix(PathBuf::from(path), &settings.grpc).await?,
+        ]));
+    }
+
+    match address.strip_prefix(QUIC_SCHEME) {
+        Some(authority) => connect_quic(authority, settings, throttle, streams).await,
+        None => Ok(NodeChannel::new(vec![
+            connect_tcp(address, &settings.grpc, throttle).await?,
+        ])),
+    }
+}
+
+/// Bandwidth limits are not applied to Unix sockets,
+/// as they only connect to nodes on the same machine
+#[cfg(unix)]
+#[instrument(skip(grpc))]
+async fn connect_unix(path: PathBuf, grpc: &GrpcSettings) -> Result<Channel, VideoEncodeError> {
+    // URI is required by the endpoint, but it's not used for connecting
+    let endpoint = endpoint("http://localhost", grpc)?;
+
+    let connector = service_fn(move |_: Uri| tokio::net::UnixStream::connect(path.clone()));
+
+    Ok(endpoint.connect_with_connector(connector).await?)
+}
+
+#[cfg(not(unix))]
+async fn connect_unix(path: PathBuf, _grpc: &GrpcSettings) -> Result<Channel, VideoEncodeError> {
+    Err(VideoEncodeError::NodeConnection(format!(
+        "Unix sockets are not supported on this platform: {:?}",
+        path
+    )))
+}
+
+/// Binds Unix socket at `path`, replacing stale socket left by previous run.
+/// Socket gets `permissions` mode if set, to control who can connect to the node.
+#[cfg(unix)]
+#[instrument]
+pub fn bind_unix(
+    path: &std::path::Path,
+    permissions: Option<u32>,
+) -> Result<tokio_stream::wrappers::UnixListenerStream, VideoEncodeError> {
+    use std::os::unix::fs::{FileTypeExt, PermissionsExt};
+
+    if let Ok(metadata) = std::fs::metadata(path) {
+        if metadata.file_type().is_socket() {
+            debug!("Removing stale socket {:?}", path);
+            std::fs::remove_file(path)?;
+        }
+    }
+
+    let listener = tokio::net::UnixListener::bind(path)?;
+
+    if let Some(mode) = permissions {
+        std::fs::set_permissions(path, std::fs::Permissions::from_mode(mode))?;
+    }
+
+    Ok(tokio_stream::wrappers::UnixListenerStream::new(listener))
+}
+
+#[instrument(skip(settings, throttle))]
+async fn connect_quic(
+    authority: &str,
+    settings: &Settings,
+    throttle: Throttle,
+    streams: usize,
+) -> Result<NodeChannel, VideoEncodeError> {
+    // gRPC still needs http URI for request authority
+    let endpoint = endpoint(&format!("http://{}", authority), &settings.grpc)?;
+
+    let host = endpoint.uri().host().unwrap_or_default().to_string();
+    let port = endpoint.uri().port_u16().unwrap_or(80);
+
+    let connection = Arc::new(tokio::sync::Mutex::new(
+        quic::connect(&host, port, &settings.quic).await?,
+    ));
+
+    let mut channels = Vec::new();
+    for _ in 0..streams.max(1) {
+        let connection = Arc::clone(&connection);
+        let quic_settings = settings.quic.clone();
+        let host = host.clone();
+        let throttle = throttle.clone();
+
+        let connector = service_fn(move |_: Uri| {
+            let connection = Arc::clone(&connection);
+            let quic_settings = quic_settings.clone();
+            let host = host.clone();
+            let throttle = throttle.clone();
+            async move {
+                let mut connection = connection.lock().await;
+                if connection.close_reason().is_some() {
+                    debug!("QUIC connection to {} is closed, reconnecting", host);
+                    *connection = quic::connect(&host, port, &quic_settings)
+                        .await
+                        .map_err(std::io::Error::other)?;
+                }
+
+                let stream = quic::open_stream(&connection).await?;
+                Ok::<_, std::io::Error>(ThrottledStream::new(
+                    stream,
+                    throttle.download,
+                    throttle.upload,
+                ))
+            }
+        });
+
+        channels.push(endpoint.connect_with_connector(connector).await?);
+    }
+
+    Ok(NodeChannel::new(channels))
+}
+
+#[instrument(skip(grpc, throttle))]
+async fn connect_tcp(
+    address: &str,
+    grpc: &GrpcSettings,
+    throttle: Throttle,
+) -> Result<Channel, VideoEncodeError> {
+    let endpoint = endpoint(address, grpc)?;
+    let tcp_keepalive = grpc.tcp_keepalive.map(Duration::from_secs);
+
+    if throttle.is_empty() {
+        return Ok(endpoint.connect().await?);
+    }
+    debug!(
+        "Connecting with {} upload and {} download limiters",
+        throttle.upload.len(),
+        throttle.download.len()
+    );
+
+    let connector = service_fn(move |uri: Uri| {
+        let throttle = throttle.clone();
+        async move {
+            let host = uri.host().unwrap_or("127.0.0.1").to_string();
+            let port = uri.port_u16().unwrap_or(80);
+
+            let stream = TcpStream::connect((host, port)).await?;
+            stream.set_nodelay(true)?;
+            if let Some(interval) = tcp_keepalive {
+                SockRef::from(&stream)
+                    .set_tcp_keepalive(&TcpKeepalive::new().with_time(interval))?;
+            }
+
+            Ok::<_, std::io::Error>(ThrottledStream::new(
+                stream,
+                throttle.download,
+                throttle.upload,
+            ))
+        }
+    });
+
+    Ok(endpoint.connect_with_connector(connector).await?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::path::Path;
+
+    #[test]
+    fn listen_address_parses_tcp_and_quic() {
+        assert!(matches!(
+            "0.0.0.0:50051".parse(),
+            Ok(ListenAddress::Tcp(address)) if address.port() == 50051
+        ));
+        assert!(matches!(
+            "quic://[::1]:4433".parse(),
+            Ok(ListenAddress::Quic(address)) if address.is_ipv6() && address.port() == 4433
+        ));
+    }
+
+    #[test]
+    fn listen_address_parses_unix_socket_paths() {
+        assert!(matches!(
+            "unix:///run/rav1an/node.sock".parse(),
+            Ok(ListenAddress::Unix(path)) if path == Path::new("/run/rav1an/node.sock")
+        ));
+    }
+
+    #[test]
+    fn listen_address_rejects_invalid_addresses() {
+        assert!("localhost".parse::<ListenAddress>().is_err());
+        assert!("quic://".parse::<ListenAddress>().is_err());
+        assert!("http://0.0.0.0:50051".parse::<ListenAddress>().is_err());
+    }
+}
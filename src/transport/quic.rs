@@ -0,0 +1,275 @@
+/// This module tunnels gRPC connections over QUIC streams.
+/// Every gRPC connection gets its own QUIC stream, so transfers that go over
+/// different connections to the same node don't block each other on packet loss.
+use std::{
+    fs,
+    io::{self, BufReader, Write},
+    net::SocketAddr,
+    path::Path,
+    pin::Pin,
+    sync::Arc,
+    task::{Context, Poll},
+    time::Duration,
+};
+
+use quinn::{
+    ClientConfig, Connection, Endpoint, RecvStream, SendStream, ServerConfig, TransportConfig,
+};
+use rustls::{Certificate, PrivateKey, RootCertStore};
+use tokio::{
+    io::{AsyncRead, AsyncWrite, ReadBuf},
+    sync::mpsc,
+};
+use tokio_stream::wrappers::ReceiverStream;
+use tonic::transport::server::Connected;
+use tracing::{debug, error, info, instrument, warn};
+
+use crate::{error::VideoEncodeError, settings::QuicSettings};
+
+/// Interval of QUIC keepalive packets. Connections are otherwise idle
+/// while node is encoding, and would be closed by idle timeout.
+const KEEPALIVE_INTERVAL: Duration = Duration::from_secs(10);
+
+const DEFAULT_SERVER_NAME: &str = "localhost";
+
+/// Bidirectional QUIC stream, that carries a single gRPC connection
+#[derive(Debug)]
+pub struct QuicStream {
+    send: SendStream,
+    recv: RecvStream,
+}
+
+impl AsyncRead for QuicStream {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.get_mut().recv).poll_read(cx, buf)
+    }
+}
+
+impl AsyncWrite for QuicStream {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        Pin::new(&mut self.get_mut().send)
+            .poll_write(cx, buf)
+            .map_err(io::Error::from)
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.get_mut().send).poll_flush(cx)
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.get_mut().send).poll_shutdown(cx)
+    }
+}
+
+impl Connected for QuicStream {
+    type ConnectInfo = ();
+
+    fn connect_info(&self) -> Self::ConnectInfo {}
+}
+
+fn transport_config() -> Arc<TransportConfig> {
+    let mut transport = TransportConfig::default();
+    transport.keep_alive_interval(Some(KEEPALIVE_INTERVAL));
+    Arc::new(transport)
+}
+
+/// Establishes QUIC connection to node at `host:port`,
+/// trusting only the certificate from settings
+#[instrument(skip(settings))]
+pub async fn connect(
+    host: &str,
+    port: u16,
+    settings: &QuicSettings,
+) -> Result<Connection, VideoEncodeError> {
+    let cert_path = settings.cert.as_ref().ok_or_else(|| {
+        VideoEncodeError::Quic("quic.cert has to be set to connect to QUIC nodes".to_string())
+    })?;
+
+    let mut roots = RootCertStore::empty();
+    for cert in load_certs(cert_path)? {
+        roots
+            .add(&cert)
+            .map_err(|e| VideoEncodeError::Quic(format!("Invalid certificate: {}", e)))?;
+    }
+
+    let mut client_config = ClientConfig::with_root_certificates(roots);
+    client_config.transport_config(transport_config());
+
+    let addr = tokio::net::lookup_host((host, port))
+        .await?
+        .next()
+        .ok_or_else(|| VideoEncodeError::Quic(format!("Failed to resolve {}", host)))?;
+
+    let bind: SocketAddr = if addr.is_ipv6() {
+        "[::]:0".parse().unwrap()
+    } else {
+        "0.0.0.0:0".parse().unwrap()
+    };
+
+    let mut endpoint = Endpoint::client(bind)?;
+    endpoint.set_default_client_config(client_config);
+
+    let server_name = settings
+        .server_name
+        .as_deref()
+        .unwrap_or(DEFAULT_SERVER_NAME);
+
+    let connection = endpoint
+        .connect(addr, server_name)
+        .map_err(|e| VideoEncodeError::Quic(e.to_string()))?
+        .await
+        .map_err(|e| VideoEncodeError::Quic(e.to_string()))?;
+
+    debug!("Established QUIC connection to {}", addr);
+    Ok(connection)
+}
+
+/// Opens new stream for a gRPC connection
+pub async fn open_stream(connection: &Connection) -> io::Result<QuicStream> {
+    let (send, recv) = connection.open_bi().await?;
+    Ok(QuicStream { send, recv })
+}
+
+/// Listens for QUIC connections on `addr`, and yields every stream
+/// opened by clients, so it can be served as separate gRPC connection
+#[instrument(skip(settings, cert_dir))]
+pub fn incoming(
+    addr: SocketAddr,
+    settings: &QuicSettings,
+    cert_dir: &Path,
+) -> Result<ReceiverStream<io::Result<QuicStream>>, VideoEncodeError> {
+    let (certs, key) = match (&settings.cert, &settings.key) {
+        (Some(cert), Some(key)) => (load_certs(cert)?, load_key(key)?),
+        _ => self_signed_cert(settings, cert_dir)?,
+    };
+
+    let mut server_config = ServerConfig::with_single_cert(certs, key)
+        .map_err(|e| VideoEncodeError::Quic(format!("Invalid certificate: {}", e)))?;
+    server_config.transport_config(transport_config());
+
+    let endpoint = Endpoint::server(server_config, addr)?;
+    info!("Listening for QUIC connections on {}", addr);
+
+    let (tx, rx) = mpsc::channel(16);
+
+    tokio::spawn(async move {
+        while let Some(connecting) = endpoint.accept().await {
+            let tx = tx.clone();
+            tokio::spawn(async move {
+                let connection = match connecting.await {
+                    Ok(connection) => connection,
+                    Err(e) => {
+                        warn!("Failed to accept QUIC connection: {}", e);
+                        return;
+                    }
+                };
+                debug!(
+                    "Accepted QUIC connection from {}",
+                    connection.remote_address()
+                );
+
+                while let Ok((send, recv)) = connection.accept_bi().await {
+                    if tx.send(Ok(QuicStream { send, recv })).await.is_err() {
+                        return;
+                    }
+                }
+                debug!(
+                    "QUIC connection from {} closed",
+                    connection.remote_address()
+                );
+            });
+        }
+        error!("QUIC endpoint stopped accepting connections");
+    });
+
+    Ok(ReceiverStream::new(rx))
+}
+
+/// Loads certificate from `cert_dir`, or generates one if there is none yet.
+/// Certificate has to be copied to clients, as they only trust configured certificate.
+fn self_signed_cert(
+    settings: &QuicSettings,
+    cert_dir: &Path,
+) -> Result<(Vec<Certificate>, PrivateKey), VideoEncodeError> {
+    let cert_path = cert_dir.join("cert.pem");
+    let key_path = cert_dir.join("key.pem");
+
+    if !cert_path.exists() || !key_path.exists() {
+        let server_name = settings
+            .server_name
+            .clone()
+            .unwrap_or_else(|| DEFAULT_SERVER_NAME.to_string());
+
+        let cert = rcgen::generate_simple_self_signed(vec![server_name])
+            .map_err(|e| VideoEncodeError::Quic(e.to_string()))?;
+
+        fs::create_dir_all(cert_dir)?;
+        fs::write(
+            &cert_path,
+            cert.serialize_pem()
+                .map_err(|e| VideoEncodeError::Quic(e.to_string()))?,
+        )?;
+        write_private_key(&key_path, &cert.serialize_private_key_pem())?;
+    }
+
+    info!(
+        "Using self-signed QUIC certificate {:?}, set it as quic.cert on clients",
+        cert_path
+    );
+
+    Ok((load_certs(&cert_path)?, load_key(&key_path)?))
+}
+
+/// Writes private key into a file only its owner can read
+fn write_private_key(path: &Path, key: &str) -> Result<(), VideoEncodeError> {
+    let mut options = fs::OpenOptions::new();
+    options.write(true).create(true).truncate(true);
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::OpenOptionsExt;
+        options.mode(0o600);
+    }
+
+    options.open(path)?.write_all(key.as_bytes())?;
+    Ok(())
+}
+
+fn load_certs(path: &Path) -> Result<Vec<Certificate>, VideoEncodeError> {
+    let mut reader = BufReader::new(fs::File::open(path)?);
+    let certs = rustls_pemfile::certs(&mut reader)?;
+
+    if certs.is_empty() {
+        return Err(VideoEncodeError::Quic(format!(
+            "No certificates found in {:?}",
+            path
+        )));
+    }
+
+    Ok(certs.into_iter().map(Certificate).collect())
+}
+
+fn load_key(path: &Path) -> Result<PrivateKey, VideoEncodeError> {
+    let mut reader = BufReader::new(fs::File::open(path)?);
+
+    while let Some(item) = rustls_pemfile::read_one(&mut reader)? {
+        match item {
+            rustls_pemfile::Item::PKCS8Key(key)
+            | rustls_pemfile::Item::RSAKey(key)
+            | rustls_pemfile::Item::ECKey(key) => return Ok(PrivateKey(key)),
+            _ => continue,
+        }
+    }
+
+    Err(VideoEncodeError::Quic(format!(
+        "No private key found in {:?}",
+        path
+    )))
+}
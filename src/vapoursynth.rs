@@ -0,0 +1,139 @@
+/// This module reads VapourSynth scripts with `vspipe`, so filtered video
+/// can be split into chunks by frame ranges, like any other input
+use std::{
+    path::Path,
+    process::{Command, Stdio},
+};
+
+use tracing::{debug, error, instrument};
+
+use crate::{error::VideoEncodeError, ffmpeg::segment::LOSSLESS_INTERMEDIATE};
+
+/// Properties of the clip produced by a script
+#[derive(Debug, Clone, Copy)]
+pub struct ScriptInfo {
+    pub frames: usize,
+    pub width: u32,
+    pub height: u32,
+    pub fps_num: u64,
+    pub fps_den: u64,
+}
+
+impl ScriptInfo {
+    pub fn fps(&self) -> f64 {
+        self.fps_num as f64 / self.fps_den as f64
+    }
+}
+
+/// Whether input is a VapourSynth script
+pub fn is_script(path: &Path) -> bool {
+    path.extension().and_then(|ext| ext.to_str()) == Some("vpy")
+}
+
+/// Evaluates script and returns properties of its output clip
+#[instrument]
+pub fn probe_script(script_path: &Path) -> Result<ScriptInfo, VideoEncodeError> {
+    let output = Command::new("vspipe")
+        .arg("--info")
+        .arg(script_path)
+        .arg("-")
+        .output()?;
+
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    if !output.status.success() {
+        error!(
+            "Failed to evaluate script {:?}: {}",
+            script_path,
+            String::from_utf8_lossy(&output.stderr)
+        );
+        return Err(VideoEncodeError::Encoding(format!(
+            "Failed to evaluate script {:?}",
+            script_path
+        )));
+    }
+
+    // Output has lines like `Frames: 1000` and `FPS: 24000/1001 (23.976 fps)`
+    let value = |key: &str| {
+        stdout.lines().find_map(|line| {
+            let (name, value) = line.split_once(':')?;
+            (name.trim() == key).then(|| value.trim().to_string())
+        })
+    };
+
+    let frames = value("Frames").and_then(|frames| frames.parse().ok());
+    // Clips with variable resolution report 0
+    let width = value("Width").and_then(|width| width.parse().ok());
+    let height = value("Height").and_then(|height| height.parse().ok());
+    let fps = value("FPS").and_then(|fps| {
+        let (num, den) = fps.split_whitespace().next()?.split_once('/')?;
+        Some((num.parse().ok()?, den.parse().ok()?))
+    });
+
+    match (frames, fps) {
+        (Some(frames), Some((fps_num, fps_den))) if fps_den > 0 => {
+            let info = ScriptInfo {
+                frames,
+                width: width.unwrap_or_default(),
+                height: height.unwrap_or_default(),
+                fps_num,
+                fps_den,
+            };
+            debug!("Script {:?} produces {:?}", script_path, info);
+            Ok(info)
+        }
+        _ => Err(VideoEncodeError::Encoding(format!(
+            "Script {:?} has to produce clip with constant frame rate",
+            script_path
+        ))),
+    }
+}
+
+/// Renders frames from `start` to `end` (exclusive) of the script and pipes them
+/// as y4m into ffmpeg, which stores them as lossless intermediate in `output_path`.
+/// Both processes are killed if returned future is dropped before it completes.
+#[instrument]
+pub async fn extract_frames(
+    script_path: &Path,
+    start: usize,
+    end: usize,
+    output_path: &Path,
+) -> Result<(), VideoEncodeError> {
+    let mut vspipe = tokio::process::Command::new("vspipe")
+        .args(["-c", "y4m"])
+        .args(["-s", &start.to_string(), "-e", &(end - 1).to_string()])
+        .arg(script_path)
+        .arg("-")
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .kill_on_drop(true)
+        .spawn()?;
+
+    let frames: Stdio = vspipe.stdout.take().expect("stdout is piped").try_into()?;
+
+    let ffmpeg = tokio::process::Command::new("ffmpeg")
+        .args(["-hide_banner", "-y", "-f", "yuv4mpegpipe", "-i", "-"])
+        .args(LOSSLESS_INTERMEDIATE)
+        .arg(output_path)
+        .stdin(frames)
+        .kill_on_drop(true)
+        .output();
+
+    let (vspipe, ffmpeg) = tokio::join!(vspipe.wait_with_output(), ffmpeg);
+    let (vspipe, ffmpeg) = (vspipe?, ffmpeg?);
+
+    if !vspipe.status.success() || !ffmpeg.status.success() {
+        error!(
+            "Failed to extract frames {}-{}: vspipe: {}, ffmpeg: {}",
+            start,
+            end,
+            String::from_utf8_lossy(&vspipe.stderr),
+            String::from_utf8_lossy(&ffmpeg.stderr)
+        );
+        return Err(VideoEncodeError::Encoding(format!(
+            "Failed to extract frames {}-{} of {:?}",
+            start, end, script_path
+        )));
+    }
+
+    Ok(())
+}
@@ -0,0 +1,165 @@
+/// This module serves a web UI of an encode, for watching the farm from a browser
+/// without the CLI: progress of the job, every node with its load and speed, a map of
+/// chunks by state, and errors of the latest failed attempts. Page and its assets are
+/// embedded in the binary, and poll `/api/status` for the state of the encode.
+use std::net::SocketAddr;
+
+use axum::extract::State;
+use axum::http::header::CONTENT_TYPE;
+use axum::response::{Html, IntoResponse};
+use axum::routing::get;
+use axum::Router;
+use hyper::server::conn::AddrIncoming;
+use serde::Serialize;
+use tokio::sync::watch;
+use tracing::{error, info, instrument};
+
+use crate::dashboard::DashboardView;
+use crate::error::VideoEncodeError;
+
+const INDEX_HTML: &str = include_str!("web/index.html");
+const APP_JS: &str = include_str!("web/app.js");
+const STYLE_CSS: &str = include_str!("web/style.css");
+
+/// State of a chunk in the chunk map
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ChunkState {
+    Pending,
+    Encoding,
+    Completed,
+}
+
+/// Chunk of the job as the chunk map shows it
+#[derive(Debug, Clone, Serialize)]
+pub struct ChunkCell {
+    pub index: usize,
+    pub state: ChunkState,
+    /// Node the chunk is encoded on, or was encoded on
+    pub node: Option<String>,
+    /// Failed attempts of the chunk
+    pub failures: usize,
+}
+
+/// Failed attempt of a chunk
+#[derive(Debug, Clone, Serialize)]
+pub struct RecentError {
+    /// Seconds since the Unix epoch the attempt failed at
+    pub time: u64,
+    pub chunk: usize,
+    pub node: String,
+    pub message: String,
+}
+
+/// Listener of the web UI, bound before the encode starts, so an address that is
+/// taken fails the encode right away
+pub struct WebServer {
+    incoming: AddrIncoming,
+}
+
+impl WebServer {
+    #[instrument]
+    pub fn bind(address: SocketAddr) -> Result<Self, VideoEncodeError> {
+        let incoming = AddrIncoming::bind(&address).map_err(|e| {
+            VideoEncodeError::Encoding(format!("Failed to serve web UI on {}: {}", address, e))
+        })?;
+        info!("Serving web UI on http://{}/", address);
+        Ok(WebServer { incoming })
+    }
+
+    /// Serves the page, and the latest status of `status` at `/api/status`, until the
+    /// task is aborted
+    pub async fn serve(self, status: watch::Receiver<String>) {
+        let app = Router::new()
+            .route("/", get(|| async { Html(INDEX_HTML) }))
+            .route(
+                "/app.js",
+                get(|| async { ([(CONTENT_TYPE, "text/javascript")], APP_JS) }),
+            )
+            .route(
+                "/style.css",
+                get(|| async { ([(CONTENT_TYPE, "text/css")], STYLE_CSS) }),
+            )
+            .route("/api/status", get(current_status))
+            .with_state(status);
+        if let Err(e) = axum::Server::builder(self.incoming)
+            .serve(app.into_make_service())
+            .await
+        {
+            error!("Web UI server failed: {}", e);
+        }
+    }
+}
+
+async fn current_status(State(status): State<watch::Receiver<String>>) -> impl IntoResponse {
+    let body = status.borrow().clone();
+    ([(CONTENT_TYPE, "application/json")], body)
+}
+
+#[derive(Serialize)]
+struct Status<'a> {
+    completed: usize,
+    pending: usize,
+    total: usize,
+    fraction: f64,
+    fps: f64,
+    /// Seconds until the job is encoded, if it can be estimated yet
+    eta: Option<f64>,
+    elapsed: f64,
+    retries: usize,
+    nodes: Vec<NodeStatus<'a>>,
+    chunks: &'a [ChunkCell],
+    errors: &'a [RecentError],
+}
+
+#[derive(Serialize)]
+struct NodeStatus<'a> {
+    address: &'a str,
+    slots: usize,
+    completed: usize,
+    failed: usize,
+    fps: f64,
+    upload_rate: f64,
+    download_rate: f64,
+    /// Chunks the node is encoding
+    chunks: Vec<usize>,
+}
+
+/// Status of the encode `view` shows, with its chunk map and latest errors, as JSON
+/// the page reads
+pub fn render_status(view: &DashboardView, chunks: &[ChunkCell], errors: &[RecentError]) -> String {
+    let nodes = view
+        .nodes
+        .iter()
+        .map(|node| {
+            let active = view
+                .chunks
+                .iter()
+                .filter(|chunk| chunk.node == node.address);
+            NodeStatus {
+                address: &node.address,
+                slots: node.slots,
+                completed: node.completed,
+                failed: node.failed,
+                fps: active.clone().map(|chunk| chunk.fps).sum(),
+                upload_rate: node.upload_rate,
+                download_rate: node.download_rate,
+                chunks: active.map(|chunk| chunk.index).collect(),
+            }
+        })
+        .collect();
+    let status = Status {
+        completed: view.completed,
+        pending: view.pending,
+        total: view.total,
+        fraction: view.fraction,
+        fps: view.fps,
+        eta: view.eta.map(|eta| eta.as_secs_f64()),
+        elapsed: view.elapsed.as_secs_f64(),
+        retries: view.retries,
+        nodes,
+        chunks,
+        errors,
+    };
+    serde_json::to_string(&status).unwrap_or_default()
+}
@@ -0,0 +1,100 @@
+// Polls status of the encode and draws it into the page
+const POLL_INTERVAL = 2000;
+
+function formatDuration(seconds) {
+  if (seconds === null || seconds === undefined) {
+    return "unknown";
+  }
+  seconds = Math.round(seconds);
+  const h = Math.floor(seconds / 3600);
+  const m = Math.floor((seconds % 3600) / 60);
+  const s = seconds % 60;
+  return h > 0 ? `${h}h ${m}m ${s}s` : m > 0 ? `${m}m ${s}s` : `${s}s`;
+}
+
+function formatRate(bytes) {
+  return `${(bytes / (1024 * 1024)).toFixed(1)} MiB/s`;
+}
+
+function cell(tag, text, className) {
+  const element = document.createElement(tag);
+  element.textContent = text;
+  if (className) {
+    element.className = className;
+  }
+  return element;
+}
+
+function row(values, classes = {}) {
+  const tr = document.createElement("tr");
+  values.forEach((value, i) => tr.appendChild(cell("td", value, classes[i])));
+  return tr;
+}
+
+function render(status) {
+  document.getElementById("progress").style.width = `${(status.fraction * 100).toFixed(1)}%`;
+  document.getElementById("summary").textContent =
+    `${(status.fraction * 100).toFixed(1)}%, ${status.completed}/${status.total} chunks, ` +
+    `${status.pending} pending, ${status.retries} retries, ${status.fps.toFixed(1)} fps, ` +
+    `elapsed ${formatDuration(status.elapsed)}, ETA ${formatDuration(status.eta)}`;
+
+  document.getElementById("nodes").replaceChildren(
+    ...status.nodes.map((node) =>
+      row([
+        node.address,
+        node.slots,
+        node.chunks.length ? node.chunks.join(", ") : "idle",
+        node.completed,
+        node.failed,
+        node.fps.toFixed(1),
+        formatRate(node.upload_rate),
+        formatRate(node.download_rate),
+      ])
+    )
+  );
+
+  document.getElementById("chunks").replaceChildren(
+    ...status.chunks.map((chunk) => {
+      const className = `cell ${chunk.state}${chunk.failures ? " retried" : ""}`;
+      const element = cell("span", "", className);
+      element.title =
+        `Chunk ${chunk.index}: ${chunk.state}` +
+        (chunk.node ? ` on ${chunk.node}` : "") +
+        (chunk.failures ? `, ${chunk.failures} failed attempts` : "");
+      return element;
+    })
+  );
+
+  document.getElementById("errors").replaceChildren(
+    ...status.errors
+      .slice()
+      .reverse()
+      .map((error) =>
+        row(
+          [
+            new Date(error.time * 1000).toLocaleTimeString(),
+            error.chunk,
+            error.node,
+            error.message,
+          ],
+          { 3: "error" }
+        )
+      )
+  );
+}
+
+async function poll() {
+  const connection = document.getElementById("connection");
+  try {
+    const response = await fetch("/api/status");
+    render(await response.json());
+    connection.textContent = `updated ${new Date().toLocaleTimeString()}`;
+    connection.className = "";
+  } catch (e) {
+    connection.textContent = "encode is not running";
+    connection.className = "lost";
+  }
+  setTimeout(poll, POLL_INTERVAL);
+}
+
+poll();
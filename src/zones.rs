@@ -0,0 +1,237 @@
+/// This module reads zones, which override encoder parameters for frame ranges of the
+/// input, so parts like credits or grainy scenes get their own settings within one job.
+/// Zones file has a zone per line, like av1an: first frame, frame after the last one,
+/// and parameters, e.g. `1000 1500 -crf 40`. Lines starting with `#` are comments.
+use std::path::Path;
+
+use tracing::{info, instrument, warn};
+
+use crate::chunk::Chunk;
+use crate::error::VideoEncodeError;
+
+/// Frames of the input from `start` to `end` exclusive, encoded with `params`
+/// added to encoder parameters. Options of `params` replace the same options.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Zone {
+    pub start: usize,
+    pub end: usize,
+    pub params: Vec<String>,
+}
+
+/// Reads zones from file, sorted by their start
+#[instrument]
+pub fn read_zones(path: &Path) -> Result<Vec<Zone>, VideoEncodeError> {
+    let content = std::fs::read_to_string(path)?;
+    let invalid = |line: usize, reason: &str| {
+        VideoEncodeError::Encoding(format!(
+            "Invalid zone on line {} of {:?}: {}",
+            line + 1,
+            path,
+            reason
+        ))
+    };
+
+    let mut zones = Vec::new();
+    for (number, line) in content.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+
+        let mut fields = line.split_whitespace();
+        let mut frame = || -> Result<usize, VideoEncodeError> {
+            fields
+                .next()
+                .and_then(|field| field.parse().ok())
+                .ok_or_else(|| invalid(number, "expected first and end frame"))
+        };
+        let (start, end) = (frame()?, frame()?);
+        if start >= end {
+            return Err(invalid(number, "end frame has to follow first frame"));
+        }
+        let params: Vec<String> = fields.map(str::to_string).collect();
+        if params.is_empty() {
+            return Err(invalid(number, "zone has no parameters"));
+        }
+
+        zones.push(Zone { start, end, params });
+    }
+
+    zones.sort_by_key(|zone| zone.start);
+    if let Some(pair) = zones.windows(2).find(|pair| pair[0].end > pair[1].start) {
+        return Err(VideoEncodeError::Encoding(format!(
+            "Zones {}-{} and {}-{} of {:?} overlap",
+            pair[0].start, pair[0].end, pair[1].start, pair[1].end, path
+        )));
+    }
+
+    info!("Read {} zones from {:?}", zones.len(), path);
+    Ok(zones)
+}
+
+/// Frames at which zones start or end, so chunks can be split there
+pub fn zone_boundaries(zones: &[Zone]) -> Vec<usize> {
+    let mut boundaries: Vec<usize> = zones
+        .iter()
+        .flat_map(|zone| [zone.start, zone.end])
+        .filter(|&frame| frame > 0)
+        .collect();
+    boundaries.sort_unstable();
+    boundaries.dedup();
+    boundaries
+}
+
+/// Timestamps in seconds at which zones start or end, from timestamps of frames
+/// of the input. Boundaries past the last frame are dropped.
+pub fn zone_splits(zones: &[Zone], frame_times: &[f64]) -> Vec<f64> {
+    zone_boundaries(zones)
+        .into_iter()
+        .filter_map(|frame| frame_times.get(frame).copied())
+        .collect()
+}
+
+/// Adds parameters of zones to chunks in zones. Chunks are placed by frame counts
+/// of their metadata, and chunk that spans zone boundary, because the input couldn't
+/// be split there, gets parameters of the zone that covers most of it.
+pub fn apply_zones(chunks: &mut [Chunk], zones: &[Zone]) -> Result<(), VideoEncodeError> {
+    let boundaries = zone_boundaries(zones);
+    let mut start = 0;
+    for chunk in chunks.iter_mut() {
+        let frames = chunk
+            .metadata
+            .as_ref()
+            .map(|metadata| metadata.frames as usize)
+            .filter(|&frames| frames > 0)
+            .ok_or_else(|| {
+                VideoEncodeError::Encoding(format!(
+                    "Frame count of chunk {} is not known, so zones can't be applied",
+                    chunk.index
+                ))
+            })?;
+        let end = start + frames;
+
+        if let Some(frame) = boundaries
+            .iter()
+            .find(|&&frame| frame > start && frame < end)
+        {
+            warn!(
+                "Chunk {} of frames {}-{} crosses zone boundary at frame {}, split input \
+                 with --lossless-intermediate to split it exactly",
+                chunk.index, start, end, frame
+            );
+        }
+
+        let overlap = |zone: &Zone| zone.end.min(end).saturating_sub(zone.start.max(start));
+        if let Some(zone) = zones
+            .iter()
+            .filter(|zone| overlap(zone) * 2 > frames)
+            .max_by_key(|zone| overlap(zone))
+        {
+            chunk.encoder_parameters = override_params(&chunk.encoder_parameters, &zone.params);
+        }
+        start = end;
+    }
+
+    Ok(())
+}
+
+/// Appends `overrides` to `params`, removing options that they set again.
+/// Value of an option is the following argument that isn't an option itself.
+pub fn override_params(params: &[String], overrides: &[String]) -> Vec<String> {
+    let options = split_options(overrides);
+    let mut result: Vec<String> = split_options(params)
+        .into_iter()
+        .filter(|option| {
+            !option[0].starts_with('-')
+                || !options.iter().any(|override_| override_[0] == option[0])
+        })
+        .flatten()
+        .cloned()
+        .collect();
+    result.extend(overrides.iter().cloned());
+    result
+}
+
+/// Groups arguments into options with their values
+fn split_options(args: &[String]) -> Vec<&[String]> {
+    let is_option = |arg: &String| arg.starts_with('-') && arg.parse::<f64>().is_err();
+
+    let mut options = Vec::new();
+    let mut start = 0;
+    while start < args.len() {
+        let end = match args.get(start + 1) {
+            Some(next) if is_option(&args[start]) && !is_option(next) => start + 2,
+            _ => start + 1,
+        };
+        options.push(&args[start..end]);
+        start = end;
+    }
+    options
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn zones_file(content: &str) -> tempfile::NamedTempFile {
+        let file = tempfile::NamedTempFile::new().unwrap();
+        std::fs::write(file.path(), content).unwrap();
+        file
+    }
+
+    fn args(args: &[&str]) -> Vec<String> {
+        args.iter().map(|arg| arg.to_string()).collect()
+    }
+
+    #[test]
+    fn read_zones_sorts_zones_and_skips_comments() {
+        let file = zones_file("# credits\n\n2000 2500 --crf 40\n0 100 --preset 8\n");
+        let zones = read_zones(file.path()).unwrap();
+        assert_eq!(
+            zones,
+            vec![
+                Zone {
+                    start: 0,
+                    end: 100,
+                    params: args(&["--preset", "8"]),
+                },
+                Zone {
+                    start: 2000,
+                    end: 2500,
+                    params: args(&["--crf", "40"]),
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn read_zones_rejects_invalid_zones() {
+        for content in [
+            "100 50 --crf 40",
+            "0 100",
+            "0 --crf 40",
+            "0 100 --crf 40\n50 150 --crf 30",
+        ] {
+            let file = zones_file(content);
+            assert!(read_zones(file.path()).is_err(), "{:?} was read", content);
+        }
+    }
+
+    #[test]
+    fn override_params_replaces_options_set_again() {
+        let params = args(&["--preset", "6", "--crf", "30", "--tune", "0"]);
+        assert_eq!(
+            override_params(&params, &args(&["--crf", "40"])),
+            args(&["--preset", "6", "--tune", "0", "--crf", "40"])
+        );
+    }
+
+    #[test]
+    fn override_params_keeps_negative_values_with_their_options() {
+        let params = args(&["-crf", "30", "-g", "-1"]);
+        assert_eq!(
+            override_params(&params, &args(&["-g", "240"])),
+            args(&["-crf", "30", "-g", "240"])
+        );
+    }
+}